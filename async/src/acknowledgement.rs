@@ -1,22 +1,39 @@
 use parking_lot::Mutex;
 
 use std::{
-  collections::HashSet,
+  collections::{HashMap, HashSet},
   sync::Arc,
 };
 
-use crate::error::{Error, ErrorKind};
+use crate::{
+  error::{Error, ErrorKind},
+  message::BasicReturnMessage,
+};
 
 pub type DeliveryTag = u64;
 
+/// The outcome of a message published on a channel in confirm mode
+#[derive(Clone, Debug, PartialEq)]
+#[allow(clippy::large_enum_variant)]
+pub enum Confirmation {
+  /// the server took responsibility for the message
+  Ack,
+  /// the server couldn't take responsibility for the message
+  Nack,
+  /// the message couldn't be routed and was returned by the server before being ack'd
+  Returned(BasicReturnMessage),
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct Acknowledgements {
   inner: Arc<Mutex<Inner>>,
 }
 
 impl Acknowledgements {
-  pub fn register_pending(&self, delivery_tag: DeliveryTag) {
-    self.inner.lock().pending.insert(delivery_tag);
+  /// `exchange` and `routing_key` are the ones the message was published to, for
+  /// `next_returned`
+  pub fn register_pending(&self, delivery_tag: DeliveryTag, exchange: &str, routing_key: &str) {
+    self.inner.lock().pending.insert(delivery_tag, (exchange.to_string(), routing_key.to_string()));
   }
 
   pub fn ack(&self, delivery_tag: DeliveryTag) -> Result<(), Error> {
@@ -30,14 +47,14 @@ impl Acknowledgements {
   pub fn ack_all_pending(&self) {
     let mut inner = self.inner.lock();
     for tag in inner.drain_pending() {
-      inner.acked.insert(tag);
+      inner.confirm(tag, Confirmation::Ack);
     }
   }
 
   pub fn nack_all_pending(&self) {
     let mut inner = self.inner.lock();
     for tag in inner.drain_pending() {
-      inner.nacked.insert(tag);
+      inner.confirm(tag, Confirmation::Nack);
     }
   }
 
@@ -57,48 +74,95 @@ impl Acknowledgements {
     Ok(())
   }
 
-  pub fn is_acked(&self, delivery_tag: DeliveryTag) -> bool {
-    self.inner.lock().acked.remove(&delivery_tag)
+  /// the number of published messages the server didn't confirm yet
+  pub fn pending_count(&self) -> usize {
+    self.inner.lock().pending.len()
   }
 
-  pub fn is_nacked(&self, delivery_tag: DeliveryTag) -> bool {
-    self.inner.lock().nacked.remove(&delivery_tag)
+  /// the delivery tag of a message the server returned from `exchange` with `routing_key`: the
+  /// server handles the messages in order, so it's the oldest pending one published there and
+  /// not returned yet, the messages published elsewhere can still wait for their confirmation
+  pub fn next_returned(&self, exchange: &str, routing_key: &str) -> Option<DeliveryTag> {
+    let mut inner = self.inner.lock();
+    let delivery_tag = inner.pending.iter()
+      .filter(|(tag, (e, r))| e == exchange && r == routing_key && !inner.returned.contains(tag))
+      .map(|(tag, _)| *tag)
+      .min();
+    if let Some(delivery_tag) = delivery_tag {
+      inner.returned.insert(delivery_tag);
+    }
+    delivery_tag
+  }
+
+  /// attaches a returned message to the ack of the given delivery_tag
+  pub fn set_returned(&self, delivery_tag: DeliveryTag, message: BasicReturnMessage) {
+    if let Some(confirmation) = self.inner.lock().confirmed.get_mut(&delivery_tag) {
+      if *confirmation == Confirmation::Ack {
+        *confirmation = Confirmation::Returned(message);
+      }
+    }
+  }
+
+  /// returns the confirmation for this delivery_tag if the server sent it, forgetting it
+  pub fn take_confirmation(&self, delivery_tag: DeliveryTag) -> Option<Confirmation> {
+    self.inner.lock().confirmed.remove(&delivery_tag)
+  }
+
+  /// stops waiting for the confirmation of this delivery_tag: it's dropped if the server
+  /// already sent it, or when it comes
+  pub fn forget(&self, delivery_tag: DeliveryTag) {
+    let mut inner = self.inner.lock();
+    if inner.confirmed.remove(&delivery_tag).is_none() && inner.pending.contains_key(&delivery_tag) {
+      inner.forgotten.insert(delivery_tag);
+    }
   }
 }
 
 #[derive(Debug, Default)]
 struct Inner {
-  pending: HashSet<DeliveryTag>,
-  acked:   HashSet<DeliveryTag>,
-  nacked:  HashSet<DeliveryTag>,
+  // with the exchange and routing key they were published to
+  pending:   HashMap<DeliveryTag, (String, String)>,
+  confirmed: HashMap<DeliveryTag, Confirmation>,
+  // the pending messages the server returned
+  returned:  HashSet<DeliveryTag>,
+  // the pending messages nobody waits for the confirmation of anymore
+  forgotten: HashSet<DeliveryTag>,
 }
 
 impl Inner {
   fn drop_pending(&mut self, delivery_tag: DeliveryTag) -> Result<(), Error> {
-    if self.pending.remove(&delivery_tag) {
+    if self.pending.remove(&delivery_tag).is_some() {
+      self.returned.remove(&delivery_tag);
       Ok(())
     } else {
       Err(ErrorKind::PreconditionFailed.into())
     }
   }
 
+  fn confirm(&mut self, delivery_tag: DeliveryTag, confirmation: Confirmation) {
+    if !self.forgotten.remove(&delivery_tag) {
+      self.confirmed.insert(delivery_tag, confirmation);
+    }
+  }
+
   fn ack(&mut self, delivery_tag: DeliveryTag) -> Result<(), Error> {
     self.drop_pending(delivery_tag)?;
-    self.acked.insert(delivery_tag);
+    self.confirm(delivery_tag, Confirmation::Ack);
     Ok(())
   }
 
   fn nack(&mut self, delivery_tag: DeliveryTag) -> Result<(), Error> {
     self.drop_pending(delivery_tag)?;
-    self.nacked.insert(delivery_tag);
+    self.confirm(delivery_tag, Confirmation::Nack);
     Ok(())
   }
 
   fn drain_pending(&mut self) -> HashSet<DeliveryTag> {
-    self.pending.drain().collect()
+    self.returned.clear();
+    self.pending.drain().map(|(tag, _)| tag).collect()
   }
 
   fn list_pending_before(&mut self, delivery_tag: DeliveryTag) -> HashSet<DeliveryTag> {
-    self.pending.iter().filter(|tag| **tag <= delivery_tag).cloned().collect()
+    self.pending.keys().filter(|tag| **tag <= delivery_tag).cloned().collect()
   }
 }
@@ -1,11 +1,18 @@
 pub use amq_protocol::protocol::{self, BasicProperties};
 
 use amq_protocol::{
-  protocol::{AMQPClass, AMQPError, AMQPSoftError},
-  frame::{AMQPContentHeader, AMQPFrame},
+  protocol::{AMQPClass, AMQPError, AMQPHardError, AMQPSoftError},
+  frame::{AMQPContentHeader, AMQPFrame, GenError, gen_frame},
 };
 use either::Either;
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
+use parking_lot::Mutex;
+
+use std::{
+  cmp,
+  collections::VecDeque,
+  sync::Arc,
+};
 
 use crate::{
   acknowledgement::{Acknowledgements, DeliveryTag},
@@ -13,16 +20,18 @@ use crate::{
   connection::Connection,
   connection_status::{ConnectionState, ConnectingState},
   consumer::{Consumer, ConsumerSubscriber},
-  error::{Error, ErrorKind},
+  error::{CloseReason, Error, ErrorKind},
   generated_names::GeneratedNames,
   id_sequence::IdSequence,
   message::{BasicGetMessage, BasicReturnMessage, Delivery},
+  message_counts::MessageCounts,
   queue::Queue,
   queues::Queues,
   replies::Replies,
   requests::{Requests, RequestId},
   returned_messages::ReturnedMessages,
   types::*,
+  unacked_deliveries::UnackedDeliveries,
 };
 
 #[derive(Clone, Debug)]
@@ -37,7 +46,17 @@ pub struct Channel {
   pub requests:          Requests,
   pub queues:            Queues,
   pub generated_names:   GeneratedNames,
-      returned_messages: ReturnedMessages,
+  pub message_counts:    MessageCounts,
+  pub returned_messages: ReturnedMessages,
+  pub unacked_deliveries: UnackedDeliveries,
+      // content frames waiting for the server to re-enable the flow
+      paused_frames:     Arc<Mutex<VecDeque<AMQPFrame>>>,
+      // the last delivery tag the server used on this channel
+      received_delivery_tag: Arc<Mutex<DeliveryTag>>,
+      // held while publishing, for the delivery tags to follow the order of the frames
+      publish_lock:      Arc<Mutex<()>>,
+      // the size of the body still to be streamed by send_body_chunk
+      streamed_body:     Arc<Mutex<Option<u64>>>,
 }
 
 impl Channel {
@@ -53,7 +72,13 @@ impl Channel {
       requests:          Requests::default(),
       queues:            Queues::default(),
       generated_names:   GeneratedNames::default(),
+      message_counts:    MessageCounts::default(),
       returned_messages: ReturnedMessages::default(),
+      unacked_deliveries: UnackedDeliveries::default(),
+      paused_frames:     Arc::new(Mutex::new(VecDeque::new())),
+      received_delivery_tag: Arc::new(Mutex::new(0)),
+      publish_lock:      Arc::new(Mutex::new(())),
+      streamed_body:     Arc::new(Mutex::new(None)),
     }
   }
 
@@ -71,6 +96,41 @@ impl Channel {
     self.connection.channels.remove(self.id)
   }
 
+  /// closes the connection with an `UNEXPECTED_FRAME` hard error instead of mixing an
+  /// out-of-sequence frame into the content being received or sent, failing the pending requests
+  /// of all the channels
+  ///
+  /// the channel is put in error with a close reason describing the frame, but it stays
+  /// registered, so that its id isn't reused, until the server answers with Connection.CloseOk
+  fn unexpected_frame(&self, description: String) -> Result<(), Error> {
+    error!("channel {}: {}", self.id, description);
+    let reply_code = AMQPHardError::UNEXPECTEDFRAME.get_id();
+    self.abort_pending(CloseReason {
+      reply_code,
+      reply_text: description.clone(),
+      class_id:   0,
+      method_id:  0,
+    });
+    self.connection.close(reply_code, &description)?;
+    self.status.set_state(ChannelState::Error);
+    Ok(())
+  }
+
+  // Content frames have to follow the method announcing them, nothing can come in between
+  pub(crate) fn check_method_sequence(&self, method: &AMQPClass) -> Result<bool, Error> {
+    let description = match self.status.state() {
+      ChannelState::WillReceiveContent(..) | ChannelState::ReceivingContent(..) => format!("received {:?} while expecting content frames", method),
+      ChannelState::Initial => match method {
+        AMQPClass::Basic(protocol::basic::AMQPMethod::Deliver(_)) |
+        AMQPClass::Basic(protocol::basic::AMQPMethod::GetOk(_))   |
+        AMQPClass::Basic(protocol::basic::AMQPMethod::Return(_))  => format!("received {:?} before the channel was opened", method),
+        _                                                         => return Ok(true),
+      },
+      _ => return Ok(true),
+    };
+    self.unexpected_frame(description).map(|_| false)
+  }
+
   pub fn id(&self) -> u16 {
     self.id
   }
@@ -85,24 +145,69 @@ impl Channel {
     self.connection.send_frame(frame);
   }
 
-  fn send_content_frames(&self, class_id: u16, slice: &[u8], properties: BasicProperties) {
-    let header = AMQPContentHeader {
-      class_id,
-      weight:    0,
-      body_size: slice.len() as u64,
-      properties,
-    };
-    self.send_frame(AMQPFrame::Header(self.id, class_id, Box::new(header)));
+  fn send_method_frame_with_body(&self, method: AMQPClass, header: AMQPContentHeader, slice: &[u8]) {
+    let mut frames = vec![
+      AMQPFrame::Method(self.id, method),
+      AMQPFrame::Header(self.id, header.class_id, Box::new(header)),
+    ];
 
+    frames.extend(self.body_frames(slice));
+    self.send_content_frames(frames);
+  }
+
+  /// a content header can't be split across frames: make sure it fits in the frame_max
+  /// instead of having the server close the connection
+  ///
+  /// only the headers table can make it grow that much, the other properties are short
+  fn check_content_header(&self, header: &AMQPContentHeader) -> Result<(), Error> {
+    let frame_max = self.connection.configuration.frame_max() as usize;
+    if header.properties.headers().is_none() || frame_max == 0 {
+      return Ok(());
+    }
+    let frame = AMQPFrame::Header(self.id, header.class_id, Box::new(header.clone()));
+    let mut buffer = vec![0; cmp::min(4096, frame_max)];
+    loop {
+      let len = buffer.len();
+      match gen_frame((&mut buffer, 0), &frame).map(|_| ()) {
+        Ok(()) => return Ok(()),
+        Err(GenError::BufferTooSmall(_)) if len < frame_max => buffer.resize(cmp::min(len * 2, frame_max), 0),
+        Err(GenError::BufferTooSmall(_)) => return Err(ErrorKind::ContentHeaderTooLarge(frame_max as u32).into()),
+        Err(e) => return Err(ErrorKind::SerialisationError(e).into()),
+      }
+    }
+  }
+
+  fn body_frames(&self, slice: &[u8]) -> Vec<AMQPFrame> {
     let frame_max = self.connection.configuration.frame_max();
     //a content body frame 8 bytes of overhead
-    for chunk in slice.chunks(frame_max as usize - 8) {
-      self.send_frame(AMQPFrame::Body(self.id, Vec::from(chunk)));
+    slice.chunks(frame_max as usize - 8).map(|chunk| AMQPFrame::Body(self.id, Vec::from(chunk))).collect()
+  }
+
+  fn send_content_frames(&self, frames: Vec<AMQPFrame>) {
+    let mut paused_frames = self.paused_frames.lock();
+    if self.status.can_send() && paused_frames.is_empty() {
+      self.connection.send_frames(frames);
+    } else {
+      trace!("channel {} flow is paused, delaying content frames", self.id);
+      paused_frames.extend(frames);
+    }
+  }
+
+  fn set_send_flow(&self, active: bool) {
+    let mut paused_frames = self.paused_frames.lock();
+    self.status.set_send_flow(active);
+    if active && !paused_frames.is_empty() {
+      trace!("channel {} flow is active again, sending {} delayed frames", self.id, paused_frames.len());
+      self.connection.send_frames(paused_frames.drain(..).collect());
     }
   }
 
   #[doc(hidden)]
   pub fn handle_content_header_frame(&self, size: u64, properties: BasicProperties) -> Result<(), Error> {
+    if self.status.is_closing() {
+      trace!("channel {} is closing, discarding content header frame", self.id);
+      return Ok(());
+    }
     if let ChannelState::WillReceiveContent(queue_name, request_id_or_consumer_tag) = self.status.state() {
       if size > 0 {
         self.status.set_state(ChannelState::ReceivingContent(queue_name.clone(), request_id_or_consumer_tag.clone(), size as usize));
@@ -112,19 +217,23 @@ impl Channel {
       if let Some(queue_name) = queue_name {
         self.queues.handle_content_header_frame(&queue_name, request_id_or_consumer_tag, size, properties);
       } else {
-        self.returned_messages.set_delivery_properties(properties);
+        self.returned_messages.receive_delivery_header(size, properties);
         if size == 0 {
-          self.returned_messages.new_delivery_complete();
+          self.returned_messages.new_delivery_complete(self.status.confirm());
         }
       }
       Ok(())
     } else {
-      self.set_error()
+      self.unexpected_frame(format!("received a content header frame while {:?}", self.status.state()))
     }
   }
 
   #[doc(hidden)]
   pub fn handle_body_frame(&self, payload: Vec<u8>) -> Result<(), Error> {
+    if self.status.is_closing() {
+      trace!("channel {} is closing, discarding content body frame", self.id);
+      return Ok(());
+    }
     let payload_size = payload.len();
 
     if let ChannelState::ReceivingContent(queue_name, request_id_or_consumer_tag, remaining_size) = self.status.state() {
@@ -134,7 +243,7 @@ impl Channel {
         } else {
           self.returned_messages.receive_delivery_content(payload);
           if remaining_size == payload_size {
-            self.returned_messages.new_delivery_complete();
+            self.returned_messages.new_delivery_complete(self.status.confirm());
           }
         }
         if remaining_size == payload_size {
@@ -144,11 +253,10 @@ impl Channel {
         }
         Ok(())
       } else {
-        error!("body frame too large");
-        self.set_error()
+        self.unexpected_frame(format!("received a content body frame of {} bytes while expecting {} more bytes", payload_size, remaining_size))
       }
     } else {
-        self.set_error()
+      self.unexpected_frame(format!("received a content body frame while {:?}", self.status.state()))
     }
   }
 
@@ -157,18 +265,16 @@ impl Channel {
     Err(error)
   }
 
-  fn on_connection_start_ok_sent(&self) -> Result<(), Error> {
-    self.connection.status.set_connecting_state(ConnectingState::SentStartOk);
-    Ok(())
-  }
-
   fn on_connection_open_sent(&self) -> Result<(), Error> {
     self.connection.status.set_connecting_state(ConnectingState::SentOpen);
     Ok(())
   }
 
-  fn on_connection_close_sent(&self) -> Result<(), Error> {
+  fn on_connection_close_sent(&self, reply_code: ShortUInt, reply_text: &str, class_id: ShortUInt, method_id: ShortUInt) -> Result<(), Error> {
+    let reason = CloseReason { reply_code, reply_text: reply_text.to_string(), class_id, method_id };
     self.connection.set_closing();
+    self.connection.status.set_close_reason(reason.clone());
+    self.connection.channels.abort_pending(reason);
     Ok(())
   }
 
@@ -176,34 +282,248 @@ impl Channel {
     self.connection.set_closed()
   }
 
-  fn on_channel_close_sent(&self) -> Result<(), Error> {
+  fn on_channel_close_sent(&self, reply_code: ShortUInt, reply_text: &str, class_id: ShortUInt, method_id: ShortUInt) -> Result<(), Error> {
     self.set_closing();
+    self.abort_pending(CloseReason { reply_code, reply_text: reply_text.to_string(), class_id, method_id });
     Ok(())
   }
 
   fn on_channel_close_ok_sent(&self) -> Result<(), Error> {
-    self.set_closed()
+    // its consumers are gone, it's not reopened on a new connection
+    self.connection.topology.channel_closed(self.id);
+    // we only ever send a CloseOk when the server closes the channel, which it does on errors
+    if self.status.close_reason().and_then(|reason| AMQPError::from_id(reason.reply_code)).is_some() {
+      self.set_error()
+    } else {
+      self.set_closed()
+    }
+  }
+
+  // the server won't answer to anything we sent before the Close, fail all the pending requests
+  // and drop the content frames we didn't get to send
+  pub(crate) fn abort_pending(&self, reason: CloseReason) {
+    self.status.set_close_reason(reason);
+    while let Some(reply) = self.replies.next() {
+      trace!("channel {} is closing, failing request {:?}", self.id, reply);
+      self.requests.finish(reply.request_id(), false);
+    }
+    let mut paused_frames = self.paused_frames.lock();
+    if !paused_frames.is_empty() {
+      trace!("channel {} is closing, dropping {} delayed frames", self.id, paused_frames.len());
+      paused_frames.clear();
+    }
+    *self.streamed_body.lock() = None;
+    self.unacked_deliveries.clear();
+  }
+
+  // RabbitMQ 3.0 and later close the connection with a NOT_IMPLEMENTED error on `immediate`
+  fn before_basic_publish(&self, immediate: bool) -> Result<(), Error> {
+    if immediate {
+      if self.connection.configuration.reject_immediate() {
+        return Err(ErrorKind::ImmediateRejected.into());
+      }
+      warn!("publishing with the immediate flag on channel {}, RabbitMQ 3.0 and later close the connection for it", self.id);
+    }
+    Ok(())
   }
 
-  fn on_basic_publish_sent(&self, class_id: u16, payload: Vec<u8>, properties: BasicProperties) -> Result<Option<DeliveryTag>, Error> {
+  fn on_basic_publish_sent(&self, method: AMQPClass, class_id: u16, payload: Vec<u8>, properties: BasicProperties) -> Result<Option<DeliveryTag>, Error> {
+    // the server numbers the messages in the order it receives them, don't let a concurrent
+    // publish take the next tag and queue its frames before ours
+    let _publish = self.publish_lock.lock();
+    if self.streamed_body.lock().is_some() {
+      return Err(ErrorKind::PublishInProgress(self.id).into());
+    }
+    let header = AMQPContentHeader {
+      class_id,
+      weight:    0,
+      body_size: payload.len() as u64,
+      properties,
+    };
+    self.check_content_header(&header)?;
     let delivery_tag = if self.status.confirm() {
       let delivery_tag = self.delivery_tag.next();
-      self.acknowledgements.register_pending(delivery_tag);
+      if let AMQPClass::Basic(protocol::basic::AMQPMethod::Publish(ref publish)) = method {
+        self.acknowledgements.register_pending(delivery_tag, &publish.exchange, &publish.routing_key);
+      }
       Some(delivery_tag)
     } else {
       None
     };
 
-    self.send_content_frames(class_id, payload.as_slice(), properties);
+    self.send_method_frame_with_body(method, header, payload.as_slice());
+    self.connection.metrics.record(|hooks| hooks.published(self.id));
     Ok(delivery_tag)
   }
 
+  /// the delivery tag the next message published on this confirm channel will get, 0 if the
+  /// channel isn't in confirm mode
+  ///
+  /// the server numbers the messages in the order it receives them, a concurrent publish on
+  /// another clone of this channel can take this tag first.
+  pub fn next_publish_seq_no(&self) -> DeliveryTag {
+    if self.status.confirm() {
+      self.delivery_tag.peek()
+    } else {
+      0
+    }
+  }
+
+  /// publishes a message whose body of `body_size` bytes is then sent with `send_body_chunk`
+  ///
+  /// The body frames of a message can't be mixed with other ones on the channel: the other
+  /// publishes fail with `ErrorKind::PublishInProgress` until the whole body is sent.
+  pub fn basic_publish_header(&self, exchange: &str, routing_key: &str, options: BasicPublishOptions, body_size: u64, properties: BasicProperties) -> Result<Option<DeliveryTag>, Error> {
+    if !self.status.is_connected() {
+      return Err(ErrorKind::NotConnected.into());
+    }
+    self.before_basic_publish(options.immediate)?;
+
+    let _publish = self.publish_lock.lock();
+    let mut streamed_body = self.streamed_body.lock();
+    if streamed_body.is_some() {
+      return Err(ErrorKind::PublishInProgress(self.id).into());
+    }
+    let header = AMQPContentHeader {
+      class_id:  60,
+      weight:    0,
+      body_size,
+      properties,
+    };
+    self.check_content_header(&header)?;
+    let delivery_tag = if self.status.confirm() {
+      let delivery_tag = self.delivery_tag.next();
+      self.acknowledgements.register_pending(delivery_tag, exchange, routing_key);
+      Some(delivery_tag)
+    } else {
+      None
+    };
+
+    let BasicPublishOptions { mandatory, immediate } = options;
+    let method = AMQPClass::Basic(protocol::basic::AMQPMethod::Publish(protocol::basic::Publish {
+      exchange:    exchange.to_string(),
+      routing_key: routing_key.to_string(),
+      mandatory,
+      immediate,
+    }));
+    self.send_content_frames(vec![
+      AMQPFrame::Method(self.id, method),
+      AMQPFrame::Header(self.id, 60, Box::new(header)),
+    ]);
+    if body_size > 0 {
+      *streamed_body = Some(body_size);
+    }
+    self.connection.metrics.record(|hooks| hooks.published(self.id));
+    Ok(delivery_tag)
+  }
+
+  /// sends the next part of the body announced by `basic_publish_header`, split according to
+  /// the frame_max
+  pub fn send_body_chunk(&self, chunk: &[u8]) -> Result<(), Error> {
+    let mut streamed_body = self.streamed_body.lock();
+    let remaining = match *streamed_body {
+      Some(remaining) if chunk.len() as u64 <= remaining => remaining - chunk.len() as u64,
+      _                                                  => return Err(ErrorKind::UnexpectedBodyChunk(chunk.len()).into()),
+    };
+    self.send_content_frames(self.body_frames(chunk));
+    *streamed_body = if remaining > 0 { Some(remaining) } else { None };
+    Ok(())
+  }
+
+  /// the size of the body still to be sent with `send_body_chunk`, if any
+  pub fn remaining_body_size(&self) -> Option<u64> {
+    *self.streamed_body.lock()
+  }
+
+  /// gives up on the body announced by `basic_publish_header`, e.g. because its source failed
+  ///
+  /// the server expects the rest of the body before anything else on this channel, so the
+  /// connection gets closed with an `UNEXPECTED_FRAME` error and the channel is put in error,
+  /// failing the pending requests
+  pub fn abort_streamed_body(&self) -> Result<(), Error> {
+    let remaining = self.streamed_body.lock().take();
+    if let Some(remaining) = remaining {
+      self.unexpected_frame(format!("the streamed body was aborted with {} bytes left to send", remaining))
+    } else {
+      Ok(())
+    }
+  }
+
+  /// stops waiting for the answer to a request, e.g. after a timeout
+  ///
+  /// The answer is still expected from the server, so that it doesn't get mistaken for the one
+  /// of a later request, but it's dropped when it comes.
+  pub fn abandon_request(&self, request_id: RequestId) {
+    trace!("channel {} abandons request {}", self.id, request_id);
+    self.requests.abandon(request_id);
+    self.generated_names.get(request_id);
+    self.message_counts.get(request_id);
+  }
+
+  fn on_basic_recover_sent(&self) -> Result<(), Error> {
+    // the unacked deliveries will come again with new delivery tags
+    self.unacked_deliveries.clear();
+    Ok(())
+  }
+
   fn on_basic_recover_async_sent(&self) -> Result<(), Error> {
     self.queues.drop_prefetched_messages();
+    self.unacked_deliveries.clear();
     Ok(())
   }
 
+  fn before_basic_ack(&self, multiple: bool, delivery_tag: DeliveryTag) -> Result<(), Error> {
+    self.check_settleable(delivery_tag, multiple)
+  }
+
+  fn before_basic_nack(&self, multiple: bool, delivery_tag: DeliveryTag) -> Result<(), Error> {
+    self.check_settleable(delivery_tag, multiple)
+  }
+
+  fn before_basic_reject(&self, delivery_tag: DeliveryTag) -> Result<(), Error> {
+    self.check_settleable(delivery_tag, false)
+  }
+
+  // The server closes the channel when a single delivery it doesn't expect an ack for is settled
+  fn check_settleable(&self, delivery_tag: DeliveryTag, multiple: bool) -> Result<(), Error> {
+    if multiple {
+      return Ok(());
+    }
+    // The server uses increasing delivery tags, anything else cannot match a delivery
+    if delivery_tag == 0 || delivery_tag > *self.received_delivery_tag.lock() {
+      Err(ErrorKind::InvalidDeliveryTag(delivery_tag).into())
+    } else if !self.unacked_deliveries.is_outstanding(delivery_tag) {
+      Err(ErrorKind::NotAwaitingAck(delivery_tag).into())
+    } else {
+      Ok(())
+    }
+  }
+
+  fn on_delivery_received(&self, delivery_tag: DeliveryTag, no_ack: bool) {
+    self.connection.metrics.record(|hooks| hooks.delivered(self.id));
+    let mut received_delivery_tag = self.received_delivery_tag.lock();
+    if delivery_tag > *received_delivery_tag {
+      *received_delivery_tag = delivery_tag;
+    }
+    if !no_ack {
+      self.unacked_deliveries.register(delivery_tag);
+    }
+  }
+
+  fn settle_delivery(&self, delivery_tag: DeliveryTag, multiple: bool) {
+    if !self.unacked_deliveries.settle(delivery_tag, multiple) {
+      warn!("channel {}: delivery {} was already acknowledged or was never received", self.id, delivery_tag);
+    }
+  }
+
   fn on_basic_ack_sent(&self, multiple: bool, delivery_tag: DeliveryTag) -> Result<(), Error> {
+    let unacked = self.unacked_deliveries.count();
+    self.settle_delivery(delivery_tag, multiple);
+    // only the deliveries which were waiting for an ack count
+    let count = unacked - self.unacked_deliveries.count();
+    if count != 0 {
+      self.connection.metrics.record(|hooks| hooks.acked(self.id, count as u64));
+    }
     if multiple && delivery_tag == 0 {
       self.queues.drop_prefetched_messages();
     }
@@ -211,12 +531,18 @@ impl Channel {
   }
 
   fn on_basic_nack_sent(&self, multiple: bool, delivery_tag: DeliveryTag) -> Result<(), Error> {
+    self.settle_delivery(delivery_tag, multiple);
     if multiple && delivery_tag == 0 {
       self.queues.drop_prefetched_messages();
     }
     Ok(())
   }
 
+  fn on_basic_reject_sent(&self, delivery_tag: DeliveryTag) -> Result<(), Error> {
+    self.settle_delivery(delivery_tag, false);
+    Ok(())
+  }
+
   fn tune_connection_configuration(&self, channel_max: u16, frame_max: u32, heartbeat: u16) {
     // If we disable the heartbeat (0) but the server don't, follow him and enable it too
     // If both us and the server want heartbeat enabled, pick the lowest value.
@@ -251,14 +577,15 @@ impl Channel {
     trace!("Server sent connection::Start: {:?}", method);
     let state = self.connection.status.state();
     if let ConnectionState::Connecting(ConnectingState::SentProtocolHeader(credentials, mut options)) = state {
+      self.connection.configuration.set_server_properties(method.server_properties);
       let mechanism = options.mechanism.to_string();
       let locale    = options.locale.clone();
 
       if !method.mechanisms.split_whitespace().any(|m| m == mechanism) {
-        error!("unsupported mechanism: {}", mechanism);
+        error!("connection: unsupported mechanism: {}", mechanism);
       }
       if !method.locales.split_whitespace().any(|l| l == locale) {
-        error!("unsupported locale: {}", mechanism);
+        error!("connection: unsupported locale: {}", locale);
       }
 
       if !options.client_properties.contains_key("product") || !options.client_properties.contains_key("version") {
@@ -266,6 +593,9 @@ impl Channel {
         options.client_properties.insert("version".to_string(), AMQPValue::LongString(env!("CARGO_PKG_VERSION").to_string()));
       }
 
+      if !options.client_properties.contains_key("information") {
+        options.client_properties.insert("information".to_string(), AMQPValue::LongString(env!("CARGO_PKG_REPOSITORY").to_string()));
+      }
       options.client_properties.insert("platform".to_string(), AMQPValue::LongString("rust".to_string()));
 
       let mut capabilities = FieldTable::new();
@@ -278,15 +608,34 @@ impl Channel {
 
       options.client_properties.insert("capabilities".to_string(), AMQPValue::FieldTable(capabilities));
 
-      self.connection_start_ok(options.client_properties, &mechanism, &credentials.sasl_plain_auth_string(), &locale)?;
+      self.connection_start_ok(options.client_properties, &mechanism, &credentials.sasl_auth_string(options.mechanism), &locale)?;
+      self.connection.status.set_connecting_state(ConnectingState::SentStartOk(credentials, options.mechanism));
       Ok(())
     } else {
-      error!("Invalid state: {:?}", state);
+      error!("Invalid connection state: {:?}", state);
       self.connection.set_error()?;
       Err(ErrorKind::InvalidConnectionState(state).into())
     }
   }
 
+  fn on_connection_secure_received(&self, method: protocol::connection::Secure) -> Result<(), Error> {
+    trace!("Server sent Connection::Secure: {:?}", method);
+    // the server can send as many challenges as the mechanism needs before Connection.Tune
+    match self.connection.status.state() {
+      ConnectionState::Connecting(ConnectingState::SentStartOk(credentials, mechanism)) |
+      ConnectionState::Connecting(ConnectingState::SentSecureOk(credentials, mechanism)) => {
+        self.connection_secure_ok(&credentials.sasl_challenge_response(mechanism, &method.challenge))?;
+        self.connection.status.set_connecting_state(ConnectingState::SentSecureOk(credentials, mechanism));
+        Ok(())
+      },
+      state => {
+        error!("Invalid connection state: {:?}", state);
+        self.connection.set_error()?;
+        Err(ErrorKind::InvalidConnectionState(state).into())
+      },
+    }
+  }
+
   fn on_connection_tune_received(&self, method: protocol::connection::Tune) -> Result<(), Error> {
     debug!("Server sent Connection::Tune: {:?}", method);
 
@@ -304,10 +653,19 @@ impl Channel {
 
   fn on_connection_close_received(&self, method: protocol::connection::Close) -> Result<(), Error> {
     if let Some(error) = AMQPError::from_id(method.reply_code) {
-      error!("Connection closed on channel {} by {}:{} => {:?} => {}", self.id, method.class_id, method.method_id, error, method.reply_text);
+      error!("Connection closed by {}:{} => {:?} => {}", method.class_id, method.method_id, error, method.reply_text);
     } else {
-      info!("Connection closed on channel {}: {:?}", self.id, method);
+      info!("Connection closed: {:?}", method);
     }
+    // if we already sent a Connection.Close, the server won't answer it, reply to its own instead
+    let reason = CloseReason {
+      reply_code: method.reply_code,
+      reply_text: method.reply_text,
+      class_id:   method.class_id,
+      method_id:  method.method_id,
+    };
+    self.connection.status.set_close_reason(reason.clone());
+    self.connection.channels.abort_pending(reason);
     self.connection_close_ok()?;
     Ok(())
   }
@@ -332,13 +690,13 @@ impl Channel {
   }
 
   fn on_channel_flow_received(&self, method: protocol::channel::Flow) -> Result<(), Error> {
-    self.status.set_send_flow(method.active);
+    self.set_send_flow(method.active);
     self.channel_flow_ok(ChannelFlowOkOptions {active: method.active}).map(|_| ())
   }
 
-  fn on_channel_flow_ok_received(&self, _: protocol::channel::FlowOk) -> Result<(), Error> {
-    // Nothing to do here, the server just confirmed that we paused/resumed the receiving flow
-    // FIXME: return method.active?
+  fn on_channel_flow_ok_received(&self, method: protocol::channel::FlowOk) -> Result<(), Error> {
+    // The server confirmed that it paused/resumed the deliveries
+    self.status.set_receive_flow(method.active);
     Ok(())
   }
 
@@ -348,22 +706,33 @@ impl Channel {
     } else {
       info!("Channel {} closed: {:?}", self.id, method);
     }
+    self.abort_pending(CloseReason {
+      reply_code: method.reply_code,
+      reply_text: method.reply_text,
+      class_id:   method.class_id,
+      method_id:  method.method_id,
+    });
     self.channel_close_ok()?;
     Ok(())
   }
 
   fn on_channel_close_ok_received(&self) -> Result<(), Error> {
+    self.connection.topology.channel_closed(self.id);
     self.set_closed()
   }
 
-  fn on_queue_delete_ok_received(&self, _method: protocol::queue::DeleteOk, queue: String) -> Result<(), Error> {
-    //FIXME: use messages_count
+  fn on_queue_delete_ok_received(&self, method: protocol::queue::DeleteOk, request_id: RequestId, queue: String) -> Result<(), Error> {
+    if request_id != 0 {
+      self.message_counts.register(request_id, method.message_count);
+    }
     self.queues.deregister(&queue);
     Ok(())
   }
 
-  fn on_queue_purge_ok_received(&self, _method: protocol::queue::PurgeOk) -> Result<(), Error> {
-    //FIXME: use messages_count
+  fn on_queue_purge_ok_received(&self, method: protocol::queue::PurgeOk, request_id: RequestId) -> Result<(), Error> {
+    if request_id != 0 {
+      self.message_counts.register(request_id, method.message_count);
+    }
     Ok(())
   }
 
@@ -375,7 +744,8 @@ impl Channel {
     Ok(())
   }
 
-  fn on_basic_get_ok_received(&self, method: protocol::basic::GetOk, request_id: RequestId, queue: String) -> Result<(), Error> {
+  fn on_basic_get_ok_received(&self, method: protocol::basic::GetOk, request_id: RequestId, queue: String, no_ack: bool) -> Result<(), Error> {
+    self.on_delivery_received(method.delivery_tag, no_ack);
     self.queues.start_basic_get_delivery(&queue, BasicGetMessage::new(method.delivery_tag, method.exchange, method.routing_key, method.redelivered, method.message_count));
     self.status.set_state(ChannelState::WillReceiveContent(Some(queue), Either::Left(request_id)));
     Ok(())
@@ -383,7 +753,7 @@ impl Channel {
 
   fn on_basic_get_empty_received(&self, _: protocol::basic::GetEmpty) -> Result<(), Error> {
     match self.replies.next() {
-      Some(Reply::AwaitingBasicGetOk(request_id, _)) => {
+      Some(Reply::AwaitingBasicGetOk(request_id, _, _)) => {
         self.requests.finish(request_id, false);
         Ok(())
       },
@@ -404,6 +774,7 @@ impl Channel {
   }
 
   fn on_basic_deliver_received(&self, method: protocol::basic::Deliver) -> Result<(), Error> {
+    self.on_delivery_received(method.delivery_tag, self.queues.consumer_no_ack(&method.consumer_tag).unwrap_or(false));
     if let Some(queue_name) = self.queues.start_consumer_delivery(&method.consumer_tag, Delivery::new(method.delivery_tag, method.exchange.to_string(), method.routing_key.to_string(), method.redelivered)) {
       self.status.set_state(ChannelState::WillReceiveContent(Some(queue_name), Either::Right(method.consumer_tag)));
     }
@@ -411,7 +782,9 @@ impl Channel {
   }
 
   fn on_basic_cancel_received(&self, method: protocol::basic::Cancel) -> Result<(), Error> {
-    self.queues.deregister_consumer(&method.consumer_tag);
+    self.queues.deregister_consumer_canceled_by_server(&method.consumer_tag);
+    // it's not resumed on a new connection
+    self.connection.topology.consumer_canceled(&method.consumer_tag);
     if !method.nowait {
       self.basic_cancel_ok(&method.consumer_tag)?;
     }
@@ -425,6 +798,7 @@ impl Channel {
 
   fn on_basic_ack_received(&self, method: protocol::basic::Ack) -> Result<(), Error> {
     if self.status.confirm() {
+      let pending = self.acknowledgements.pending_count();
       if method.multiple {
         if method.delivery_tag > 0 {
           self.acknowledgements.ack_all_before(method.delivery_tag).or_else(|err| self.acknowledgement_error(err, method.get_amqp_class_id(), method.get_amqp_method_id()))?;
@@ -434,12 +808,19 @@ impl Channel {
       } else {
         self.acknowledgements.ack(method.delivery_tag).or_else(|err| self.acknowledgement_error(err, method.get_amqp_class_id(), method.get_amqp_method_id()))?;
       }
+      let count = pending - self.acknowledgements.pending_count();
+      self.connection.metrics.record(|hooks| hooks.confirmed(self.id, count as u64));
+      // The server sends Basic.Return before the Basic.Ack of the unroutable message
+      for (delivery_tag, message) in self.returned_messages.take_waiting_messages(method.delivery_tag, method.multiple) {
+        self.acknowledgements.set_returned(delivery_tag, message);
+      }
     }
     Ok(())
   }
 
   fn on_basic_nack_received(&self, method: protocol::basic::Nack) -> Result<(), Error> {
     if self.status.confirm() {
+      let pending = self.acknowledgements.pending_count();
       if method.multiple {
         if method.delivery_tag > 0 {
           self.acknowledgements.nack_all_before(method.delivery_tag).or_else(|err| self.acknowledgement_error(err, method.get_amqp_class_id(), method.get_amqp_method_id()))?;
@@ -449,12 +830,18 @@ impl Channel {
       } else {
         self.acknowledgements.nack(method.delivery_tag).or_else(|err| self.acknowledgement_error(err, method.get_amqp_class_id(), method.get_amqp_method_id()))?;
       }
+      let count = pending - self.acknowledgements.pending_count();
+      self.connection.metrics.record(|hooks| hooks.nacked(self.id, count as u64));
+      // a nack is reported as is, forget the messages returned before it
+      self.returned_messages.take_waiting_messages(method.delivery_tag, method.multiple);
     }
     Ok(())
   }
 
   fn on_basic_return_received(&self, method: protocol::basic::Return) -> Result<(), Error> {
-    self.returned_messages.start_new_delivery(BasicReturnMessage::new(method.exchange, method.routing_key, method.reply_code, method.reply_text));
+    let delivery_tag = if self.status.confirm() { self.acknowledgements.next_returned(&method.exchange, &method.routing_key) } else { None };
+    self.returned_messages.start_new_delivery(BasicReturnMessage::new(method.exchange, method.routing_key, method.reply_code, method.reply_text), delivery_tag);
+    self.connection.metrics.record(|hooks| hooks.returned(self.id));
     self.status.set_state(ChannelState::WillReceiveContent(None, Either::Left(0)));
     Ok(())
   }
@@ -464,11 +851,37 @@ impl Channel {
     Ok(())
   }
 
+  fn before_confirm_select(&self) -> Result<(), Error> {
+    if self.status.tx() {
+      Err(ErrorKind::IncompatibleChannelMode.into())
+    } else {
+      Ok(())
+    }
+  }
+
+  fn on_basic_qos_ok_received(&self, prefetch_count: ShortUInt) -> Result<(), Error> {
+    self.status.set_prefetch_count(prefetch_count);
+    Ok(())
+  }
+
   fn on_confirm_select_ok_received(&self) -> Result<(), Error> {
     self.status.set_confirm();
     Ok(())
   }
 
+  fn before_tx_select(&self) -> Result<(), Error> {
+    if self.status.confirm() {
+      Err(ErrorKind::IncompatibleChannelMode.into())
+    } else {
+      Ok(())
+    }
+  }
+
+  fn on_tx_select_ok_received(&self) -> Result<(), Error> {
+    self.status.set_tx();
+    Ok(())
+  }
+
   fn on_access_request_ok_received(&self, _: protocol::access::RequestOk) -> Result<(), Error> {
     Ok(())
   }
@@ -1,13 +1,17 @@
 use either::Either;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 
-use std::sync::Arc;
+use std::{fmt, sync::Arc};
 
-use crate::requests::RequestId;
+use crate::{
+  error::CloseReason,
+  requests::RequestId,
+};
 
 #[derive(Clone, Debug, Default)]
 pub struct ChannelStatus {
-  inner: Arc<RwLock<Inner>>,
+  inner:       Arc<RwLock<Inner>>,
+  listeners:   StateListeners,
 }
 
 impl ChannelStatus {
@@ -19,6 +23,10 @@ impl ChannelStatus {
     !&[ChannelState::Initial, ChannelState::Closing, ChannelState::Closed, ChannelState::Error].contains(&self.inner.read().state)
   }
 
+  pub fn is_closing(&self) -> bool {
+    self.inner.read().state == ChannelState::Closing
+  }
+
   pub fn confirm(&self) -> bool {
     self.inner.read().confirm
   }
@@ -27,12 +35,66 @@ impl ChannelStatus {
     self.inner.write().confirm = true
   }
 
+  pub fn tx(&self) -> bool {
+    self.inner.read().tx
+  }
+
+  pub fn set_tx(&self) {
+    self.inner.write().tx = true
+  }
+
   pub fn state(&self) -> ChannelState {
     self.inner.read().state.clone()
   }
 
   pub fn set_state(&self, state: ChannelState) {
-    self.inner.write().state = state
+    let mut inner = self.inner.write();
+    if inner.state != state {
+      // keep the lock while notifying, for the listeners to get the transitions in order
+      self.listeners.0.lock().retain(|listener| listener(&state));
+    }
+    inner.state = state;
+  }
+
+  /// calls `listener` with each new state of the channel from now on, including the content
+  /// sending and receiving ones, e.g. to log its lifecycle
+  ///
+  /// it's called while the state changes, it should return quickly and not call back into
+  /// lapin, e.g. forward the state to a channel. It's dropped once it returns false, e.g. when
+  /// nothing receives the states anymore
+  pub fn on_state_change(&self, listener: Box<StateListener>) {
+    self.listeners.0.lock().push(listener);
+  }
+
+  /// the reason why the channel is closing or got closed, by the client or the server
+  pub fn close_reason(&self) -> Option<CloseReason> {
+    self.inner.read().close_reason.clone()
+  }
+
+  pub fn set_close_reason(&self, reason: CloseReason) {
+    self.inner.write().close_reason = Some(reason);
+  }
+
+  /// whether the server delivers messages to us, we can pause it by sending Channel.Flow
+  pub fn receive_flow(&self) -> bool {
+    self.inner.read().receive_flow
+  }
+
+  pub fn set_receive_flow(&self, flow: bool) {
+    self.inner.write().receive_flow = flow;
+  }
+
+  /// the prefetch count acknowledged by the server for the next consumers, 0 means no limit
+  pub fn prefetch_count(&self) -> u16 {
+    self.inner.read().prefetch_count
+  }
+
+  pub fn set_prefetch_count(&self, prefetch_count: u16) {
+    self.inner.write().prefetch_count = prefetch_count;
+  }
+
+  pub fn can_send(&self) -> bool {
+    self.inner.read().send_flow
   }
 
   pub fn set_send_flow(&self, flow: bool) {
@@ -40,8 +102,22 @@ impl ChannelStatus {
   }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+/// The functions `ChannelStatus::on_state_change` calls, returning false once they don't want
+/// the next states
+pub type StateListener = dyn Fn(&ChannelState) -> bool + Send + Sync;
+
+#[derive(Clone, Default)]
+struct StateListeners(Arc<Mutex<Vec<Box<StateListener>>>>);
+
+impl fmt::Debug for StateListeners {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("StateListeners").field("count", &self.0.lock().len()).finish()
+  }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub enum ChannelState {
+    #[default]
     Initial,
     Connected,
     Closing,
@@ -52,25 +128,27 @@ pub enum ChannelState {
     ReceivingContent(Option<String>, Either<RequestId, String>, usize),
 }
 
-impl Default for ChannelState {
-  fn default() -> Self {
-    ChannelState::Initial
-  }
-}
-
 #[derive(Debug)]
 struct Inner {
-  confirm:   bool,
-  send_flow: bool, // FIXME: we should respect that
-  state:     ChannelState,
+  confirm:        bool,
+  tx:             bool,
+  send_flow:      bool,
+  receive_flow:   bool,
+  state:          ChannelState,
+  close_reason:   Option<CloseReason>,
+  prefetch_count: u16,
 }
 
 impl Default for Inner {
   fn default() -> Self {
     Self {
-      confirm:   false,
-      send_flow: true,
-      state:     ChannelState::default(),
+      confirm:        false,
+      tx:             false,
+      send_flow:      true,
+      receive_flow:   true,
+      state:          ChannelState::default(),
+      close_reason:   None,
+      prefetch_count: 0,
     }
   }
 }
@@ -11,7 +11,7 @@ use crate::{
   channel::{BasicProperties, Channel},
   channel_status::ChannelState,
   connection::Connection,
-  error::{Error, ErrorKind},
+  error::{CloseReason, Error, ErrorKind},
   id_sequence::IdSequence,
 };
 
@@ -25,6 +25,10 @@ impl Channels {
     self.inner.lock().create(connection)
   }
 
+  pub fn create_with_id(&self, id: u16, connection: Connection) -> Result<Channel, Error> {
+    self.inner.lock().create_with_id(id, connection)
+  }
+
   pub(crate) fn create_zero(&self, connection: Connection) {
     self.inner.lock().create_channel(0, connection).status.set_state(ChannelState::Connected);
   }
@@ -66,24 +70,47 @@ impl Channels {
   }
 
   pub fn set_closing(&self) {
-    for channel in self.inner.lock().channels.values() {
+    for channel in self.list() {
       channel.set_closing();
     }
   }
 
+  pub(crate) fn abort_pending(&self, reason: CloseReason) {
+    for channel in self.list() {
+      channel.abort_pending(reason.clone());
+    }
+  }
+
   pub fn set_closed(&self) -> Result<(), Error> {
-    for channel in self.inner.lock().channels.values() {
+    for channel in self.list() {
       channel.set_closed()?;
     }
     Ok(())
   }
 
   pub fn set_error(&self) -> Result<(), Error> {
-    for channel in self.inner.lock().channels.values() {
+    for channel in self.list() {
       channel.set_error()?;
     }
     Ok(())
   }
+
+  /// the number of open channels, not counting the channel 0 used for the connection methods
+  pub fn count(&self) -> usize {
+    self.inner.lock().channels.keys().filter(|id| **id != 0).count()
+  }
+
+  /// the ids and states of the open channels, sorted by id and without the channel 0
+  pub fn states(&self) -> Vec<(u16, ChannelState)> {
+    let mut states: Vec<(u16, ChannelState)> = self.inner.lock().channels.iter().filter(|(id, _)| **id != 0).map(|(id, channel)| (*id, channel.status.state())).collect();
+    states.sort_by_key(|(id, _)| *id);
+    states
+  }
+
+  // Closing a channel removes it from the list, don't hold the lock while doing so
+  fn list(&self) -> Vec<Channel> {
+    self.inner.lock().channels.values().cloned().collect()
+  }
 }
 
 #[derive(Debug)]
@@ -111,19 +138,31 @@ impl Inner {
 
   fn create(&mut self, connection: Connection) -> Result<Channel, Error> {
     debug!("create channel");
-    self.channel_id.set_max(connection.configuration.channel_max());
-    let first_id = self.channel_id.next();
-    let mut looped = false;
-    let mut id = first_id;
-    while !looped || id < first_id {
-      if id == 1 {
-        looped = true;
-      }
+    // channel_max is the highest usable id, 0 means no limit
+    let channel_max = match connection.configuration.channel_max() {
+      0           => u16::MAX,
+      channel_max => channel_max,
+    };
+    self.channel_id.set_max(channel_max);
+    // the ids of the closed channels get reused once the sequence loops
+    for _ in 0..channel_max {
+      let id = self.channel_id.next();
       if !self.channels.contains_key(&id) {
         return Ok(self.create_channel(id, connection))
       }
-      id = self.channel_id.next();
     }
     Err(ErrorKind::ChannelLimitReached.into())
   }
+
+  fn create_with_id(&mut self, id: u16, connection: Connection) -> Result<Channel, Error> {
+    debug!("create channel with requested id {}", id);
+    let channel_max = connection.configuration.channel_max();
+    if id == 0 || (channel_max != 0 && id > channel_max) {
+      Err(ErrorKind::InvalidChannel(id).into())
+    } else if self.channels.contains_key(&id) {
+      Err(ErrorKind::ChannelInUse(id).into())
+    } else {
+      Ok(self.create_channel(id, connection))
+    }
+  }
 }
@@ -1,13 +1,18 @@
 use parking_lot::RwLock;
 
+use crate::types::{AMQPValue, FieldTable};
+
 use std::sync::Arc;
 
+/// the limits of a connection, set to the values negotiated with the server once it's connected,
+/// and the properties the server sent in Connection.Start
 #[derive(Clone, Debug, Default)]
 pub struct Configuration {
   inner: Arc<RwLock<Inner>>,
 }
 
 impl Configuration {
+  /// the highest channel id we can use
   pub fn channel_max(&self) -> u16 {
     self.inner.read().channel_max
   }
@@ -16,6 +21,7 @@ impl Configuration {
     self.inner.write().channel_max = channel_max;
   }
 
+  /// the maximum size of a frame, the bodies of larger messages are split over several frames
   pub fn frame_max(&self) -> u32 {
     self.inner.read().frame_max
   }
@@ -24,6 +30,7 @@ impl Configuration {
     self.inner.write().frame_max = frame_max;
   }
 
+  /// the heartbeat timeout in seconds, 0 if heartbeats are disabled
   pub fn heartbeat(&self) -> u16 {
     self.inner.read().heartbeat
   }
@@ -31,11 +38,40 @@ impl Configuration {
   pub fn set_heartbeat(&self, heartbeat: u16) {
     self.inner.write().heartbeat = heartbeat;
   }
+
+  /// whether publishing with the `immediate` flag fails instead of being sent to the server,
+  /// which would close the connection if it's RabbitMQ 3.0 or later
+  pub fn reject_immediate(&self) -> bool {
+    self.inner.read().reject_immediate
+  }
+
+  pub fn set_reject_immediate(&self, reject_immediate: bool) {
+    self.inner.write().reject_immediate = reject_immediate;
+  }
+
+  /// the properties of the server, such as its `product`, `version` and `capabilities`
+  pub fn server_properties(&self) -> FieldTable {
+    self.inner.read().server_properties.clone()
+  }
+
+  pub fn set_server_properties(&self, server_properties: FieldTable) {
+    self.inner.write().server_properties = server_properties;
+  }
+
+  /// whether the server announced the given capability, e.g. `publisher_confirms` or `basic.nack`
+  pub fn server_supports(&self, capability: &str) -> bool {
+    match self.inner.read().server_properties.get("capabilities") {
+      Some(AMQPValue::FieldTable(capabilities)) => capabilities.get(capability) == Some(&AMQPValue::Boolean(true)),
+      _                                         => false,
+    }
+  }
 }
 
 #[derive(Debug, Default)]
 struct Inner {
-  channel_max: u16,
-  frame_max:   u32,
-  heartbeat:   u16,
+  channel_max:       u16,
+  frame_max:         u32,
+  heartbeat:         u16,
+  reject_immediate:  bool,
+  server_properties: FieldTable,
 }
@@ -10,6 +10,9 @@ use crate::{
   credentials::Credentials,
   error::{Error, ErrorKind},
   frames::Frames,
+  inspector::FrameInspector,
+  metrics::Metrics,
+  topology::TopologyRecorder,
 };
 
 #[derive(Clone, Debug)]
@@ -18,6 +21,12 @@ pub struct Connection {
   pub status:          ConnectionStatus,
   pub channels:        Channels,
   pub configuration:   Configuration,
+  /// the hooks called on the publishes, confirmations and deliveries of the channels
+  pub metrics:         Metrics,
+  /// the function called with the frames sent and received by the transport
+  pub inspector:       FrameInspector,
+  /// the exchanges, queues, bindings and consumers declared through the futures API
+  pub topology:        TopologyRecorder,
   // list of frames to send
       frames:          Frames,
 }
@@ -31,6 +40,9 @@ impl Default for Connection {
       status:          ConnectionStatus::default(),
       channels,
       configuration,
+      metrics:         Metrics::default(),
+      inspector:       FrameInspector::default(),
+      topology:        TopologyRecorder::default(),
       frames:          Frames::default(),
     };
 
@@ -49,6 +61,11 @@ impl Connection {
     self.channels.create(self.clone())
   }
 
+  /// creates a channel with the given id, which must be free and not above `channel_max`
+  pub fn create_channel_with_id(&self, id: u16) -> Result<Channel, Error> {
+    self.channels.create_with_id(id, self.clone())
+  }
+
   /// starts the process of connecting to the server
   ///
   /// this will set up the state machine and generates the required messages.
@@ -67,10 +84,29 @@ impl Connection {
     }
   }
 
+  /// starts closing the connection, along with all its channels
+  ///
+  /// the connection is closed once the server answers with Connection.CloseOk. Nothing is sent
+  /// if the connection is already closing or closed.
+  pub fn close(&self, reply_code: u16, reply_text: &str) -> Result<(), Error> {
+    if !self.status.is_connected() {
+      return Ok(());
+    }
+    match self.channels.get(0) {
+      Some(channel0) => channel0.connection_close(reply_code, reply_text, 0, 0).map(|_| ()),
+      None           => Err(ErrorKind::InvalidChannel(0).into()),
+    }
+  }
+
   pub fn send_frame(&self, frame: AMQPFrame) {
     self.frames.push(frame);
   }
 
+  /// queues several frames at once, ensuring no other frame gets inserted between them
+  pub fn send_frames(&self, frames: Vec<AMQPFrame>) {
+    self.frames.push_all(frames);
+  }
+
   /// next message to send to the network
   ///
   /// returns None if there's no message to send
@@ -178,6 +214,11 @@ impl Connection {
     !self.frames.is_empty()
   }
 
+  /// how many frames are waiting to be sent
+  pub fn pending_frames(&self) -> usize {
+    self.frames.len()
+  }
+
   pub fn set_closing(&self) {
     self.status.set_state(ConnectionState::Closing);
     self.channels.set_closing();
@@ -200,6 +241,7 @@ mod tests {
 
   use super::*;
   use crate::channel::BasicProperties;
+  use crate::channel::options::{BasicNackOptions, BasicPublishOptions};
   use crate::channel_status::ChannelState;
   use crate::consumer::ConsumerSubscriber;
   use crate::message::Delivery;
@@ -216,6 +258,16 @@ mod tests {
     fn cancel(&self) {}
   }
 
+  /// a connection done with its handshake, and an opened channel
+  fn connected_channel() -> (Connection, Channel) {
+    let conn = Connection::new();
+    conn.status.set_state(ConnectionState::Connected);
+    conn.configuration.set_channel_max(2047);
+    let channel = conn.create_channel().unwrap();
+    channel.status.set_state(ChannelState::Connected);
+    (conn, channel)
+  }
+
   #[test]
   fn basic_consume_small_payload() {
     let _ = env_logger::try_init();
@@ -224,11 +276,7 @@ mod tests {
     use crate::queue::Queue;
 
     // Bootstrap connection state to a consuming state
-    let conn = Connection::new();
-    conn.status.set_state(ConnectionState::Connected);
-    conn.configuration.set_channel_max(2047);
-    let channel = conn.create_channel().unwrap();
-    channel.status.set_state(ChannelState::Connected);
+    let (conn, channel) = connected_channel();
     let queue_name = "consumed".to_string();
     let mut queue = Queue::new(queue_name.clone(), 0, 0);
     let consumer_tag = "consumer-tag".to_string();
@@ -287,18 +335,165 @@ mod tests {
   }
 
   #[test]
-  fn basic_consume_empty_payload() {
+  fn channel_state_changes() {
     let _ = env_logger::try_init();
 
+    use parking_lot::Mutex;
+    use std::sync::Arc;
     use crate::consumer::Consumer;
     use crate::queue::Queue;
+    use crate::requests::RequestId;
 
-    // Bootstrap connection state to a consuming state
     let conn = Connection::new();
     conn.status.set_state(ConnectionState::Connected);
     conn.configuration.set_channel_max(2047);
     let channel = conn.create_channel().unwrap();
+    let mut queue = Queue::new("queue".to_string(), 0, 0);
+    queue.consumers.insert("consumer-tag".to_string(), Consumer::new("consumer-tag".to_string(), false, false, false, Box::new(DummySubscriber)));
+    channel.queues.register(queue);
+    let state_changes = Arc::new(Mutex::new(Vec::new()));
+    let states = state_changes.clone();
+    channel.status.on_state_change(Box::new(move |state| {
+      states.lock().push(state.clone());
+      true
+    }));
+    // dropped once it doesn't want more states
+    channel.status.on_state_change(Box::new(|_| false));
     channel.status.set_state(ChannelState::Connected);
+    channel.status.set_state(ChannelState::Connected);
+    conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Basic(basic::AMQPMethod::Deliver(basic::Deliver {
+      consumer_tag: "consumer-tag".to_string(),
+      delivery_tag: 1,
+      redelivered:  false,
+      exchange:     "".to_string(),
+      routing_key:  "queue".to_string(),
+    })))).unwrap();
+    conn.handle_frame(AMQPFrame::Header(channel.id(), 60, Box::new(AMQPContentHeader {
+      class_id:   60,
+      weight:     0,
+      body_size:  2,
+      properties: BasicProperties::default(),
+    }))).unwrap();
+    conn.handle_frame(AMQPFrame::Body(channel.id(), b"{}".to_vec())).unwrap();
+    let consumer: Either<RequestId, String> = Either::Right("consumer-tag".to_string());
+    assert_eq!(*state_changes.lock(), vec![
+      ChannelState::Connected,
+      ChannelState::WillReceiveContent(Some("queue".to_string()), consumer.clone()),
+      ChannelState::ReceivingContent(Some("queue".to_string()), consumer, 2),
+      ChannelState::Connected,
+    ]);
+  }
+
+  #[test]
+  fn out_of_sequence_frames() {
+    let _ = env_logger::try_init();
+
+    use amq_protocol::protocol::{AMQPError, AMQPHardError, connection};
+
+    let (conn, channel) = connected_channel();
+    // a content body frame without the method and header announcing it
+    conn.handle_frame(AMQPFrame::Body(channel.id(), b"{}".to_vec())).unwrap();
+    assert_eq!(channel.status.state(), ChannelState::Error);
+    assert_eq!(channel.status.close_reason().unwrap().error(), Some(AMQPError::Hard(AMQPHardError::UNEXPECTEDFRAME)));
+    // the whole connection gets closed, the channel id stays reserved until the server answers
+    match conn.next_frame() {
+      Some(AMQPFrame::Method(0, AMQPClass::Connection(connection::AMQPMethod::Close(close)))) => {
+        assert_eq!(close.reply_code, AMQPHardError::UNEXPECTEDFRAME.get_id());
+      },
+      frame => panic!("unexpected frame: {:?}", frame),
+    }
+    assert_eq!(conn.status.state(), ConnectionState::Closing);
+    assert!(conn.channels.get(channel.id()).is_some());
+    conn.handle_frame(AMQPFrame::Method(0, AMQPClass::Connection(connection::AMQPMethod::CloseOk(connection::CloseOk {})))).unwrap();
+    assert!(conn.channels.get(channel.id()).is_none());
+
+    // a delivery on a channel which isn't open yet
+    let (conn, _) = connected_channel();
+    let channel = conn.create_channel().unwrap();
+    conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Basic(basic::AMQPMethod::Deliver(basic::Deliver {
+      consumer_tag: "consumer-tag".to_string(),
+      delivery_tag: 1,
+      redelivered:  false,
+      exchange:     "".to_string(),
+      routing_key:  "queue".to_string(),
+    })))).unwrap();
+    assert_eq!(channel.status.state(), ChannelState::Error);
+    assert!(channel.status.close_reason().unwrap().reply_text.contains("before the channel was opened"));
+  }
+
+  #[test]
+  fn basic_consume_large_payload() {
+    let _ = env_logger::try_init();
+
+    use parking_lot::Mutex;
+    use std::sync::Arc;
+    use crate::consumer::Consumer;
+    use crate::queue::Queue;
+
+    #[derive(Debug)]
+    struct RecordingSubscriber(Arc<Mutex<Vec<Delivery>>>);
+
+    impl ConsumerSubscriber for RecordingSubscriber {
+      fn new_delivery(&self, delivery: Delivery) {
+        self.0.lock().push(delivery);
+      }
+      fn drop_prefetched_messages(&self) {}
+      fn cancel(&self) {}
+    }
+
+    let (conn, channel) = connected_channel();
+    conn.configuration.set_frame_max(4096);
+    let queue_name = "consumed".to_string();
+    let consumer_tag = "consumer-tag".to_string();
+    let deliveries = Arc::new(Mutex::new(Vec::new()));
+    let mut queue = Queue::new(queue_name.clone(), 0, 0);
+    queue.consumers.insert(consumer_tag.clone(), Consumer::new(consumer_tag.clone(), false, false, false, Box::new(RecordingSubscriber(deliveries.clone()))));
+    channel.queues.register(queue);
+
+    // publish a body spanning several frames and echo them back as a delivery
+    let payload = (0..10_000u32).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+    channel.basic_publish("", &queue_name, BasicPublishOptions::default(), payload.clone(), BasicProperties::default()).unwrap();
+    let mut bodies = Vec::new();
+    while let Some(frame) = conn.next_frame() {
+      if let AMQPFrame::Body(_, body) = frame {
+        assert!(body.len() <= 4096 - 8);
+        bodies.push(body);
+      }
+    }
+    assert_eq!(bodies.len(), 3);
+
+    conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Basic(basic::AMQPMethod::Deliver(basic::Deliver {
+      consumer_tag: consumer_tag.clone(),
+      delivery_tag: 1,
+      redelivered:  false,
+      exchange:     "".to_string(),
+      routing_key:  queue_name.clone(),
+    })))).unwrap();
+    conn.handle_frame(AMQPFrame::Header(channel.id(), 60, Box::new(AMQPContentHeader {
+      class_id:   60,
+      weight:     0,
+      body_size:  payload.len() as u64,
+      properties: BasicProperties::default(),
+    }))).unwrap();
+    for body in bodies {
+      assert!(deliveries.lock().is_empty());
+      conn.handle_frame(AMQPFrame::Body(channel.id(), body)).unwrap();
+    }
+    assert_eq!(channel.status.state(), ChannelState::Connected);
+    let deliveries = deliveries.lock();
+    assert_eq!(deliveries.len(), 1);
+    assert_eq!(deliveries[0].data, payload);
+  }
+
+  #[test]
+  fn basic_consume_empty_payload() {
+    let _ = env_logger::try_init();
+
+    use crate::consumer::Consumer;
+    use crate::queue::Queue;
+
+    // Bootstrap connection state to a consuming state
+    let (conn, channel) = connected_channel();
     let queue_name = "consumed".to_string();
     let mut queue = Queue::new(queue_name.clone(), 0, 0);
     let consumer_tag = "consumer-tag".to_string();
@@ -348,4 +543,1002 @@ mod tests {
       assert_eq!(channel_state, expected_state);
     }
   }
+
+  #[test]
+  fn connection_closed_with_channels() {
+    let _ = env_logger::try_init();
+
+    let (conn, channel) = connected_channel();
+    assert!(conn.status.is_connected());
+    conn.set_closed().unwrap();
+    assert!(conn.status.is_closed());
+    assert_eq!(channel.status.state(), ChannelState::Closed);
+    assert!(conn.channels.get(channel.id()).is_none());
+  }
+
+  #[test]
+  fn connection_close() {
+    let _ = env_logger::try_init();
+
+    use amq_protocol::protocol::connection;
+    use crate::channel::options::QueueDeclareOptions;
+    use crate::types::FieldTable;
+
+    let (conn, channel) = connected_channel();
+    let declare = channel.queue_declare("queue", QueueDeclareOptions::default(), FieldTable::default()).unwrap().unwrap();
+    conn.close(200, "bye").unwrap();
+    assert_eq!(conn.status.state(), ConnectionState::Closing);
+    assert_eq!(channel.requests.was_successful(declare), Some(false));
+    assert_eq!(channel.status.close_reason().unwrap().reply_text, "bye");
+    conn.handle_frame(AMQPFrame::Method(0, AMQPClass::Connection(connection::AMQPMethod::CloseOk(connection::CloseOk {})))).unwrap();
+    assert!(conn.status.is_closed());
+    assert!(conn.channels.get(channel.id()).is_none());
+
+    // the server closes the connection while we're closing it too, we answer its Close
+    let conn = Connection::new();
+    conn.status.set_state(ConnectionState::Connected);
+    conn.close(200, "bye").unwrap();
+    while conn.next_frame().is_some() {}
+    conn.handle_frame(AMQPFrame::Method(0, AMQPClass::Connection(connection::AMQPMethod::Close(connection::Close {
+      reply_code: 320,
+      reply_text: "CONNECTION_FORCED".to_string(),
+      class_id:   0,
+      method_id:  0,
+    })))).unwrap();
+    assert_eq!(conn.next_frame(), Some(AMQPFrame::Method(0, AMQPClass::Connection(connection::AMQPMethod::CloseOk(connection::CloseOk {})))));
+    assert!(conn.status.is_closed());
+  }
+
+  #[test]
+  fn heartbeat_negotiation() {
+    let _ = env_logger::try_init();
+
+    use amq_protocol::protocol::connection;
+
+    let tune = |heartbeat: u16| {
+      let conn = Connection::new();
+      conn.configuration.set_heartbeat(heartbeat);
+      conn.handle_frame(AMQPFrame::Method(0, AMQPClass::Connection(connection::AMQPMethod::Tune(connection::Tune {
+        channel_max: 2047,
+        frame_max:   131_072,
+        heartbeat:   60,
+      })))).unwrap();
+      match conn.next_frame() {
+        Some(AMQPFrame::Method(0, AMQPClass::Connection(connection::AMQPMethod::TuneOk(tune_ok)))) => assert_eq!(tune_ok.heartbeat, conn.configuration.heartbeat()),
+        frame => panic!("unexpected frame: {:?}", frame),
+      }
+      conn.configuration.heartbeat()
+    };
+    // we can ask for a shorter interval, but not a longer one
+    assert_eq!(tune(10), 10);
+    assert_eq!(tune(120), 60);
+    assert_eq!(tune(0), 60);
+  }
+
+  #[test]
+  fn channel_max_enforced() {
+    let _ = env_logger::try_init();
+
+    let conn = Connection::new();
+    conn.status.set_state(ConnectionState::Connected);
+    conn.configuration.set_channel_max(3);
+    let channels = (0..3).map(|_| conn.create_channel().unwrap()).collect::<Vec<_>>();
+    assert_eq!(channels.iter().map(Channel::id).collect::<Vec<_>>(), vec![1, 2, 3]);
+    assert_eq!(conn.channels.count(), 3);
+    match conn.create_channel() {
+      Err(e) => match e.kind() {
+        ErrorKind::ChannelLimitReached => {},
+        kind => panic!("expected ChannelLimitReached, got {:?}", kind),
+      },
+      Ok(channel) => panic!("expected ChannelLimitReached, got channel {}", channel.id()),
+    }
+
+    // the id of a closed channel gets reused
+    channels[1].set_closed().unwrap();
+    assert_eq!(conn.channels.count(), 2);
+    assert_eq!(conn.create_channel().unwrap().id(), 2);
+    assert!(conn.create_channel().is_err());
+  }
+
+  #[test]
+  fn create_channel_with_id() {
+    let _ = env_logger::try_init();
+
+    let conn = Connection::new();
+    conn.status.set_state(ConnectionState::Connected);
+    conn.configuration.set_channel_max(10);
+    assert_eq!(conn.create_channel_with_id(7).unwrap().id(), 7);
+    for (id, expected) in &[(7, "channel 7 is already in use"), (0, "invalid channel: 0"), (11, "invalid channel: 11")] {
+      assert_eq!(conn.create_channel_with_id(*id).unwrap_err().to_string(), *expected);
+    }
+    assert_eq!(conn.create_channel().unwrap().id(), 1);
+    assert_eq!(conn.channels.states(), vec![(1, ChannelState::Initial), (7, ChannelState::Initial)]);
+    conn.channels.get(7).unwrap().set_closed().unwrap();
+    assert_eq!(conn.channels.states(), vec![(1, ChannelState::Initial)]);
+  }
+
+  #[test]
+  fn basic_publish_paused_flow() {
+    let _ = env_logger::try_init();
+
+    use amq_protocol::protocol::channel;
+
+    let (conn, channel) = connected_channel();
+    conn.configuration.set_frame_max(8192);
+
+    let flow_frame = |active| AMQPFrame::Method(channel.id(), AMQPClass::Channel(channel::AMQPMethod::Flow(channel::Flow { active })));
+    conn.handle_frame(flow_frame(false)).unwrap();
+    assert!(!channel.status.can_send());
+    // Channel.FlowOk
+    assert!(conn.next_frame().is_some());
+
+    channel.basic_publish("", "queue", BasicPublishOptions::default(), b"data".to_vec(), BasicProperties::default()).unwrap();
+    assert!(conn.next_frame().is_none());
+
+    conn.handle_frame(flow_frame(true)).unwrap();
+    assert!(channel.status.can_send());
+    match conn.next_frame() {
+      Some(AMQPFrame::Method(_, AMQPClass::Basic(basic::AMQPMethod::Publish(_)))) => {},
+      frame => panic!("expected Basic.Publish, got {:?}", frame),
+    }
+    match conn.next_frame() {
+      Some(AMQPFrame::Header(_, 60, header)) => assert_eq!(header.body_size, 4),
+      frame => panic!("expected content header, got {:?}", frame),
+    }
+    assert_eq!(conn.next_frame(), Some(AMQPFrame::Body(channel.id(), b"data".to_vec())));
+    // Channel.FlowOk
+    assert!(conn.next_frame().is_some());
+    assert!(conn.next_frame().is_none());
+  }
+
+  #[test]
+  fn basic_publish_streamed_body() {
+    let _ = env_logger::try_init();
+
+    let (conn, channel) = connected_channel();
+    conn.configuration.set_frame_max(4096);
+
+    channel.basic_publish_header("", "queue", BasicPublishOptions::default(), 6000, BasicProperties::default()).unwrap();
+    match conn.next_frame() {
+      Some(AMQPFrame::Method(_, AMQPClass::Basic(basic::AMQPMethod::Publish(_)))) => {},
+      frame => panic!("expected Basic.Publish, got {:?}", frame),
+    }
+    match conn.next_frame() {
+      Some(AMQPFrame::Header(_, 60, header)) => assert_eq!(header.body_size, 6000),
+      frame => panic!("expected content header, got {:?}", frame),
+    }
+    // the body frames of another message can't come in between
+    match channel.basic_publish("", "queue", BasicPublishOptions::default(), b"data".to_vec(), BasicProperties::default()) {
+      Err(err) => assert_eq!(err.to_string(), ErrorKind::PublishInProgress(channel.id()).to_string()),
+      res      => panic!("expected the publish to fail, got {:?}", res),
+    }
+
+    channel.send_body_chunk(&[0; 5000]).unwrap();
+    assert_eq!(channel.remaining_body_size(), Some(1000));
+    let mut sizes = Vec::new();
+    while let Some(AMQPFrame::Body(_, body)) = conn.next_frame() {
+      sizes.push(body.len());
+    }
+    assert_eq!(sizes, vec![4088, 912]);
+    assert!(channel.send_body_chunk(&[0; 1001]).is_err());
+    channel.send_body_chunk(&[0; 1000]).unwrap();
+    assert_eq!(channel.remaining_body_size(), None);
+    assert_eq!(conn.next_frame(), Some(AMQPFrame::Body(channel.id(), vec![0; 1000])));
+    channel.basic_publish("", "queue", BasicPublishOptions::default(), b"data".to_vec(), BasicProperties::default()).unwrap();
+  }
+
+  #[test]
+  fn basic_publish_immediate() {
+    let _ = env_logger::try_init();
+
+    let (conn, channel) = connected_channel();
+    conn.configuration.set_frame_max(8192);
+
+    let options = BasicPublishOptions { immediate: true, ..BasicPublishOptions::default() };
+    // sent as is by default
+    channel.basic_publish("", "queue", options.clone(), b"data".to_vec(), BasicProperties::default()).unwrap();
+    match conn.next_frame() {
+      Some(AMQPFrame::Method(_, AMQPClass::Basic(basic::AMQPMethod::Publish(publish)))) => assert!(publish.immediate),
+      frame => panic!("expected Basic.Publish, got {:?}", frame),
+    }
+    while conn.next_frame().is_some() {}
+
+    conn.configuration.set_reject_immediate(true);
+    match channel.basic_publish("", "queue", options.clone(), b"data".to_vec(), BasicProperties::default()) {
+      Err(err) => assert_eq!(err.to_string(), ErrorKind::ImmediateRejected.to_string()),
+      res      => panic!("expected the publish to fail, got {:?}", res),
+    }
+    assert!(channel.basic_publish_header("", "queue", options, 4, BasicProperties::default()).is_err());
+    assert_eq!(conn.next_frame(), None);
+  }
+
+  #[test]
+  fn nested_headers_round_trip() {
+    use crate::types::{AMQPValue, FieldTable};
+
+    let _ = env_logger::try_init();
+
+    let (conn, channel) = connected_channel();
+    conn.configuration.set_frame_max(8192);
+
+    let mut headers = FieldTable::new();
+    headers.insert("trace-id".to_string(), AMQPValue::LongString("abc".to_string()));
+    for depth in 0..16 {
+      let mut nested = FieldTable::new();
+      nested.insert(format!("level-{}", depth), AMQPValue::FieldTable(headers));
+      nested.insert("baggage".to_string(), AMQPValue::FieldArray(vec![AMQPValue::LongLongInt(depth), AMQPValue::Boolean(true), AMQPValue::Double(0.5)]));
+      headers = nested;
+    }
+    let properties = BasicProperties::default().with_headers(headers);
+    channel.basic_publish("", "queue", BasicPublishOptions::default(), b"data".to_vec(), properties.clone()).unwrap();
+    assert!(conn.next_frame().is_some());
+    let header = conn.next_frame().unwrap();
+    let mut buffer = vec![0; 8192];
+    let size = gen_frame((&mut buffer, 0), &header).unwrap().1;
+    match parse_frame(&buffer[..size]) {
+      Ok((_, AMQPFrame::Header(_, _, header))) => assert_eq!(header.properties, properties),
+      frame                                     => panic!("expected a content header, got {:?}", frame),
+    }
+    while conn.next_frame().is_some() {}
+
+    // a content header can't be split, it has to fit in one frame
+    let mut headers = FieldTable::new();
+    headers.insert("baggage".to_string(), AMQPValue::LongString("x".repeat(8192)));
+    match channel.basic_publish("", "queue", BasicPublishOptions::default(), b"data".to_vec(), BasicProperties::default().with_headers(headers)) {
+      Err(err) => assert_eq!(err.to_string(), ErrorKind::ContentHeaderTooLarge(8192).to_string()),
+      res      => panic!("expected the publish to fail, got {:?}", res),
+    }
+    assert_eq!(conn.next_frame(), None);
+  }
+
+  #[test]
+  fn channel_flow_pauses_deliveries() {
+    let _ = env_logger::try_init();
+
+    use amq_protocol::protocol::channel;
+    use crate::channel::options::ChannelFlowOptions;
+
+    let (conn, channel) = connected_channel();
+    assert!(channel.status.receive_flow());
+
+    let request_id = channel.channel_flow(ChannelFlowOptions { active: false }).unwrap().unwrap();
+    match conn.next_frame() {
+      Some(AMQPFrame::Method(_, AMQPClass::Channel(channel::AMQPMethod::Flow(flow)))) => assert!(!flow.active),
+      frame => panic!("expected Channel.Flow, got {:?}", frame),
+    }
+    conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Channel(channel::AMQPMethod::FlowOk(channel::FlowOk { active: false })))).unwrap();
+    assert_eq!(channel.requests.was_successful(request_id), Some(true));
+    assert!(!channel.status.receive_flow());
+    // only our receiving side is paused
+    assert!(channel.status.can_send());
+  }
+
+  #[test]
+  fn basic_nack_needs_connected_channel() {
+    let _ = env_logger::try_init();
+
+    let (_conn, channel) = connected_channel();
+    assert!(channel.basic_nack(1, BasicNackOptions { multiple: true, requeue: true }).is_ok());
+    channel.status.set_state(ChannelState::Closing);
+    assert!(channel.basic_nack(1, BasicNackOptions::default()).is_err());
+    channel.status.set_state(ChannelState::Error);
+    assert!(channel.basic_nack(1, BasicNackOptions::default()).is_err());
+  }
+
+  #[test]
+  fn connection_blocked_and_unblocked() {
+    let _ = env_logger::try_init();
+
+    use amq_protocol::protocol::connection;
+
+    let conn = Connection::new();
+    conn.status.set_state(ConnectionState::Connected);
+    conn.handle_frame(AMQPFrame::Method(0, AMQPClass::Connection(connection::AMQPMethod::Blocked(connection::Blocked { reason: "low on memory".to_string() })))).unwrap();
+    assert!(conn.status.is_blocked());
+    conn.handle_frame(AMQPFrame::Method(0, AMQPClass::Connection(connection::AMQPMethod::Unblocked(connection::Unblocked {})))).unwrap();
+    assert!(!conn.status.is_blocked());
+  }
+
+  #[test]
+  fn connection_start_external_mechanism() {
+    let _ = env_logger::try_init();
+
+    use amq_protocol::protocol::connection;
+    use crate::connection_properties::ConnectionSASLMechanism;
+    use crate::types::{AMQPValue, FieldTable};
+
+    let mut capabilities = FieldTable::new();
+    capabilities.insert("basic.nack".to_string(), AMQPValue::Boolean(true));
+    capabilities.insert("publisher_confirms".to_string(), AMQPValue::Boolean(false));
+    let mut server_properties = FieldTable::new();
+    server_properties.insert("product".to_string(), AMQPValue::LongString("RabbitMQ".to_string()));
+    server_properties.insert("capabilities".to_string(), AMQPValue::FieldTable(capabilities));
+
+    let conn = Connection::new();
+    let properties = ConnectionProperties {
+      mechanism: ConnectionSASLMechanism::EXTERNAL,
+      ..ConnectionProperties::default()
+    };
+    conn.connect(Credentials::new("user".to_string(), "pass".to_string()), properties).unwrap();
+    assert_eq!(conn.next_frame(), Some(AMQPFrame::ProtocolHeader));
+    conn.handle_frame(AMQPFrame::Method(0, AMQPClass::Connection(connection::AMQPMethod::Start(connection::Start {
+      version_major:     0,
+      version_minor:     9,
+      server_properties: server_properties.clone(),
+      mechanisms:        "PLAIN EXTERNAL".to_string(),
+      locales:           "en_US".to_string(),
+    })))).unwrap();
+    assert_eq!(conn.configuration.server_properties(), server_properties);
+    assert!(conn.configuration.server_supports("basic.nack"));
+    assert!(!conn.configuration.server_supports("publisher_confirms"));
+    assert!(!conn.configuration.server_supports("connection.blocked"));
+    match conn.next_frame() {
+      Some(AMQPFrame::Method(0, AMQPClass::Connection(connection::AMQPMethod::StartOk(start_ok)))) => {
+        assert_eq!(start_ok.mechanism, "EXTERNAL");
+        assert_eq!(start_ok.response, "");
+      },
+      frame => panic!("expected Connection.StartOk, got {:?}", frame),
+    }
+  }
+
+  #[test]
+  fn connection_secure_challenges() {
+    let _ = env_logger::try_init();
+
+    use amq_protocol::protocol::connection;
+    use crate::connection_properties::ConnectionSASLMechanism;
+    use crate::types::FieldTable;
+
+    let conn = Connection::new();
+    let credentials = Credentials::new("user".to_string(), "pass".to_string());
+    conn.connect(credentials.clone(), ConnectionProperties::default()).unwrap();
+    assert_eq!(conn.next_frame(), Some(AMQPFrame::ProtocolHeader));
+    conn.handle_frame(AMQPFrame::Method(0, AMQPClass::Connection(connection::AMQPMethod::Start(connection::Start {
+      version_major:     0,
+      version_minor:     9,
+      server_properties: FieldTable::new(),
+      mechanisms:        "PLAIN".to_string(),
+      locales:           "en_US".to_string(),
+    })))).unwrap();
+    assert!(matches!(conn.next_frame(), Some(AMQPFrame::Method(0, AMQPClass::Connection(connection::AMQPMethod::StartOk(_))))));
+    assert_eq!(conn.status.state(), ConnectionState::Connecting(ConnectingState::SentStartOk(credentials.clone(), ConnectionSASLMechanism::PLAIN)));
+    for _ in 0..2 {
+      conn.handle_frame(AMQPFrame::Method(0, AMQPClass::Connection(connection::AMQPMethod::Secure(connection::Secure {
+        challenge: "".to_string(),
+      })))).unwrap();
+      assert_eq!(conn.next_frame(), Some(AMQPFrame::Method(0, AMQPClass::Connection(connection::AMQPMethod::SecureOk(connection::SecureOk {
+        response: credentials.sasl_plain_auth_string(),
+      })))));
+    }
+    assert_eq!(conn.status.state(), ConnectionState::Connecting(ConnectingState::SentSecureOk(credentials, ConnectionSASLMechanism::PLAIN)));
+    conn.handle_frame(AMQPFrame::Method(0, AMQPClass::Connection(connection::AMQPMethod::Tune(connection::Tune {
+      channel_max: 2047,
+      frame_max:   131_072,
+      heartbeat:   60,
+    })))).unwrap();
+    assert!(matches!(conn.next_frame(), Some(AMQPFrame::Method(0, AMQPClass::Connection(connection::AMQPMethod::TuneOk(_))))));
+  }
+
+  #[test]
+  fn tune_takes_server_frame_max() {
+    let _ = env_logger::try_init();
+
+    use amq_protocol::protocol::connection;
+
+    let conn = Connection::new();
+    conn.status.set_state(ConnectionState::Connected);
+    conn.configuration.set_frame_max(0);
+    conn.handle_frame(AMQPFrame::Method(0, AMQPClass::Connection(connection::AMQPMethod::Tune(connection::Tune {
+      channel_max: 2047,
+      frame_max:   4096,
+      heartbeat:   60,
+    })))).unwrap();
+    assert_eq!(conn.configuration.frame_max(), 4096);
+    match conn.next_frame() {
+      Some(AMQPFrame::Method(0, AMQPClass::Connection(connection::AMQPMethod::TuneOk(tune_ok)))) => assert_eq!(tune_ok.frame_max, 4096),
+      frame => panic!("expected Connection.TuneOk, got {:?}", frame),
+    }
+    while conn.next_frame().is_some() {}
+
+    // the bodies are split according to the negotiated value, minus the frame overhead
+    let channel = conn.create_channel().unwrap();
+    channel.status.set_state(ChannelState::Connected);
+    channel.basic_publish("", "queue", BasicPublishOptions::default(), vec![0; 5000], BasicProperties::default()).unwrap();
+    let mut sizes = Vec::new();
+    while let Some(frame) = conn.next_frame() {
+      if let AMQPFrame::Body(_, body) = frame {
+        sizes.push(body.len());
+      }
+    }
+    assert_eq!(sizes, vec![4088, 912]);
+  }
+
+  #[test]
+  fn basic_publish_confirmations() {
+    let _ = env_logger::try_init();
+
+    use crate::acknowledgement::Confirmation;
+
+    let (conn, channel) = connected_channel();
+    conn.configuration.set_frame_max(8192);
+    assert_eq!(channel.next_publish_seq_no(), 0);
+    channel.status.set_confirm();
+
+    let mut tags = Vec::new();
+    for _ in 0..3 {
+      let next_publish_seq_no = channel.next_publish_seq_no();
+      tags.push(channel.basic_publish("", "queue", BasicPublishOptions { mandatory: true, ..BasicPublishOptions::default() }, Vec::new(), BasicProperties::default()).unwrap().unwrap());
+      assert_eq!(tags.last(), Some(&next_publish_seq_no));
+    }
+    assert_eq!(channel.next_publish_seq_no(), 4);
+
+    // The first message is unroutable, the server returns it before acking it
+    conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Basic(basic::AMQPMethod::Return(basic::Return {
+      reply_code:  312,
+      reply_text:  "NO_ROUTE".to_string(),
+      exchange:    "".to_string(),
+      routing_key: "queue".to_string(),
+    })))).unwrap();
+    conn.handle_frame(AMQPFrame::Header(channel.id(), 60, Box::new(AMQPContentHeader {
+      class_id:   60,
+      weight:     0,
+      body_size:  0,
+      properties: BasicProperties::default(),
+    }))).unwrap();
+    conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Basic(basic::AMQPMethod::Ack(basic::Ack { delivery_tag: tags[0], multiple: false })))).unwrap();
+    conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Basic(basic::AMQPMethod::Ack(basic::Ack { delivery_tag: tags[2], multiple: true })))).unwrap();
+
+    match channel.acknowledgements.take_confirmation(tags[0]) {
+      Some(Confirmation::Returned(message)) => assert_eq!(message.reply_code, 312),
+      confirmation => panic!("expected the message to be returned, got {:?}", confirmation),
+    }
+    assert_eq!(channel.acknowledgements.take_confirmation(tags[1]), Some(Confirmation::Ack));
+    assert_eq!(channel.acknowledgements.take_confirmation(tags[2]), Some(Confirmation::Ack));
+    assert_eq!(channel.acknowledgements.take_confirmation(tags[2]), None);
+  }
+
+  #[test]
+  fn basic_publish_returns_before_multiple_ack() {
+    let _ = env_logger::try_init();
+
+    use crate::acknowledgement::Confirmation;
+
+    let (conn, channel) = connected_channel();
+    conn.configuration.set_frame_max(8192);
+    channel.status.set_confirm();
+    for routing_key in &["first", "second", "third"] {
+      channel.basic_publish("", routing_key, BasicPublishOptions { mandatory: true, ..BasicPublishOptions::default() }, Vec::new(), BasicProperties::default()).unwrap();
+    }
+
+    // the first two messages are unroutable, they're returned before a single ack of all three
+    for routing_key in &["first", "second"] {
+      conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Basic(basic::AMQPMethod::Return(basic::Return {
+        reply_code:  312,
+        reply_text:  "NO_ROUTE".to_string(),
+        exchange:    "".to_string(),
+        routing_key: routing_key.to_string(),
+      })))).unwrap();
+      conn.handle_frame(AMQPFrame::Header(channel.id(), 60, Box::new(AMQPContentHeader {
+        class_id:   60,
+        weight:     0,
+        body_size:  0,
+        properties: BasicProperties::default(),
+      }))).unwrap();
+    }
+    conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Basic(basic::AMQPMethod::Ack(basic::Ack { delivery_tag: 3, multiple: true })))).unwrap();
+
+    for (delivery_tag, routing_key) in &[(1, "first"), (2, "second")] {
+      match channel.acknowledgements.take_confirmation(*delivery_tag) {
+        Some(Confirmation::Returned(message)) => assert_eq!(message.delivery.routing_key, *routing_key),
+        confirmation                          => panic!("expected message {} to be returned, got {:?}", delivery_tag, confirmation),
+      }
+    }
+    assert_eq!(channel.acknowledgements.take_confirmation(3), Some(Confirmation::Ack));
+  }
+
+  #[test]
+  fn basic_publish_returned_while_earlier_unconfirmed() {
+    let _ = env_logger::try_init();
+
+    use crate::acknowledgement::Confirmation;
+
+    let (conn, channel) = connected_channel();
+    conn.configuration.set_frame_max(8192);
+    channel.status.set_confirm();
+    for routing_key in &["routed", "unroutable"] {
+      channel.basic_publish("", routing_key, BasicPublishOptions { mandatory: true, ..BasicPublishOptions::default() }, Vec::new(), BasicProperties::default()).unwrap();
+    }
+
+    // the second message is returned and acked while the first one still waits to be confirmed
+    conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Basic(basic::AMQPMethod::Return(basic::Return {
+      reply_code:  312,
+      reply_text:  "NO_ROUTE".to_string(),
+      exchange:    "".to_string(),
+      routing_key: "unroutable".to_string(),
+    })))).unwrap();
+    conn.handle_frame(AMQPFrame::Header(channel.id(), 60, Box::new(AMQPContentHeader {
+      class_id:   60,
+      weight:     0,
+      body_size:  0,
+      properties: BasicProperties::default(),
+    }))).unwrap();
+    conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Basic(basic::AMQPMethod::Ack(basic::Ack { delivery_tag: 2, multiple: false })))).unwrap();
+    conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Basic(basic::AMQPMethod::Ack(basic::Ack { delivery_tag: 1, multiple: false })))).unwrap();
+
+    match channel.acknowledgements.take_confirmation(2) {
+      Some(Confirmation::Returned(message)) => assert_eq!(message.delivery.routing_key, "unroutable"),
+      confirmation                          => panic!("expected the second message to be returned, got {:?}", confirmation),
+    }
+    assert_eq!(channel.acknowledgements.take_confirmation(1), Some(Confirmation::Ack));
+  }
+
+  #[test]
+  fn basic_return_listened() {
+    let _ = env_logger::try_init();
+
+    let (conn, channel) = connected_channel();
+    channel.returned_messages.listen();
+
+    conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Basic(basic::AMQPMethod::Return(basic::Return {
+      reply_code:  312,
+      reply_text:  "NO_ROUTE".to_string(),
+      exchange:    "exchange".to_string(),
+      routing_key: "key".to_string(),
+    })))).unwrap();
+    conn.handle_frame(AMQPFrame::Header(channel.id(), 60, Box::new(AMQPContentHeader {
+      class_id:   60,
+      weight:     0,
+      body_size:  10,
+      properties: BasicProperties::default().with_content_type("text/plain".to_string()),
+    }))).unwrap();
+    assert_eq!(channel.returned_messages.next_returned_message(), None);
+    conn.handle_frame(AMQPFrame::Body(channel.id(), b"hello".to_vec())).unwrap();
+    assert_eq!(channel.returned_messages.next_returned_message(), None);
+    conn.handle_frame(AMQPFrame::Body(channel.id(), b"world".to_vec())).unwrap();
+    assert_eq!(channel.status.state(), ChannelState::Connected);
+
+    let message = channel.returned_messages.next_returned_message().unwrap();
+    assert_eq!(message.reply_code, 312);
+    assert_eq!(message.reply_text, "NO_ROUTE");
+    assert_eq!(message.delivery.exchange, "exchange");
+    assert_eq!(message.delivery.routing_key, "key");
+    assert_eq!(message.delivery.properties.content_type(), &Some("text/plain".to_string()));
+    assert_eq!(message.delivery.data, b"helloworld".to_vec());
+    assert_eq!(channel.returned_messages.next_returned_message(), None);
+  }
+
+  #[test]
+  fn queue_delete_message_count() {
+    let _ = env_logger::try_init();
+
+    use amq_protocol::protocol::queue;
+    use crate::channel::options::QueueDeleteOptions;
+    use crate::queue::Queue;
+
+    let (conn, channel) = connected_channel();
+    channel.queues.register(Queue::new("deleted".to_string(), 5, 0));
+
+    let request_id = channel.queue_delete("deleted", QueueDeleteOptions::default()).unwrap().unwrap();
+    conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Queue(queue::AMQPMethod::DeleteOk(queue::DeleteOk { message_count: 5 })))).unwrap();
+    assert_eq!(channel.requests.was_successful(request_id), Some(true));
+    assert_eq!(channel.message_counts.get(request_id), Some(5));
+    assert_eq!(channel.message_counts.get(request_id), None);
+  }
+
+  #[test]
+  fn abandoned_request_answer_dropped() {
+    let _ = env_logger::try_init();
+
+    use amq_protocol::protocol::queue;
+    use crate::channel::options::QueuePurgeOptions;
+
+    let (conn, channel) = connected_channel();
+
+    let purge_ok = |message_count| AMQPFrame::Method(channel.id(), AMQPClass::Queue(queue::AMQPMethod::PurgeOk(queue::PurgeOk { message_count })));
+    let abandoned = channel.queue_purge("queue", QueuePurgeOptions::default()).unwrap().unwrap();
+    let request_id = channel.queue_purge("queue", QueuePurgeOptions::default()).unwrap().unwrap();
+    channel.abandon_request(abandoned);
+
+    // the late answer doesn't get mistaken for the one of the next request
+    conn.handle_frame(purge_ok(1)).unwrap();
+    assert_eq!(channel.requests.was_successful(abandoned), None);
+    assert_eq!(channel.requests.was_successful(request_id), None);
+    conn.handle_frame(purge_ok(2)).unwrap();
+    assert_eq!(channel.requests.was_successful(request_id), Some(true));
+    assert_eq!(channel.message_counts.get(request_id), Some(2));
+  }
+
+  #[test]
+  fn queue_purge_message_count() {
+    let _ = env_logger::try_init();
+
+    use amq_protocol::protocol::queue;
+    use crate::channel::options::QueuePurgeOptions;
+
+    let (conn, channel) = connected_channel();
+
+    let request_id = channel.queue_purge("purged", QueuePurgeOptions::default()).unwrap().unwrap();
+    conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Queue(queue::AMQPMethod::PurgeOk(queue::PurgeOk { message_count: 12 })))).unwrap();
+    assert_eq!(channel.requests.was_successful(request_id), Some(true));
+    assert_eq!(channel.message_counts.get(request_id), Some(12));
+  }
+
+  #[test]
+  fn exchange_bind_and_delete() {
+    let _ = env_logger::try_init();
+
+    use amq_protocol::protocol::exchange;
+    use crate::channel::options::{ExchangeBindOptions, ExchangeDeleteOptions, ExchangeUnbindOptions};
+    use crate::types::FieldTable;
+
+    let conn = Connection::new();
+    conn.status.set_state(ConnectionState::Connected);
+    conn.configuration.set_channel_max(2047);
+    let channel = conn.create_channel().unwrap();
+    assert!(channel.exchange_bind("topic", "fanout", "", ExchangeBindOptions::default(), FieldTable::new()).is_err());
+    channel.status.set_state(ChannelState::Connected);
+
+    let request_id = channel.exchange_bind("topic", "fanout", "", ExchangeBindOptions::default(), FieldTable::new()).unwrap().unwrap();
+    conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Exchange(exchange::AMQPMethod::BindOk(exchange::BindOk {})))).unwrap();
+    assert_eq!(channel.requests.was_successful(request_id), Some(true));
+
+    let request_id = channel.exchange_unbind("topic", "fanout", "", ExchangeUnbindOptions::default(), FieldTable::new()).unwrap().unwrap();
+    conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Exchange(exchange::AMQPMethod::UnbindOk(exchange::UnbindOk {})))).unwrap();
+    assert_eq!(channel.requests.was_successful(request_id), Some(true));
+
+    let request_id = channel.exchange_delete("fanout", ExchangeDeleteOptions { if_unused: true, ..ExchangeDeleteOptions::default() }).unwrap().unwrap();
+    conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Exchange(exchange::AMQPMethod::DeleteOk(exchange::DeleteOk {})))).unwrap();
+    assert_eq!(channel.requests.was_successful(request_id), Some(true));
+  }
+
+  #[test]
+  fn queue_redeclare_keeps_consumers() {
+    let _ = env_logger::try_init();
+
+    use amq_protocol::protocol::queue;
+    use crate::channel::options::QueueDeclareOptions;
+    use crate::consumer::Consumer;
+    use crate::queue::Queue;
+    use crate::types::FieldTable;
+
+    let (conn, channel) = connected_channel();
+    let queue_name = "consumed".to_string();
+    let consumer_tag = "consumer-tag".to_string();
+    let mut queue = Queue::new(queue_name.clone(), 0, 0);
+    queue.consumers.insert(consumer_tag.clone(), Consumer::new(consumer_tag.clone(), false, false, false, Box::new(DummySubscriber)));
+    channel.queues.register(queue);
+
+    let request_id = channel.queue_declare(&queue_name, QueueDeclareOptions::default(), FieldTable::new()).unwrap().unwrap();
+    conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Queue(queue::AMQPMethod::DeclareOk(queue::DeclareOk {
+      queue:          queue_name.clone(),
+      message_count:  3,
+      consumer_count: 1,
+    })))).unwrap();
+    assert_eq!(channel.generated_names.get(request_id), Some(queue_name.clone()));
+    assert_eq!(channel.queues.get_stats(&queue_name).message_count, 3);
+    assert_eq!(channel.queues.start_consumer_delivery(&consumer_tag, Delivery::new(1, "".to_string(), queue_name.clone(), false)), Some(queue_name));
+  }
+
+  #[test]
+  fn basic_cancel_by_server() {
+    let _ = env_logger::try_init();
+
+    use crate::consumer::Consumer;
+    use crate::queue::Queue;
+    use parking_lot::Mutex;
+    use std::sync::Arc;
+
+    #[derive(Debug, Default)]
+    struct CancelSubscriber(Arc<Mutex<Option<bool>>>);
+
+    impl ConsumerSubscriber for CancelSubscriber {
+      fn new_delivery(&self, _delivery: Delivery) {}
+      fn drop_prefetched_messages(&self) {}
+      fn cancel(&self) {
+        *self.0.lock() = Some(false);
+      }
+      fn cancel_by_server(&self) {
+        *self.0.lock() = Some(true);
+      }
+    }
+
+    let (conn, channel) = connected_channel();
+    let canceled = Arc::new(Mutex::new(None));
+    let consumer_tag = "consumer-tag".to_string();
+    let mut queue = Queue::new("consumed".to_string(), 0, 0);
+    queue.consumers.insert(consumer_tag.clone(), Consumer::new(consumer_tag.clone(), false, false, false, Box::new(CancelSubscriber(canceled.clone()))));
+    channel.queues.register(queue);
+
+    conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Basic(basic::AMQPMethod::Cancel(basic::Cancel {
+      consumer_tag: consumer_tag.clone(),
+      nowait:       false,
+    })))).unwrap();
+    assert_eq!(*canceled.lock(), Some(true));
+    assert_eq!(conn.next_frame(), Some(AMQPFrame::Method(channel.id(), AMQPClass::Basic(basic::AMQPMethod::CancelOk(basic::CancelOk { consumer_tag })))));
+  }
+
+  #[test]
+  fn metrics_hooks() {
+    let _ = env_logger::try_init();
+
+    use crate::channel::options::BasicAckOptions;
+    use crate::metrics::MetricsHooks;
+    use parking_lot::Mutex;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    #[derive(Default)]
+    struct Counters(Mutex<HashMap<&'static str, u64>>);
+
+    impl Counters {
+      fn add(&self, name: &'static str, count: u64) {
+        *self.0.lock().entry(name).or_insert(0) += count;
+      }
+    }
+
+    impl MetricsHooks for Counters {
+      fn published(&self, _: u16)            { self.add("published", 1) }
+      fn confirmed(&self, _: u16, count: u64) { self.add("confirmed", count) }
+      fn nacked(&self, _: u16, count: u64)    { self.add("nacked", count) }
+      fn returned(&self, _: u16)             { self.add("returned", 1) }
+      fn delivered(&self, _: u16)            { self.add("delivered", 1) }
+      fn acked(&self, _: u16, count: u64)     { self.add("acked", count) }
+    }
+
+    let conn = Connection::new();
+    conn.status.set_state(ConnectionState::Connected);
+    conn.configuration.set_channel_max(2047);
+    conn.configuration.set_frame_max(8192);
+    let counters = Arc::new(Counters::default());
+    conn.metrics.set(counters.clone());
+    let channel = conn.create_channel().unwrap();
+    channel.status.set_state(ChannelState::Connected);
+    channel.status.set_confirm();
+
+    for _ in 0..3 {
+      channel.basic_publish("", "queue", BasicPublishOptions::default(), Vec::new(), BasicProperties::default()).unwrap();
+    }
+    conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Basic(basic::AMQPMethod::Nack(basic::Nack { delivery_tag: 1, multiple: false, requeue: false })))).unwrap();
+    conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Basic(basic::AMQPMethod::Ack(basic::Ack { delivery_tag: 3, multiple: true })))).unwrap();
+    conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Basic(basic::AMQPMethod::Return(basic::Return {
+      reply_code:  312,
+      reply_text:  "NO_ROUTE".to_string(),
+      exchange:    "".to_string(),
+      routing_key: "queue".to_string(),
+    })))).unwrap();
+    conn.handle_frame(AMQPFrame::Header(channel.id(), 60, Box::new(AMQPContentHeader {
+      class_id:   60,
+      weight:     0,
+      body_size:  0,
+      properties: BasicProperties::default(),
+    }))).unwrap();
+    for delivery_tag in 1..=2 {
+      conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Basic(basic::AMQPMethod::Deliver(basic::Deliver {
+        consumer_tag: "consumer-tag".to_string(),
+        delivery_tag,
+        redelivered:  false,
+        exchange:     "".to_string(),
+        routing_key:  "queue".to_string(),
+      })))).unwrap();
+    }
+    channel.basic_ack(2, BasicAckOptions { multiple: true }).unwrap();
+    // nothing is waiting for an ack anymore
+    channel.basic_ack(2, BasicAckOptions { multiple: true }).unwrap();
+
+    let counts = counters.0.lock().clone();
+    assert_eq!(counts.get("published"), Some(&3));
+    assert_eq!(counts.get("confirmed"), Some(&2));
+    assert_eq!(counts.get("nacked"),    Some(&1));
+    assert_eq!(counts.get("returned"),  Some(&1));
+    assert_eq!(counts.get("delivered"), Some(&2));
+    assert_eq!(counts.get("acked"),     Some(&2));
+  }
+
+  #[test]
+  fn basic_reject_needs_delivery() {
+    let _ = env_logger::try_init();
+
+    use crate::channel::options::BasicRejectOptions;
+
+    let (conn, channel) = connected_channel();
+    assert!(channel.basic_reject(1, BasicRejectOptions { requeue: true }).is_err());
+    conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Basic(basic::AMQPMethod::Deliver(basic::Deliver {
+      consumer_tag: "consumer-tag".to_string(),
+      delivery_tag: 1,
+      redelivered:  false,
+      exchange:     "".to_string(),
+      routing_key:  "queue".to_string(),
+    })))).unwrap();
+    assert!(channel.basic_reject(0, BasicRejectOptions::default()).is_err());
+    assert!(channel.basic_reject(2, BasicRejectOptions::default()).is_err());
+    assert!(channel.basic_reject(1, BasicRejectOptions { requeue: true }).is_ok());
+    channel.status.set_state(ChannelState::Closed);
+    assert!(channel.basic_reject(1, BasicRejectOptions::default()).is_err());
+  }
+
+  #[test]
+  fn basic_ack_multiple_settles_deliveries() {
+    let _ = env_logger::try_init();
+
+    use crate::channel::options::BasicAckOptions;
+
+    let (conn, channel) = connected_channel();
+    for delivery_tag in 1..=4 {
+      conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Basic(basic::AMQPMethod::Deliver(basic::Deliver {
+        consumer_tag: "consumer-tag".to_string(),
+        delivery_tag,
+        redelivered:  false,
+        exchange:     "".to_string(),
+        routing_key:  "queue".to_string(),
+      })))).unwrap();
+    }
+    assert_eq!(channel.unacked_deliveries.count(), 4);
+    channel.basic_ack(2, BasicAckOptions::default()).unwrap();
+    assert_eq!(channel.unacked_deliveries.tags(), vec![1, 3, 4]);
+    channel.basic_ack(3, BasicAckOptions { multiple: true }).unwrap();
+    assert_eq!(channel.unacked_deliveries.count(), 1);
+    assert!(channel.unacked_deliveries.is_outstanding(4));
+    assert!(!channel.unacked_deliveries.settle(2, false));
+    channel.basic_ack(4, BasicAckOptions::default()).unwrap();
+    assert_eq!(channel.unacked_deliveries.count(), 0);
+    channel.status.set_state(ChannelState::Closed);
+    assert!(channel.basic_ack(4, BasicAckOptions::default()).is_err());
+  }
+
+
+  #[test]
+  fn no_ack_deliveries_not_acked() {
+    let _ = env_logger::try_init();
+
+    use crate::channel::options::BasicAckOptions;
+    use crate::consumer::Consumer;
+    use crate::queue::Queue;
+
+    let (conn, channel) = connected_channel();
+    let mut queue = Queue::new("queue".to_string(), 0, 0);
+    queue.consumers.insert("consumer-tag".to_string(), Consumer::new("consumer-tag".to_string(), false, true, false, Box::new(DummySubscriber)));
+    channel.queues.register(queue);
+    conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Basic(basic::AMQPMethod::Deliver(basic::Deliver {
+      consumer_tag: "consumer-tag".to_string(),
+      delivery_tag: 1,
+      redelivered:  false,
+      exchange:     "".to_string(),
+      routing_key:  "queue".to_string(),
+    })))).unwrap();
+    assert_eq!(channel.unacked_deliveries.count(), 0);
+    // the server would close the channel
+    match channel.basic_ack(1, BasicAckOptions::default()) {
+      Err(err) => assert_eq!(err.to_string(), ErrorKind::NotAwaitingAck(1).to_string()),
+      Ok(_)    => panic!("acking a no_ack delivery should fail"),
+    }
+    assert_eq!(conn.next_frame(), None);
+  }
+  #[test]
+  fn basic_recover_forgets_unacked_deliveries() {
+    let _ = env_logger::try_init();
+
+    use crate::channel::options::BasicRecoverOptions;
+
+    let (conn, channel) = connected_channel();
+    conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Basic(basic::AMQPMethod::Deliver(basic::Deliver {
+      consumer_tag: "consumer-tag".to_string(),
+      delivery_tag: 1,
+      redelivered:  false,
+      exchange:     "".to_string(),
+      routing_key:  "queue".to_string(),
+    })))).unwrap();
+    assert_eq!(channel.unacked_deliveries.count(), 1);
+    let request_id = channel.basic_recover(BasicRecoverOptions { requeue: true }).unwrap().unwrap();
+    assert_eq!(channel.unacked_deliveries.count(), 0);
+    conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Basic(basic::AMQPMethod::RecoverOk(basic::RecoverOk {})))).unwrap();
+    assert_eq!(channel.requests.was_successful(request_id), Some(true));
+    channel.status.set_state(ChannelState::Closed);
+    assert!(channel.basic_recover(BasicRecoverOptions { requeue: true }).is_err());
+  }
+
+  #[test]
+  fn tx_select_excludes_confirm_select() {
+    let _ = env_logger::try_init();
+
+    use amq_protocol::protocol::tx;
+    use crate::channel::options::ConfirmSelectOptions;
+
+    let (conn, channel) = connected_channel();
+    assert!(channel.tx_select().unwrap().is_some());
+    conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Tx(tx::AMQPMethod::SelectOk(tx::SelectOk {})))).unwrap();
+    assert!(channel.status.tx());
+    assert!(channel.confirm_select(ConfirmSelectOptions::default()).is_err());
+
+    let channel = conn.create_channel().unwrap();
+    channel.status.set_state(ChannelState::Connected);
+    channel.status.set_confirm();
+    assert!(channel.tx_select().is_err());
+  }
+
+  #[test]
+  fn confirm_select_nowait() {
+    let _ = env_logger::try_init();
+
+    use amq_protocol::protocol::{channel, AMQPError, AMQPHardError};
+    use crate::channel::options::ConfirmSelectOptions;
+
+    let (conn, channel) = connected_channel();
+    conn.configuration.set_frame_max(8192);
+    assert_eq!(channel.confirm_select(ConfirmSelectOptions { nowait: true }).unwrap(), None);
+    assert!(channel.status.confirm());
+    let delivery_tag = channel.basic_publish("", "queue", BasicPublishOptions::default(), b"hello".to_vec(), BasicProperties::default()).unwrap().unwrap();
+
+    // the server refusing the confirm mode closes the channel
+    conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Channel(channel::AMQPMethod::Close(channel::Close {
+      reply_code: 540,
+      reply_text: "NOT_IMPLEMENTED".to_string(),
+      class_id:   85,
+      method_id:  10,
+    })))).unwrap();
+    assert_eq!(channel.status.state(), ChannelState::Error);
+    assert_eq!(channel.status.close_reason().and_then(|reason| reason.error()), Some(AMQPError::Hard(AMQPHardError::NOTIMPLEMENTED)));
+    assert!(channel.acknowledgements.take_confirmation(delivery_tag).is_none());
+  }
+
+  #[test]
+  fn channel_close() {
+    let _ = env_logger::try_init();
+
+    use amq_protocol::protocol::{channel, queue};
+    use crate::channel::options::QueueDeclareOptions;
+    use crate::error::CloseReason;
+    use crate::topology::ConsumerDefinition;
+    use crate::types::FieldTable;
+
+    let conn = Connection::new();
+    conn.status.set_state(ConnectionState::Connected);
+    conn.configuration.set_channel_max(2047);
+
+    // closed by the client, the pending requests fail and the replies to them are discarded
+    let channel = conn.create_channel().unwrap();
+    channel.status.set_state(ChannelState::Connected);
+    let declare = channel.queue_declare("queue", QueueDeclareOptions::default(), FieldTable::default()).unwrap().unwrap();
+    conn.topology.channel_opened(channel.id());
+    conn.topology.consumer_added(channel.id(), ConsumerDefinition { tag: "first".to_string(), queue: "queue".to_string(), ..ConsumerDefinition::default() });
+    let close = channel.channel_close(200, "bye", 0, 0).unwrap().unwrap();
+    assert_eq!(channel.status.state(), ChannelState::Closing);
+    assert_eq!(channel.requests.was_successful(declare), Some(false));
+    conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Queue(queue::AMQPMethod::DeclareOk(queue::DeclareOk {
+      queue:          "queue".to_string(),
+      message_count:  0,
+      consumer_count: 0,
+    })))).unwrap();
+    conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Channel(channel::AMQPMethod::CloseOk(channel::CloseOk {})))).unwrap();
+    assert_eq!(channel.requests.was_successful(close), Some(true));
+    assert_eq!(channel.status.state(), ChannelState::Closed);
+    assert!(conn.channels.get(channel.id()).is_none());
+    // it's not reopened on a new connection, nor are its consumers resumed
+    assert!(conn.topology.channels().is_empty());
+    assert!(conn.topology.snapshot().consumers.is_empty());
+
+    // closed by the server
+    let channel = conn.create_channel().unwrap();
+    channel.status.set_state(ChannelState::Connected);
+    let declare = channel.queue_declare("queue", QueueDeclareOptions::default(), FieldTable::default()).unwrap().unwrap();
+    conn.topology.channel_opened(channel.id());
+    conn.topology.consumer_added(channel.id(), ConsumerDefinition { tag: "second".to_string(), queue: "queue".to_string(), ..ConsumerDefinition::default() });
+    let reason = CloseReason {
+      reply_code: 406,
+      reply_text: "PRECONDITION_FAILED".to_string(),
+      class_id:   50,
+      method_id:  10,
+    };
+    conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Channel(channel::AMQPMethod::Close(channel::Close {
+      reply_code: reason.reply_code,
+      reply_text: reason.reply_text.clone(),
+      class_id:   reason.class_id,
+      method_id:  reason.method_id,
+    })))).unwrap();
+    assert_eq!(channel.requests.was_successful(declare), Some(false));
+    assert_eq!(channel.status.close_reason(), Some(reason));
+    assert_eq!(channel.status.state(), ChannelState::Error);
+    assert!(conn.channels.get(channel.id()).is_none());
+    assert!(conn.topology.channels().is_empty());
+    assert!(conn.topology.snapshot().consumers.is_empty());
+  }
 }
@@ -2,15 +2,13 @@ use std::fmt;
 
 use crate::types::FieldTable;
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
 pub enum ConnectionSASLMechanism {
+  /// authenticate using the username and password
+  #[default]
   PLAIN,
-}
-
-impl Default for ConnectionSASLMechanism {
-  fn default() -> Self {
-    ConnectionSASLMechanism::PLAIN
-  }
+  /// let the server authenticate us through other means, such as a TLS client certificate
+  EXTERNAL,
 }
 
 impl fmt::Display for ConnectionSASLMechanism {
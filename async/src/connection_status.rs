@@ -3,8 +3,9 @@ use parking_lot::RwLock;
 use std::sync::Arc;
 
 use crate::{
-  connection_properties::ConnectionProperties,
+  connection_properties::{ConnectionProperties, ConnectionSASLMechanism},
   credentials::Credentials,
+  error::CloseReason,
 };
 
 #[derive(Clone, Debug, Default)]
@@ -38,16 +39,38 @@ impl ConnectionStatus {
   }
 
   pub fn unblock(&self) {
-    self.inner.write().blocked = true;
+    self.inner.write().blocked = false;
+  }
+
+  pub fn is_connecting(&self) -> bool {
+    matches!(self.inner.read().state, ConnectionState::Connecting(_))
+  }
+
+  pub fn is_connected(&self) -> bool {
+    self.inner.read().state == ConnectionState::Connected
   }
 
-  pub fn blocked(&self) -> bool {
+  pub fn is_blocked(&self) -> bool {
     self.inner.read().blocked
   }
+
+  pub fn is_closed(&self) -> bool {
+    self.inner.read().state == ConnectionState::Closed
+  }
+
+  /// the reason why the connection is closing or got closed, by the client or the server
+  pub fn close_reason(&self) -> Option<CloseReason> {
+    self.inner.read().close_reason.clone()
+  }
+
+  pub fn set_close_reason(&self, reason: CloseReason) {
+    self.inner.write().close_reason = Some(reason);
+  }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, Default, PartialEq)]
 pub enum ConnectionState {
+  #[default]
   Initial,
   Connecting(ConnectingState),
   Connected,
@@ -60,29 +83,26 @@ pub enum ConnectionState {
 pub enum ConnectingState {
   Initial,
   SentProtocolHeader(Credentials, ConnectionProperties),
-  SentStartOk,
+  SentStartOk(Credentials, ConnectionSASLMechanism),
+  SentSecureOk(Credentials, ConnectionSASLMechanism),
   SentOpen,
 }
 
-impl Default for ConnectionState {
-  fn default() -> Self {
-    ConnectionState::Initial
-  }
-}
-
 #[derive(Debug)]
 struct Inner {
-  state:   ConnectionState,
-  vhost:   String,
-  blocked: bool,
+  state:        ConnectionState,
+  vhost:        String,
+  blocked:      bool,
+  close_reason: Option<CloseReason>,
 }
 
 impl Default for Inner {
   fn default() -> Self {
     Self {
-      state:   ConnectionState::default(),
-      vhost:   "/".to_string(),
-      blocked: false,
+      state:        ConnectionState::default(),
+      vhost:        "/".to_string(),
+      blocked:      false,
+      close_reason: None,
     }
   }
 }
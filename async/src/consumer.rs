@@ -1,5 +1,6 @@
 use std::fmt::Debug;
 
+use crate::acknowledgement::DeliveryTag;
 use crate::channel::BasicProperties;
 use crate::message::Delivery;
 
@@ -25,25 +26,42 @@ impl Consumer {
     }
   }
 
+  pub fn no_ack(&self) -> bool {
+    self.no_ack
+  }
+
   pub fn start_new_delivery(&mut self, delivery: Delivery) {
     self.current_message = Some(delivery)
   }
 
-  pub fn set_delivery_properties(&mut self, properties: BasicProperties) {
+  pub fn receive_delivery_header(&mut self, size: u64, properties: BasicProperties) {
     if let Some(delivery) = self.current_message.as_mut() {
-      delivery.properties = properties;
+      if self.subscriber.streams_body() {
+        delivery.properties = properties;
+        self.subscriber.new_delivery_header(delivery.clone(), size);
+      } else {
+        delivery.receive_content_header(size, properties);
+      }
     }
   }
 
   pub fn receive_delivery_content(&mut self, payload: Vec<u8>) {
     if let Some(delivery) = self.current_message.as_mut() {
-      delivery.receive_content(payload);
+      if self.subscriber.streams_body() {
+        self.subscriber.new_body_chunk(delivery.delivery_tag, payload);
+      } else {
+        delivery.receive_content(payload);
+      }
     }
   }
 
   pub fn new_delivery_complete(&mut self) {
     if let Some(delivery) = self.current_message.take() {
-      self.subscriber.new_delivery(delivery);
+      if self.subscriber.streams_body() {
+        self.subscriber.delivery_body_complete(delivery.delivery_tag);
+      } else {
+        self.subscriber.new_delivery(delivery);
+      }
     }
   }
 
@@ -54,10 +72,28 @@ impl Consumer {
   pub fn cancel(&self) {
     self.subscriber.cancel();
   }
+
+  pub fn cancel_by_server(&self) {
+    self.subscriber.cancel_by_server();
+  }
 }
 
 pub trait ConsumerSubscriber: Debug+Send+Sync {
   fn new_delivery(&self, delivery: Delivery);
   fn drop_prefetched_messages(&self);
   fn cancel(&self);
+  /// the server canceled the consumer, for example because its queue was deleted
+  fn cancel_by_server(&self) {
+    self.cancel();
+  }
+  /// whether the body of the deliveries is handed over frame by frame through
+  /// `new_delivery_header`, `new_body_chunk` and `delivery_body_complete` instead of being
+  /// buffered for `new_delivery`
+  fn streams_body(&self) -> bool {
+    false
+  }
+  /// a delivery with an empty body, of which `body_size` bytes will follow
+  fn new_delivery_header(&self, _delivery: Delivery, _body_size: u64) {}
+  fn new_body_chunk(&self, _delivery_tag: DeliveryTag, _chunk: Vec<u8>) {}
+  fn delivery_body_complete(&self, _delivery_tag: DeliveryTag) {}
 }
@@ -0,0 +1,60 @@
+use crate::field_table::FieldTableBuilder;
+use crate::types::{AMQPValue, FieldTable};
+
+/// Builds the `x-` arguments of a consumer without having to remember their keys
+///
+/// the result is passed to `basic_consume` through `into_field_table`, or `FieldTable::from`.
+/// Arguments the builder doesn't know about can still be added with `insert`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConsumerArguments {
+  arguments: FieldTableBuilder,
+}
+
+impl ConsumerArguments {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// the priority of the consumer, the server sends the messages to the consumers with the
+  /// highest priority first while they can take them (`x-priority`, 0 by default)
+  pub fn priority(self, priority: i32) -> Self {
+    self.insert("x-priority", AMQPValue::LongLongInt(priority.into()))
+  }
+
+  /// asks the server to cancel the consumer when the master of its mirrored queue fails over
+  /// (`x-cancel-on-ha-failover`)
+  pub fn cancel_on_ha_failover(self, cancel: bool) -> Self {
+    self.insert("x-cancel-on-ha-failover", AMQPValue::Boolean(cancel))
+  }
+
+  /// adds a raw argument, replacing any previous value for this key
+  pub fn insert(self, key: &str, value: AMQPValue) -> Self {
+    Self { arguments: self.arguments.insert(key, value) }
+  }
+
+  pub fn into_field_table(self) -> FieldTable {
+    self.arguments.build()
+  }
+}
+
+impl From<ConsumerArguments> for FieldTable {
+  fn from(arguments: ConsumerArguments) -> Self {
+    arguments.into_field_table()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn consumer_arguments() {
+    let arguments: FieldTable = ConsumerArguments::new()
+      .priority(-1)
+      .cancel_on_ha_failover(true)
+      .into();
+
+    assert_eq!(arguments.get("x-priority"),              Some(&AMQPValue::LongLongInt(-1)));
+    assert_eq!(arguments.get("x-cancel-on-ha-failover"), Some(&AMQPValue::Boolean(true)));
+  }
+}
@@ -1,5 +1,7 @@
 use amq_protocol::sasl;
 
+use crate::connection_properties::ConnectionSASLMechanism;
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Credentials {
   username: String,
@@ -14,6 +16,23 @@ impl Credentials {
   pub fn sasl_plain_auth_string(&self) -> String {
     sasl::plain_auth_string(&self.username, &self.password)
   }
+
+  /// the response to send in Connection.StartOk for the given mechanism
+  ///
+  /// EXTERNAL ignores the username and password and sends an empty response
+  pub fn sasl_auth_string(&self, mechanism: ConnectionSASLMechanism) -> String {
+    match mechanism {
+      ConnectionSASLMechanism::PLAIN    => self.sasl_plain_auth_string(),
+      ConnectionSASLMechanism::EXTERNAL => String::new(),
+    }
+  }
+
+  /// the response to send in Connection.SecureOk to a `challenge` of the server
+  ///
+  /// PLAIN and EXTERNAL don't expect any challenge, they send the same response again
+  pub fn sasl_challenge_response(&self, mechanism: ConnectionSASLMechanism, _challenge: &str) -> String {
+    self.sasl_auth_string(mechanism)
+  }
 }
 
 impl Default for Credentials {
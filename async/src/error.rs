@@ -1,6 +1,6 @@
 use amq_protocol::{
   frame::GenError,
-  protocol::AMQPClass,
+  protocol::{AMQPClass, AMQPError},
 };
 use failure::{Backtrace, Context, Fail};
 
@@ -40,8 +40,24 @@ pub enum ErrorKind {
   UnexpectedReply,
   #[fail(display = "precondition failed")]
   PreconditionFailed,
+  #[fail(display = "no delivery with tag {} on this channel", _0)]
+  InvalidDeliveryTag(u64),
+  #[fail(display = "delivery {} isn't awaiting an ack, it was received with no_ack or was already settled", _0)]
+  NotAwaitingAck(u64),
+  #[fail(display = "a message body is being streamed on channel {}", _0)]
+  PublishInProgress(u16),
+  #[fail(display = "publishing with the immediate flag is rejected, the server would close the connection")]
+  ImmediateRejected,
+  #[fail(display = "the content header doesn't fit in a {} bytes frame, the headers of the message are too large", _0)]
+  ContentHeaderTooLarge(u32),
+  #[fail(display = "a body chunk of {} bytes goes beyond the announced body size", _0)]
+  UnexpectedBodyChunk(usize),
+  #[fail(display = "a channel cannot be both transactional and in confirm mode")]
+  IncompatibleChannelMode,
   #[fail(display = "The maximum number of channels for this connection has been reached")]
   ChannelLimitReached,
+  #[fail(display = "channel {} is already in use", _0)]
+  ChannelInUse(u16),
   #[fail(display = "invalid connection state: {:?}", _0)]
   InvalidConnectionState(ConnectionState),
   #[fail(display = "Failed to parse: {}", _0)]
@@ -52,6 +68,12 @@ pub enum ErrorKind {
   NoNewMessage,
   #[fail(display = "IO error: {:?}", _0)]
   IOError(#[fail(cause)] io::Error),
+  #[cfg(feature = "serde_json")]
+  #[fail(display = "the message isn't JSON but {}", _0)]
+  NotJson(String),
+  #[cfg(feature = "serde_json")]
+  #[fail(display = "JSON error: {}", _0)]
+  JsonError(#[fail(cause)] serde_json::Error),
   /// A hack to prevent developers from exhaustively match on the enum's variants
   ///
   /// The purpose of this variant is to let the `ErrorKind` enumeration grow more variants
@@ -98,3 +120,29 @@ impl From<Context<ErrorKind>> for Error {
     Error { inner }
   }
 }
+
+/// The reply code and text sent along a Close method, and the method that triggered it if any
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CloseReason {
+  pub reply_code: u16,
+  pub reply_text: String,
+  pub class_id:   u16,
+  pub method_id:  u16,
+}
+
+impl CloseReason {
+  /// the AMQP error matching the reply code, `None` for a normal close
+  pub fn error(&self) -> Option<AMQPError> {
+    AMQPError::from_id(self.reply_code)
+  }
+}
+
+impl fmt::Display for CloseReason {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{} ({})", self.reply_text, self.reply_code)?;
+    if self.class_id != 0 || self.method_id != 0 {
+      write!(f, " triggered by {}:{}", self.class_id, self.method_id)?;
+    }
+    Ok(())
+  }
+}
@@ -0,0 +1,39 @@
+/// The type of an exchange, as passed to `exchange_declare`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum ExchangeKind {
+  /// routes messages to the queues bound with the exact routing key
+  #[default]
+  Direct,
+  /// routes messages to all the bound queues, ignoring the routing key
+  Fanout,
+  /// routes messages according to their headers instead of their routing key
+  Headers,
+  /// routes messages to the queues whose binding pattern matches the routing key
+  Topic,
+  /// an exchange type provided by a plugin, such as `x-delayed-message`
+  Custom(String),
+}
+
+impl ExchangeKind {
+  pub fn kind(&self) -> &str {
+    match self {
+      ExchangeKind::Direct       => "direct",
+      ExchangeKind::Fanout       => "fanout",
+      ExchangeKind::Headers      => "headers",
+      ExchangeKind::Topic        => "topic",
+      ExchangeKind::Custom(kind) => kind,
+    }
+  }
+}
+
+impl From<&str> for ExchangeKind {
+  fn from(kind: &str) -> Self {
+    match kind {
+      "direct"  => ExchangeKind::Direct,
+      "fanout"  => ExchangeKind::Fanout,
+      "headers" => ExchangeKind::Headers,
+      "topic"   => ExchangeKind::Topic,
+      kind      => ExchangeKind::Custom(kind.to_string()),
+    }
+  }
+}
@@ -0,0 +1,57 @@
+use crate::field_table::FieldTableBuilder;
+use crate::types::{AMQPValue, FieldTable};
+
+/// Builds the arguments of an exchange declaration without having to remember their keys
+///
+/// the result is passed to `exchange_declare` through `into_field_table`, or `FieldTable::from`.
+/// Arguments the builder doesn't know about can still be added with `insert`
+///
+/// like for queues, declaring an existing exchange again with other arguments fails with a
+/// `PRECONDITION_FAILED` (406) error
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExchangeArguments {
+  arguments: FieldTableBuilder,
+}
+
+impl ExchangeArguments {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// the exchange the messages no binding matched get routed to, instead of being dropped or
+  /// returned to mandatory publishers (`alternate-exchange`, a RabbitMQ extension)
+  pub fn alternate_exchange(self, exchange: &str) -> Self {
+    self.insert("alternate-exchange", AMQPValue::LongString(exchange.to_string()))
+  }
+
+  /// adds a raw argument, replacing any previous value for this key
+  pub fn insert(self, key: &str, value: AMQPValue) -> Self {
+    Self { arguments: self.arguments.insert(key, value) }
+  }
+
+  pub fn into_field_table(self) -> FieldTable {
+    self.arguments.build()
+  }
+}
+
+impl From<ExchangeArguments> for FieldTable {
+  fn from(arguments: ExchangeArguments) -> Self {
+    arguments.into_field_table()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn alternate_exchange_argument() {
+    let arguments: FieldTable = ExchangeArguments::new()
+      .alternate_exchange("unroutable")
+      .insert("x-delayed-type", AMQPValue::LongString("direct".to_string()))
+      .into();
+
+    assert_eq!(arguments.get("alternate-exchange"), Some(&AMQPValue::LongString("unroutable".to_string())));
+    assert_eq!(arguments.get("x-delayed-type"),     Some(&AMQPValue::LongString("direct".to_string())));
+  }
+}
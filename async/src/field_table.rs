@@ -0,0 +1,107 @@
+use crate::types::{AMQPValue, FieldArray, FieldTable, Timestamp};
+
+/// Gives `FieldTable::builder`, `FieldTable` being a type of amq-protocol
+pub trait FieldTableExt {
+  fn builder() -> FieldTableBuilder;
+}
+
+impl FieldTableExt for FieldTable {
+  fn builder() -> FieldTableBuilder {
+    FieldTableBuilder::new()
+  }
+}
+
+/// Builds a `FieldTable` without spelling out each `AMQPValue`
+///
+/// each `insert_` method replaces any previous value for its key, `insert` takes any other type
+/// of value. The specific builders (`QueueArguments`, `ExchangeArguments`, `ConsumerArguments`)
+/// already know the keys and types of the usual `x-` arguments
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FieldTableBuilder {
+  table: FieldTable,
+}
+
+impl FieldTableBuilder {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn insert_bool(self, key: &str, value: bool) -> Self {
+    self.insert(key, AMQPValue::Boolean(value))
+  }
+
+  /// a 32 bits signed integer
+  pub fn insert_long(self, key: &str, value: i32) -> Self {
+    self.insert(key, AMQPValue::LongInt(value))
+  }
+
+  /// a 64 bits signed integer
+  pub fn insert_long_long(self, key: &str, value: i64) -> Self {
+    self.insert(key, AMQPValue::LongLongInt(value))
+  }
+
+  pub fn insert_float(self, key: &str, value: f32) -> Self {
+    self.insert(key, AMQPValue::Float(value))
+  }
+
+  pub fn insert_double(self, key: &str, value: f64) -> Self {
+    self.insert(key, AMQPValue::Double(value))
+  }
+
+  /// a long string
+  pub fn insert_str(self, key: &str, value: &str) -> Self {
+    self.insert(key, AMQPValue::LongString(value.to_string()))
+  }
+
+  /// a timestamp, in seconds since the epoch
+  pub fn insert_timestamp(self, key: &str, value: Timestamp) -> Self {
+    self.insert(key, AMQPValue::Timestamp(value))
+  }
+
+  /// a nested table, which can be built by another `FieldTableBuilder`
+  pub fn insert_table(self, key: &str, value: FieldTable) -> Self {
+    self.insert(key, AMQPValue::FieldTable(value))
+  }
+
+  pub fn insert_array(self, key: &str, value: FieldArray) -> Self {
+    self.insert(key, AMQPValue::FieldArray(value))
+  }
+
+  pub fn insert(mut self, key: &str, value: AMQPValue) -> Self {
+    self.table.insert(key.to_string(), value);
+    self
+  }
+
+  pub fn build(self) -> FieldTable {
+    self.table
+  }
+}
+
+impl From<FieldTableBuilder> for FieldTable {
+  fn from(builder: FieldTableBuilder) -> Self {
+    builder.build()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn field_table_builder() {
+    let table = FieldTable::builder()
+      .insert_long("x-max-priority", 10)
+      .insert_str("x-dead-letter-exchange", "dlx")
+      .insert_table("nested", FieldTable::builder().insert_bool("flag", true).build())
+      .insert_array("list", vec![AMQPValue::Double(1.5)])
+      .build();
+
+    assert_eq!(table.get("x-max-priority"),         Some(&AMQPValue::LongInt(10)));
+    assert_eq!(table.get("x-dead-letter-exchange"), Some(&AMQPValue::LongString("dlx".to_string())));
+    assert_eq!(table.get("list"),                   Some(&AMQPValue::FieldArray(vec![AMQPValue::Double(1.5)])));
+    match table.get("nested") {
+      Some(AMQPValue::FieldTable(nested)) => assert_eq!(nested.get("flag"), Some(&AMQPValue::Boolean(true))),
+      value                               => panic!("expected a nested table, got {:?}", value),
+    }
+  }
+}
@@ -16,6 +16,10 @@ impl Frames {
     self.frames.lock().frames.push_back(frame)
   }
 
+  pub fn push_all(&self, frames: Vec<AMQPFrame>) {
+    self.frames.lock().frames.extend(frames)
+  }
+
   pub fn push_preemptive(&self, frame: AMQPFrame) {
     self.frames.lock().priority_frames.push_front(frame)
   }
@@ -31,6 +35,10 @@ impl Frames {
   pub fn is_empty(&self) -> bool {
     self.frames.lock().is_empty()
   }
+
+  pub fn len(&self) -> usize {
+    self.frames.lock().len()
+  }
 }
 
 #[derive(Debug, Default)]
@@ -47,4 +55,8 @@ impl Inner {
   fn is_empty(&self) -> bool {
     self.priority_frames.is_empty() && self.frames.is_empty()
   }
+
+  fn len(&self) -> usize {
+    self.priority_frames.len() + self.frames.len()
+  }
 }
@@ -21,12 +21,17 @@ impl<T: Default + Copy + AddAssign<T> + PartialEq<T> + PartialOrd<T> + From<u8>>
     self.inner.lock().next()
   }
 
+  /// the id `next` will return
+  pub fn peek(&self) -> T {
+    self.inner.lock().clone().next()
+  }
+
   pub fn set_max(&self, max: T) {
     self.inner.lock().max = Some(max);
   }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Inner<T> {
   allow_zero: bool,
   zero:       T,
@@ -53,7 +58,12 @@ impl<T: Default + Copy + AddAssign<T> + PartialEq<T> + PartialOrd<T> + From<u8>>
     }
     if self.check_max() {
       let id = self.id;
-      self.id += self.one;
+      // the max is a valid id, don't overflow when it's the max of T
+      if Some(id) == self.max {
+        self.id = self.zero;
+      } else {
+        self.id += self.one;
+      }
       id
     } else {
       self.id = self.zero;
@@ -63,7 +73,7 @@ impl<T: Default + Copy + AddAssign<T> + PartialEq<T> + PartialOrd<T> + From<u8>>
 
   fn check_max(&self) -> bool {
     if let Some(max) = self.max {
-      self.id < max
+      self.id <= max
     } else {
       true
     }
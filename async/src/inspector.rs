@@ -0,0 +1,53 @@
+use amq_protocol::frame::AMQPFrame;
+use parking_lot::RwLock;
+
+use std::{fmt, sync::Arc};
+
+/// Whether a frame was sent to the server or received from it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+  Sent,
+  Received,
+}
+
+/// The functions a `FrameInspector` calls
+pub type Inspector = dyn Fn(&Direction, &AMQPFrame) + Send + Sync;
+
+/// The function called with the frames of a connection, for protocol debugging
+///
+/// the transport calls it for each frame it writes to or reads from the socket, while it's
+/// locked: it should return quickly and not call back into lapin, e.g. just log the frame.
+/// Nothing is done for the frames when it's not set
+#[derive(Clone, Default)]
+pub struct FrameInspector {
+  inspector: Arc<RwLock<Option<Arc<Inspector>>>>,
+}
+
+impl FrameInspector {
+  /// registers the inspector, replacing the previous one
+  pub fn set(&self, inspector: Arc<Inspector>) {
+    *self.inspector.write() = Some(inspector);
+  }
+
+  pub fn unset(&self) {
+    *self.inspector.write() = None;
+  }
+
+  pub fn is_set(&self) -> bool {
+    self.inspector.read().is_some()
+  }
+
+  pub fn inspect(&self, direction: Direction, frame: &AMQPFrame) {
+    // don't hold the lock while calling it, it could replace itself
+    let inspector = self.inspector.read().clone();
+    if let Some(inspector) = inspector {
+      inspector(&direction, frame);
+    }
+  }
+}
+
+impl fmt::Debug for FrameInspector {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("FrameInspector").field("is_set", &self.is_set()).finish()
+  }
+}
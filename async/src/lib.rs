@@ -122,17 +122,28 @@ pub mod connection_properties;
 pub mod connection_status;
 pub mod configuration;
 pub mod consumer;
+pub mod consumer_arguments;
 pub mod credentials;
 pub mod error;
+pub mod exchange;
+pub mod exchange_arguments;
+pub mod field_table;
 pub mod generated_names;
 pub mod id_sequence;
+pub mod inspector;
 pub mod io;
 pub mod message;
+pub mod message_counts;
+pub mod metrics;
+pub mod properties;
 pub mod frames;
 pub mod queue;
+pub mod queue_arguments;
 pub mod queues;
 pub mod replies;
 pub mod requests;
 pub mod returned_messages;
+pub mod topology;
 pub mod types;
+pub mod unacked_deliveries;
 pub mod uri;
@@ -1,7 +1,19 @@
+#[cfg(feature = "serde_json")]
+use serde::de::DeserializeOwned;
+
 use crate::{
   channel::BasicProperties,
-  types::{LongLongUInt, LongUInt, ShortUInt},
+  types::{AMQPValue, FieldTable, LongLongUInt, LongUInt, ShortUInt, Timestamp},
 };
+#[cfg(feature = "serde_json")]
+use crate::error::{Error, ErrorKind};
+
+/// the `content_type` of the JSON messages
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+
+/// the most allocated upfront for a body, the size announced by the server isn't trusted: a
+/// larger body grows as its frames are received
+const MAX_RESERVED_BODY_SIZE: u64 = 1024 * 1024;
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Delivery {
@@ -25,9 +37,97 @@ impl Delivery {
     }
   }
 
+  /// the content header gives the total size of the body, allocate it upfront, up to
+  /// `MAX_RESERVED_BODY_SIZE`
+  pub fn receive_content_header(&mut self, size: u64, properties: BasicProperties) {
+    self.properties = properties;
+    self.data.reserve_exact(size.min(MAX_RESERVED_BODY_SIZE) as usize);
+  }
+
   pub fn receive_content(&mut self, data: Vec<u8>) {
     self.data.extend(data);
   }
+
+  /// the entries of the `x-death` header, most recent first, empty if the message was never
+  /// dead-lettered
+  pub fn x_death(&self) -> Vec<XDeath> {
+    match self.properties.headers().as_ref().and_then(|headers| headers.get("x-death")) {
+      Some(AMQPValue::FieldArray(entries)) => entries.iter().filter_map(|entry| match entry {
+        AMQPValue::FieldTable(entry) => Some(XDeath::from_table(entry)),
+        _                            => None,
+      }).collect(),
+      _                                    => Vec::new(),
+    }
+  }
+
+  /// how many times the message was dead-lettered, from all queues and for all reasons
+  pub fn death_count(&self) -> u64 {
+    self.x_death().iter().fold(0, |count, death| count.saturating_add(death.count))
+  }
+
+  /// deserializes the body from JSON (requires the `serde_json` feature)
+  ///
+  /// fails with `ErrorKind::NotJson` if the `content_type` is set to anything else than
+  /// `application/json`, a message without `content_type` is deserialized anyway
+  #[cfg(feature = "serde_json")]
+  pub fn json<T: DeserializeOwned>(&self) -> Result<T, Error> {
+    if let Some(content_type) = self.properties.content_type() {
+      if content_type.split(';').next().map(str::trim) != Some(JSON_CONTENT_TYPE) {
+        return Err(ErrorKind::NotJson(content_type.clone()).into());
+      }
+    }
+    serde_json::from_slice(&self.data).map_err(|e| ErrorKind::JsonError(e).into())
+  }
+}
+
+/// An entry of the `x-death` header, which the server updates each time it dead-letters a message
+///
+/// there's one entry per queue and reason, `count` gives how many times it happened
+#[derive(Clone, Debug, PartialEq)]
+pub struct XDeath {
+  pub queue:        String,
+  /// `rejected`, `expired`, `maxlen` or `delivery_limit`
+  pub reason:       String,
+  pub exchange:     String,
+  pub routing_keys: Vec<String>,
+  pub count:        u64,
+  /// when the message was dead-lettered for the first time
+  pub time:         Option<Timestamp>,
+}
+
+impl XDeath {
+  fn from_table(table: &FieldTable) -> Self {
+    let string = |key: &str| match table.get(key) {
+      Some(AMQPValue::LongString(value)) => value.clone(),
+      _                                  => String::new(),
+    };
+    let routing_keys = match table.get("routing-keys") {
+      Some(AMQPValue::FieldArray(keys)) => keys.iter().filter_map(|key| match key {
+        AMQPValue::LongString(key) => Some(key.clone()),
+        _                          => None,
+      }).collect(),
+      _                                 => Vec::new(),
+    };
+    // a negative count makes no sense, don't let it wrap around
+    let count = match table.get("count") {
+      Some(AMQPValue::LongLongInt(count)) => (*count).max(0) as u64,
+      Some(AMQPValue::LongInt(count))     => i64::from(*count).max(0) as u64,
+      Some(AMQPValue::LongUInt(count))    => u64::from(*count),
+      _                                   => 0,
+    };
+    let time = match table.get("time") {
+      Some(AMQPValue::Timestamp(time)) => Some(*time),
+      _                                => None,
+    };
+    XDeath {
+      queue: string("queue"),
+      reason: string("reason"),
+      exchange: string("exchange"),
+      routing_keys,
+      count,
+      time,
+    }
+  }
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -61,3 +161,55 @@ impl BasicReturnMessage {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn announced_body_size_not_trusted() {
+    let mut delivery = Delivery::new(1, "".to_string(), "queue".to_string(), false);
+    delivery.receive_content_header(u64::MAX, BasicProperties::default());
+    assert!(delivery.data.capacity() <= MAX_RESERVED_BODY_SIZE as usize);
+    delivery.receive_content(b"hello".to_vec());
+    assert_eq!(delivery.data, b"hello".to_vec());
+  }
+
+  #[test]
+  fn x_death() {
+    let mut delivery = Delivery::new(1, "".to_string(), "queue".to_string(), true);
+    assert!(delivery.x_death().is_empty());
+
+    let death = |queue: &str, count: i64| {
+      let mut entry = FieldTable::new();
+      entry.insert("queue".to_string(), AMQPValue::LongString(queue.to_string()));
+      entry.insert("reason".to_string(), AMQPValue::LongString("rejected".to_string()));
+      entry.insert("routing-keys".to_string(), AMQPValue::FieldArray(vec![AMQPValue::LongString("queue".to_string())]));
+      entry.insert("count".to_string(), AMQPValue::LongLongInt(count));
+      entry.insert("time".to_string(), AMQPValue::Timestamp(1_500_000_000));
+      AMQPValue::FieldTable(entry)
+    };
+    let mut headers = FieldTable::new();
+    headers.insert("x-death".to_string(), AMQPValue::FieldArray(vec![death("queue", 2), death("retry", 1)]));
+    delivery.properties = BasicProperties::default().with_headers(headers);
+
+    let deaths = delivery.x_death();
+    assert_eq!(deaths.len(), 2);
+    assert_eq!(deaths[0], XDeath {
+      queue:        "queue".to_string(),
+      reason:       "rejected".to_string(),
+      exchange:     String::new(),
+      routing_keys: vec!["queue".to_string()],
+      count:        2,
+      time:         Some(1_500_000_000),
+    });
+    assert_eq!(delivery.death_count(), 3);
+
+    // a negative count is taken as none, and the total doesn't overflow
+    let mut headers = FieldTable::new();
+    headers.insert("x-death".to_string(), AMQPValue::FieldArray(vec![death("queue", -2), death("retry", i64::MAX), death("other", i64::MAX), death("last", 2)]));
+    delivery.properties = BasicProperties::default().with_headers(headers);
+    assert_eq!(delivery.x_death()[0].count, 0);
+    assert_eq!(delivery.death_count(), u64::MAX);
+  }
+}
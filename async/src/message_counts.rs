@@ -0,0 +1,24 @@
+use parking_lot::Mutex;
+
+use std::{
+  collections::HashMap,
+  sync::Arc,
+};
+
+use crate::requests::RequestId;
+
+/// the message counts sent back by the server in the replies to our requests
+#[derive(Clone, Debug, Default)]
+pub struct MessageCounts {
+  counts: Arc<Mutex<HashMap<RequestId, u32>>>,
+}
+
+impl MessageCounts {
+  pub fn register(&self, request_id: RequestId, message_count: u32) {
+    self.counts.lock().insert(request_id, message_count);
+  }
+
+  pub fn get(&self, request_id: RequestId) -> Option<u32> {
+    self.counts.lock().remove(&request_id)
+  }
+}
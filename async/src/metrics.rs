@@ -0,0 +1,60 @@
+use parking_lot::RwLock;
+
+use std::{fmt, sync::Arc};
+
+/// Gets called on the events of a connection, e.g. to count them in a metrics system
+///
+/// every method defaults to doing nothing. They're called from the code handling the frames,
+/// while the transport is locked, so they should return quickly and not call back into lapin.
+/// A message returned on a confirm channel is reported both as `returned` and `confirmed`,
+/// since the server acks it afterwards
+pub trait MetricsHooks: Send + Sync {
+  /// a message was published on `channel_id`
+  fn published(&self, _channel_id: u16) {}
+  /// the server acked `count` messages published on a confirm channel
+  fn confirmed(&self, _channel_id: u16, _count: u64) {}
+  /// the server nacked `count` messages published on a confirm channel
+  fn nacked(&self, _channel_id: u16, _count: u64) {}
+  /// the server returned a message published with the `mandatory` or `immediate` flag
+  fn returned(&self, _channel_id: u16) {}
+  /// a message was delivered to a consumer or through `basic_get`
+  fn delivered(&self, _channel_id: u16) {}
+  /// we acked `count` deliveries which were waiting for an ack, unknown or already acked delivery
+  /// tags don't count
+  fn acked(&self, _channel_id: u16, _count: u64) {}
+}
+
+/// The `MetricsHooks` registered on a connection, shared by its channels
+#[derive(Clone, Default)]
+pub struct Metrics {
+  hooks: Arc<RwLock<Option<Arc<dyn MetricsHooks>>>>,
+}
+
+impl Metrics {
+  /// registers the hooks, replacing the previous ones
+  pub fn set(&self, hooks: Arc<dyn MetricsHooks>) {
+    *self.hooks.write() = Some(hooks);
+  }
+
+  pub fn unset(&self) {
+    *self.hooks.write() = None;
+  }
+
+  pub fn is_set(&self) -> bool {
+    self.hooks.read().is_some()
+  }
+
+  pub(crate) fn record<F: FnOnce(&dyn MetricsHooks)>(&self, f: F) {
+    // don't hold the lock while calling the hooks, they could replace themselves
+    let hooks = self.hooks.read().clone();
+    if let Some(hooks) = hooks {
+      f(hooks.as_ref());
+    }
+  }
+}
+
+impl fmt::Debug for Metrics {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("Metrics").field("is_set", &self.is_set()).finish()
+  }
+}
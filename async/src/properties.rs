@@ -0,0 +1,24 @@
+use crate::channel::BasicProperties;
+
+use std::time::Duration;
+
+/// Helpers over `BasicProperties` for the properties the protocol encodes as strings
+pub trait BasicPropertiesExt {
+  /// sets the `expiration` of a message, sent as the decimal number of milliseconds the
+  /// server expects: the message gets discarded or dead-lettered once it stayed that long
+  /// in a queue
+  fn with_expiration_duration(self, expiration: Duration) -> Self;
+
+  /// parses the `expiration` of a message, `None` if it's not set or not a number
+  fn expiration_duration(&self) -> Option<Duration>;
+}
+
+impl BasicPropertiesExt for BasicProperties {
+  fn with_expiration_duration(self, expiration: Duration) -> Self {
+    self.with_expiration(expiration.as_millis().to_string())
+  }
+
+  fn expiration_duration(&self) -> Option<Duration> {
+    self.expiration().as_ref().and_then(|expiration| expiration.parse().ok()).map(Duration::from_millis)
+  }
+}
@@ -48,9 +48,9 @@ impl Queue {
     self.current_get_message = Some(delivery)
   }
 
-  pub fn set_delivery_properties(&mut self, properties: BasicProperties) {
+  pub fn receive_delivery_header(&mut self, size: u64, properties: BasicProperties) {
     if let Some(delivery) = self.current_get_message.as_mut() {
-      delivery.delivery.properties = properties;
+      delivery.delivery.receive_content_header(size, properties);
     }
   }
 
@@ -60,9 +60,12 @@ impl Queue {
     }
   }
 
+  /// the message is dropped if nobody waits for it anymore, with a request_id of 0
   pub fn new_delivery_complete(&mut self, request_id: RequestId) {
     if let Some(message) = self.current_get_message.take() {
-      self.get_messages.insert(request_id, message);
+      if request_id != 0 {
+        self.get_messages.insert(request_id, message);
+      }
     }
   }
 }
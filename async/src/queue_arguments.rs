@@ -0,0 +1,109 @@
+use crate::field_table::FieldTableBuilder;
+use crate::types::{AMQPValue, FieldTable};
+
+use std::time::Duration;
+
+/// Builds the arguments of a queue declaration without having to remember the `x-` keys
+///
+/// the result is passed to `queue_declare` through `into_field_table`, or `FieldTable::from`.
+/// Arguments the builder doesn't know about can still be added with `insert`
+///
+/// the server doesn't change the arguments of an existing queue: declaring it again with other
+/// ones (e.g. a new TTL) fails with a `PRECONDITION_FAILED` (406) error, the queue has to be
+/// deleted and declared again
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QueueArguments {
+  arguments: FieldTableBuilder,
+}
+
+impl QueueArguments {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// the exchange rejected and expired messages get republished to (`x-dead-letter-exchange`)
+  pub fn dead_letter_exchange(self, exchange: &str) -> Self {
+    self.insert("x-dead-letter-exchange", AMQPValue::LongString(exchange.to_string()))
+  }
+
+  /// the routing key dead-lettered messages get republished with, instead of their own
+  /// (`x-dead-letter-routing-key`)
+  pub fn dead_letter_routing_key(self, routing_key: &str) -> Self {
+    self.insert("x-dead-letter-routing-key", AMQPValue::LongString(routing_key.to_string()))
+  }
+
+  /// how long a message can stay in the queue, sent in milliseconds (`x-message-ttl`)
+  pub fn message_ttl(self, ttl: Duration) -> Self {
+    self.insert("x-message-ttl", AMQPValue::LongLongInt(milliseconds(ttl)))
+  }
+
+  /// how long the queue can stay unused before the server deletes it, sent in milliseconds
+  /// (`x-expires`)
+  pub fn expires(self, expires: Duration) -> Self {
+    self.insert("x-expires", AMQPValue::LongLongInt(milliseconds(expires)))
+  }
+
+  /// how many messages the queue can hold before dropping or dead-lettering the oldest ones
+  /// (`x-max-length`)
+  pub fn max_length(self, max_length: u32) -> Self {
+    self.insert("x-max-length", AMQPValue::LongLongInt(max_length.into()))
+  }
+
+  /// how many bytes of message bodies the queue can hold before dropping or dead-lettering the
+  /// oldest messages (`x-max-length-bytes`)
+  pub fn max_length_bytes(self, max_length_bytes: u64) -> Self {
+    self.insert("x-max-length-bytes", AMQPValue::LongLongInt(max_length_bytes.min(i64::MAX as u64) as i64))
+  }
+
+  /// the highest priority the queue supports, making it a priority queue (`x-max-priority`)
+  pub fn max_priority(self, max_priority: u8) -> Self {
+    self.insert("x-max-priority", AMQPValue::LongLongInt(max_priority.into()))
+  }
+
+  /// adds a raw argument, replacing any previous value for this key
+  pub fn insert(self, key: &str, value: AMQPValue) -> Self {
+    Self { arguments: self.arguments.insert(key, value) }
+  }
+
+  pub fn into_field_table(self) -> FieldTable {
+    self.arguments.build()
+  }
+}
+
+fn milliseconds(duration: Duration) -> i64 {
+  duration.as_millis().min(i64::MAX as u128) as i64
+}
+
+impl From<QueueArguments> for FieldTable {
+  fn from(arguments: QueueArguments) -> Self {
+    arguments.into_field_table()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn dead_letter_arguments() {
+    let arguments = QueueArguments::new()
+      .dead_letter_exchange("dlx")
+      .dead_letter_routing_key("dead")
+      .message_ttl(Duration::from_secs(60))
+      .expires(Duration::from_millis(1500))
+      .max_length(1000)
+      .max_length_bytes(1 << 20)
+      .max_priority(10)
+      .insert("x-queue-mode", AMQPValue::LongString("lazy".to_string()))
+      .into_field_table();
+
+    assert_eq!(arguments.get("x-dead-letter-exchange"),    Some(&AMQPValue::LongString("dlx".to_string())));
+    assert_eq!(arguments.get("x-dead-letter-routing-key"), Some(&AMQPValue::LongString("dead".to_string())));
+    assert_eq!(arguments.get("x-message-ttl"),             Some(&AMQPValue::LongLongInt(60_000)));
+    assert_eq!(arguments.get("x-expires"),                 Some(&AMQPValue::LongLongInt(1500)));
+    assert_eq!(arguments.get("x-max-length"),              Some(&AMQPValue::LongLongInt(1000)));
+    assert_eq!(arguments.get("x-max-length-bytes"),        Some(&AMQPValue::LongLongInt(1 << 20)));
+    assert_eq!(arguments.get("x-max-priority"),            Some(&AMQPValue::LongLongInt(10)));
+    assert_eq!(arguments.get("x-queue-mode"),              Some(&AMQPValue::LongString("lazy".to_string())));
+  }
+}
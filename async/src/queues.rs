@@ -20,8 +20,14 @@ pub struct Queues {
 }
 
 impl Queues {
+  /// registers a declared queue, only refreshing the stats if it was already known
   pub fn register(&self, queue: Queue) {
-    self.queues.lock().insert(queue.name.clone(), queue);
+    let mut queues = self.queues.lock();
+    if let Some(known) = queues.get_mut(&queue.name) {
+      known.stats = queue.stats;
+    } else {
+      queues.insert(queue.name.clone(), queue);
+    }
   }
 
   pub fn deregister(&self, queue: &str) {
@@ -35,13 +41,21 @@ impl Queues {
   }
 
   pub fn deregister_consumer(&self, consumer_tag: &str) {
-    for queue in self.queues.lock().values_mut() {
-      if let Some(consumer) = queue.consumers.remove(consumer_tag) {
-        consumer.cancel();
-      }
+    if let Some(consumer) = self.remove_consumer(consumer_tag) {
+      consumer.cancel();
+    }
+  }
+
+  pub fn deregister_consumer_canceled_by_server(&self, consumer_tag: &str) {
+    if let Some(consumer) = self.remove_consumer(consumer_tag) {
+      consumer.cancel_by_server();
     }
   }
 
+  fn remove_consumer(&self, consumer_tag: &str) -> Option<Consumer> {
+    self.queues.lock().values_mut().find_map(|queue| queue.consumers.remove(consumer_tag))
+  }
+
   pub fn drop_prefetched_messages(&self) {
     for queue in self.queues.lock().values_mut() {
       queue.drop_prefetched_messages();
@@ -56,6 +70,10 @@ impl Queues {
     self.queues.lock().get_mut(queue).and_then(|queue| queue.get_basic_get_message(request_id))
   }
 
+  pub fn consumer_no_ack(&self, consumer_tag: &str) -> Option<bool> {
+    self.queues.lock().values().find_map(|queue| queue.consumers.get(consumer_tag)).map(Consumer::no_ack)
+  }
+
   pub fn start_consumer_delivery(&self, consumer_tag: &str, message: Delivery) -> Option<String> {
     for queue in self.queues.lock().values_mut() {
       if let Some(consumer) = queue.consumers.get_mut(consumer_tag) {
@@ -77,14 +95,14 @@ impl Queues {
       match request_id_or_consumer_tag {
         Either::Right(consumer_tag) => {
           if let Some(consumer) = queue.consumers.get_mut(&consumer_tag) {
-            consumer.set_delivery_properties(properties);
+            consumer.receive_delivery_header(size, properties);
             if size == 0 {
               consumer.new_delivery_complete();
             }
           }
         },
         Either::Left(request_id) => {
-          queue.set_delivery_properties(properties);
+          queue.receive_delivery_header(size, properties);
           if size == 0 {
             queue.new_delivery_complete(request_id);
           }
@@ -1,7 +1,8 @@
+use log::trace;
 use parking_lot::Mutex;
 
 use std::{
-  collections::HashMap,
+  collections::{HashMap, HashSet},
   sync::Arc,
 };
 
@@ -9,15 +10,31 @@ pub type RequestId = u64;
 
 #[derive(Clone, Debug, Default)]
 pub struct Requests {
-  finished: Arc<Mutex<HashMap<RequestId, bool>>>,
+  finished:  Arc<Mutex<HashMap<RequestId, bool>>>,
+  // the requests nobody waits for anymore, their answer is dropped when it comes
+  abandoned: Arc<Mutex<HashSet<RequestId>>>,
 }
 
 impl Requests {
-  pub fn finish(&self, request_id: RequestId, success: bool) {
-    self.finished.lock().insert(request_id, success);
+  /// returns whether somebody still waits for the answer
+  pub fn finish(&self, request_id: RequestId, success: bool) -> bool {
+    if self.abandoned.lock().remove(&request_id) {
+      trace!("dropping the answer to abandoned request {}", request_id);
+      false
+    } else {
+      self.finished.lock().insert(request_id, success);
+      true
+    }
   }
 
   pub fn was_successful(&self, request_id: RequestId) -> Option<bool> {
     self.finished.lock().remove(&request_id)
   }
+
+  /// stops waiting for the answer to a request
+  pub fn abandon(&self, request_id: RequestId) {
+    if self.finished.lock().remove(&request_id).is_none() {
+      self.abandoned.lock().insert(request_id);
+    }
+  }
 }
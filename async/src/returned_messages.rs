@@ -2,11 +2,12 @@ use log::error;
 use parking_lot::Mutex;
 
 use std::{
-  collections::VecDeque,
+  collections::{HashMap, VecDeque},
   sync::Arc,
 };
 
 use crate::{
+  acknowledgement::DeliveryTag,
   channel::BasicProperties,
   message::BasicReturnMessage,
 };
@@ -17,22 +18,47 @@ pub struct ReturnedMessages {
 }
 
 impl ReturnedMessages {
-  pub fn start_new_delivery(&self, message: BasicReturnMessage) {
-    self.inner.lock().current_message = Some(message);
+  /// `delivery_tag` is the one of the returned message on a channel in confirm mode
+  pub fn start_new_delivery(&self, message: BasicReturnMessage, delivery_tag: Option<DeliveryTag>) {
+    self.inner.lock().current_message = Some((message, delivery_tag));
   }
 
-  pub fn set_delivery_properties(&self, properties: BasicProperties) {
-    if let Some(message) = self.inner.lock().current_message.as_mut() {
-      message.delivery.properties = properties;
+  pub fn receive_delivery_header(&self, size: u64, properties: BasicProperties) {
+    if let Some((message, _)) = self.inner.lock().current_message.as_mut() {
+      message.delivery.receive_content_header(size, properties);
     }
   }
 
-  pub fn new_delivery_complete(&self) {
-    self.inner.lock().new_delivery_complete();
+  /// on a channel in confirm mode, the message is kept aside until the server acks it
+  pub fn new_delivery_complete(&self, confirm_mode: bool) {
+    self.inner.lock().new_delivery_complete(confirm_mode);
+  }
+
+  /// the returned messages waiting for the server to ack them covered by an ack (or nack) of
+  /// `delivery_tag`, with their delivery tag
+  pub fn take_waiting_messages(&self, delivery_tag: DeliveryTag, multiple: bool) -> Vec<(DeliveryTag, BasicReturnMessage)> {
+    let mut inner = self.inner.lock();
+    if !multiple {
+      return inner.messages.remove(&delivery_tag).map(|message| (delivery_tag, message)).into_iter().collect();
+    }
+    // a multiple ack of 0 covers all the pending messages
+    let tags = inner.messages.keys().filter(|tag| delivery_tag == 0 || **tag <= delivery_tag).cloned().collect::<Vec<_>>();
+    tags.into_iter().filter_map(|tag| inner.messages.remove(&tag).map(|message| (tag, message))).collect()
+  }
+
+  /// starts keeping the returned messages aside for `next_returned_message`, whatever the mode
+  /// of the channel
+  pub fn listen(&self) {
+    self.inner.lock().listening = true;
+  }
+
+  /// the oldest returned message not consumed yet, if any
+  pub fn next_returned_message(&self) -> Option<BasicReturnMessage> {
+    self.inner.lock().returned.pop_front()
   }
 
   pub fn receive_delivery_content(&self, data: Vec<u8>) {
-    if let Some(message) = self.inner.lock().current_message.as_mut() {
+    if let Some((message, _)) = self.inner.lock().current_message.as_mut() {
       message.delivery.data.extend(data);
     }
   }
@@ -40,15 +66,24 @@ impl ReturnedMessages {
 
 #[derive(Debug, Default)]
 pub struct Inner {
-  current_message: Option<BasicReturnMessage>,
-  messages:        VecDeque<BasicReturnMessage>,
+  current_message: Option<(BasicReturnMessage, Option<DeliveryTag>)>,
+  // by the delivery tag they were published with
+  messages:        HashMap<DeliveryTag, BasicReturnMessage>,
+  listening:       bool,
+  returned:        VecDeque<BasicReturnMessage>,
 }
 
 impl Inner {
-  fn new_delivery_complete(&mut self) {
-    if let Some(message) = self.current_message.take() {
-      // FIXME, once there's a way to consume it: self.messages.push_back(message);
-      error!("Server returned us a message: {:?}", message);
+  fn new_delivery_complete(&mut self, confirm_mode: bool) {
+    if let Some((message, delivery_tag)) = self.current_message.take() {
+      if self.listening {
+        self.returned.push_back(message.clone());
+      }
+      if let (true, Some(delivery_tag)) = (confirm_mode, delivery_tag) {
+        self.messages.insert(delivery_tag, message);
+      } else if !self.listening {
+        error!("Server returned us a message: {:?}", message);
+      }
     }
   }
 }
@@ -0,0 +1,326 @@
+use parking_lot::Mutex;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use crate::channel::options::{BasicConsumeOptions, ExchangeDeclareOptions, QueueDeclareOptions};
+use crate::types::FieldTable;
+
+/// The exchanges, queues, bindings and consumers declared on a connection
+///
+/// a snapshot of what was recorded by the `TopologyRecorder` of a connection, which can be
+/// serialized with serde (requires the `serde` feature) and declared again, e.g. after a
+/// reconnection. Passive declares aren't recorded, and the deletes, unbinds and cancels remove
+/// what they undo
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct Topology {
+  pub exchanges: Vec<ExchangeDefinition>,
+  pub queues:    Vec<QueueDefinition>,
+  pub bindings:  Vec<BindingDefinition>,
+  pub consumers: Vec<ConsumerDefinition>,
+}
+
+impl Topology {
+  pub fn is_empty(&self) -> bool {
+    self.exchanges.is_empty() && self.queues.is_empty() && self.bindings.is_empty() && self.consumers.is_empty()
+  }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct ExchangeDefinition {
+  pub name:        String,
+  /// the type of the exchange, as given by `ExchangeKind::kind`
+  pub kind:        String,
+  pub durable:     bool,
+  pub auto_delete: bool,
+  pub internal:    bool,
+  pub arguments:   FieldTable,
+}
+
+impl ExchangeDefinition {
+  pub fn options(&self) -> ExchangeDeclareOptions {
+    ExchangeDeclareOptions {
+      durable:     self.durable,
+      auto_delete: self.auto_delete,
+      internal:    self.internal,
+      ..ExchangeDeclareOptions::default()
+    }
+  }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct QueueDefinition {
+  pub name:         String,
+  /// the name was assigned by the server: the queue gets a new one when declared again
+  pub server_named: bool,
+  pub durable:      bool,
+  pub exclusive:    bool,
+  pub auto_delete:  bool,
+  pub arguments:    FieldTable,
+}
+
+impl QueueDefinition {
+  pub fn options(&self) -> QueueDeclareOptions {
+    QueueDeclareOptions {
+      durable:     self.durable,
+      exclusive:   self.exclusive,
+      auto_delete: self.auto_delete,
+      ..QueueDeclareOptions::default()
+    }
+  }
+}
+
+/// What a binding routes the messages of its source exchange to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub enum BindingDestination {
+  Queue,
+  Exchange,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct BindingDefinition {
+  pub destination_kind: BindingDestination,
+  pub destination:      String,
+  pub source:           String,
+  pub routing_key:      String,
+  pub arguments:        FieldTable,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct ConsumerDefinition {
+  pub tag:       String,
+  pub queue:     String,
+  pub no_local:  bool,
+  pub no_ack:    bool,
+  pub exclusive: bool,
+  pub arguments: FieldTable,
+}
+
+impl ConsumerDefinition {
+  pub fn options(&self) -> BasicConsumeOptions {
+    BasicConsumeOptions {
+      no_local:  self.no_local,
+      no_ack:    self.no_ack,
+      exclusive: self.exclusive,
+      ..BasicConsumeOptions::default()
+    }
+  }
+}
+
+/// An open channel of a connection, with what has to be set up again when it's reopened
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct ChannelDefinition {
+  pub id:             u16,
+  /// `confirm_select` was called on the channel
+  pub confirm:        bool,
+  /// the prefetch count set by the last `basic_qos`, 0 if it wasn't called
+  pub prefetch_count: u16,
+  pub global_qos:     bool,
+  /// the tags of the consumers of the channel
+  pub consumers:      Vec<String>,
+}
+
+/// Records the `Topology` of a connection as it gets declared, and its open channels
+///
+/// the clones share the same topology. Declaring something again replaces its definition
+#[derive(Clone, Debug, Default)]
+pub struct TopologyRecorder {
+  topology:          Arc<Mutex<Topology>>,
+  // the channel of each consumer, they're gone once it's closed
+  consumer_channels: Arc<Mutex<HashMap<String, u16>>>,
+  channels:          Arc<Mutex<BTreeMap<u16, ChannelDefinition>>>,
+}
+
+impl TopologyRecorder {
+  /// a copy of the recorded topology
+  pub fn snapshot(&self) -> Topology {
+    self.topology.lock().clone()
+  }
+
+  /// the channels opened and not closed since, sorted by id
+  pub fn channels(&self) -> Vec<ChannelDefinition> {
+    let mut channels: Vec<ChannelDefinition> = self.channels.lock().values().cloned().collect();
+    let consumer_channels = self.consumer_channels.lock();
+    let topology = self.topology.lock();
+    for channel in &mut channels {
+      channel.consumers = topology.consumers.iter().filter(|c| consumer_channels.get(&c.tag) == Some(&channel.id)).map(|c| c.tag.clone()).collect();
+    }
+    channels
+  }
+
+  pub fn exchange_declared(&self, name: &str, kind: &str, options: &ExchangeDeclareOptions, arguments: &FieldTable) {
+    if options.passive {
+      return;
+    }
+    let exchange = ExchangeDefinition {
+      name:        name.to_string(),
+      kind:        kind.to_string(),
+      durable:     options.durable,
+      auto_delete: options.auto_delete,
+      internal:    options.internal,
+      arguments:   arguments.clone(),
+    };
+    let mut topology = self.topology.lock();
+    topology.exchanges.retain(|e| e.name != name);
+    topology.exchanges.push(exchange);
+  }
+
+  /// forgets the exchange and the bindings from or to it
+  pub fn exchange_deleted(&self, name: &str) {
+    let mut topology = self.topology.lock();
+    topology.exchanges.retain(|e| e.name != name);
+    topology.bindings.retain(|b| b.source != name && !(b.destination_kind == BindingDestination::Exchange && b.destination == name));
+  }
+
+  pub fn queue_declared(&self, name: &str, server_named: bool, options: &QueueDeclareOptions, arguments: &FieldTable) {
+    if options.passive {
+      return;
+    }
+    let queue = QueueDefinition {
+      name:         name.to_string(),
+      server_named,
+      durable:      options.durable,
+      exclusive:    options.exclusive,
+      auto_delete:  options.auto_delete,
+      arguments:    arguments.clone(),
+    };
+    let mut topology = self.topology.lock();
+    topology.queues.retain(|q| q.name != name);
+    topology.queues.push(queue);
+  }
+
+  /// forgets the queue, its bindings and its consumers
+  pub fn queue_deleted(&self, name: &str) {
+    let mut topology = self.topology.lock();
+    topology.queues.retain(|q| q.name != name);
+    topology.bindings.retain(|b| !(b.destination_kind == BindingDestination::Queue && b.destination == name));
+    topology.consumers.retain(|c| c.queue != name);
+  }
+
+  /// the server-named queue `old` was declared again as `new`: its bindings and consumers
+  /// follow it
+  pub fn queue_renamed(&self, old: &str, new: &str) {
+    let mut topology = self.topology.lock();
+    if old != new {
+      topology.queues.retain(|q| q.name != old);
+    }
+    for binding in topology.bindings.iter_mut().filter(|b| b.destination_kind == BindingDestination::Queue && b.destination == old) {
+      binding.destination = new.to_string();
+    }
+    for consumer in topology.consumers.iter_mut().filter(|c| c.queue == old) {
+      consumer.queue = new.to_string();
+    }
+  }
+
+  pub fn bound(&self, binding: BindingDefinition) {
+    let mut topology = self.topology.lock();
+    if !topology.bindings.contains(&binding) {
+      topology.bindings.push(binding);
+    }
+  }
+
+  pub fn unbound(&self, binding: &BindingDefinition) {
+    self.topology.lock().bindings.retain(|b| b != binding);
+  }
+
+  pub fn consumer_added(&self, channel_id: u16, consumer: ConsumerDefinition) {
+    self.consumer_channels.lock().insert(consumer.tag.clone(), channel_id);
+    let mut topology = self.topology.lock();
+    topology.consumers.retain(|c| c.tag != consumer.tag);
+    topology.consumers.push(consumer);
+  }
+
+  pub fn consumer_canceled(&self, tag: &str) {
+    self.consumer_channels.lock().remove(tag);
+    self.topology.lock().consumers.retain(|c| c.tag != tag);
+  }
+
+  pub fn channel_opened(&self, channel_id: u16) {
+    self.channels.lock().insert(channel_id, ChannelDefinition { id: channel_id, ..ChannelDefinition::default() });
+  }
+
+  pub fn confirm_selected(&self, channel_id: u16) {
+    if let Some(channel) = self.channels.lock().get_mut(&channel_id) {
+      channel.confirm = true;
+    }
+  }
+
+  pub fn qos_set(&self, channel_id: u16, prefetch_count: u16, global: bool) {
+    if let Some(channel) = self.channels.lock().get_mut(&channel_id) {
+      channel.prefetch_count = prefetch_count;
+      channel.global_qos = global;
+    }
+  }
+
+  /// forgets a closed channel and its consumers
+  pub fn channel_closed(&self, channel_id: u16) {
+    self.channels.lock().remove(&channel_id);
+    let mut consumer_channels = self.consumer_channels.lock();
+    let mut topology = self.topology.lock();
+    topology.consumers.retain(|c| consumer_channels.get(&c.tag) != Some(&channel_id));
+    consumer_channels.retain(|_, id| *id != channel_id);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn deletes_forget_what_depends_on_them() {
+    let recorder = TopologyRecorder::default();
+    recorder.exchange_declared("logs", "fanout", &ExchangeDeclareOptions::default(), &FieldTable::new());
+    recorder.queue_declared("amq.gen-1", true, &QueueDeclareOptions::default(), &FieldTable::new());
+    recorder.queue_declared("other", false, &QueueDeclareOptions { passive: true, ..QueueDeclareOptions::default() }, &FieldTable::new());
+    recorder.bound(BindingDefinition {
+      destination_kind: BindingDestination::Queue,
+      destination:      "amq.gen-1".to_string(),
+      source:           "logs".to_string(),
+      routing_key:      String::new(),
+      arguments:        FieldTable::new(),
+    });
+    recorder.consumer_added(1, ConsumerDefinition { tag: "ctag".to_string(), queue: "amq.gen-1".to_string(), ..ConsumerDefinition::default() });
+
+    recorder.queue_renamed("amq.gen-1", "amq.gen-2");
+    let topology = recorder.snapshot();
+    assert!(topology.queues.is_empty());
+    assert_eq!(topology.bindings[0].destination, "amq.gen-2");
+    assert_eq!(topology.consumers[0].queue, "amq.gen-2");
+
+    recorder.channel_closed(1);
+    assert!(recorder.snapshot().consumers.is_empty());
+    recorder.exchange_deleted("logs");
+    let topology = recorder.snapshot();
+    assert!(topology.exchanges.is_empty());
+    assert!(topology.bindings.is_empty());
+  }
+
+  #[test]
+  fn channels_recorded() {
+    let recorder = TopologyRecorder::default();
+    recorder.channel_opened(2);
+    recorder.channel_opened(1);
+    recorder.confirm_selected(1);
+    recorder.qos_set(2, 10, false);
+    // a channel which isn't open anymore
+    recorder.qos_set(3, 10, false);
+    recorder.consumer_added(2, ConsumerDefinition { tag: "ctag".to_string(), queue: "queue".to_string(), ..ConsumerDefinition::default() });
+    assert_eq!(recorder.channels(), vec![
+      ChannelDefinition { id: 1, confirm: true, ..ChannelDefinition::default() },
+      ChannelDefinition { id: 2, prefetch_count: 10, consumers: vec!["ctag".to_string()], ..ChannelDefinition::default() },
+    ]);
+
+    recorder.channel_closed(2);
+    assert_eq!(recorder.channels(), vec![ChannelDefinition { id: 1, confirm: true, ..ChannelDefinition::default() }]);
+    assert!(recorder.snapshot().consumers.is_empty());
+  }
+}
@@ -1 +1,3 @@
 pub use amq_protocol::types::*;
+
+pub use crate::field_table::{FieldTableBuilder, FieldTableExt};
@@ -0,0 +1,56 @@
+use parking_lot::Mutex;
+
+use std::{
+  collections::BTreeSet,
+  sync::Arc,
+};
+
+use crate::acknowledgement::DeliveryTag;
+
+/// the delivery tags received on a channel that we didn't ack, nack or reject yet
+#[derive(Clone, Debug, Default)]
+pub struct UnackedDeliveries {
+  tags: Arc<Mutex<BTreeSet<DeliveryTag>>>,
+}
+
+impl UnackedDeliveries {
+  pub fn register(&self, delivery_tag: DeliveryTag) {
+    self.tags.lock().insert(delivery_tag);
+  }
+
+  /// settles `delivery_tag`, or all the deliveries up to it if `multiple` is set (all of them if
+  /// it's 0 too)
+  ///
+  /// returns false if `delivery_tag` wasn't outstanding, e.g. because it was already acked
+  pub fn settle(&self, delivery_tag: DeliveryTag, multiple: bool) -> bool {
+    let mut tags = self.tags.lock();
+    if multiple {
+      if delivery_tag == 0 {
+        tags.clear();
+        return true;
+      }
+      let outstanding = tags.contains(&delivery_tag);
+      *tags = tags.split_off(&(delivery_tag + 1));
+      outstanding
+    } else {
+      tags.remove(&delivery_tag)
+    }
+  }
+
+  pub fn is_outstanding(&self, delivery_tag: DeliveryTag) -> bool {
+    self.tags.lock().contains(&delivery_tag)
+  }
+
+  /// the outstanding delivery tags, in increasing order
+  pub fn tags(&self) -> Vec<DeliveryTag> {
+    self.tags.lock().iter().cloned().collect()
+  }
+
+  pub fn count(&self) -> usize {
+    self.tags.lock().len()
+  }
+
+  pub fn clear(&self) {
+    self.tags.lock().clear();
+  }
+}
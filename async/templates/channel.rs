@@ -37,8 +37,44 @@ pub enum Reply {
   {{/each ~}}
 }
 
+impl Reply {
+  pub fn request_id(&self) -> RequestId {
+    match *self {
+      {{#each protocol.classes as |class| ~}}
+      {{#each class.methods as |method| ~}}
+      {{#if method.c2s ~}}
+      {{#if method.synchronous ~}}
+      Reply::Awaiting{{camel class.name}}{{camel method.name}}Ok(request_id{{#each method.metadata.state as |state| ~}}, _{{/each ~}}) => request_id,
+      {{/if ~}}
+      {{/if ~}}
+      {{/each ~}}
+      {{/each ~}}
+    }
+  }
+}
+
 impl Channel {
   pub(crate) fn receive_method(&self, method: AMQPClass) -> Result<(), Error> {
+    if self.status.is_closing() {
+      match method {
+        {{#each protocol.classes as |class| ~}}
+        {{#each class.methods as |method| ~}}
+        {{#if method.metadata.channel_deinit ~}}
+        AMQPClass::{{camel class.name}}(protocol::{{snake class.name}}::AMQPMethod::{{camel method.name}}(_)) => {},
+        {{/if ~}}
+        {{/each ~}}
+        {{/each ~}}
+        m => {
+          trace!("channel {} is closing, discarding method: {:?}", self.id, m);
+          return Ok(());
+        }
+      }
+    }
+
+    if !self.check_method_sequence(&method)? {
+      return Ok(());
+    }
+
     match method {
       {{#each protocol.classes as |class| ~}}
       {{#each class.methods as |method| ~}}
@@ -64,11 +100,19 @@ impl Channel {
     {{#if method.metadata.channel_init ~}}
     if !self.status.is_initializing() {
     {{else}}
+    {{#if method.metadata.channel_deinit ~}}
+    if !self.status.is_connected() && !self.status.is_closing() {
+    {{else}}
     if !self.status.is_connected() {
+    {{/if ~}}
     {{/if ~}}
       return Err(ErrorKind::NotConnected.into());
     }
 
+    {{#if method.metadata.start_hook ~}}
+    self.before_{{snake class.name false}}_{{snake method.name false}}({{#each method.metadata.start_hook.params as |param| ~}}{{#unless @first ~}}, {{/unless ~}}{{param}}{{/each ~}})?;
+    {{/if ~}}
+
     {{#unless method.ignore_args ~}}
     {{#each_argument method.arguments as |argument| ~}}
     {{#unless argument_is_value ~}}
@@ -101,7 +145,9 @@ impl Channel {
       {{/each_argument ~}}
     }));
 
+    {{#unless method.metadata.end_hook.send_method ~}}
     self.send_method_frame(method);
+    {{/unless ~}}
 
     {{#if method.metadata.end_hook ~}}
     {{#if method.metadata.end_hook.return_type ~}}let end_hook_ret = {{/if ~}}self.on_{{snake class.name false}}_{{snake method.name false}}_sent({{#each method.metadata.end_hook.params as |param| ~}}{{#unless @first ~}}, {{/unless ~}}{{param}}{{/each ~}})?;
@@ -138,14 +184,23 @@ impl Channel {
     {{#if method.metadata.channel_init ~}}
     if !self.status.is_initializing() {
     {{else}}
+    {{#if method.metadata.channel_deinit ~}}
+    if !self.status.is_closing() {
+    {{else}}
     if !self.status.is_connected() {
+    {{/if ~}}
     {{/if ~}}
       return Err(ErrorKind::NotConnected.into());
     }
 
     match self.replies.next() {
       Some(Reply::Awaiting{{camel class.name}}{{camel method.name}}(request_id{{#each method.metadata.state as |state| ~}}, {{state.name}}{{/each ~}})) => {
+        {{#if method.metadata.uses_request_id ~}}
+        // nothing is kept for the answer to an abandoned request
+        let request_id = if self.requests.finish(request_id, true) { request_id } else { 0 };
+        {{else}}
         self.requests.finish(request_id, true);
+        {{/if ~}}
         {{#if method.arguments ~}}
         self.on_{{snake class.name false}}_{{snake method.name false}}_received(method{{#if method.metadata.uses_request_id ~}}, request_id{{/if ~}}{{#each method.metadata.state as |state| ~}}, {{state.name}}{{/each ~}})
         {{else}}
@@ -164,7 +219,11 @@ impl Channel {
   }
   {{else}}
   fn receive_{{snake class.name false}}_{{snake method.name false}}(&self, method: protocol::{{snake class.name}}::{{camel method.name}}) -> Result<(), Error> {
+    {{#if method.metadata.channel_deinit ~}}
+    if !self.status.is_connected() && !self.status.is_closing() {
+    {{else}}
     if !self.status.is_connected() {
+    {{/if ~}}
       return Err(ErrorKind::NotConnected.into());
     }
     self.on_{{snake class.name false}}_{{snake method.name false}}_received(method)
@@ -0,0 +1,126 @@
+//! measures the throughput of confirmed publishes on several channels sharing a connection,
+//! against a minimal broker running in another thread
+//!
+//! each channel waits for the confirmation of a message before publishing the next one, so the
+//! channels keep waiting for their turn to poll the transport
+//!
+//! `cargo bench -p lapin-futures --bench confirmed_publish -- [channels] [messages per channel]`
+
+use amq_protocol::frame::AMQPFrame;
+use amq_protocol::protocol::{AMQPClass, basic, channel, confirm, connection};
+use bytes::BytesMut;
+use futures::{future, Future};
+use futures::sync::oneshot;
+use lapin_futures as lapin;
+use crate::lapin::channel::{BasicProperties, BasicPublishOptions, ConfirmSelectOptions};
+use crate::lapin::client::{Client, ConnectionOptions};
+use crate::lapin::transport::AMQPCodec;
+use crate::lapin::types::FieldTable;
+use tokio::net::TcpStream;
+use tokio::runtime::Runtime;
+use tokio_codec::{Decoder, Encoder};
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::thread;
+use std::time::Instant;
+
+const FRAME_MAX: u32 = 131_072;
+
+/// accepts a single connection, and answers what the client needs to publish with confirms
+fn broker() -> SocketAddr {
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let addr = listener.local_addr().unwrap();
+  thread::spawn(move || {
+    let (mut socket, _) = listener.accept().unwrap();
+    socket.set_nodelay(true).unwrap();
+    let mut codec = AMQPCodec { frame_max: FRAME_MAX };
+    let mut protocol_header = [0; 8];
+    socket.read_exact(&mut protocol_header).unwrap();
+
+    let mut input = BytesMut::with_capacity(FRAME_MAX as usize);
+    let mut output = BytesMut::with_capacity(FRAME_MAX as usize);
+    let mut buffer = vec![0; FRAME_MAX as usize];
+    let mut delivery_tags = HashMap::new();
+    let mut reply = Some(AMQPFrame::Method(0, AMQPClass::Connection(connection::AMQPMethod::Start(connection::Start {
+      version_major:     0,
+      version_minor:     9,
+      server_properties: FieldTable::new(),
+      mechanisms:        "PLAIN".to_string(),
+      locales:           "en_US".to_string(),
+    }))));
+    loop {
+      if let Some(frame) = reply.take() {
+        codec.encode(frame, &mut output).unwrap();
+      }
+      while let Some(frame) = codec.decode(&mut input).unwrap() {
+        let reply = match frame {
+          AMQPFrame::Method(0, AMQPClass::Connection(connection::AMQPMethod::StartOk(_))) => {
+            AMQPClass::Connection(connection::AMQPMethod::Tune(connection::Tune { channel_max: 2047, frame_max: FRAME_MAX, heartbeat: 0 }))
+          },
+          AMQPFrame::Method(0, AMQPClass::Connection(connection::AMQPMethod::Open(_)))    => AMQPClass::Connection(connection::AMQPMethod::OpenOk(connection::OpenOk {})),
+          AMQPFrame::Method(0, AMQPClass::Connection(connection::AMQPMethod::Close(_)))   => AMQPClass::Connection(connection::AMQPMethod::CloseOk(connection::CloseOk {})),
+          AMQPFrame::Method(_, AMQPClass::Channel(channel::AMQPMethod::Open(_)))          => AMQPClass::Channel(channel::AMQPMethod::OpenOk(channel::OpenOk {})),
+          AMQPFrame::Method(_, AMQPClass::Confirm(confirm::AMQPMethod::Select(_)))        => AMQPClass::Confirm(confirm::AMQPMethod::SelectOk(confirm::SelectOk {})),
+          // the body of each message fits in a frame
+          AMQPFrame::Body(channel_id, _) => {
+            let delivery_tag = delivery_tags.entry(channel_id).or_insert(0);
+            *delivery_tag += 1;
+            AMQPClass::Basic(basic::AMQPMethod::Ack(basic::Ack { delivery_tag: *delivery_tag, multiple: false }))
+          },
+          _ => continue,
+        };
+        let channel_id = match frame {
+          AMQPFrame::Method(channel_id, _) | AMQPFrame::Body(channel_id, _) => channel_id,
+          _                                                                 => unreachable!(),
+        };
+        codec.encode(AMQPFrame::Method(channel_id, reply), &mut output).unwrap();
+      }
+      if !output.is_empty() {
+        socket.write_all(&output.take()).unwrap();
+      }
+      match socket.read(&mut buffer) {
+        Ok(0) | Err(_) => return,
+        Ok(n)          => input.extend_from_slice(&buffer[..n]),
+      }
+    }
+  });
+  addr
+}
+
+fn main() {
+  let mut args = std::env::args().skip(1).filter(|arg| arg != "--bench");
+  let channels: usize = args.next().map(|arg| arg.parse().unwrap()).unwrap_or(8);
+  let messages: usize = args.next().map(|arg| arg.parse().unwrap()).unwrap_or(5_000);
+
+  let addr = broker();
+  let runtime = Runtime::new().unwrap();
+  let executor = runtime.executor();
+  let client = TcpStream::connect(&addr).map_err(failure::Error::from).and_then(|stream| {
+    Client::connect(stream, ConnectionOptions { frame_max: FRAME_MAX, ..ConnectionOptions::default() }).map_err(failure::Error::from)
+  }).map(|(client, _)| client);
+  let started = oneshot::spawn(client, &executor).and_then(move |client| {
+    future::join_all((0..channels).map(move |_| client.create_confirm_channel(ConfirmSelectOptions::default()).map_err(failure::Error::from)))
+  }).and_then(move |channels| {
+    let start = Instant::now();
+    let publishers = channels.into_iter().map(|channel| {
+      let publisher = future::loop_fn((channel, 0), move |(channel, published)| {
+        channel.basic_publish("", "bench", vec![0; 32], BasicPublishOptions::default(), BasicProperties::default()).map(move |_| {
+          if published + 1 == messages {
+            future::Loop::Break(())
+          } else {
+            future::Loop::Continue((channel, published + 1))
+          }
+        })
+      }).map_err(failure::Error::from);
+      oneshot::spawn(publisher, &executor)
+    }).collect::<Vec<_>>();
+    future::join_all(publishers).map(move |_| start.elapsed())
+  });
+
+  let elapsed = runtime.block_on_all(started).unwrap();
+  let total = channels * messages;
+  let seconds = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+  println!("{} channels x {} confirmed publishes: {:.3}s, {:.0} msg/s", channels, messages, seconds, total as f64 / seconds);
+}
@@ -3,9 +3,9 @@
 
 use env_logger;
 use failure::Error;
-use futures::{future::Future, Stream};
+use futures::{future::{self, Future}, Stream};
 use lapin_futures as lapin;
-use crate::lapin::channel::{BasicConsumeOptions, BasicGetOptions, BasicPublishOptions, BasicProperties, ConfirmSelectOptions, ExchangeBindOptions, ExchangeUnbindOptions, ExchangeDeclareOptions, ExchangeDeleteOptions, QueueBindOptions, QueueDeclareOptions};
+use crate::lapin::channel::{BasicConsumeOptions, BasicGetOptions, BasicPublishOptions, BasicProperties, ConfirmSelectOptions, ExchangeBindOptions, ExchangeUnbindOptions, ExchangeDeclareOptions, ExchangeDeleteOptions, ExchangeKind, QueueBindOptions, QueueDeclareOptions};
 use crate::lapin::client::ConnectionOptions;
 use crate::lapin::types::FieldTable;
 use log::{debug, info};
@@ -25,7 +25,9 @@ fn main() {
         ..Default::default()
       }).map_err(Error::from)
     }).and_then(|(client, heartbeat)| {
-      tokio::spawn(heartbeat.map_err(|e| eprintln!("heartbeat error: {}", e)));
+      if let Some(heartbeat) = heartbeat {
+        tokio::spawn(heartbeat.map_err(|e| eprintln!("heartbeat error: {}", e)));
+      }
 
       client.create_confirm_channel(ConfirmSelectOptions::default()).and_then(|channel| {
         let id = channel.id();
@@ -34,7 +36,7 @@ fn main() {
         channel.queue_declare("hello", QueueDeclareOptions::default(), FieldTable::new()).and_then(move |_| {
           info!("channel {} declared queue {}", id, "hello");
 
-          channel.exchange_declare("hello_exchange", "direct", ExchangeDeclareOptions::default(), FieldTable::new()).and_then(move |_| {
+          channel.exchange_declare("hello_exchange", ExchangeKind::Direct, ExchangeDeclareOptions::default(), FieldTable::new()).and_then(move |_| {
             channel.queue_bind("hello", "hello_exchange", "hello_2", QueueBindOptions::default(), FieldTable::new()).and_then(move |_| {
               channel.basic_publish(
                 "hello_exchange",
@@ -68,9 +70,14 @@ fn main() {
 
           let ch = channel.clone();
           channel.basic_get("hello", BasicGetOptions::default()).and_then(move |message| {
-            info!("got message: {:?}", message);
-            info!("decoded message: {:?}", std::str::from_utf8(&message.delivery.data).unwrap());
-            channel.basic_ack(message.delivery.delivery_tag, false)
+            if let Some(message) = message {
+              info!("got message: {:?}", message);
+              info!("decoded message: {:?}", std::str::from_utf8(&message.delivery.data).unwrap());
+              future::Either::A(channel.basic_ack(message.delivery.delivery_tag, false))
+            } else {
+              info!("queue was empty");
+              future::Either::B(future::ok(()))
+            }
           }).and_then(move |_| {
             ch.basic_consume(&queue, "my_consumer", BasicConsumeOptions::default(), FieldTable::new())
           })
@@ -49,11 +49,11 @@ fn main() {
                 heartbeat: 20,
                 ..Default::default()
             }).map_err(Error::from)
-        }).and_then(|(client, heartbeat)| {
-            tokio::spawn(heartbeat.map_err(|e| eprintln!("heartbeat error: {}", e)))
-                .into_future()
-                .map(|_| client)
-                .map_err(|_| err_msg("Couldn't spawn the heartbeat task"))
+        }).map(|(client, heartbeat)| {
+            if let Some(heartbeat) = heartbeat {
+                tokio::spawn(heartbeat.map_err(|e| eprintln!("heartbeat error: {}", e)));
+            }
+            client
         }).and_then(|client| {
             let _client = client.clone();
             futures::stream::iter_ok(0..N_CONSUMERS)
@@ -25,7 +25,9 @@ fn main() {
         ..Default::default()
       }).map_err(Error::from)
     }).map(|(client, heartbeat)| {
-      tokio::spawn(heartbeat.map_err(|e| eprintln!("heartbeat error: {}", e)));
+      if let Some(heartbeat) = heartbeat {
+        tokio::spawn(heartbeat.map_err(|e| eprintln!("heartbeat error: {}", e)));
+      }
       client
     }).and_then(|client| {
       let publisher = client.create_confirm_channel(ConfirmSelectOptions::default()).and_then(|pub_channel| {
@@ -1,8 +1,8 @@
 use env_logger;
-use failure::{err_msg, Error};
-use futures::{future::Future, IntoFuture};
+use failure::Error;
+use futures::future::Future;
 use lapin_futures as lapin;
-use crate::lapin::channel::{BasicProperties, BasicPublishOptions, ConfirmSelectOptions, ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions};
+use crate::lapin::channel::{BasicProperties, BasicPublishOptions, ConfirmSelectOptions, ExchangeDeclareOptions, ExchangeKind, QueueBindOptions, QueueDeclareOptions};
 use crate::lapin::client::{Client, ConnectionOptions};
 use crate::lapin::types::FieldTable;
 use tokio;
@@ -22,15 +22,15 @@ fn main() {
                 heartbeat: 20,
                 ..Default::default()
             }).map_err(Error::from)
-        }).and_then(|(client, heartbeat)| {
-            tokio::spawn(heartbeat.map_err(|e| eprintln!("heartbeat error: {}", e)))
-                .into_future()
-                .map(|_| client)
-                .map_err(|_| err_msg("spawn error"))
+        }).map(|(client, heartbeat)| {
+            if let Some(heartbeat) = heartbeat {
+                tokio::spawn(heartbeat.map_err(|e| eprintln!("heartbeat error: {}", e)));
+            }
+            client
         }).and_then(|client| {
             client.create_confirm_channel(ConfirmSelectOptions::default())
                 .and_then(|channel| {
-                    channel.clone().exchange_declare("hello_topic", "topic", ExchangeDeclareOptions::default(), FieldTable::new()).map(move |_| channel)
+                    channel.clone().exchange_declare("hello_topic", ExchangeKind::Topic, ExchangeDeclareOptions::default(), FieldTable::new()).map(move |_| channel)
                 }).and_then(|channel| {
                     channel.clone().queue_declare("topic_queue", QueueDeclareOptions::default(), FieldTable::new()).map(move |_| channel)
                 }).and_then(|channel| {
@@ -0,0 +1,128 @@
+use futures::{Async, Future, Poll, Stream};
+use lapin_async::acknowledgement::DeliveryTag;
+use log::trace;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use crate::channel::Channel;
+use crate::consumer::Consumer;
+use crate::error::Error;
+use crate::message::Delivery;
+
+/// When an `AutoAckConsumer` acks the deliveries
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AutoAckMode {
+  /// acks a delivery when the next one is polled for, i.e. once the caller is done with it
+  AfterProcessing,
+  /// acks a delivery before yielding it, which loses it if the processing fails
+  BeforeYield,
+}
+
+/// A `Consumer` acking each delivery it yields, see `Consumer::auto_ack_stream`
+///
+/// in `AfterProcessing` mode, the last delivery is acked when the stream ends, or in the next
+/// poll after the item was yielded: a delivery whose processing crashed the program stays
+/// unacked and gets redelivered. A failed ack fails the stream.
+pub struct AutoAckConsumer<T> {
+  consumer: Consumer<T>,
+  channel:  Channel<T>,
+  mode:     AutoAckMode,
+  // the delivery to ack once the caller polls again, in AfterProcessing mode
+  to_ack:   Option<DeliveryTag>,
+  pending:  Option<Box<dyn Future<Item = (), Error = Error> + Send + 'static>>,
+  // the delivery to yield once its ack is sent, in BeforeYield mode
+  ready:    Option<Delivery>,
+}
+
+impl<T: AsyncRead+AsyncWrite+Send+Sync+'static> AutoAckConsumer<T> {
+  pub(crate) fn new(consumer: Consumer<T>, channel: Channel<T>, mode: AutoAckMode) -> Self {
+    Self {
+      consumer,
+      channel,
+      mode,
+      to_ack:  None,
+      pending: None,
+      ready:   None,
+    }
+  }
+
+  fn ack(&mut self, delivery_tag: DeliveryTag) {
+    trace!("auto ack; channel={} delivery_tag={}", self.channel.id(), delivery_tag);
+    self.pending = Some(Box::new(self.channel.basic_ack(delivery_tag, false)));
+  }
+}
+
+impl<T: AsyncRead+AsyncWrite+Send+Sync+'static> Stream for AutoAckConsumer<T> {
+  type Item = Delivery;
+  type Error = Error;
+
+  fn poll(&mut self) -> Poll<Option<Delivery>, Error> {
+    loop {
+      if let Some(pending) = self.pending.as_mut() {
+        if let Async::NotReady = pending.poll()? {
+          return Ok(Async::NotReady);
+        }
+        self.pending = None;
+      }
+      if let Some(delivery) = self.ready.take() {
+        return Ok(Async::Ready(Some(delivery)));
+      }
+      if let Some(delivery_tag) = self.to_ack.take() {
+        self.ack(delivery_tag);
+        continue;
+      }
+
+      match self.consumer.poll()? {
+        Async::Ready(Some(delivery)) => match self.mode {
+          AutoAckMode::AfterProcessing => {
+            self.to_ack = Some(delivery.delivery_tag);
+            return Ok(Async::Ready(Some(delivery)));
+          },
+          AutoAckMode::BeforeYield => {
+            self.ack(delivery.delivery_tag);
+            self.ready = Some(delivery);
+          },
+        },
+        Async::Ready(None) => return Ok(Async::Ready(None)),
+        Async::NotReady    => return Ok(Async::NotReady),
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use env_logger;
+  use futures::future;
+
+  use super::*;
+  use crate::test_utils::*;
+
+  #[test]
+  fn auto_ack_stream() {
+    let _ = env_logger::try_init();
+
+    let conn = connected_connection();
+    let inner = connected_channel(&conn);
+    let mut frames = delivery_frames(1, "consumer", 1, &[]);
+    frames.extend(delivery_frames(1, "consumer", 2, &[]));
+    let transport = test_transport(&conn, frames);
+    let consumer = Consumer::new(transport.clone(), inner.id(), "queue".to_string(), "consumer".to_string());
+    register_consumer(&inner, "queue", "consumer", consumer.subscriber());
+    let channel = Channel::from_inner(transport, conn.clone(), inner.clone());
+    let mut stream = consumer.auto_ack_stream(&channel, AutoAckMode::AfterProcessing);
+
+    future::lazy(move || {
+      // the previous delivery is acked once the next one is polled for, both were read at once
+      for delivery_tag in 1..=2 {
+        match stream.poll() {
+          Ok(Async::Ready(Some(delivery))) => assert_eq!(delivery.delivery_tag, delivery_tag),
+          res                              => panic!("expected a delivery, got {:?}", res.map(|_| ())),
+        }
+        assert_eq!(inner.unacked_deliveries.count(), 3 - delivery_tag as usize);
+      }
+      assert!(matches!(stream.poll(), Ok(Async::NotReady)));
+      assert_eq!(inner.unacked_deliveries.count(), 0);
+      Ok::<(), ()>(())
+    }).wait().unwrap();
+  }
+}
@@ -0,0 +1,189 @@
+//! A blocking API over the futures one, for programs without an event loop (requires the
+//! `blocking` feature)
+//!
+//! `BlockingClient` runs a tokio runtime with a single worker thread, which drives the
+//! heartbeat in the background. Each method of `BlockingClient` and `BlockingChannel` returns
+//! once the matching future resolved.
+//!
+//! ```rust,no_run
+//! use lapin_futures::blocking::BlockingClient;
+//! use lapin_futures::channel::{BasicGetOptions, BasicProperties, BasicPublishOptions, QueueDeclareOptions};
+//! use lapin_futures::types::FieldTable;
+//!
+//! let client = BlockingClient::connect_uri("amqp://127.0.0.1:5672/%2f").expect("connection failed");
+//! let channel = client.create_channel().expect("create_channel failed");
+//! channel.queue_declare("hello", QueueDeclareOptions::default(), FieldTable::new()).expect("queue_declare failed");
+//! channel.basic_publish("", "hello", b"hello".to_vec(), BasicPublishOptions::default(), BasicProperties::default()).expect("basic_publish failed");
+//! if let Some(message) = channel.basic_get("hello", BasicGetOptions::default()).expect("basic_get failed") {
+//!   channel.basic_ack(message.delivery.delivery_tag, false).expect("basic_ack failed");
+//! }
+//! ```
+
+use futures::Future;
+use log::error;
+use parking_lot::Mutex;
+use tokio::net::TcpStream;
+use tokio::runtime::{Builder, Runtime};
+
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+
+use crate::channel::*;
+use crate::client::{Client, ConnectionOptions};
+use crate::error::{Error, ErrorKind};
+use crate::message::BasicGetMessage;
+use crate::queue::Queue;
+use crate::types::{FieldTable, ShortUInt};
+
+/// A connection to the server whose methods block until they're done
+pub struct BlockingClient {
+  client:  Client<TcpStream>,
+  runtime: Arc<Mutex<Runtime>>,
+}
+
+impl BlockingClient {
+  /// connects to the server at `addr` over TCP, with Nagle's algorithm disabled, and starts the
+  /// heartbeat unless it's disabled
+  pub fn connect(addr: &SocketAddr, options: ConnectionOptions) -> Result<Self, Error> {
+    let mut runtime = Builder::new().core_threads(1).name_prefix("lapin-blocking-").build()?;
+    let stream = TcpStream::connect(addr).and_then(|stream| stream.set_nodelay(true).map(|_| stream));
+    let (client, heartbeat) = runtime.block_on(stream.map_err(|e| ErrorKind::ConnectionFailed(e).into()).and_then(move |stream| {
+      Client::connect(stream, options)
+    }))?;
+    if let Some(heartbeat) = heartbeat {
+      runtime.spawn(heartbeat.map_err(|e| error!("heartbeat error: {}", e)));
+    }
+    Ok(Self {
+      client,
+      runtime: Arc::new(Mutex::new(runtime)),
+    })
+  }
+
+  /// connects to the server given by an `amqp://` URI
+  pub fn connect_uri(uri: &str) -> Result<Self, Error> {
+    let options = uri.parse::<ConnectionOptions>()?;
+    let addr = options.address().to_socket_addrs()?.next().ok_or_else(|| {
+      ErrorKind::ConnectionFailed(io::Error::new(io::ErrorKind::NotFound, format!("couldn't resolve {}", options.address())))
+    })?;
+    Self::connect(&addr, options)
+  }
+
+  /// the futures based client, to mix both APIs
+  pub fn client(&self) -> &Client<TcpStream> {
+    &self.client
+  }
+
+  pub fn create_channel(&self) -> Result<BlockingChannel, Error> {
+    let channel = self.block_on(self.client.create_channel())?;
+    Ok(BlockingChannel {
+      channel,
+      runtime: self.runtime.clone(),
+    })
+  }
+
+  pub fn create_confirm_channel(&self, options: ConfirmSelectOptions) -> Result<BlockingChannel, Error> {
+    let channel = self.block_on(self.client.create_confirm_channel(options))?;
+    Ok(BlockingChannel {
+      channel,
+      runtime: self.runtime.clone(),
+    })
+  }
+
+  /// closes the connection, see `Client::close`
+  pub fn close(self, reply_code: u16, reply_text: &str) -> Result<(), Error> {
+    self.block_on(self.client.close(reply_code, reply_text))
+  }
+
+  fn block_on<F, R>(&self, future: F) -> Result<R, Error>
+      where F: Future<Item = R, Error = Error> + Send + 'static,
+            R: Send + 'static {
+    self.runtime.lock().block_on(future)
+  }
+}
+
+/// A channel whose methods block until the server answered
+pub struct BlockingChannel {
+  channel: Channel<TcpStream>,
+  runtime: Arc<Mutex<Runtime>>,
+}
+
+impl BlockingChannel {
+  /// the futures based channel, to mix both APIs
+  pub fn channel(&self) -> &Channel<TcpStream> {
+    &self.channel
+  }
+
+  pub fn id(&self) -> u16 {
+    self.channel.id()
+  }
+
+  pub fn exchange_declare(&self, name: &str, kind: ExchangeKind, options: ExchangeDeclareOptions, arguments: FieldTable) -> Result<(), Error> {
+    self.block_on(self.channel.exchange_declare(name, kind, options, arguments))
+  }
+
+  pub fn exchange_delete(&self, name: &str, options: ExchangeDeleteOptions) -> Result<(), Error> {
+    self.block_on(self.channel.exchange_delete(name, options))
+  }
+
+  pub fn queue_declare(&self, name: &str, options: QueueDeclareOptions, arguments: FieldTable) -> Result<Queue, Error> {
+    self.block_on(self.channel.queue_declare(name, options, arguments))
+  }
+
+  /// declares an exclusive, auto-delete queue named by the server, see `Channel::declare_temporary_queue`
+  pub fn declare_temporary_queue(&self) -> Result<Queue, Error> {
+    self.block_on(self.channel.declare_temporary_queue())
+  }
+
+  pub fn queue_bind(&self, name: &str, exchange: &str, routing_key: &str, options: QueueBindOptions, arguments: FieldTable) -> Result<(), Error> {
+    self.block_on(self.channel.queue_bind(name, exchange, routing_key, options, arguments))
+  }
+
+  pub fn queue_purge(&self, name: &str, options: QueuePurgeOptions) -> Result<u32, Error> {
+    self.block_on(self.channel.queue_purge(name, options))
+  }
+
+  pub fn queue_delete(&self, name: &str, options: QueueDeleteOptions) -> Result<u32, Error> {
+    self.block_on(self.channel.queue_delete(name, options))
+  }
+
+  pub fn basic_qos(&self, prefetch_count: ShortUInt, options: BasicQosOptions) -> Result<(), Error> {
+    self.block_on(self.channel.basic_qos(prefetch_count, options))
+  }
+
+  /// publishes a message, on a confirm channel this waits for the server to confirm it
+  pub fn basic_publish(&self, exchange: &str, routing_key: &str, payload: Vec<u8>, options: BasicPublishOptions, properties: BasicProperties) -> Result<Option<Confirmation>, Error> {
+    self.block_on(self.channel.basic_publish(exchange, routing_key, payload, options, properties))
+  }
+
+  pub fn basic_get(&self, queue: &str, options: BasicGetOptions) -> Result<Option<BasicGetMessage>, Error> {
+    self.block_on(self.channel.basic_get(queue, options))
+  }
+
+  pub fn basic_ack(&self, delivery_tag: u64, multiple: bool) -> Result<(), Error> {
+    self.block_on(self.channel.basic_ack(delivery_tag, multiple))
+  }
+
+  pub fn basic_nack(&self, delivery_tag: u64, multiple: bool, requeue: bool) -> Result<(), Error> {
+    self.block_on(self.channel.basic_nack(delivery_tag, multiple, requeue))
+  }
+
+  pub fn basic_reject(&self, delivery_tag: u64, requeue: bool) -> Result<(), Error> {
+    self.block_on(self.channel.basic_reject(delivery_tag, requeue))
+  }
+
+  /// asks the server to redeliver the messages we didn't ack yet, see `Channel::basic_recover`
+  pub fn basic_recover(&self, requeue: bool) -> Result<(), Error> {
+    self.block_on(self.channel.basic_recover(requeue))
+  }
+
+  pub fn close(self, code: u16, message: &str) -> Result<(), Error> {
+    self.block_on(self.channel.close(code, message))
+  }
+
+  fn block_on<F, R>(&self, future: F) -> Result<R, Error>
+      where F: Future<Item = R, Error = Error> + Send + 'static,
+            R: Send + 'static {
+    self.runtime.lock().block_on(future)
+  }
+}
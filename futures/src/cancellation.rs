@@ -0,0 +1,30 @@
+use log::debug;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Cancels the pending requests of the channels created by `Channel::with_cancellation`
+///
+/// once `cancel` is called, the requests of these channels waiting for the server's answer fail
+/// with `ErrorKind::Canceled`, and so do the next ones. Their answers are dropped when they come,
+/// see `lapin_async::channel::Channel::abandon_request`. A `Cancellation` can be shared by
+/// several channels, e.g. to stop everything at once on shutdown.
+#[derive(Clone, Debug, Default)]
+pub struct Cancellation {
+  canceled: Arc<AtomicBool>,
+}
+
+impl Cancellation {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn cancel(&self) {
+    debug!("canceling the pending requests");
+    self.canceled.store(true, Ordering::SeqCst);
+  }
+
+  pub fn is_canceled(&self) -> bool {
+    self.canceled.load(Ordering::SeqCst)
+  }
+}
@@ -1,51 +1,127 @@
 pub use lapin_async::channel::BasicProperties;
+pub use lapin_async::properties::BasicPropertiesExt;
 pub use lapin_async::channel::options::*;
+pub use lapin_async::acknowledgement::{Confirmation, DeliveryTag};
+pub use lapin_async::exchange::ExchangeKind;
+pub use lapin_async::exchange_arguments::ExchangeArguments;
+pub use lapin_async::topology::{BindingDefinition, BindingDestination, ChannelDefinition, ConsumerDefinition, ExchangeDefinition, QueueDefinition, Topology};
 
 use futures::{Async, Future, future, Poll, Stream, task};
+use futures::sync::mpsc;
 use lapin_async;
 use lapin_async::channel::Channel as InnerChannel;
+use lapin_async::channel::protocol::{AMQPError, AMQPSoftError};
 use lapin_async::channel_status::ChannelState;
+use lapin_async::consumer::ConsumerSubscriber;
 use lapin_async::connection::Connection;
 use lapin_async::queue::QueueStats;
 use lapin_async::requests::RequestId;
-use log::{debug, trace};
-use parking_lot::Mutex;
+use lapin_async::unacked_deliveries::UnackedDeliveries;
+use log::{debug, error, trace};
+#[cfg(feature = "serde_json")]
+use serde::Serialize;
 use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_timer::Delay;
 
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use crate::consumer::Consumer;
+use crate::cancellation::Cancellation;
+use crate::consumer::{Consumer, ConsumerSub, DeliveryChunk, generate_consumer_tag};
+use crate::consumer_builder::ConsumerBuilder;
 use crate::error::{Error, ErrorKind};
-use crate::message::BasicGetMessage;
+use crate::message::{BasicGetMessage, BasicReturnMessage};
+use crate::publish_sink::PublishSink;
+#[cfg(feature = "serde_json")]
+use crate::message::JSON_CONTENT_TYPE;
 use crate::queue::Queue;
-use crate::transport::AMQPTransport;
+use crate::transport::{AMQPTransport, SharedTransport, poll_shared};
 use crate::types::*;
 
 pub type RequestResult = Result<Option<RequestId>, lapin_async::error::Error>;
 
 /// `Channel` provides methods to act on a channel, such as managing queues
+///
+/// Cloning a `Channel` is cheap: the clones share the transport and the state of the channel,
+/// and can be used from several tasks or threads at once. The frames of a request are queued
+/// at once, so concurrent publishes never get their content frames interleaved, and on a
+/// confirm channel each one gets the delivery tag matching the order the server receives them.
+/// The clones also share the fate of the channel: once it's closed, the requests of all of them
+/// fail.
+///
+/// The methods taking a `nowait` option (the declares, binds, deletes and purges, as well as
+/// `basic_consume`, `basic_cancel` and `confirm_select`) don't wait for the server's answer
+/// when it's set: their future resolves once the request is sent, and whatever the server would
+/// have answered is unknown. If the server refuses the request, it closes the channel and the
+/// next requests fail with `ErrorKind::ChannelClosed`.
+///
+/// The requests wait for the server's answer forever, unless they're made through a clone
+/// created by `with_timeout`, or canceled through a clone created by `with_cancellation`.
+/// Dropping the future of a request abandons it too: its answer is dropped when it comes.
 //#[derive(Clone)]
 pub struct Channel<T> {
-  pub transport: Arc<Mutex<AMQPTransport<T>>>,
-      conn:      Connection,
-      inner:     InnerChannel,
+  pub transport:    Arc<SharedTransport<T>>,
+      conn:         Connection,
+      inner:        InnerChannel,
+      timeout:      Option<Duration>,
+      cancellation: Option<Cancellation>,
 }
 
 impl<T> Clone for Channel<T>
 where T: Send {
   fn clone(&self) -> Channel<T> {
     Channel {
-      transport: self.transport.clone(),
-      conn:      self.conn.clone(),
-      inner:     self.inner.clone(),
+      transport:    self.transport.clone(),
+      conn:         self.conn.clone(),
+      inner:        self.inner.clone(),
+      timeout:      self.timeout,
+      cancellation: self.cancellation.clone(),
     }
   }
 }
 
 impl<T: AsyncRead+AsyncWrite+Send+Sync+'static> Channel<T> {
     /// create a channel
-    pub fn create(transport: Arc<Mutex<AMQPTransport<T>>>, conn: Connection) -> impl Future<Item = Self, Error = Error> + Send + 'static {
-        future::result(conn.create_channel().map(|inner| Channel { transport, inner, conn }).map_err(|err| ErrorKind::ProtocolError("Failed to create channel".to_string(), err).into())).and_then(|channel| {
+    pub fn create(transport: Arc<SharedTransport<T>>, conn: Connection) -> impl Future<Item = Self, Error = Error> + Send + 'static {
+        let inner = conn.create_channel();
+        Self::open(transport, conn, inner)
+    }
+
+    /// create a channel with the given id
+    pub fn create_with_id(transport: Arc<SharedTransport<T>>, conn: Connection, id: u16) -> impl Future<Item = Self, Error = Error> + Send + 'static {
+        let inner = conn.create_channel_with_id(id);
+        Self::open(transport, conn, inner)
+    }
+
+    pub(crate) fn from_inner(transport: Arc<SharedTransport<T>>, conn: Connection, inner: InnerChannel) -> Self {
+        Channel { transport, conn, inner, timeout: None, cancellation: None }
+    }
+
+    /// a clone of this channel whose requests fail with `ErrorKind::Timeout` if the server
+    /// doesn't answer within `timeout`
+    ///
+    /// this bounds the wait for the answers to the synchronous methods (declares, binds, qos,
+    /// `basic_get`...), not the publisher confirms. The answer to a request that timed out is
+    /// dropped when it comes, the next requests get their own answer.
+    pub fn with_timeout(&self, timeout: Duration) -> Self {
+        let mut channel = self.clone();
+        channel.timeout = Some(timeout);
+        channel
+    }
+
+    /// a clone of this channel whose requests fail with `ErrorKind::Canceled` once
+    /// `cancellation` is canceled
+    ///
+    /// like for `with_timeout`, the answer to a canceled request is dropped when it comes
+    pub fn with_cancellation(&self, cancellation: &Cancellation) -> Self {
+        let mut channel = self.clone();
+        channel.cancellation = Some(cancellation.clone());
+        channel
+    }
+
+    fn open(transport: Arc<SharedTransport<T>>, conn: Connection, inner: Result<InnerChannel, lapin_async::error::Error>) -> impl Future<Item = Self, Error = Error> + Send + 'static {
+        future::result(inner.map(|inner| Channel { transport, inner, conn, timeout: None, cancellation: None }).map_err(|err| ErrorKind::ProtocolError("Failed to create channel".to_string(), err).into())).and_then(|channel| {
             let request_id = channel.inner.channel_open();
             let inner = channel.inner.clone();
             channel.run_on_locked_transport("create", "Could not create channel", request_id).and_then(move |_| {
@@ -62,6 +138,7 @@ impl<T: AsyncRead+AsyncWrite+Send+Sync+'static> Channel<T> {
                     }
                 })
             }).map(move |_| {
+                channel.conn.topology.channel_opened(channel.id());
                 channel
             })
         })
@@ -71,9 +148,24 @@ impl<T: AsyncRead+AsyncWrite+Send+Sync+'static> Channel<T> {
       self.inner.id()
     }
 
+    /// yields each new state of the channel from now on, including the content sending and
+    /// receiving ones, e.g. to log its lifecycle
+    ///
+    /// the states wait in the stream until it's polled, the channel stops sending them once it's
+    /// dropped
+    pub fn state_changes(&self) -> mpsc::UnboundedReceiver<ChannelState> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.inner.status.on_state_change(Box::new(move |state| sender.unbounded_send(state.clone()).is_ok()));
+        receiver
+    }
+
     /// request access
     ///
     /// returns a future that resolves once the access is granted
+    ///
+    /// the ticket granted by the server isn't available: amq-protocol doesn't parse it, and sends
+    /// a ticket of 0 with the methods taking one, which is what RabbitMQ expects. Brokers
+    /// requiring the granted ticket aren't supported
     pub fn access_request(&self, realm: &str, options: AccessRequestOptions) -> impl Future<Item = (), Error = Error> + Send + 'static {
         let request_id = self.inner.access_request(realm, options);
 
@@ -83,186 +175,653 @@ impl<T: AsyncRead+AsyncWrite+Send+Sync+'static> Channel<T> {
     /// declares an exchange
     ///
     /// returns a future that resolves once the exchange is available
-    pub fn exchange_declare(&self, name: &str, exchange_type: &str, options: ExchangeDeclareOptions, arguments: FieldTable) -> impl Future<Item = (), Error = Error> + Send + 'static {
-        let request_id = self.inner.exchange_declare(name, exchange_type, options, arguments);
-
-        self.run_on_locked_transport("exchange_declare", "Could not declare exchange", request_id).map(|_| ())
+    ///
+    /// with the `passive` option, the exchange isn't created: the other options and the arguments
+    /// are not sent, and if the exchange doesn't exist the server closes the channel and the
+    /// future fails with `ErrorKind::ChannelClosed`, whose reason is a `NOT_FOUND` error
+    ///
+    /// the arguments can be built with `ExchangeArguments`. Declaring an existing exchange with
+    /// another kind, options or arguments (e.g. another `alternate-exchange`) fails the same
+    /// way, with a `PRECONDITION_FAILED` error
+    pub fn exchange_declare(&self, name: &str, kind: ExchangeKind, options: ExchangeDeclareOptions, arguments: FieldTable) -> impl Future<Item = (), Error = Error> + Send + 'static {
+        let (options, arguments) = if options.passive {
+            (ExchangeDeclareOptions { passive: true, nowait: options.nowait, ..ExchangeDeclareOptions::default() }, FieldTable::new())
+        } else {
+            (options, arguments)
+        };
+        let topology = self.conn.topology.clone();
+        let record = (name.to_string(), kind.kind().to_string(), options.clone(), arguments.clone());
+        let request_id = self.inner.exchange_declare(name, kind.kind(), options, arguments);
+
+        self.run_on_locked_transport("exchange_declare", "Could not declare exchange", request_id).map(move |_| {
+            let (name, kind, options, arguments) = record;
+            topology.exchange_declared(&name, &kind, &options, &arguments);
+        })
     }
 
     /// deletes an exchange
     ///
+    /// with `if_unused`, the server refuses to delete an exchange that still has bindings and
+    /// closes the channel with a `PRECONDITION_FAILED` error
+    ///
     /// returns a future that resolves once the exchange is deleted
     pub fn exchange_delete(&self, name: &str, options: ExchangeDeleteOptions) -> impl Future<Item = (), Error = Error> + Send + 'static {
-        let request_id = self.inner.exchange_delete(name, options);
+        let topology = self.conn.topology.clone();
+        let name = name.to_string();
+        let request_id = self.inner.exchange_delete(&name, options);
 
-        self.run_on_locked_transport("exchange_delete", "Could not delete exchange", request_id).map(|_| ())
+        self.run_on_locked_transport("exchange_delete", "Could not delete exchange", request_id).map(move |_| topology.exchange_deleted(&name))
     }
 
     /// binds an exchange to another exchange
     ///
+    /// messages published to `source` matching `routing_key` and `arguments` get routed to
+    /// `destination` (this is a RabbitMQ extension)
+    ///
     /// returns a future that resolves once the exchanges are bound
     pub fn exchange_bind(&self, destination: &str, source: &str, routing_key: &str, options: ExchangeBindOptions, arguments: FieldTable) -> impl Future<Item = (), Error = Error> + Send + 'static {
+        let topology = self.conn.topology.clone();
+        let binding = binding_definition(BindingDestination::Exchange, destination, source, routing_key, &arguments);
         let request_id = self.inner.exchange_bind(destination, source, routing_key, options, arguments);
 
-        self.run_on_locked_transport("exchange_bind", "Could not bind exchange", request_id).map(|_| ())
+        self.run_on_locked_transport("exchange_bind", "Could not bind exchange", request_id).map(move |_| topology.bound(binding))
     }
 
     /// unbinds an exchange from another one
     ///
+    /// `routing_key` and `arguments` have to match the ones used by `exchange_bind`
+    ///
     /// returns a future that resolves once the exchanges are unbound
     pub fn exchange_unbind(&self, destination: &str, source: &str, routing_key: &str, options: ExchangeUnbindOptions, arguments: FieldTable) -> impl Future<Item = (), Error = Error> + Send + 'static {
+        let topology = self.conn.topology.clone();
+        let binding = binding_definition(BindingDestination::Exchange, destination, source, routing_key, &arguments);
         let request_id = self.inner.exchange_unbind(destination, source, routing_key, options, arguments);
 
-        self.run_on_locked_transport("exchange_unbind", "Could not unbind exchange", request_id).map(|_| ())
+        self.run_on_locked_transport("exchange_unbind", "Could not unbind exchange", request_id).map(move |_| topology.unbound(&binding))
     }
 
     /// declares a queue
     ///
     /// returns a future that resolves once the queue is available
     ///
-    /// the `mandatory` and `ìmmediate` options can be set to true,
-    /// but the return message will not be handled
+    /// the resulting `Queue` holds the name assigned by the server if `name` was empty, and the
+    /// message and consumer counts sent in Queue.DeclareOk. With the `nowait` option, the server
+    /// doesn't answer: the future resolves right away and both counts are 0
+    ///
+    /// with the `passive` option, the queue isn't created: the other options and the arguments
+    /// are not sent, and if the queue doesn't exist the server closes the channel and the future
+    /// fails with `ErrorKind::ChannelClosed`, whose reason is a `NOT_FOUND` error
     pub fn queue_declare(&self, name: &str, options: QueueDeclareOptions, arguments: FieldTable) -> impl Future<Item = Queue, Error = Error> + Send + 'static {
+        let (options, arguments) = if options.passive {
+            (QueueDeclareOptions { passive: true, nowait: options.nowait, ..QueueDeclareOptions::default() }, FieldTable::new())
+        } else {
+            (options, arguments)
+        };
+        let topology = self.conn.topology.clone();
+        let record = (options.clone(), arguments.clone());
         let request_id = self.inner.queue_declare(name, options, arguments);
         let inner = self.inner.clone();
+        let name = name.to_string();
+        let server_named = name.is_empty();
 
         self.run_on_locked_transport("queue_declare", "Could not declare queue", request_id).and_then(move |request_id| {
             future::poll_fn(move || {
-              if let Some(queue) = inner.generated_names.get(request_id.expect("expected request_id")) {
+              let queue = match request_id {
+                Some(request_id) => inner.generated_names.get(request_id),
+                // with nowait, the server doesn't answer and we don't know the stats
+                None             => return Ok(Async::Ready(Queue::new(name.clone(), 0, 0))),
+              };
+              if let Some(queue) = queue {
                 let QueueStats {consumer_count, message_count} = inner.queues.get_stats(&queue);
-                return Ok(Async::Ready(Queue::new(queue, consumer_count, message_count)))
+                Ok(Async::Ready(Queue::new(queue, consumer_count, message_count)))
               } else {
                 task::current().notify();
-                return Ok(Async::NotReady)
+                Ok(Async::NotReady)
               }
             })
+        }).map(move |queue| {
+            let (options, arguments) = record;
+            // with nowait, the name given by the server is unknown
+            if !queue.name().is_empty() {
+              topology.queue_declared(&queue.name(), server_named, &options, &arguments);
+            }
+            queue
         })
     }
 
+    /// checks whether a queue exists, using a passive declare
+    ///
+    /// the server closes the channel when the queue doesn't exist, so the channel can't be used
+    /// anymore once the future resolved to `false`
+    pub fn queue_exists(&self, name: &str) -> impl Future<Item = bool, Error = Error> + Send + 'static {
+        self.queue_declare(name, QueueDeclareOptions { passive: true, ..QueueDeclareOptions::default() }, FieldTable::new()).then(|result| match result {
+            Ok(_)  => Ok(true),
+            Err(e) => match e.kind() {
+                ErrorKind::ChannelClosed(reason) if reason.error() == Some(AMQPError::Soft(AMQPSoftError::NOTFOUND)) => Ok(false),
+                _ => Err(e),
+            },
+        })
+    }
+
+    /// the number of messages ready in a queue, using a passive declare
+    ///
+    /// only the name and the `passive` flag are sent, so the properties and arguments of the
+    /// queue don't have to be known. If the queue doesn't exist, the server closes the channel
+    /// and the future fails with `ErrorKind::ChannelClosed`, whose reason is a `NOT_FOUND` (404)
+    /// error
+    pub fn queue_message_count(&self, name: &str) -> impl Future<Item = u32, Error = Error> + Send + 'static {
+        self.queue_declare(name, QueueDeclareOptions { passive: true, ..QueueDeclareOptions::default() }, FieldTable::new()).map(|queue| queue.message_count())
+    }
+
+    /// declares an exclusive, auto-delete queue named by the server
+    ///
+    /// the queue is deleted once its last consumer is gone or the connection is closed, e.g. for
+    /// reply queues or temporary subscriptions. Its name is given by `Queue::name`
+    pub fn declare_temporary_queue(&self) -> impl Future<Item = Queue, Error = Error> + Send + 'static {
+        self.queue_declare("", QueueDeclareOptions { exclusive: true, auto_delete: true, ..QueueDeclareOptions::default() }, FieldTable::new())
+    }
+
     /// binds a queue to an exchange
     ///
     /// returns a future that resolves once the queue is bound to the exchange
+    ///
+    /// the routing key is ignored by fanout exchanges and can be left empty. For headers
+    /// exchanges, the `x-match` argument and the headers to match go in `arguments`
     pub fn queue_bind(&self, name: &str, exchange: &str, routing_key: &str, options: QueueBindOptions, arguments: FieldTable) -> impl Future<Item = (), Error = Error> + Send + 'static {
+        let topology = self.conn.topology.clone();
+        let binding = binding_definition(BindingDestination::Queue, name, exchange, routing_key, &arguments);
         let request_id = self.inner.queue_bind(name, exchange, routing_key, options, arguments);
 
-        self.run_on_locked_transport("queue_bind", "Could not bind queue", request_id).map(|_| ())
+        self.run_on_locked_transport("queue_bind", "Could not bind queue", request_id).map(move |_| topology.bound(binding))
     }
 
     /// unbinds a queue from the exchange
     ///
     /// returns a future that resolves once the queue is unbound from the exchange
+    ///
+    /// the routing key and arguments must match the ones used in `queue_bind`
     pub fn queue_unbind(&self, name: &str, exchange: &str, routing_key: &str, arguments: FieldTable) -> impl Future<Item = (), Error = Error> + Send + 'static {
+        let topology = self.conn.topology.clone();
+        let binding = binding_definition(BindingDestination::Queue, name, exchange, routing_key, &arguments);
         let request_id = self.inner.queue_unbind(name, exchange, routing_key, arguments);
 
-        self.run_on_locked_transport("queue_unbind", "Could not unbind queue from the exchange", request_id).map(|_| ())
+        self.run_on_locked_transport("queue_unbind", "Could not unbind queue from the exchange", request_id).map(move |_| topology.unbound(&binding))
     }
 
     /// sets up confirm extension for this channel
+    ///
+    /// fails if the channel is in transactional mode, a channel cannot be both
+    ///
+    /// with the `nowait` option, the future resolves right away and the channel is considered in
+    /// confirm mode without waiting for Confirm.SelectOk. If the server refuses it, it closes the
+    /// channel and the pending publishes fail with `ErrorKind::ChannelClosed`
     pub fn confirm_select(&self, options: ConfirmSelectOptions) -> impl Future<Item = (), Error = Error> + Send + 'static {
+        let topology = self.conn.topology.clone();
+        let channel_id = self.id();
         let request_id = self.inner.confirm_select(options);
 
-        self.run_on_locked_transport("confirm_select", "Could not activate confirm extension", request_id).map(|_| ())
+        self.run_on_locked_transport("confirm_select", "Could not activate confirm extension", request_id).map(move |_| topology.confirm_selected(channel_id))
     }
 
     /// specifies quality of service for a channel
+    ///
+    /// returns a future that resolves once the server acknowledged the new prefetch count
+    ///
+    /// `options.global` is passed through as is: RabbitMQ applies the limit to each new consumer
+    /// of the channel when it's false, and shares it between all the consumers of the channel when
+    /// it's true, whereas the specification applies it to the channel or to the whole connection.
+    /// The prefetch size is always 0 (no limit) as RabbitMQ doesn't support anything else
+    ///
+    /// the server doesn't send more than `prefetch_count` unacked deliveries to the consumers
+    /// created afterwards, so their buffer doesn't grow beyond that as long as they ack what
+    /// they get. The consumers with `no_ack` aren't limited by the server, they only stop
+    /// reading from the network while `prefetch_count` deliveries are buffered, see
+    /// `Consumer::set_prefetch_count`
     pub fn basic_qos(&self, prefetch_count: ShortUInt, options: BasicQosOptions) -> impl Future<Item = (), Error = Error> + Send + 'static {
+        let topology = self.conn.topology.clone();
+        let channel_id = self.id();
+        let global = options.global;
         let request_id = self.inner.basic_qos(prefetch_count, options);
 
-        self.run_on_locked_transport("basic_qos", "Could not setup qos", request_id).map(|_| ())
+        self.run_on_locked_transport("basic_qos", "Could not setup qos", request_id).map(move |_| topology.qos_set(channel_id, prefetch_count, global))
     }
 
     /// publishes a message on a queue
     ///
     /// the future's result is:
-    /// - `Some(true)` if we're on a confirm channel and the message was ack'd
-    /// - `Some(false)` if we're on a confirm channel and the message was nack'd
+    /// - `Some(Confirmation::Ack)` if we're on a confirm channel and the message was ack'd
+    /// - `Some(Confirmation::Nack)` if we're on a confirm channel and the message was nack'd
+    /// - `Some(Confirmation::Returned(message))` if we're on a confirm channel and the message
+    ///   was returned by the server (for example a mandatory message that couldn't be routed)
     /// - `None` if we're not on a confirm channel
-    pub fn basic_publish(&self, exchange: &str, routing_key: &str, payload: Vec<u8>, options: BasicPublishOptions, properties: BasicProperties) -> impl Future<Item = Option<bool>, Error = Error> + Send + 'static {
-      let delivery_tag = self.inner.basic_publish(exchange, routing_key, options, payload, properties);
+    ///
+    /// if the server paused the flow of this channel, the message will be kept aside and sent
+    /// once it gets active again, in the order it was published
+    ///
+    /// `options.mandatory` asks the server to return the message if it can't be routed to any
+    /// queue, see `returned_messages`. `options.immediate` is sent with a warning, but RabbitMQ
+    /// 3.0 and later don't support it and close the connection with a `NOT_IMPLEMENTED` error:
+    /// `ConnectionOptions::reject_immediate` makes the publish fail instead. Both default to
+    /// `false`
+    ///
+    /// `properties` are built with the `BasicProperties::with_*` methods, only the ones which
+    /// are set get sent. Deliveries expose the properties they were published with
+    ///
+    /// when `ConnectionOptions::max_pending_frames` frames are already waiting to be sent, the
+    /// message is only published once the socket drained enough of them, when the future is
+    /// polled. Messages published meanwhile without waiting for the previous futures could then
+    /// get sent first
+    pub fn basic_publish(&self, exchange: &str, routing_key: &str, payload: Vec<u8>, options: BasicPublishOptions, properties: BasicProperties) -> impl Future<Item = Option<Confirmation>, Error = Error> + Send + 'static {
+      if !self.transport.lock().is_send_buffer_full() {
+        return future::Either::A(self.publish(exchange, routing_key, payload, options, properties));
+      }
+
+      trace!("send buffer full, delaying basic_publish; channel={}", self.id());
+      let channel = self.clone();
       let transport = self.transport.clone();
-      let inner = self.inner.clone();
+      let exchange = exchange.to_string();
+      let routing_key = routing_key.to_string();
+      future::Either::B(future::poll_fn(move || transport.lock().poll_send_buffer()).and_then(move |_| {
+        channel.publish(&exchange, &routing_key, payload, options, properties)
+      }))
+    }
+
+    /// publishes a message straight to the queue `queue`, through the default exchange, with the
+    /// default options
+    ///
+    /// the same as `basic_publish` with an empty exchange and `queue` as routing key, it resolves
+    /// to the confirmation on a confirm channel. Without the `mandatory` option, a message sent to
+    /// a queue which doesn't exist is silently dropped
+    pub fn publish_to_queue(&self, queue: &str, payload: Vec<u8>, properties: BasicProperties) -> impl Future<Item = Option<Confirmation>, Error = Error> + Send + 'static {
+      self.basic_publish("", queue, payload, BasicPublishOptions::default(), properties)
+    }
 
-      future::result(delivery_tag.map_err(|e| ErrorKind::ProtocolError("Could not publish".to_string(), e).into())).and_then(move |delivery_tag| {
-        if delivery_tag.is_some() {
-          trace!("{} returning closure", "basic_publish");
+    /// serializes `message` to JSON and publishes it with the `application/json` content type
+    /// and the default options, see `basic_publish` (requires the `serde_json` feature)
+    ///
+    /// the consumers get it back with `Delivery::json`
+    #[cfg(feature = "serde_json")]
+    pub fn publish_json<S: Serialize>(&self, exchange: &str, routing_key: &str, message: &S) -> impl Future<Item = Option<Confirmation>, Error = Error> + Send + 'static {
+      match serde_json::to_vec(message) {
+        Ok(payload) => {
+          let properties = BasicProperties::default().with_content_type(JSON_CONTENT_TYPE.to_string());
+          future::Either::A(self.basic_publish(exchange, routing_key, payload, BasicPublishOptions::default(), properties))
+        },
+        Err(e)      => future::Either::B(future::err(ErrorKind::JsonError(e).into())),
+      }
+    }
+
+    fn publish(&self, exchange: &str, routing_key: &str, payload: Vec<u8>, options: BasicPublishOptions, properties: BasicProperties) -> impl Future<Item = Option<Confirmation>, Error = Error> + Send + 'static {
+      let delivery_tag = self.inner.basic_publish(exchange, routing_key, options, payload, properties).map_err(|e| Self::request_error(&self.inner, "Could not publish", e));
+      let channel = self.clone();
+
+      future::result(delivery_tag).and_then(move |delivery_tag| {
+        if let Some(delivery_tag) = delivery_tag {
+          trace!("basic_publish returning closure; channel={} delivery_tag={}", channel.id(), delivery_tag);
         }
+        channel.wait_for_confirmation(delivery_tag)
+      })
+    }
+
+    /// a `Sink` publishing the messages given to it, to `forward` a stream of messages with
+    /// backpressure, see `PublishSink`
+    pub fn publish_sink(&self) -> PublishSink<T> {
+      PublishSink::new(self.clone(), self.conn.clone())
+    }
+
+    /// publishes several messages at once, and resolves once they're all confirmed
+    ///
+    /// on a confirm channel, the future resolves to the confirmation of every message, or as soon
+    /// as one of them is nacked or returned: `BatchConfirmation::nacked` and `returned` tell
+    /// which ones failed, `unconfirmed` the ones whose confirmation isn't waited for anymore. On
+    /// other channels, it resolves to `None` once the messages are sent.
+    ///
+    /// If a publish fails, the rest of the batch isn't published. The future fails if it's the
+    /// first message, and resolves to a `BatchConfirmation` counting the messages left in
+    /// `unpublished` otherwise, also on a channel which isn't in confirm mode
+    pub fn basic_publish_batch(&self, messages: Vec<PublishMessage>) -> impl Future<Item = Option<BatchConfirmation>, Error = Error> + Send + 'static {
+      let mut unpublished = messages.len();
+      let mut delivery_tags = Vec::with_capacity(messages.len());
+      let mut published = Ok(());
+      for message in messages {
+        match self.inner.basic_publish(&message.exchange, &message.routing_key, message.options, message.payload, message.properties) {
+          Ok(delivery_tag) => {
+            unpublished -= 1;
+            delivery_tags.extend(delivery_tag);
+          },
+          Err(e) => {
+            published = Err(e);
+            break;
+          },
+        }
+      }
+      let first_failed = delivery_tags.is_empty() && unpublished > 0;
+      let published = match published {
+        Err(e) if !first_failed => {
+          error!("the last {} messages of the batch couldn't be published; channel={}: {}", unpublished, self.id(), e);
+          Ok(())
+        },
+        published => published,
+      };
+      let transport = self.transport.clone();
+      let inner = self.inner.clone();
+
+      future::result(published.map_err(|e| Self::request_error(&inner, "Could not publish", e))).and_then(move |_| {
+        trace!("published a batch; channel={} delivery_tags={:?} unpublished={}", inner.id(), delivery_tags, unpublished);
+        let mut pending = UnconfirmedTags { channel: inner.clone(), delivery_tags };
+        let mut confirmations = Vec::with_capacity(pending.delivery_tags.len());
 
         future::poll_fn(move || {
-          let mut transport = transport.lock();
-
-          if let Some(delivery_tag) = delivery_tag {
-            trace!("wait for aack; delivery_tag={:?}", delivery_tag);
-            transport.poll()?;
-            trace!("wait for ack; transport poll; delivery_tag={:?} status=NotReady", delivery_tag);
-            if inner.status.confirm() {
-              if inner.acknowledgements.is_acked(delivery_tag) {
-                Ok(Async::Ready(Some(true)))
-              } else if inner.acknowledgements.is_nacked(delivery_tag) {
-                Ok(Async::Ready(Some(false)))
-              } else {
-                debug!("message with tag {} still in unacked", delivery_tag);
-                task::current().notify();
-                Ok(Async::NotReady)
-              }
+          if !inner.status.confirm() {
+            return transport.lock().poll().map(|r| r.map(|sent| sent.and(if unpublished > 0 {
+              Some(BatchConfirmation { confirmations: Vec::new(), unconfirmed: Vec::new(), unpublished })
             } else {
-              Ok(Async::Ready(None))
+              None
+            })));
+          }
+          poll_shared(&transport).map_err(|error| Self::closed_error(&inner).unwrap_or(error))?;
+          // registered before looking at the confirmations, not to miss the ones another task
+          // receives meanwhile
+          transport.notify_on_unlock();
+          let mut failed = false;
+          pending.delivery_tags.retain(|delivery_tag| {
+            match inner.acknowledgements.take_confirmation(*delivery_tag) {
+              Some(confirmation) => {
+                failed |= confirmation != Confirmation::Ack;
+                confirmations.push((*delivery_tag, confirmation));
+                false
+              },
+              None => true,
             }
+          });
+          if pending.delivery_tags.is_empty() || failed {
+            confirmations.sort_by_key(|(delivery_tag, _)| *delivery_tag);
+            Ok(Async::Ready(Some(BatchConfirmation { confirmations: confirmations.split_off(0), unconfirmed: pending.forget(), unpublished })))
+          } else if let Some(error) = Self::closed_error(&inner) {
+            Err(error)
           } else {
-            transport.poll().map(|r| r.map(|_| None))
+            debug!("{} messages of the batch still in unacked; channel={}", pending.delivery_tags.len(), inner.id());
+            Ok(Async::NotReady)
           }
         })
       })
     }
 
+    /// the delivery tag the next message published on this confirm channel will get, matching
+    /// the `delivery_tag` of its ack or nack, 0 if the channel isn't in confirm mode
+    pub fn next_publish_seq_no(&self) -> DeliveryTag {
+      self.inner.next_publish_seq_no()
+    }
+
+    /// publishes a message whose body of `body_size` bytes is read from the `body` stream
+    ///
+    /// the chunks are sent as they come, split according to the frame_max, and the next one is
+    /// only read once the previous one is written to the socket, so that the body is never
+    /// held in memory. The other publishes on this channel fail with lapin-async's
+    /// `ErrorKind::PublishInProgress` until the whole body is sent, and the stream has to yield
+    /// exactly `body_size` bytes: the future fails with `ErrorKind::BodySizeMismatch` otherwise,
+    /// or with the error of the stream if it fails. The channel is then put in error, the
+    /// server can't make sense of it anymore, and its requests fail with
+    /// `ErrorKind::ChannelClosed`
+    ///
+    /// an `AsyncRead` can be turned into such a stream with `tokio_codec::FramedRead` and
+    /// `tokio_codec::BytesCodec`
+    pub fn basic_publish_stream<S>(&self, exchange: &str, routing_key: &str, body: S, body_size: u64, options: BasicPublishOptions, properties: BasicProperties) -> impl Future<Item = Option<Confirmation>, Error = Error> + Send + 'static
+        where S: Stream<Item = Vec<u8>, Error = Error> + Send + 'static {
+      let delivery_tag = self.inner.basic_publish_header(exchange, routing_key, options, body_size, properties);
+      let channel = self.clone();
+      let send_inner = self.inner.clone();
+      let send_transport = self.transport.clone();
+      let check_inner = self.inner.clone();
+      let abort_inner = self.inner.clone();
+
+      future::result(delivery_tag.map_err(|e| Self::request_error(&check_inner, "Could not publish", e))).and_then(move |delivery_tag| {
+        body.for_each(move |chunk| {
+          let sent = body_size - send_inner.remaining_body_size().unwrap_or(0) + chunk.len() as u64;
+          if send_inner.send_body_chunk(&chunk).is_err() {
+            return future::Either::A(future::err(ErrorKind::BodySizeMismatch(body_size, sent).into()));
+          }
+          let transport = send_transport.clone();
+          // wait for the chunk to be written before reading the next one
+          future::Either::B(future::poll_fn(move || transport.lock().poll()).map(|_| ()))
+        }).and_then(move |_| {
+          match check_inner.remaining_body_size() {
+            Some(remaining) => Err(ErrorKind::BodySizeMismatch(body_size, body_size - remaining).into()),
+            None            => Ok(delivery_tag),
+          }
+        }).map_err(move |err| {
+          debug!("aborting the streamed body; channel={} error={}", abort_inner.id(), err);
+          // the channel is already gone if it fails
+          let _ = abort_inner.abort_streamed_body();
+          err
+        })
+      }).and_then(move |delivery_tag| channel.wait_for_confirmation(delivery_tag))
+    }
+
+    /// resolves to the confirmation of the message, once it's sent if it doesn't need one
+    fn wait_for_confirmation(&self, delivery_tag: Option<DeliveryTag>) -> impl Future<Item = Option<Confirmation>, Error = Error> + Send + 'static {
+      let transport = self.transport.clone();
+      let inner = self.inner.clone();
+
+      future::poll_fn(move || {
+        if let Some(delivery_tag) = delivery_tag {
+          trace!("wait for ack; channel={} delivery_tag={:?}", inner.id(), delivery_tag);
+          poll_shared(&transport).map_err(|error| Self::closed_error(&inner).unwrap_or(error))?;
+          trace!("wait for ack; transport poll; channel={} delivery_tag={:?} status=NotReady", inner.id(), delivery_tag);
+          if inner.status.confirm() {
+            // registered before looking at the confirmation, not to miss it if another task
+            // receives it meanwhile
+            transport.notify_on_unlock();
+            if let Some(confirmation) = inner.acknowledgements.take_confirmation(delivery_tag) {
+              Ok(Async::Ready(Some(confirmation)))
+            } else if let Some(error) = Self::closed_error(&inner) {
+              Err(error)
+            } else {
+              debug!("message with tag {} still in unacked; channel={}", delivery_tag, inner.id());
+              Ok(Async::NotReady)
+            }
+          } else {
+            Ok(Async::Ready(None))
+          }
+        } else {
+          // resolves once our frames are sent, we can't leave that to whoever polls the transport
+          transport.lock().poll().map(|r| r.map(|_| None))
+        }
+      })
+    }
+
+    /// returns a stream of the messages returned by the server, for example the mandatory ones
+    ///
+    /// only the messages returned after this call are yielded, on confirm channels they're also
+    /// reported through the `basic_publish` future. The stream ends once the channel is closed
+    pub fn returned_messages(&self) -> ReturnedMessages<T> {
+        self.inner.returned_messages.listen();
+        ReturnedMessages {
+            transport: self.transport.clone(),
+            inner:     self.inner.clone(),
+        }
+    }
+
     /// creates a consumer stream
     ///
     /// returns a future of a `Consumer` that resolves once the method succeeds
     ///
     /// `Consumer` implements `futures::Stream`, so it can be used with any of
     /// the usual combinators
+    ///
+    /// the arguments, such as the priority of the consumer, can be built with `ConsumerArguments`
+    ///
+    /// an empty `consumer_tag` is replaced by one generated here instead of by the server, it's
+    /// given by `Consumer::tag`. With the `nowait` option, the consumer is registered right away
+    ///
+    /// with the `no_ack` option, the server considers each message acked as soon as it's sent:
+    /// it's the fastest way to consume, but the messages in flight or being processed are lost
+    /// if the connection or the program fails (at-most-once delivery). Their deliveries must not
+    /// be acked, `basic_ack`, `basic_nack` and `basic_reject` fail for them
+    ///
+    /// with the `exclusive` option, no other consumer can consume the queue while this one is
+    /// active. If another consumer already does, the server closes the channel and the future
+    /// fails with `ErrorKind::ChannelClosed`, whose reason is an `ACCESS_REFUSED` error. The
+    /// `no_local` option asks the server not to deliver the messages published on this connection
     pub fn basic_consume(&self, queue: &Queue, consumer_tag: &str, options: BasicConsumeOptions, arguments: FieldTable) -> impl Future<Item = Consumer<T>, Error = Error> + Send + 'static {
-        let consumer_tag = consumer_tag.to_string();
+        self.consume(queue, consumer_tag, options, arguments)
+    }
+
+    /// creates a consumer stream yielding the deliveries body chunk by body chunk
+    ///
+    /// like `basic_consume`, but the bodies aren't buffered: each delivery is yielded as a
+    /// `DeliveryChunk::Start`, followed by its content body frames and a `DeliveryChunk::End`
+    pub fn basic_consume_chunks(&self, queue: &Queue, consumer_tag: &str, options: BasicConsumeOptions, arguments: FieldTable) -> impl Future<Item = Consumer<T, DeliveryChunk>, Error = Error> + Send + 'static {
+        self.consume(queue, consumer_tag, options, arguments)
+    }
+
+    fn consume<D>(&self, queue: &Queue, consumer_tag: &str, options: BasicConsumeOptions, arguments: FieldTable) -> impl Future<Item = Consumer<T, D>, Error = Error> + Send + 'static
+        where D: Send + 'static,
+              ConsumerSub<D>: ConsumerSubscriber + 'static {
+        // generate the tag ourselves, for it to be known right away and to be reusable after a
+        // reconnection, e.g. by the recorded topology
+        let consumer_tag = if consumer_tag.is_empty() { generate_consumer_tag() } else { consumer_tag.to_string() };
         let queue_name = queue.name();
-        let mut consumer = Consumer::new(self.transport.clone(), self.id(), queue.name(), consumer_tag.to_owned());
+        let mut consumer = Consumer::with_items(self.transport.clone(), self.id(), queue.name(), consumer_tag.to_owned());
+        consumer.set_prefetch_count(self.inner.status.prefetch_count());
         let subscriber = consumer.subscriber();
+        let topology = self.conn.topology.clone();
+        let mut record = ConsumerDefinition {
+          tag:       String::new(),
+          queue:     queue_name.clone(),
+          no_local:  options.no_local,
+          no_ack:    options.no_ack,
+          exclusive: options.exclusive,
+          arguments: arguments.clone(),
+        };
         let request_id = self.inner.basic_consume(&queue_name, &consumer_tag, options, arguments, Box::new(subscriber));
         let inner = self.inner.clone();
+        let channel_id = self.id();
 
         self.run_on_locked_transport("basic_consume", "Could not start consumer", request_id).and_then(move |request_id| {
             future::poll_fn(move || {
-              if let Some(consumer_tag) = inner.generated_names.get(request_id.expect("expected request_id")) {
-                return Ok(Async::Ready(consumer_tag))
+              let generated_tag = match request_id {
+                Some(request_id) => inner.generated_names.get(request_id),
+                // with nowait, the server doesn't answer and we keep our own tag
+                None             => return Ok(Async::Ready(consumer_tag.clone())),
+              };
+              if let Some(consumer_tag) = generated_tag {
+                Ok(Async::Ready(consumer_tag))
               } else {
                 task::current().notify();
-                return Ok(Async::NotReady)
+                Ok(Async::NotReady)
               }
             })
-          }).map(|consumer_tag| {
-            trace!("basic_consume received response, returning consumer");
+          }).map(move |consumer_tag| {
+            trace!("basic_consume received response, returning consumer; channel={} consumer_tag={}", channel_id, consumer_tag);
+            record.tag = consumer_tag.clone();
+            topology.consumer_added(channel_id, record);
             consumer.update_consumer_tag(consumer_tag);
             consumer
         })
     }
 
+    /// cancels a consumer
+    ///
+    /// returns a future that resolves once the server acknowledged the cancellation, the
+    /// `Consumer` stream ends once its buffered deliveries are consumed. Canceling an unknown
+    /// consumer tag resolves as well
     pub fn basic_cancel(&self, consumer_tag: &str, options: BasicCancelOptions) -> impl Future<Item = (), Error = Error> + Send + 'static {
-        let request_id = self.inner.basic_cancel(consumer_tag, options);
+        let topology = self.conn.topology.clone();
+        let consumer_tag = consumer_tag.to_string();
+        let request_id = self.inner.basic_cancel(&consumer_tag, options);
 
-        self.run_on_locked_transport("basic_cancel", "Could not cancel consumer", request_id).map(|_| ())
+        self.run_on_locked_transport("basic_cancel", "Could not cancel consumer", request_id).map(move |_| topology.consumer_canceled(&consumer_tag))
     }
 
-    pub fn basic_recover(&self, options: BasicRecoverOptions) -> impl Future<Item = (), Error = Error> + Send + 'static {
-        let request_id = self.inner.basic_recover(options);
+    /// asks the server to redeliver the messages we didn't ack yet on this channel
+    ///
+    /// if `requeue` is set, the messages are requeued and may go to other consumers, otherwise
+    /// they're redelivered to their original consumer (RabbitMQ doesn't support the latter). The
+    /// redeliveries get new delivery tags, the former ones mustn't be acked anymore
+    pub fn basic_recover(&self, requeue: bool) -> impl Future<Item = (), Error = Error> + Send + 'static {
+        let request_id = self.inner.basic_recover(BasicRecoverOptions { requeue });
 
         self.run_on_locked_transport("basic_recover", "Could not recover", request_id).map(|_| ())
     }
 
-    pub fn basic_recover_async(&self, options: BasicRecoverAsyncOptions) -> impl Future<Item = (), Error = Error> + Send + 'static {
-        let request_id = self.inner.basic_recover_async(options);
+    /// like `basic_recover`, without waiting for the server to answer (deprecated by the spec)
+    pub fn basic_recover_async(&self, requeue: bool) -> impl Future<Item = (), Error = Error> + Send + 'static {
+        let request_id = self.inner.basic_recover_async(BasicRecoverAsyncOptions { requeue });
 
         self.run_on_locked_transport("basic_recover_async", "Could not recover", request_id).map(|_| ())
     }
 
+    /// declares a queue, binds it and consumes it through a `ConsumerBuilder`
+    pub fn consumer_builder(&self) -> ConsumerBuilder<T> {
+        ConsumerBuilder::new(self.clone())
+    }
+
+    /// declares the exchanges, queues, bindings and consumers of `topology`, in this order
+    ///
+    /// e.g. the one recorded on a lost connection (see `Client::topology`), which
+    /// `Client::connect_with_recovery` does with `RecoveryConfig::redeclare_topology`, or one
+    /// deserialized from a file. The server-named queues get a new name, which their bindings and consumers
+    /// use instead of the recorded one. The future resolves to the consumers, in the order of
+    /// `topology.consumers`, and fails with the first error the server answered: it then closed
+    /// the channel
+    pub fn declare_topology(&self, topology: &Topology) -> impl Future<Item = Vec<Consumer<T>>, Error = Error> + Send + 'static {
+        let consumers = topology.consumers.clone();
+        let channel = self.clone();
+        self.declare_topology_without_consumers(topology).and_then(move |renamed| {
+            let consumers: Vec<_> = consumers.iter().map(|consumer| {
+                let queue = Queue::new(renamed.get(&consumer.queue).cloned().unwrap_or_else(|| consumer.queue.clone()), 0, 0);
+                channel.basic_consume(&queue, &consumer.tag, consumer.options(), consumer.arguments.clone())
+            }).collect();
+            future::join_all(consumers)
+        })
+    }
+
+    /// declares the exchanges, queues and bindings of `topology`, but not its consumers
+    ///
+    /// the future resolves to the new name of each server-named queue, by recorded name
+    pub(crate) fn declare_topology_without_consumers(&self, topology: &Topology) -> impl Future<Item = HashMap<String, String>, Error = Error> + Send + 'static {
+        let topology = topology.clone();
+        let channel = self.clone();
+        let exchanges: Vec<_> = topology.exchanges.iter().map(|exchange| {
+            self.exchange_declare(&exchange.name, ExchangeKind::from(exchange.kind.as_str()), exchange.options(), exchange.arguments.clone())
+        }).collect();
+
+        future::join_all(exchanges).and_then(move |_| {
+            let queues: Vec<_> = topology.queues.iter().map(|queue| {
+                let name = if queue.server_named { "" } else { queue.name.as_str() };
+                channel.queue_declare(name, queue.options(), queue.arguments.clone())
+            }).collect();
+            future::join_all(queues).map(move |queues| (channel, topology, queues))
+        }).and_then(|(channel, topology, queues)| {
+            let mut renamed = HashMap::new();
+            for (definition, queue) in topology.queues.iter().zip(queues) {
+                if definition.server_named {
+                    channel.conn.topology.queue_renamed(&definition.name, &queue.name());
+                    renamed.insert(definition.name.clone(), queue.name());
+                }
+            }
+            let bindings: Vec<_> = topology.bindings.iter().map(|binding| match binding.destination_kind {
+                BindingDestination::Queue    => future::Either::A(channel.queue_bind(renamed.get(&binding.destination).unwrap_or(&binding.destination), &binding.source, &binding.routing_key, QueueBindOptions::default(), binding.arguments.clone())),
+                BindingDestination::Exchange => future::Either::B(channel.exchange_bind(&binding.destination, &binding.source, &binding.routing_key, ExchangeBindOptions::default(), binding.arguments.clone())),
+            }).collect();
+            future::join_all(bindings).map(move |_| renamed)
+        })
+    }
+
+    /// the deliveries of this channel which still have to be acked, nacked or rejected
+    ///
+    /// `basic_ack(delivery_tag, false)` acks them out of order, `basic_ack(delivery_tag, true)`
+    /// acks all of them up to `delivery_tag`
+    pub fn unacked_deliveries(&self) -> UnackedDeliveries {
+        self.inner.unacked_deliveries.clone()
+    }
+
     /// acks a message
+    ///
+    /// if `multiple` is set, all the unacked messages up to and including `delivery_tag` are
+    /// acked with a single frame. The future fails if the channel isn't connected anymore, or
+    /// if the delivery isn't awaiting an ack (it was received with `no_ack` or already settled)
     pub fn basic_ack(&self, delivery_tag: u64, multiple: bool) -> impl Future<Item = (), Error = Error> + Send + 'static {
         let request_id = self.inner.basic_ack(delivery_tag, BasicAckOptions { multiple });
 
@@ -270,6 +829,10 @@ impl<T: AsyncRead+AsyncWrite+Send+Sync+'static> Channel<T> {
     }
 
     /// nacks a message
+    ///
+    /// if `multiple` is set, all the unacked messages up to and including `delivery_tag` are nacked.
+    /// if `requeue` is set, the server will try to requeue the messages, otherwise they're
+    /// discarded or dead-lettered
     pub fn basic_nack(&self, delivery_tag: u64, multiple: bool, requeue: bool) -> impl Future<Item = (), Error = Error> + Send + 'static {
         let request_id = self.inner.basic_nack(delivery_tag, BasicNackOptions { multiple, requeue });
 
@@ -277,14 +840,23 @@ impl<T: AsyncRead+AsyncWrite+Send+Sync+'static> Channel<T> {
     }
 
     /// rejects a message
-    pub fn basic_reject(&self, delivery_tag: u64, options: BasicRejectOptions) -> impl Future<Item = (), Error = Error> + Send + 'static {
-        let request_id = self.inner.basic_reject(delivery_tag, options);
+    ///
+    /// if `requeue` is set, the server will try to requeue the message, otherwise it's discarded
+    /// or dead-lettered. The delivery tag must have been received on this channel
+    pub fn basic_reject(&self, delivery_tag: u64, requeue: bool) -> impl Future<Item = (), Error = Error> + Send + 'static {
+        let request_id = self.inner.basic_reject(delivery_tag, BasicRejectOptions { requeue });
 
         self.run_on_locked_transport("basic_reject", "Could not reject message", request_id).map(|_| ())
     }
 
     /// gets a message
-    pub fn basic_get(&self, queue: &str, options: BasicGetOptions) -> impl Future<Item = BasicGetMessage, Error = Error> + Send + 'static {
+    ///
+    /// the future's result is:
+    /// - `Some(message)` if a message was available in the queue
+    /// - `None` if the queue was empty
+    ///
+    /// unless the `no_ack` option is set, the message should be acked using its `delivery_tag`
+    pub fn basic_get(&self, queue: &str, options: BasicGetOptions) -> impl Future<Item = Option<BasicGetMessage>, Error = Error> + Send + 'static {
         let _queue = queue.to_string();
         let receive_transport = self.transport.clone();
         let inner = self.inner.clone();
@@ -294,31 +866,57 @@ impl<T: AsyncRead+AsyncWrite+Send+Sync+'static> Channel<T> {
             match channel.requests.was_successful(request_id) {
                 Some(answer) => if answer {
                     Ok(Async::Ready(Some(request_id)))
+                } else if let Some(error) = Self::closed_error(channel) {
+                    Err(error)
                 } else {
-                    Err(ErrorKind::EmptyBasicGet.into())
+                    Ok(Async::Ready(None))
                 },
                 None         => {
                     task::current().notify();
                     Ok(Async::NotReady)
                 }
             }
-        }).and_then(|request_id| future::poll_fn(move || {
-              let mut transport = receive_transport.lock();
-              transport.poll()?;
-              if let Some(message) = inner.queues.get_basic_get_message(&_queue, request_id.expect("request id")) {
-                  return Ok(Async::Ready(message));
-              }
-              Ok(Async::NotReady)
-        }))
+        }).and_then(|request_id| {
+            if let Some(request_id) = request_id {
+                future::Either::A(future::poll_fn(move || {
+                    poll_shared(&receive_transport)?;
+                    if let Some(message) = inner.queues.get_basic_get_message(&_queue, request_id) {
+                        return Ok(Async::Ready(Some(message)));
+                    }
+                    task::current().notify();
+                    Ok(Async::NotReady)
+                }))
+            } else {
+                future::Either::B(future::ok(None))
+            }
+        })
     }
 
     /// Purge a queue.
     ///
     /// This method removes all messages from a queue which are not awaiting acknowledgment.
-    pub fn queue_purge(&self, queue_name: &str, options: QueuePurgeOptions) -> impl Future<Item = (), Error = Error> + Send + 'static {
+    ///
+    /// The future resolves to the number of purged messages, or 0 with the `nowait` option. If
+    /// the queue doesn't exist, the server closes the channel and the future fails with
+    /// `ErrorKind::ChannelClosed`, whose reason is a `NOT_FOUND` error.
+    pub fn queue_purge(&self, queue_name: &str, options: QueuePurgeOptions) -> impl Future<Item = u32, Error = Error> + Send + 'static {
         let request_id = self.inner.queue_purge(queue_name, options);
+        let inner = self.inner.clone();
 
-        self.run_on_locked_transport("queue_purge", "Could not purge queue", request_id).map(|_| ())
+        self.run_on_locked_transport("queue_purge", "Could not purge queue", request_id).and_then(move |request_id| {
+            future::poll_fn(move || {
+              let message_count = match request_id {
+                Some(request_id) => inner.message_counts.get(request_id),
+                None             => return Ok(Async::Ready(0)),
+              };
+              if let Some(message_count) = message_count {
+                Ok(Async::Ready(message_count))
+              } else {
+                task::current().notify();
+                Ok(Async::NotReady)
+              }
+            })
+        })
     }
 
     /// Delete a queue.
@@ -330,13 +928,41 @@ impl<T: AsyncRead+AsyncWrite+Send+Sync+'static> Channel<T> {
     /// If the queue has consumers the server does not delete it but raises a channel exception instead.
     ///
     /// If `if_empty` is set, the server will only delete the queue if it has no messages.
-    pub fn queue_delete(&self, queue_name: &str, options: QueueDeleteOptions) -> impl Future<Item = (), Error = Error> + Send + 'static {
+    ///
+    /// The future resolves to the number of messages deleted along with the queue, or 0 with
+    /// the `nowait` option. When one of the conditions isn't met, the server closes the channel
+    /// and the future fails with `ErrorKind::ChannelClosed`, whose reason is a
+    /// `PRECONDITION_FAILED` error.
+    pub fn queue_delete(&self, queue_name: &str, options: QueueDeleteOptions) -> impl Future<Item = u32, Error = Error> + Send + 'static {
+        let topology = self.conn.topology.clone();
+        let name = queue_name.to_string();
         let request_id = self.inner.queue_delete(queue_name, options);
+        let inner = self.inner.clone();
 
-        self.run_on_locked_transport("queue_purge", "Could not purge queue", request_id).map(|_| ())
+        self.run_on_locked_transport("queue_delete", "Could not delete queue", request_id).and_then(move |request_id| {
+            future::poll_fn(move || {
+              let message_count = match request_id {
+                Some(request_id) => inner.message_counts.get(request_id),
+                None             => return Ok(Async::Ready(0)),
+              };
+              if let Some(message_count) = message_count {
+                Ok(Async::Ready(message_count))
+              } else {
+                task::current().notify();
+                Ok(Async::NotReady)
+              }
+            })
+        }).map(move |message_count| {
+            topology.queue_deleted(&name);
+            message_count
+        })
     }
 
     /// closes the channel
+    ///
+    /// the future resolves once the server acknowledged the close. The requests still waiting for
+    /// an answer and the publishes still waiting for a confirmation fail with
+    /// `ErrorKind::ChannelClosed`, and so do they if the server closes the channel itself.
     pub fn close(&self, code: u16, message: &str) -> impl Future<Item = (), Error = Error> + Send + 'static {
         let request_id = self.inner.channel_close(code, message, 0, 0);
 
@@ -350,6 +976,15 @@ impl<T: AsyncRead+AsyncWrite+Send+Sync+'static> Channel<T> {
         self.run_on_locked_transport("close_ok", "Could not ack closed channel", request_id).map(|_| ())
     }
 
+    /// asks the server to pause (`active: false`) or resume the deliveries on this channel
+    ///
+    /// the future resolves once the server acknowledged it, `ChannelStatus::receive_flow` is then
+    /// updated. The consumers are not canceled. RabbitMQ doesn't support it and closes the
+    /// connection with a `NOT_IMPLEMENTED` error, use `basic_qos` to limit the deliveries instead
+    pub fn flow(&self, active: bool) -> impl Future<Item = (), Error = Error> + Send + 'static {
+        self.channel_flow(ChannelFlowOptions { active })
+    }
+
     /// update a channel flow
     pub fn channel_flow(&self, options: ChannelFlowOptions) -> impl Future<Item = (), Error = Error> + Send + 'static {
         let request_id = self.inner.channel_flow(options);
@@ -364,18 +999,23 @@ impl<T: AsyncRead+AsyncWrite+Send+Sync+'static> Channel<T> {
         self.run_on_locked_transport("channel_flow_ok", "Could not ack update to channel flow", request_id).map(|_| ())
     }
 
+    /// puts the channel in transactional mode
+    ///
+    /// fails if the channel is in confirm mode, a channel cannot be both
     pub fn tx_select(&self) -> impl Future<Item = (), Error = Error> + Send + 'static {
         let request_id = self.inner.tx_select();
 
         self.run_on_locked_transport("tx_select", "Could not start transaction", request_id).map(|_| ())
     }
 
+    /// commits the publishes and acks of the current transaction
     pub fn tx_commit(&self) -> impl Future<Item = (), Error = Error> + Send + 'static {
         let request_id = self.inner.tx_commit();
 
         self.run_on_locked_transport("tx_commit", "Could not commit transaction", request_id).map(|_| ())
     }
 
+    /// discards the publishes and acks of the current transaction
     pub fn tx_rollback(&self) -> impl Future<Item = (), Error = Error> + Send + 'static {
         let request_id = self.inner.tx_rollback();
 
@@ -384,25 +1024,49 @@ impl<T: AsyncRead+AsyncWrite+Send+Sync+'static> Channel<T> {
 
     fn run_on_locked_transport_full<Finished>(&self, method: &str, error_msg: &str, request_id: RequestResult, finished: Finished) -> impl Future<Item = Option<RequestId>, Error = Error> + Send + 'static
         where Finished: 'static + Send + Fn(&mut InnerChannel, RequestId) -> Poll<Option<RequestId>, Error> {
-        trace!("run on locked transport; method={:?}", method);
+        trace!("run on locked transport; channel={} method={:?}", self.id(), method);
         let transport = self.transport.clone();
         let method = method.to_string();
         let error_msg = error_msg.to_string();
         let mut inner = self.inner.clone();
-
-        trace!("run on locked transport; method={:?} request_id={:?}", method, request_id);
-        future::result(request_id.map_err(|e| ErrorKind::ProtocolError(error_msg.clone(), e).into())).and_then(move |request_id| {
-            if request_id.is_some() {
-                trace!("{} returning closure", method);
+        let timeout = self.timeout;
+        let cancellation = self.cancellation.clone();
+
+        trace!("run on locked transport; channel={} method={:?} request_id={:?}", inner.id(), method, request_id);
+        // abandoned as soon as this future is dropped, even if it's never polled
+        let mut pending = PendingRequest { channel: inner.clone(), request_id: request_id.as_ref().ok().cloned().flatten() };
+        future::result(request_id.map_err(|e| Self::request_error(&inner, &error_msg, e))).and_then(move |request_id| {
+            if let Some(request_id) = request_id {
+                trace!("{} returning closure; channel={} request_id={}", method, inner.id(), request_id);
             }
+            let mut delay = timeout.map(|timeout| Delay::new(Instant::now() + timeout));
 
             future::poll_fn(move || {
-                let mut transport = transport.lock();
+                if let (Some(request_id), Some(delay), Some(timeout)) = (request_id, delay.as_mut(), timeout) {
+                    if let Async::Ready(()) = delay.poll().map_err(ErrorKind::TimeoutTimer)? {
+                        debug!("request timed out; channel={} method={:?} request_id={:?}", inner.id(), method, request_id);
+                        pending.abandon();
+                        return Err(ErrorKind::Timeout(method.clone(), timeout).into());
+                    }
+                }
+                if let (Some(request_id), Some(cancellation)) = (request_id, cancellation.as_ref()) {
+                    if cancellation.is_canceled() {
+                        debug!("request canceled; channel={} method={:?} request_id={:?}", inner.id(), method, request_id);
+                        pending.abandon();
+                        return Err(ErrorKind::Canceled(method.clone()).into());
+                    }
+                }
 
                 if let Some(request_id) = request_id {
-                    Self::wait_for_answer(&mut inner, &mut transport, request_id, &finished)
+                    trace!("wait for answer; channel={} request_id={:?}", inner.id(), request_id);
+                    poll_shared(&transport).map_err(|error| Self::closed_error(&inner).unwrap_or(error))?;
+                    let answer = Self::check_answer(&mut inner, request_id, &finished);
+                    if !matches!(answer, Ok(Async::NotReady)) {
+                        pending.request_id = None;
+                    }
+                    answer
                 } else {
-                    transport.poll().map(|r| r.map(|_| None))
+                    transport.lock().poll().map(|r| r.map(|_| None))
                 }
             })
         })
@@ -410,14 +1074,32 @@ impl<T: AsyncRead+AsyncWrite+Send+Sync+'static> Channel<T> {
 
     fn run_on_lock_transport_basic_finished(channel: &mut InnerChannel, request_id: RequestId) -> Poll<Option<RequestId>, Error> {
         match channel.requests.was_successful(request_id) {
-            Some(answer) if answer => Ok(Async::Ready(Some(request_id))),
-            _                      => {
+            Some(true)  => Ok(Async::Ready(Some(request_id))),
+            Some(false) => Err(Self::closed_error(channel).unwrap_or_else(|| ErrorKind::ChannelClosed(Default::default()).into())),
+            None        => {
                 task::current().notify();
                 Ok(Async::NotReady)
             }
         }
     }
 
+    /// the error to report to the requests that the channel refused to send
+    fn request_error(channel: &InnerChannel, context: &str, error: lapin_async::error::Error) -> Error {
+        match error.kind() {
+            lapin_async::error::ErrorKind::NotConnected => Self::closed_error(channel).unwrap_or_else(|| ErrorKind::InvalidChannelState(channel.status.state()).into()),
+            _                                           => ErrorKind::ProtocolError(context.to_string(), error).into(),
+        }
+    }
+
+    /// the error to report to the requests that won't get an answer because the channel got closed
+    fn closed_error(channel: &InnerChannel) -> Option<Error> {
+        if channel.status.is_connected() {
+            None
+        } else {
+            channel.status.close_reason().map(|reason| ErrorKind::ChannelClosed(reason).into())
+        }
+    }
+
     fn run_on_locked_transport(&self, method: &str, error: &str, request_id: RequestResult) -> impl Future<Item = Option<RequestId>, Error = Error> + Send + 'static {
         self.run_on_locked_transport_full(method, error, request_id, Self::run_on_lock_transport_basic_finished)
     }
@@ -425,15 +1107,646 @@ impl<T: AsyncRead+AsyncWrite+Send+Sync+'static> Channel<T> {
     /// internal method to wait until a request succeeds
     pub fn wait_for_answer<Finished>(channel: &mut InnerChannel, tr: &mut AMQPTransport<T>, request_id: RequestId, finished: &Finished) -> Poll<Option<RequestId>, Error>
         where Finished: 'static + Send + Fn(&mut InnerChannel, RequestId) -> Poll<Option<RequestId>, Error> {
-            trace!("wait for answer; request_id={:?}", request_id);
-            tr.poll()?;
-            trace!("wait for answer transport poll; request_id={:?} status=NotReady", request_id);
+            trace!("wait for answer; channel={} request_id={:?}", channel.id(), request_id);
+            // the server closes the socket after closing the connection, report why it did
+            tr.poll().map_err(|error| Self::closed_error(channel).unwrap_or(error))?;
+            Self::check_answer(channel, request_id, finished)
+    }
+
+    fn check_answer<Finished>(channel: &mut InnerChannel, request_id: RequestId, finished: &Finished) -> Poll<Option<RequestId>, Error>
+        where Finished: 'static + Send + Fn(&mut InnerChannel, RequestId) -> Poll<Option<RequestId>, Error> {
+            trace!("wait for answer transport poll; channel={} request_id={:?} status=NotReady", channel.id(), request_id);
             if let Async::Ready(r) = finished(channel, request_id)? {
-                trace!("wait for answer; request_id={:?} status=Ready result={:?}", request_id, r);
+                trace!("wait for answer; channel={} request_id={:?} status=Ready result={:?}", channel.id(), request_id, r);
                 return Ok(Async::Ready(r));
             }
-            trace!("wait for answer; request_id={:?} status=NotReady", request_id);
+            trace!("wait for answer; channel={} request_id={:?} status=NotReady", channel.id(), request_id);
             task::current().notify();
             Ok(Async::NotReady)
     }
 }
+
+/// A stream of the messages returned by the server, see `Channel::returned_messages`
+pub struct ReturnedMessages<T> {
+    transport: Arc<SharedTransport<T>>,
+    inner:     InnerChannel,
+}
+
+impl<T: AsyncRead+AsyncWrite+Send+'static> Stream for ReturnedMessages<T> {
+    type Item = BasicReturnMessage;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<BasicReturnMessage>, Error> {
+        trace!("returned messages poll; channel={} polling transport", self.inner.id());
+        poll_shared(&self.transport)?;
+        if let Some(message) = self.inner.returned_messages.next_returned_message() {
+            Ok(Async::Ready(Some(message)))
+        } else if self.inner.status.is_connected() {
+            task::current().notify();
+            Ok(Async::NotReady)
+        } else {
+            Ok(Async::Ready(None))
+        }
+    }
+}
+
+/// A request whose answer is waited for, abandoned if it isn't anymore before it comes
+struct PendingRequest {
+    channel:    InnerChannel,
+    request_id: Option<RequestId>,
+}
+
+impl PendingRequest {
+    fn abandon(&mut self) {
+        if let Some(request_id) = self.request_id.take() {
+            self.channel.abandon_request(request_id);
+        }
+    }
+}
+
+impl Drop for PendingRequest {
+    fn drop(&mut self) {
+        self.abandon();
+    }
+}
+
+/// The messages of a batch whose confirmation is waited for, forgotten when it isn't anymore
+struct UnconfirmedTags {
+    channel:       InnerChannel,
+    delivery_tags: Vec<DeliveryTag>,
+}
+
+impl UnconfirmedTags {
+    fn forget(&mut self) -> Vec<DeliveryTag> {
+        for delivery_tag in &self.delivery_tags {
+            self.channel.acknowledgements.forget(*delivery_tag);
+        }
+        self.delivery_tags.split_off(0)
+    }
+}
+
+impl Drop for UnconfirmedTags {
+    fn drop(&mut self) {
+        self.forget();
+    }
+}
+
+/// A message to publish with `Channel::basic_publish_batch`
+#[derive(Clone, Debug, PartialEq)]
+pub struct PublishMessage {
+    pub exchange:    String,
+    pub routing_key: String,
+    pub payload:     Vec<u8>,
+    pub options:     BasicPublishOptions,
+    pub properties:  BasicProperties,
+}
+
+impl PublishMessage {
+    /// a message with the default options and properties
+    pub fn new(exchange: &str, routing_key: &str, payload: Vec<u8>) -> Self {
+        Self {
+            exchange:    exchange.to_string(),
+            routing_key: routing_key.to_string(),
+            payload,
+            options:     BasicPublishOptions::default(),
+            properties:  BasicProperties::default(),
+        }
+    }
+}
+
+fn binding_definition(destination_kind: BindingDestination, destination: &str, source: &str, routing_key: &str, arguments: &FieldTable) -> BindingDefinition {
+    BindingDefinition {
+        destination_kind,
+        destination: destination.to_string(),
+        source:      source.to_string(),
+        routing_key: routing_key.to_string(),
+        arguments:   arguments.clone(),
+    }
+}
+
+/// The confirmations of the messages published by `Channel::basic_publish_batch`
+#[derive(Clone, Debug, PartialEq)]
+pub struct BatchConfirmation {
+    /// the delivery tag and confirmation of each message, in publishing order
+    pub confirmations: Vec<(DeliveryTag, Confirmation)>,
+    /// the delivery tags of the messages published after a nacked or returned one, whose
+    /// confirmation didn't come yet and isn't waited for anymore
+    pub unconfirmed:   Vec<DeliveryTag>,
+    /// how many messages at the end of the batch weren't published because a publish failed
+    pub unpublished:   usize,
+}
+
+impl BatchConfirmation {
+    /// whether the server took responsibility for all the messages
+    pub fn is_acked(&self) -> bool {
+        self.unconfirmed.is_empty() && self.unpublished == 0 && self.confirmations.iter().all(|(_, confirmation)| *confirmation == Confirmation::Ack)
+    }
+
+    /// the delivery tags of the messages the server couldn't take responsibility for
+    pub fn nacked(&self) -> Vec<DeliveryTag> {
+        self.confirmations.iter().filter(|(_, confirmation)| *confirmation == Confirmation::Nack).map(|(delivery_tag, _)| *delivery_tag).collect()
+    }
+
+    /// the delivery tags of the messages returned by the server because they couldn't be routed
+    pub fn returned(&self) -> Vec<DeliveryTag> {
+        self.confirmations.iter().filter(|(_, confirmation)| matches!(confirmation, Confirmation::Returned(_))).map(|(delivery_tag, _)| *delivery_tag).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+  use amq_protocol::frame::AMQPFrame;
+  use amq_protocol::protocol::{AMQPClass, AMQPHardError, basic, channel, connection, exchange, queue};
+  use env_logger;
+
+  use super::*;
+  use crate::client::Client;
+  use crate::consumer::ConsumerArguments;
+  use crate::test_utils::*;
+
+  #[test]
+  #[cfg(feature = "serde_json")]
+  fn publish_json() {
+    use crate::message::Delivery;
+
+    let _ = env_logger::try_init();
+
+    let conn = connected_connection();
+    let inner = connected_channel(&conn);
+    let channel = Channel::from_inner(test_transport(&conn, Vec::new()), conn.clone(), inner);
+
+    future::lazy(move || {
+      let _publish = channel.publish_json("", "queue", &vec![1, 2, 3]);
+      assert!(matches!(conn.next_frame(), Some(AMQPFrame::Method(1, AMQPClass::Basic(basic::AMQPMethod::Publish(_))))));
+      let mut delivery = Delivery::new(1, "".to_string(), "queue".to_string(), false);
+      match (conn.next_frame(), conn.next_frame()) {
+        (Some(AMQPFrame::Header(1, 60, header)), Some(AMQPFrame::Body(1, body))) => {
+          assert_eq!(header.properties.content_type(), &Some("application/json".to_string()));
+          assert_eq!(body, b"[1,2,3]".to_vec());
+          delivery.receive_content_header(header.body_size, header.properties);
+          delivery.receive_content(body);
+        },
+        frames => panic!("expected the content of the message, got {:?}", frames),
+      }
+      assert_eq!(delivery.json::<Vec<u32>>().unwrap(), vec![1, 2, 3]);
+
+      delivery.properties = BasicProperties::default().with_content_type("text/plain".to_string());
+      match delivery.json::<Vec<u32>>() {
+        Err(err) => assert_eq!(err.to_string(), lapin_async::error::ErrorKind::NotJson("text/plain".to_string()).to_string()),
+        Ok(_)    => panic!("expected text/plain not to be deserialized"),
+      }
+      Ok::<(), ()>(())
+    }).wait().unwrap();
+  }
+
+  #[test]
+  fn state_changes() {
+    let _ = env_logger::try_init();
+
+    let conn = connected_connection();
+    let inner = connected_channel(&conn);
+    let channel = Channel::from_inner(test_transport(&conn, Vec::new()), conn.clone(), inner.clone());
+    let state_changes = channel.state_changes();
+    drop(channel.state_changes());
+    let _close = channel.close(200, "Bye");
+    conn.handle_frame(AMQPFrame::Method(1, AMQPClass::Channel(channel::AMQPMethod::CloseOk(channel::CloseOk {})))).unwrap();
+    assert_eq!(state_changes.take(2).collect().wait().unwrap(), vec![ChannelState::Closing, ChannelState::Closed]);
+  }
+
+  #[test]
+  fn requests_refused_by_the_channel() {
+    let _ = env_logger::try_init();
+
+    let conn = connected_connection();
+    let inner = conn.create_channel().unwrap();
+    let channel = Channel::from_inner(test_transport(&conn, Vec::new()), conn.clone(), inner.clone());
+    // not opened yet
+    match channel.queue_declare("queue", QueueDeclareOptions::default(), FieldTable::new()).wait() {
+      Err(err) => assert!(matches!(err.kind(), ErrorKind::InvalidChannelState(ChannelState::Initial))),
+      Ok(_)    => panic!("expected the request to be refused"),
+    }
+    // closed by the server
+    inner.status.set_state(ChannelState::Connected);
+    conn.handle_frame(AMQPFrame::Method(inner.id(), AMQPClass::Channel(channel::AMQPMethod::Close(channel::Close {
+      reply_code: 404,
+      reply_text: "NOT_FOUND".to_string(),
+      class_id:   50,
+      method_id:  10,
+    })))).unwrap();
+    match channel.basic_publish("", "queue", Vec::new(), BasicPublishOptions::default(), BasicProperties::default()).wait() {
+      Err(err) => assert!(matches!(err.kind(), ErrorKind::ChannelClosed(reason) if reason.error() == Some(AMQPError::Soft(AMQPSoftError::NOTFOUND)))),
+      Ok(_)    => panic!("expected the publish to be refused"),
+    }
+  }
+
+  #[test]
+  fn basic_consume_nowait() {
+    let _ = env_logger::try_init();
+
+    let conn = connected_connection();
+    let inner = connected_channel(&conn);
+    inner.queues.register(lapin_async::queue::Queue::new("queue".to_string(), 0, 0));
+    let channel = Channel::from_inner(test_transport(&conn, Vec::new()), conn, inner.clone());
+
+    let options = BasicConsumeOptions { nowait: true, ..BasicConsumeOptions::default() };
+    let generated = future::lazy(move || {
+      channel.basic_consume(&Queue::new("queue".to_string(), 0, 0), "consumer", options.clone(), FieldTable::new()).and_then(move |_| {
+        channel.basic_consume(&Queue::new("queue".to_string(), 0, 0), "", options, FieldTable::new())
+      })
+    }).wait().unwrap();
+    assert_eq!(inner.queues.consumer_no_ack("consumer"), Some(false));
+    // an empty tag is generated by us, the server wouldn't tell us its own one
+    assert!(generated.tag().starts_with("lapin-ctag-"));
+    assert_eq!(inner.queues.consumer_no_ack(generated.tag()), Some(false));
+  }
+
+  #[test]
+  fn topology_recorded() {
+    let conn = connected_connection();
+    let inner = connected_channel(&conn);
+    inner.queues.register(lapin_async::queue::Queue::new("queue".to_string(), 0, 0));
+    let transport = test_transport(&conn, Vec::new());
+    let client = Client::from_transport(transport.clone(), conn.clone());
+    let channel = Channel::from_inner(transport, conn, inner);
+
+    let ch = channel.clone();
+    future::lazy(move || {
+      ch.exchange_declare("logs", ExchangeKind::Fanout, ExchangeDeclareOptions { durable: true, nowait: true, ..ExchangeDeclareOptions::default() }, FieldTable::new()).and_then(move |_| {
+        ch.queue_declare("queue", QueueDeclareOptions { nowait: true, ..QueueDeclareOptions::default() }, FieldTable::new()).and_then(move |queue| {
+          ch.queue_bind(&queue.name(), "logs", "", QueueBindOptions { nowait: true }, FieldTable::new()).and_then(move |_| {
+            ch.basic_consume(&Queue::new("queue".to_string(), 0, 0), "consumer", BasicConsumeOptions { nowait: true, ..BasicConsumeOptions::default() }, FieldTable::new())
+          })
+        })
+      })
+    }).wait().unwrap();
+
+    let topology = client.topology();
+    assert_eq!(topology.exchanges[0].name, "logs");
+    assert_eq!(topology.exchanges[0].kind, "fanout");
+    assert!(topology.exchanges[0].durable);
+    assert_eq!(topology.queues[0].name, "queue");
+    assert_eq!(topology.bindings[0].destination, "queue");
+    assert_eq!(topology.consumers[0].tag, "consumer");
+
+    channel.exchange_delete("logs", ExchangeDeleteOptions { nowait: true, ..ExchangeDeleteOptions::default() }).wait().unwrap();
+    let topology = client.topology();
+    assert!(topology.exchanges.is_empty());
+    assert!(topology.bindings.is_empty());
+    assert_eq!(topology.queues.len(), 1);
+  }
+
+  #[test]
+  fn topology_declared_again() {
+    let _ = env_logger::try_init();
+
+    let conn = connected_connection();
+    let sent = record_sent_frames(&conn);
+    let inner = connected_channel(&conn);
+    let transport = test_transport(&conn, Vec::new());
+    let client = Client::from_transport(transport.clone(), conn.clone());
+    let channel = Channel::from_inner(transport, conn.clone(), inner);
+    let topology = Topology {
+      exchanges: vec![ExchangeDefinition { name: "logs".to_string(), kind: "fanout".to_string(), durable: true, ..ExchangeDefinition::default() }],
+      queues:    vec![QueueDefinition { name: "amq.gen-old".to_string(), server_named: true, exclusive: true, ..QueueDefinition::default() }],
+      bindings:  vec![BindingDefinition {
+        destination_kind: BindingDestination::Queue,
+        destination:      "amq.gen-old".to_string(),
+        source:           "logs".to_string(),
+        routing_key:      String::new(),
+        arguments:        FieldTable::new(),
+      }],
+      consumers: vec![ConsumerDefinition { tag: "consumer".to_string(), queue: "amq.gen-old".to_string(), ..ConsumerDefinition::default() }],
+    };
+    future::lazy(move || {
+      // each kind is declared once the previous one is
+      let mut declare = channel.declare_topology(&topology);
+      let mut answer = |method: AMQPClass| {
+        assert!(declare.poll().unwrap().is_not_ready());
+        let frames: Vec<AMQPFrame> = sent.lock().drain(..).collect();
+        assert_eq!(frames.len(), 1, "expected a single frame to be sent, got {:?}", frames);
+        conn.handle_frame(AMQPFrame::Method(1, method)).unwrap();
+        frames.into_iter().next().unwrap()
+      };
+
+      match answer(AMQPClass::Exchange(exchange::AMQPMethod::DeclareOk(exchange::DeclareOk {}))) {
+        AMQPFrame::Method(1, AMQPClass::Exchange(exchange::AMQPMethod::Declare(declare))) => {
+          assert_eq!((declare.exchange.as_str(), declare.kind.as_str()), ("logs", "fanout"));
+          assert!(declare.durable && !declare.passive);
+        },
+        frame => panic!("expected Exchange.Declare, got {:?}", frame),
+      }
+      let renamed = queue::DeclareOk { queue: "amq.gen-new".to_string(), message_count: 0, consumer_count: 0 };
+      match answer(AMQPClass::Queue(queue::AMQPMethod::DeclareOk(renamed))) {
+        // the server names the queue again
+        AMQPFrame::Method(1, AMQPClass::Queue(queue::AMQPMethod::Declare(declare))) => assert!(declare.queue.is_empty() && declare.exclusive),
+        frame                                                                       => panic!("expected Queue.Declare, got {:?}", frame),
+      }
+      match answer(AMQPClass::Queue(queue::AMQPMethod::BindOk(queue::BindOk {}))) {
+        AMQPFrame::Method(1, AMQPClass::Queue(queue::AMQPMethod::Bind(bind))) => {
+          assert_eq!((bind.queue.as_str(), bind.exchange.as_str()), ("amq.gen-new", "logs"));
+        },
+        frame => panic!("expected Queue.Bind, got {:?}", frame),
+      }
+      match answer(AMQPClass::Basic(basic::AMQPMethod::ConsumeOk(basic::ConsumeOk { consumer_tag: "consumer".to_string() }))) {
+        AMQPFrame::Method(1, AMQPClass::Basic(basic::AMQPMethod::Consume(consume))) => {
+          assert_eq!((consume.queue.as_str(), consume.consumer_tag.as_str()), ("amq.gen-new", "consumer"));
+        },
+        frame => panic!("expected Basic.Consume, got {:?}", frame),
+      }
+      match declare.poll() {
+        Ok(Async::Ready(consumers)) => assert_eq!(consumers.iter().map(Consumer::tag).collect::<Vec<_>>(), vec!["consumer"]),
+        res                         => panic!("expected the consumers, got {:?}", res.map(|_| ())),
+      }
+      Ok::<(), ()>(())
+    }).wait().unwrap();
+
+    // the recorded topology follows the queue under its new name
+    let topology = client.topology();
+    assert_eq!(topology.exchanges[0].name, "logs");
+    assert_eq!(topology.queues.iter().map(|queue| queue.name.as_str()).collect::<Vec<_>>(), vec!["amq.gen-new"]);
+    assert!(topology.queues[0].server_named);
+    assert_eq!(topology.bindings.len(), 1);
+    assert_eq!(topology.bindings[0].destination, "amq.gen-new");
+    assert_eq!(topology.consumers.len(), 1);
+    assert_eq!(topology.consumers[0].queue, "amq.gen-new");
+  }
+
+  #[test]
+  fn exclusive_consume_refused() {
+    let _ = env_logger::try_init();
+
+    let conn = connected_connection();
+    let inner = connected_channel(&conn);
+    inner.queues.register(lapin_async::queue::Queue::new("queue".to_string(), 0, 0));
+    // another consumer already has exclusive access to the queue
+    let transport = test_transport(&conn, vec![AMQPFrame::Method(1, AMQPClass::Channel(channel::AMQPMethod::Close(channel::Close {
+      reply_code: 403,
+      reply_text: "ACCESS_REFUSED - queue 'queue' in vhost '/' in exclusive use".to_string(),
+      class_id:   60,
+      method_id:  20,
+    })))]);
+    let channel = Channel::from_inner(transport, conn.clone(), inner);
+
+    let options = BasicConsumeOptions { exclusive: true, no_local: true, ..BasicConsumeOptions::default() };
+    future::lazy(move || {
+      let mut consume = channel.basic_consume(&Queue::new("queue".to_string(), 0, 0), "consumer", options, ConsumerArguments::new().priority(10).into());
+      match conn.next_frame() {
+        Some(AMQPFrame::Method(1, AMQPClass::Basic(basic::AMQPMethod::Consume(consume)))) => {
+          assert!(consume.exclusive && consume.no_local);
+          assert!(!consume.no_ack && !consume.nowait);
+          assert_eq!(consume.arguments.get("x-priority"), Some(&AMQPValue::LongLongInt(10)));
+        },
+        frame => panic!("expected Basic.Consume, got {:?}", frame),
+      }
+      match consume.poll() {
+        Err(err) => match err.kind() {
+          ErrorKind::ChannelClosed(reason) => assert_eq!(reason.error(), Some(AMQPError::Soft(AMQPSoftError::ACCESSREFUSED))),
+          kind                             => panic!("expected the close reason, got {}", kind),
+        },
+        res => panic!("expected the consumer to be refused, got {:?}", res.map(|_| ())),
+      }
+      Ok::<(), ()>(())
+    }).wait().unwrap();
+  }
+
+  #[test]
+  fn canceled_request() {
+    let _ = env_logger::try_init();
+
+    let conn = connected_connection();
+    let inner = connected_channel(&conn);
+    let cancellation = Cancellation::new();
+    let channel = Channel::from_inner(test_transport(&conn, Vec::new()), conn, inner).with_cancellation(&cancellation);
+
+    future::lazy(move || {
+      let mut purge = channel.queue_purge("queue", QueuePurgeOptions::default());
+      // the server never answers
+      assert!(purge.poll().unwrap().is_not_ready());
+      cancellation.cancel();
+      match purge.poll() {
+        Err(err) => assert_eq!(err.to_string(), ErrorKind::Canceled("queue_purge".to_string()).to_string()),
+        res      => panic!("expected the request to be canceled, got {:?}", res),
+      }
+      Ok::<(), ()>(())
+    }).wait().unwrap();
+  }
+
+  #[test]
+  fn busy_transport_not_waited_for() {
+    let _ = env_logger::try_init();
+
+    let conn = connected_connection();
+    let inner = connected_channel(&conn);
+    let transport = test_transport(&conn, Vec::new());
+    let channel = Channel::from_inner(transport.clone(), conn, inner);
+
+    future::lazy(move || {
+      let mut purge = channel.queue_purge("queue", QueuePurgeOptions::default());
+      assert!(purge.poll().unwrap().is_not_ready());
+      // another task is polling the transport, we don't wait for it to be done
+      let guard = transport.lock();
+      assert!(purge.poll().unwrap().is_not_ready());
+      drop(guard);
+      assert!(purge.poll().unwrap().is_not_ready());
+      Ok::<(), ()>(())
+    }).wait().unwrap();
+  }
+
+  #[test]
+  fn connection_closed_by_server() {
+    use std::io;
+
+    let _ = env_logger::try_init();
+
+    let conn = connected_connection();
+    let inner = connected_channel(&conn);
+    // the server closes the connection, then the socket
+    let stream = io::Cursor::new(encode(vec![AMQPFrame::Method(0, AMQPClass::Connection(connection::AMQPMethod::Close(connection::Close {
+      reply_code: 320,
+      reply_text: "CONNECTION_FORCED - broker forced connection closure".to_string(),
+      class_id:   0,
+      method_id:  0,
+    })))]));
+    let transport = Arc::new(SharedTransport::new(AMQPTransport::for_tests(stream, conn.clone())));
+    let channel = Channel::from_inner(transport, conn.clone(), inner);
+
+    future::lazy(move || {
+      match channel.queue_declare("queue", QueueDeclareOptions::default(), FieldTable::new()).poll() {
+        Err(err) => match err.kind() {
+          ErrorKind::ChannelClosed(reason) => {
+            assert_eq!(reason.reply_code, 320);
+            assert_eq!(reason.reply_text, "CONNECTION_FORCED - broker forced connection closure");
+          },
+          kind => panic!("expected the close reason, got {}", kind),
+        },
+        res => panic!("expected the request to fail, got {:?}", res),
+      }
+      Ok::<(), ()>(())
+    }).wait().unwrap();
+    assert!(conn.status.is_closed());
+    assert_eq!(conn.status.close_reason().unwrap().reply_code, 320);
+  }
+
+  #[test]
+  fn temporary_queue_options() {
+    let conn = connected_connection();
+    let inner = connected_channel(&conn);
+    let channel = Channel::from_inner(test_transport(&conn, Vec::new()), conn.clone(), inner);
+
+    let _declare = channel.declare_temporary_queue();
+    match conn.next_frame() {
+      Some(AMQPFrame::Method(_, AMQPClass::Queue(queue::AMQPMethod::Declare(declare)))) => {
+        assert_eq!(declare.queue, "");
+        assert!(declare.exclusive && declare.auto_delete);
+        assert!(!declare.durable && !declare.passive && !declare.nowait);
+      },
+      frame => panic!("expected Queue.Declare, got {:?}", frame),
+    }
+  }
+
+  #[test]
+  fn basic_publish_stream_failed() {
+    use futures::stream;
+
+    let _ = env_logger::try_init();
+
+    let conn = connected_connection();
+    let sent = record_sent_frames(&conn);
+    let inner = connected_channel(&conn);
+    let channel = Channel::from_inner(test_transport(&conn, Vec::new()), conn.clone(), inner.clone());
+
+    // the source of the body fails after its first chunk
+    let body = stream::iter_result(vec![Ok(b"hello".to_vec()), Err(ErrorKind::Canceled("read".to_string()).into())]);
+    let res = channel.basic_publish_stream("", "queue", body, 10, BasicPublishOptions::default(), BasicProperties::default()).wait();
+    match res.as_ref().map_err(Error::kind) {
+      Err(ErrorKind::Canceled(request)) => assert_eq!(request, "read"),
+      res                               => panic!("expected the error of the stream, got {:?}", res),
+    }
+    // the rest of the body can't be sent anymore, the server gets told by closing the connection
+    assert_eq!(inner.remaining_body_size(), None);
+    assert_eq!(inner.status.state(), ChannelState::Error);
+    let mut sent = sent.lock().clone();
+    sent.extend(std::iter::from_fn(|| conn.next_frame()));
+    assert!(sent.iter().any(|frame| match frame {
+      AMQPFrame::Method(0, AMQPClass::Connection(connection::AMQPMethod::Close(close))) => close.reply_code == AMQPHardError::UNEXPECTEDFRAME.get_id(),
+      _                                                                                => false,
+    }), "expected the connection to be closed, sent {:?}", sent);
+    assert!(conn.channels.get(inner.id()).is_some());
+    match channel.basic_publish("", "queue", Vec::new(), BasicPublishOptions::default(), BasicProperties::default()).wait() {
+      Err(_) => {},
+      res    => panic!("expected the channel to be unusable, got {:?}", res),
+    }
+  }
+
+  #[test]
+  fn basic_publish_batch_confirmations() {
+    let _ = env_logger::try_init();
+
+    let conn = connected_connection();
+    let inner = connected_channel(&conn);
+    inner.status.set_confirm();
+    let channel = Channel::from_inner(test_transport(&conn, Vec::new()), conn.clone(), inner);
+
+    future::lazy(move || {
+      let messages = (0..3).map(|i| PublishMessage::new("", "queue", vec![i])).collect();
+      let mut batch = channel.basic_publish_batch(messages);
+      assert!(batch.poll().unwrap().is_not_ready());
+      // the first two messages are acked at once, the last one is nacked
+      conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Basic(basic::AMQPMethod::Ack(basic::Ack { delivery_tag: 2, multiple: true })))).unwrap();
+      assert!(batch.poll().unwrap().is_not_ready());
+      conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Basic(basic::AMQPMethod::Nack(basic::Nack { delivery_tag: 3, multiple: false, requeue: false })))).unwrap();
+      match batch.poll() {
+        Ok(Async::Ready(Some(confirmation))) => {
+          assert!(!confirmation.is_acked());
+          assert_eq!(confirmation.confirmations.len(), 3);
+          assert_eq!(confirmation.nacked(), vec![3]);
+          assert!(confirmation.returned().is_empty());
+        },
+        res => panic!("expected the confirmations of the batch, got {:?}", res),
+      }
+      Ok::<(), ()>(())
+    }).wait().unwrap();
+  }
+
+  #[test]
+  fn late_answer_after_timeout() {
+    let _ = env_logger::try_init();
+
+    let conn = connected_connection();
+    let inner = connected_channel(&conn);
+    let channel = Channel::from_inner(test_transport(&conn, Vec::new()), conn.clone(), inner.clone());
+    let declare_ok = |queue: &str| AMQPFrame::Method(channel.id(), AMQPClass::Queue(queue::AMQPMethod::DeclareOk(queue::DeclareOk {
+      queue:          queue.to_string(),
+      message_count:  0,
+      consumer_count: 0,
+    })));
+
+    let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+    let declare = channel.with_timeout(Duration::from_millis(10)).queue_declare("", QueueDeclareOptions::default(), FieldTable::new());
+    match runtime.block_on(declare).map_err(|e| e.kind().to_string()) {
+      Err(kind) => assert_eq!(kind, ErrorKind::Timeout("queue_declare".to_string(), Duration::from_millis(10)).to_string()),
+      res       => panic!("expected the declare to time out, got {:?}", res),
+    }
+    // the late answer to the first request isn't kept for anybody, nor mistaken for the next one
+    conn.handle_frame(declare_ok("amq.gen-late")).unwrap();
+    assert_eq!(inner.generated_names.get(1), None);
+    let declare = channel.queue_declare("", QueueDeclareOptions::default(), FieldTable::new());
+    conn.handle_frame(declare_ok("amq.gen-next")).unwrap();
+    assert_eq!(runtime.block_on(declare).unwrap().name(), "amq.gen-next");
+  }
+
+  #[test]
+  fn dropped_request_abandoned() {
+    let _ = env_logger::try_init();
+
+    let conn = connected_connection();
+    let inner = connected_channel(&conn);
+    let channel = Channel::from_inner(test_transport(&conn, Vec::new()), conn.clone(), inner.clone());
+
+    // the purge is sent, but nobody waits for its answer anymore
+    drop(channel.queue_purge("queue", QueuePurgeOptions::default()));
+    conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Queue(queue::AMQPMethod::PurgeOk(queue::PurgeOk { message_count: 3 })))).unwrap();
+    assert_eq!(inner.requests.was_successful(1), None);
+    assert_eq!(inner.message_counts.get(1), None);
+  }
+
+  #[test]
+  fn basic_publish_batch_partial() {
+    let _ = env_logger::try_init();
+
+    let conn = connected_connection();
+    conn.configuration.set_reject_immediate(true);
+    let inner = connected_channel(&conn);
+    inner.status.set_confirm();
+    let channel = Channel::from_inner(test_transport(&conn, Vec::new()), conn.clone(), inner.clone());
+
+    future::lazy(move || {
+      // the third message can't be published, the batch stops there
+      let mut messages = (0..4).map(|i| PublishMessage::new("", "queue", vec![i])).collect::<Vec<_>>();
+      messages[2].options.immediate = true;
+      let mut batch = channel.basic_publish_batch(messages);
+      assert!(batch.poll().unwrap().is_not_ready());
+      // the first message is nacked, the batch doesn't wait for the second one
+      conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Basic(basic::AMQPMethod::Nack(basic::Nack { delivery_tag: 1, multiple: false, requeue: false })))).unwrap();
+      match batch.poll() {
+        Ok(Async::Ready(Some(confirmation))) => {
+          assert!(!confirmation.is_acked());
+          assert_eq!(confirmation.nacked(), vec![1]);
+          assert_eq!(confirmation.unconfirmed, vec![2]);
+          assert_eq!(confirmation.unpublished, 2);
+        },
+        res => panic!("expected the confirmations of the batch, got {:?}", res),
+      }
+      // the late ack isn't kept for anybody
+      conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Basic(basic::AMQPMethod::Ack(basic::Ack { delivery_tag: 2, multiple: false })))).unwrap();
+      assert_eq!(inner.acknowledgements.take_confirmation(2), None);
+      assert_eq!(inner.acknowledgements.pending_count(), 0);
+
+      // nothing is published when the first message fails
+      let mut messages = vec![PublishMessage::new("", "queue", Vec::new())];
+      messages[0].options.immediate = true;
+      assert!(channel.basic_publish_batch(messages).poll().is_err());
+      Ok::<(), ()>(())
+    }).wait().unwrap();
+  }
+}
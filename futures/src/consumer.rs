@@ -1,29 +1,43 @@
-use futures::{Async, Poll, Stream, task};
+pub use lapin_async::consumer_arguments::ConsumerArguments;
+
+use futures::{Async, Future, Poll, Stream, future::{self, Either}, task};
+use lapin_async::acknowledgement::DeliveryTag;
 use lapin_async::consumer::ConsumerSubscriber;
-use log::trace;
+use log::{trace, warn};
 use parking_lot::Mutex;
 use tokio_io::{AsyncRead, AsyncWrite};
 
 use std::collections::VecDeque;
+use std::process;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-use crate::error::Error;
+use crate::auto_ack::{AutoAckConsumer, AutoAckMode};
+use crate::channel::{BasicCancelOptions, Channel};
+use crate::error::{Error, ErrorKind};
 use crate::message::Delivery;
 use crate::transport::*;
 
+static CONSUMER_TAG_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// a consumer tag unique to this process, for the consumers given an empty one
+pub(crate) fn generate_consumer_tag() -> String {
+  format!("lapin-ctag-{}-{}", process::id(), CONSUMER_TAG_COUNTER.fetch_add(1, Ordering::Relaxed) + 1)
+}
+
 #[derive(Clone,Debug)]
-pub struct ConsumerSub {
-  inner: Arc<Mutex<ConsumerInner>>,
+pub struct ConsumerSub<D = Delivery> {
+  inner: Arc<Mutex<ConsumerInner<D>>>,
 }
 
 impl ConsumerSubscriber for ConsumerSub {
   fn new_delivery(&self, delivery: Delivery) {
     trace!("new_delivery;");
     let mut inner = self.inner.lock();
-    inner.deliveries.push_back(delivery);
-    if let Some(task) = inner.task.as_ref() {
-      task.notify();
+    if inner.prefetch_count > 0 && inner.deliveries.len() >= usize::from(inner.prefetch_count) {
+      warn!("buffering delivery {} beyond the prefetch count of {}, read by another future sharing the transport", delivery.delivery_tag, inner.prefetch_count);
     }
+    inner.push(delivery);
   }
   fn drop_prefetched_messages(&self) {
     trace!("drop_prefetched_messages;");
@@ -32,40 +46,174 @@ impl ConsumerSubscriber for ConsumerSub {
   }
   fn cancel(&self) {
     trace!("cancel;");
+    self.inner.lock().cancel(false);
+  }
+  fn cancel_by_server(&self) {
+    trace!("cancel_by_server;");
+    self.inner.lock().cancel(true);
+  }
+}
+
+impl ConsumerSubscriber for ConsumerSub<DeliveryChunk> {
+  fn new_delivery(&self, mut delivery: Delivery) {
+    trace!("new_delivery;");
+    let data = delivery.data.split_off(0);
+    let mut inner = self.inner.lock();
+    inner.push(DeliveryChunk::Start(delivery, data.len() as u64));
+    if !data.is_empty() {
+      inner.push(DeliveryChunk::Body(data));
+    }
+    inner.push(DeliveryChunk::End);
+  }
+  fn drop_prefetched_messages(&self) {
+    trace!("drop_prefetched_messages;");
     let mut inner = self.inner.lock();
     inner.deliveries.clear();
-    inner.canceled = true;
   }
+  fn cancel(&self) {
+    trace!("cancel;");
+    self.inner.lock().cancel(false);
+  }
+  fn cancel_by_server(&self) {
+    trace!("cancel_by_server;");
+    self.inner.lock().cancel(true);
+  }
+  fn streams_body(&self) -> bool {
+    true
+  }
+  fn new_delivery_header(&self, delivery: Delivery, body_size: u64) {
+    trace!("new_delivery_header; delivery_tag={} body_size={}", delivery.delivery_tag, body_size);
+    self.inner.lock().push(DeliveryChunk::Start(delivery, body_size));
+  }
+  fn new_body_chunk(&self, _delivery_tag: DeliveryTag, chunk: Vec<u8>) {
+    trace!("new_body_chunk; size={}", chunk.len());
+    self.inner.lock().push(DeliveryChunk::Body(chunk));
+  }
+  fn delivery_body_complete(&self, delivery_tag: DeliveryTag) {
+    trace!("delivery_body_complete; delivery_tag={}", delivery_tag);
+    self.inner.lock().push(DeliveryChunk::End);
+  }
+}
+
+/// A part of a delivery, yielded by the consumers of `Channel::basic_consume_chunks`
+///
+/// The parts of a delivery always come in order: `Start`, the `Body` chunks, then `End`.
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeliveryChunk {
+  /// a new delivery, with an empty `data`, and the size of its body
+  Start(Delivery, u64),
+  /// the next part of the body of the current delivery, as received in a content body frame
+  Body(Vec<u8>),
+  /// the body of the current delivery is complete
+  End,
 }
 
+/// A stream of the messages delivered to a consumer, returned by `Channel::basic_consume`
+///
+/// Deliveries are buffered until the stream is polled. Dropping the `Consumer` doesn't cancel
+/// the subscription on the server, use `Channel::basic_cancel` for that: the stream ends once
+/// the buffered deliveries are consumed. If the server cancels the consumer (e.g. because its
+/// queue was deleted), the stream fails with `ErrorKind::ConsumerCanceled` instead.
+///
+/// `drain` cancels the subscription too, for a shutdown which doesn't leave buffered deliveries
+/// unprocessed.
+///
+/// The deliveries are yielded in the order the server sent them: they're buffered in a FIFO
+/// queue, and the server never interleaves the content frames of two deliveries on a channel
+/// (the channel is put in error if it does), whatever consumer they're for. Nothing is kept
+/// across a reconnection: the buffered ones are still yielded before the stream ends, and the
+/// new `Consumer` only gets the deliveries of the new connection.
+///
+/// The consumers of `Channel::basic_consume_chunks` yield `DeliveryChunk`s instead, for the bodies
+/// not to be buffered in memory.
 #[derive(Clone)]
-pub struct Consumer<T> {
-  transport:    Arc<Mutex<AMQPTransport<T>>>,
-  inner:        Arc<Mutex<ConsumerInner>>,
+pub struct Consumer<T, D = Delivery> {
+  transport:    Arc<SharedTransport<T>>,
+  inner:        Arc<Mutex<ConsumerInner<D>>>,
   channel_id:   u16,
   queue:        String,
   consumer_tag: String,
 }
 
 #[derive(Debug)]
-struct ConsumerInner {
-  deliveries: VecDeque<Delivery>,
-  task:       Option<task::Task>,
-  canceled:   bool,
+struct ConsumerInner<D> {
+  deliveries:         VecDeque<D>,
+  task:               Option<task::Task>,
+  canceled:           bool,
+  canceled_by_server: bool,
+  draining:           bool,
+  // 0 means no limit
+  prefetch_count:     u16,
 }
 
-impl Default for ConsumerInner {
+impl<D> Default for ConsumerInner<D> {
   fn default() -> Self {
     Self {
-      deliveries: VecDeque::new(),
-      task:       None,
-      canceled:   false,
+      deliveries:         VecDeque::new(),
+      task:               None,
+      canceled:           false,
+      canceled_by_server: false,
+      draining:           false,
+      prefetch_count:     0,
+    }
+  }
+}
+
+impl<D> ConsumerInner<D> {
+  fn push(&mut self, item: D) {
+    self.deliveries.push_back(item);
+    if let Some(task) = self.task.as_ref() {
+      task.notify();
+    }
+  }
+
+  fn cancel(&mut self, by_server: bool) {
+    // Keep the buffered deliveries, the stream ends once they're consumed
+    self.canceled = true;
+    self.canceled_by_server = by_server;
+    if let Some(task) = self.task.as_ref() {
+      task.notify();
     }
   }
 }
 
 impl<T: AsyncRead+AsyncWrite+Sync+Send+'static> Consumer<T> {
-  pub fn new(transport: Arc<Mutex<AMQPTransport<T>>>, channel_id: u16, queue: String, consumer_tag: String) -> Consumer<T> {
+  pub fn new(transport: Arc<SharedTransport<T>>, channel_id: u16, queue: String, consumer_tag: String) -> Consumer<T> {
+    Self::with_items(transport, channel_id, queue, consumer_tag)
+  }
+
+  /// a stream acking each delivery on `channel`, which must be the one this consumer was created
+  /// on: the processing doesn't have to call `basic_ack`
+  ///
+  /// manual acks remain the default: with `AutoAckMode::BeforeYield`, a message whose processing
+  /// fails or crashes the program is lost
+  pub fn auto_ack_stream(self, channel: &Channel<T>, mode: AutoAckMode) -> AutoAckConsumer<T> {
+    AutoAckConsumer::new(self, channel.clone(), mode)
+  }
+
+  /// cancels the consumer on `channel`, which must be the one this consumer was created on: the
+  /// stream still yields the buffered deliveries, and the ones the server sends until it
+  /// acknowledges the cancellation, then ends
+  ///
+  /// draining a consumer again, or a consumer already canceled, doesn't send anything and
+  /// resolves right away
+  pub fn drain(&self, channel: &Channel<T>) -> impl Future<Item = (), Error = Error> + Send + 'static {
+    {
+      let mut inner = self.inner.lock();
+      if inner.draining || inner.canceled {
+        trace!("consumer already draining; channel={} consumer_tag={:?}", self.channel_id, self.consumer_tag);
+        return Either::A(future::ok(()));
+      }
+      inner.draining = true;
+    }
+    trace!("draining consumer; channel={} consumer_tag={:?}", self.channel_id, self.consumer_tag);
+    Either::B(channel.basic_cancel(&self.consumer_tag, BasicCancelOptions::default()))
+  }
+}
+
+impl<T: AsyncRead+AsyncWrite+Sync+Send+'static, D> Consumer<T, D> {
+  pub(crate) fn with_items(transport: Arc<SharedTransport<T>>, channel_id: u16, queue: String, consumer_tag: String) -> Consumer<T, D> {
     Consumer {
       transport,
       inner: Arc::new(Mutex::new(ConsumerInner::default())),
@@ -79,37 +227,275 @@ impl<T: AsyncRead+AsyncWrite+Sync+Send+'static> Consumer<T> {
     self.consumer_tag = consumer_tag;
   }
 
-  pub fn subscriber(&self) -> ConsumerSub {
+  /// the tag of this consumer, as given to `basic_consume` or generated for an empty one
+  pub fn tag(&self) -> &str {
+    &self.consumer_tag
+  }
+
+  /// a local limit on the buffered deliveries (or chunks): this consumer doesn't read the
+  /// transport while they're as many as `prefetch_count`
+  ///
+  /// it isn't backpressure: the other futures sharing the transport still read it and buffer
+  /// the deliveries they get, a warning is logged then. Only the server's `basic_qos` bounds the
+  /// buffer, for the consumers which ack their deliveries
+  pub fn set_prefetch_count(&mut self, prefetch_count: u16) {
+    self.inner.lock().prefetch_count = prefetch_count;
+  }
+
+  pub fn subscriber(&self) -> ConsumerSub<D> {
     ConsumerSub {
       inner: self.inner.clone(),
     }
   }
 }
 
-impl<T: AsyncRead+AsyncWrite+Sync+Send+'static> Stream for Consumer<T> {
-  type Item = Delivery;
+impl<T: AsyncRead+AsyncWrite+Sync+Send+'static, D> Stream for Consumer<T, D> {
+  type Item = D;
   type Error = Error;
 
-  fn poll(&mut self) -> Poll<Option<Delivery>, Error> {
-    trace!("consumer poll; consumer_tag={:?} polling transport", self.consumer_tag);
-    let mut transport = self.transport.lock();
-    transport.poll()?;
+  fn poll(&mut self) -> Poll<Option<D>, Error> {
+    let (buffered, prefetch_count) = {
+      let inner = self.inner.lock();
+      (inner.deliveries.len(), inner.prefetch_count)
+    };
+    // don't read more deliveries from the network while the buffer is full
+    if prefetch_count == 0 || buffered < usize::from(prefetch_count) {
+      trace!("consumer poll; channel={} consumer_tag={:?} polling transport", self.channel_id, self.consumer_tag);
+      poll_shared(&self.transport)?;
+    }
     let mut inner = self.inner.lock();
-    trace!("consumer poll; consumer_tag={:?} acquired inner lock", self.consumer_tag);
+    trace!("consumer poll; channel={} consumer_tag={:?} acquired inner lock", self.channel_id, self.consumer_tag);
+    // The consumer may be polled from a different task than last time, always notify the current one
+    let task = task::current();
     if inner.task.is_none() {
-      let task = task::current();
       task.notify();
-      inner.task = Some(task);
     }
+    inner.task = Some(task);
     if let Some(delivery) = inner.deliveries.pop_front() {
-      trace!("delivery; consumer_tag={:?} delivery_tag={:?}", self.consumer_tag, delivery.delivery_tag);
+      trace!("delivery; channel={} consumer_tag={:?} status=Ready", self.channel_id, self.consumer_tag);
       Ok(Async::Ready(Some(delivery)))
     } else if inner.canceled {
-      trace!("consumer canceled; consumer_tag={:?}", self.consumer_tag);
-      Ok(Async::Ready(None))
+      trace!("consumer canceled; channel={} consumer_tag={:?} by_server={}", self.channel_id, self.consumer_tag, inner.canceled_by_server);
+      if inner.canceled_by_server {
+        // Only report it once, the stream ends afterwards
+        inner.canceled_by_server = false;
+        Err(ErrorKind::ConsumerCanceled(self.consumer_tag.clone()).into())
+      } else {
+        Ok(Async::Ready(None))
+      }
     } else {
-      trace!("delivery; consumer_tag={:?} status=NotReady", self.consumer_tag);
+      trace!("delivery; channel={} consumer_tag={:?} status=NotReady", self.channel_id, self.consumer_tag);
       Ok(Async::NotReady)
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use amq_protocol::frame::AMQPFrame;
+  use amq_protocol::protocol::{AMQPClass, basic};
+  use env_logger;
+
+  use super::*;
+  use crate::channel::{BasicProperties, BasicPublishOptions};
+  use crate::test_utils::*;
+
+  #[test]
+  fn consumer_buffer_bounded_by_prefetch() {
+    let _ = env_logger::try_init();
+
+    let conn = connected_connection();
+    let channel = connected_channel(&conn);
+    // the second delivery is waiting on the socket
+    let transport = test_transport(&conn, delivery_frames(1, "consumer", 2, &[b"hi"]));
+    let mut consumer = Consumer::new(transport.clone(), channel.id(), "queue".to_string(), "consumer".to_string());
+    consumer.set_prefetch_count(1);
+    register_consumer(&channel, "queue", "consumer", consumer.subscriber());
+
+    // the first one is already buffered
+    for frame in delivery_frames(1, "consumer", 1, &[b"hi"]) {
+      conn.handle_frame(frame).unwrap();
+    }
+
+    future::lazy(move || {
+      match consumer.poll() {
+        Ok(Async::Ready(Some(delivery))) => assert_eq!(delivery.delivery_tag, 1),
+        res                              => panic!("expected the buffered delivery, got {:?}", res.map(|_| ())),
+      }
+      assert_eq!(transport.lock().stream().0.position(), 0);
+      match consumer.poll() {
+        Ok(Async::Ready(Some(delivery))) => assert_eq!(delivery.delivery_tag, 2),
+        res                              => panic!("expected the second delivery, got {:?}", res.map(|_| ())),
+      }
+      Ok::<(), ()>(())
+    }).wait().unwrap();
+  }
+
+  #[test]
+  fn consumer_canceled_by_server() {
+    let _ = env_logger::try_init();
+
+    let conn = connected_connection();
+    let channel = connected_channel(&conn);
+    // the server cancels the consumer, e.g. because its queue got deleted
+    let transport = test_transport(&conn, vec![AMQPFrame::Method(1, AMQPClass::Basic(basic::AMQPMethod::Cancel(basic::Cancel {
+      consumer_tag: "consumer".to_string(),
+      nowait:       true,
+    })))]);
+    let mut consumer = Consumer::new(transport, channel.id(), "queue".to_string(), "consumer".to_string());
+    register_consumer(&channel, "queue", "consumer", consumer.subscriber());
+
+    future::lazy(move || {
+      match consumer.poll() {
+        Err(err) => assert_eq!(err.to_string(), ErrorKind::ConsumerCanceled("consumer".to_string()).to_string()),
+        res      => panic!("expected the consumer to be canceled, got {:?}", res.map(|_| ())),
+      }
+      // the stream ends afterwards instead of hanging
+      match consumer.poll() {
+        Ok(Async::Ready(None)) => {},
+        res                    => panic!("expected the stream to end, got {:?}", res.map(|_| ())),
+      }
+      Ok::<(), ()>(())
+    }).wait().unwrap();
+  }
+
+  #[test]
+  fn consumer_drain() {
+    let _ = env_logger::try_init();
+
+    let conn = connected_connection();
+    let inner = connected_channel(&conn);
+    // a delivery still in flight when the cancellation is sent, then its acknowledgement
+    let mut frames = delivery_frames(1, "consumer", 2, &[]);
+    frames.push(AMQPFrame::Method(1, AMQPClass::Basic(basic::AMQPMethod::CancelOk(basic::CancelOk {
+      consumer_tag: "consumer".to_string(),
+    }))));
+    let transport = test_transport(&conn, frames);
+    let mut consumer = Consumer::new(transport.clone(), inner.id(), "queue".to_string(), "consumer".to_string());
+    register_consumer(&inner, "queue", "consumer", consumer.subscriber());
+    consumer.subscriber().new_delivery(Delivery::new(1, "".to_string(), "queue".to_string(), false));
+    let channel = Channel::from_inner(transport, conn.clone(), inner.clone());
+
+    future::lazy(move || {
+      consumer.drain(&channel).wait().unwrap();
+      // the buffered delivery is still yielded, then the one which came before the CancelOk
+      for delivery_tag in 1..=2 {
+        match consumer.poll() {
+          Ok(Async::Ready(Some(delivery))) => assert_eq!(delivery.delivery_tag, delivery_tag),
+          res                              => panic!("expected delivery {}, got {:?}", delivery_tag, res.map(|_| ())),
+        }
+      }
+      assert!(matches!(consumer.poll(), Ok(Async::Ready(None))));
+      // draining again doesn't send another cancellation
+      consumer.drain(&channel).wait().unwrap();
+      assert!(conn.next_frame().is_none());
+      Ok::<(), ()>(())
+    }).wait().unwrap();
+  }
+
+  #[test]
+  fn consumer_yields_body_chunks() {
+    let _ = env_logger::try_init();
+
+    let conn = connected_connection();
+    let channel = connected_channel(&conn);
+    let transport = test_transport(&conn, delivery_frames(1, "consumer", 1, &[b"hel", b"lo"]));
+    let mut consumer: Consumer<_, DeliveryChunk> = Consumer::with_items(transport, channel.id(), "queue".to_string(), "consumer".to_string());
+    register_consumer(&channel, "queue", "consumer", consumer.subscriber());
+
+    future::lazy(move || {
+      match consumer.poll() {
+        Ok(Async::Ready(Some(DeliveryChunk::Start(delivery, 5)))) => {
+          assert_eq!(delivery.delivery_tag, 1);
+          assert!(delivery.data.is_empty());
+        },
+        res => panic!("expected the start of the delivery, got {:?}", res.map(|_| ())),
+      }
+      match consumer.poll() {
+        Ok(Async::Ready(chunk)) => assert_eq!(chunk, Some(DeliveryChunk::Body(b"hel".to_vec()))),
+        res                     => panic!("expected a body chunk, got {:?}", res.map(|_| ())),
+      }
+      match consumer.poll() {
+        Ok(Async::Ready(chunk)) => assert_eq!(chunk, Some(DeliveryChunk::Body(b"lo".to_vec()))),
+        res                     => panic!("expected a body chunk, got {:?}", res.map(|_| ())),
+      }
+      match consumer.poll() {
+        Ok(Async::Ready(chunk)) => assert_eq!(chunk, Some(DeliveryChunk::End)),
+        res                     => panic!("expected the end of the delivery, got {:?}", res.map(|_| ())),
+      }
+      Ok::<(), ()>(())
+    }).wait().unwrap();
+  }
+
+  #[test]
+  fn consumers_keep_delivery_order() {
+    let _ = env_logger::try_init();
+
+    const COUNT: u64 = 1000;
+
+    let conn = connected_connection();
+    let channel = connected_channel(&conn);
+    // both consumers of the channel get every other message, each body split in two frames
+    let mut frames = Vec::new();
+    for number in 0..COUNT {
+      let body = number.to_string().repeat(1 + number as usize % 7).into_bytes();
+      let (start, end) = body.split_at(body.len() / 2);
+      frames.extend(delivery_frames(1, if number % 2 == 0 { "even" } else { "odd" }, number + 1, &[start, end]));
+    }
+    let transport = test_transport(&conn, frames);
+    let mut consumers = Vec::new();
+    for tag in &["even", "odd"] {
+      let consumer: Consumer<_> = Consumer::with_items(transport.clone(), channel.id(), "queue".to_string(), tag.to_string());
+      register_consumer(&channel, "queue", tag, consumer.subscriber());
+      consumers.push(consumer);
+    }
+
+    future::lazy(move || {
+      for (first, consumer) in consumers.iter_mut().enumerate() {
+        for number in (first as u64..COUNT).step_by(2) {
+          match consumer.poll() {
+            Ok(Async::Ready(Some(delivery))) => {
+              assert_eq!(delivery.delivery_tag, number + 1);
+              assert_eq!(delivery.data, number.to_string().repeat(1 + number as usize % 7).into_bytes());
+            },
+            res => panic!("expected delivery {}, got {:?}", number + 1, res.map(|_| ())),
+          }
+        }
+      }
+      Ok::<(), ()>(())
+    }).wait().unwrap();
+  }
+
+  #[test]
+  fn empty_message_roundtrip() {
+    let _ = env_logger::try_init();
+
+    let conn = connected_connection();
+    let inner = connected_channel(&conn);
+    let transport = test_transport(&conn, delivery_frames(1, "consumer", 1, &[]));
+    let mut consumer = Consumer::new(transport.clone(), inner.id(), "queue".to_string(), "consumer".to_string());
+    register_consumer(&inner, "queue", "consumer", consumer.subscriber());
+    let channel = Channel::from_inner(transport, conn.clone(), inner);
+
+    future::lazy(move || {
+      let mut publish = channel.basic_publish("", "queue", Vec::new(), BasicPublishOptions::default(), BasicProperties::default());
+      // a content header announcing an empty body, and no body frame
+      assert!(matches!(conn.next_frame(), Some(AMQPFrame::Method(1, AMQPClass::Basic(basic::AMQPMethod::Publish(_))))));
+      match conn.next_frame() {
+        Some(AMQPFrame::Header(1, 60, header)) => assert_eq!(header.body_size, 0),
+        frame                                  => panic!("expected a content header, got {:?}", frame),
+      }
+      assert_eq!(conn.next_frame(), None);
+      assert!(matches!(publish.poll(), Ok(Async::Ready(None))));
+
+      match consumer.poll() {
+        Ok(Async::Ready(Some(delivery))) => {
+          assert_eq!(delivery.delivery_tag, 1);
+          assert!(delivery.data.is_empty());
+        },
+        res => panic!("expected an empty delivery, got {:?}", res.map(|_| ())),
+      }
+      Ok::<(), ()>(())
+    }).wait().unwrap();
+  }
+}
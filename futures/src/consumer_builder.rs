@@ -0,0 +1,289 @@
+use futures::{Future, Stream, future::{self, Either}, stream};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use crate::channel::{BasicConsumeOptions, BasicQosOptions, Channel, QueueBindOptions, QueueDeclareOptions};
+use crate::consumer::Consumer;
+use crate::error::Error;
+use crate::types::{FieldTable, ShortUInt};
+
+/// Declares a queue, binds it, sets the prefetch count and starts consuming it in one go
+///
+/// Returned by `Channel::consumer_builder`. `consume` issues the requests in that order and fails
+/// as soon as one of them fails. The low-level methods of `Channel` remain available for what
+/// the builder doesn't cover.
+///
+/// The builder can be kept around and reused with `for_channel` to set the same topology up again
+/// on a new connection, e.g. after a `RecoveryEvent::Connected`. The consumer keeps the same tag
+/// then if one was set with `consumer_tag`, otherwise each `consume` generates a new one.
+pub struct ConsumerBuilder<T> {
+  channel:           Channel<T>,
+  queue:             String,
+  queue_options:     QueueDeclareOptions,
+  queue_arguments:   FieldTable,
+  bindings:          Vec<(String, String, FieldTable)>,
+  prefetch_count:    Option<ShortUInt>,
+  consumer_tag:      String,
+  consume_options:   BasicConsumeOptions,
+  consume_arguments: FieldTable,
+}
+
+impl<T> Clone for ConsumerBuilder<T>
+where T: Send {
+  fn clone(&self) -> Self {
+    Self {
+      channel:           self.channel.clone(),
+      queue:             self.queue.clone(),
+      queue_options:     self.queue_options.clone(),
+      queue_arguments:   self.queue_arguments.clone(),
+      bindings:          self.bindings.clone(),
+      prefetch_count:    self.prefetch_count,
+      consumer_tag:      self.consumer_tag.clone(),
+      consume_options:   self.consume_options.clone(),
+      consume_arguments: self.consume_arguments.clone(),
+    }
+  }
+}
+
+impl<T: AsyncRead+AsyncWrite+Send+Sync+'static> ConsumerBuilder<T> {
+  pub(crate) fn new(channel: Channel<T>) -> Self {
+    Self {
+      channel,
+      queue:             String::new(),
+      queue_options:     QueueDeclareOptions::default(),
+      queue_arguments:   FieldTable::new(),
+      bindings:          Vec::new(),
+      prefetch_count:    None,
+      consumer_tag:      String::new(),
+      consume_options:   BasicConsumeOptions::default(),
+      consume_arguments: FieldTable::new(),
+    }
+  }
+
+  /// the queue to declare and consume, the server generates a name if it's empty (the default)
+  pub fn queue(mut self, name: &str) -> Self {
+    self.queue = name.to_string();
+    self
+  }
+
+  pub fn durable(mut self, durable: bool) -> Self {
+    self.queue_options.durable = durable;
+    self
+  }
+
+  /// the queue is only usable from this connection, and deleted once it's closed
+  pub fn exclusive_queue(mut self, exclusive: bool) -> Self {
+    self.queue_options.exclusive = exclusive;
+    self
+  }
+
+  pub fn auto_delete(mut self, auto_delete: bool) -> Self {
+    self.queue_options.auto_delete = auto_delete;
+    self
+  }
+
+  /// the arguments of the queue declaration, e.g. `x-message-ttl`
+  pub fn queue_arguments(mut self, arguments: FieldTable) -> Self {
+    self.queue_arguments = arguments;
+    self
+  }
+
+  /// binds the queue to `exchange`, can be called several times
+  pub fn bind(self, exchange: &str, routing_key: &str) -> Self {
+    self.bind_with_arguments(exchange, routing_key, FieldTable::new())
+  }
+
+  /// binds the queue to `exchange` with binding arguments, e.g. for headers exchanges
+  pub fn bind_with_arguments(mut self, exchange: &str, routing_key: &str, arguments: FieldTable) -> Self {
+    self.bindings.push((exchange.to_string(), routing_key.to_string(), arguments));
+    self
+  }
+
+  /// sets the prefetch count of the consumer before it starts, see `Channel::basic_qos`
+  pub fn prefetch(mut self, prefetch_count: ShortUInt) -> Self {
+    self.prefetch_count = Some(prefetch_count);
+    self
+  }
+
+  /// the consumer tag, an empty one (the default) is generated by each `consume`, see
+  /// `Channel::basic_consume`
+  pub fn consumer_tag(mut self, consumer_tag: &str) -> Self {
+    self.consumer_tag = consumer_tag.to_string();
+    self
+  }
+
+  /// doesn't receive the messages published on this connection
+  pub fn no_local(mut self, no_local: bool) -> Self {
+    self.consume_options.no_local = no_local;
+    self
+  }
+
+  pub fn no_ack(mut self, no_ack: bool) -> Self {
+    self.consume_options.no_ack = no_ack;
+    self
+  }
+
+  /// no other consumer can consume the queue while this one does
+  pub fn exclusive_consumer(mut self, exclusive: bool) -> Self {
+    self.consume_options.exclusive = exclusive;
+    self
+  }
+
+  /// the arguments of the consumer, e.g. `x-priority`
+  pub fn consume_arguments(mut self, arguments: FieldTable) -> Self {
+    self.consume_arguments = arguments;
+    self
+  }
+
+  /// the same builder, set up on another channel
+  pub fn for_channel(&self, channel: &Channel<T>) -> Self {
+    let mut builder = self.clone();
+    builder.channel = channel.clone();
+    builder
+  }
+
+  /// declares the queue, binds it, sets the prefetch count and starts consuming it
+  pub fn consume(self) -> impl Future<Item = Consumer<T>, Error = Error> + Send + 'static {
+    let ConsumerBuilder { channel, queue, queue_options, queue_arguments, bindings, prefetch_count, consumer_tag, consume_options, consume_arguments } = self;
+    let bind_channel = channel.clone();
+    let qos_channel = channel.clone();
+
+    channel.queue_declare(&queue, queue_options, queue_arguments).and_then(move |queue| {
+      let name = queue.name();
+      stream::iter_ok(bindings).for_each(move |(exchange, routing_key, arguments)| {
+        bind_channel.queue_bind(&name, &exchange, &routing_key, QueueBindOptions::default(), arguments)
+      }).map(move |_| queue)
+    }).and_then(move |queue| {
+      let qos = match prefetch_count {
+        Some(prefetch_count) => Either::A(qos_channel.basic_qos(prefetch_count, BasicQosOptions::default())),
+        None                 => Either::B(future::ok(())),
+      };
+      qos.map(move |_| queue)
+    }).and_then(move |queue| {
+      channel.basic_consume(&queue, &consumer_tag, consume_options, consume_arguments)
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use amq_protocol::frame::AMQPFrame;
+  use amq_protocol::protocol::{AMQPClass, basic, queue};
+  use env_logger;
+  use futures::Async;
+
+  use super::*;
+  use crate::test_utils::*;
+
+  #[test]
+  fn declare_bind_and_consume() {
+    let _ = env_logger::try_init();
+
+    let conn = connected_connection();
+    let sent = record_sent_frames(&conn);
+    let next_sent = move || {
+      let mut sent = sent.lock();
+      assert_eq!(sent.len(), 1, "expected a single frame to be sent, got {:?}", *sent);
+      sent.pop()
+    };
+    let inner = connected_channel(&conn);
+    let channel = Channel::from_inner(test_transport(&conn, Vec::new()), conn.clone(), inner);
+    let builder = channel.consumer_builder()
+      .queue("jobs")
+      .durable(true)
+      .exclusive_queue(true)
+      .bind("work", "jobs.#")
+      .prefetch(10)
+      .consumer_tag("worker")
+      .no_ack(true);
+
+    future::lazy(move || {
+      // each request is only sent once the previous one is answered
+      let mut consume = builder.consume();
+      assert!(consume.poll().unwrap().is_not_ready());
+      match next_sent() {
+        Some(AMQPFrame::Method(1, AMQPClass::Queue(queue::AMQPMethod::Declare(declare)))) => {
+          assert_eq!(declare.queue, "jobs");
+          assert!(declare.durable && declare.exclusive);
+          assert!(!declare.auto_delete && !declare.passive && !declare.nowait);
+        },
+        frame => panic!("expected Queue.Declare, got {:?}", frame),
+      }
+      conn.handle_frame(AMQPFrame::Method(1, AMQPClass::Queue(queue::AMQPMethod::DeclareOk(queue::DeclareOk {
+        queue:          "jobs".to_string(),
+        message_count:  0,
+        consumer_count: 0,
+      })))).unwrap();
+
+      assert!(consume.poll().unwrap().is_not_ready());
+      match next_sent() {
+        Some(AMQPFrame::Method(1, AMQPClass::Queue(queue::AMQPMethod::Bind(bind)))) => {
+          assert_eq!((bind.queue.as_str(), bind.exchange.as_str(), bind.routing_key.as_str()), ("jobs", "work", "jobs.#"));
+        },
+        frame => panic!("expected Queue.Bind, got {:?}", frame),
+      }
+      conn.handle_frame(AMQPFrame::Method(1, AMQPClass::Queue(queue::AMQPMethod::BindOk(queue::BindOk {})))).unwrap();
+
+      assert!(consume.poll().unwrap().is_not_ready());
+      match next_sent() {
+        Some(AMQPFrame::Method(1, AMQPClass::Basic(basic::AMQPMethod::Qos(qos)))) => assert_eq!(qos.prefetch_count, 10),
+        frame                                                                     => panic!("expected Basic.Qos, got {:?}", frame),
+      }
+      conn.handle_frame(AMQPFrame::Method(1, AMQPClass::Basic(basic::AMQPMethod::QosOk(basic::QosOk {})))).unwrap();
+
+      assert!(consume.poll().unwrap().is_not_ready());
+      match next_sent() {
+        Some(AMQPFrame::Method(1, AMQPClass::Basic(basic::AMQPMethod::Consume(consume)))) => {
+          assert_eq!((consume.queue.as_str(), consume.consumer_tag.as_str()), ("jobs", "worker"));
+          // only the queue is exclusive
+          assert!(consume.no_ack && !consume.exclusive && !consume.no_local);
+        },
+        frame => panic!("expected Basic.Consume, got {:?}", frame),
+      }
+      conn.handle_frame(AMQPFrame::Method(1, AMQPClass::Basic(basic::AMQPMethod::ConsumeOk(basic::ConsumeOk {
+        consumer_tag: "worker".to_string(),
+      })))).unwrap();
+
+      match consume.poll() {
+        Ok(Async::Ready(consumer)) => assert_eq!(consumer.tag(), "worker"),
+        res                        => panic!("expected the consumer, got {:?}", res.map(|_| ())),
+      }
+      Ok::<(), ()>(())
+    }).wait().unwrap();
+  }
+
+  #[test]
+  fn clones_consume_with_their_own_tag() {
+    let _ = env_logger::try_init();
+
+    let conn = connected_connection();
+    let sent = record_sent_frames(&conn);
+    let inner = connected_channel(&conn);
+    let channel = Channel::from_inner(test_transport(&conn, Vec::new()), conn.clone(), inner);
+    let builder = channel.consumer_builder().queue("jobs");
+
+    future::lazy(move || {
+      let mut tags = Vec::new();
+      for builder in [builder.clone(), builder] {
+        let mut consume = builder.consume();
+        assert!(consume.poll().unwrap().is_not_ready());
+        conn.handle_frame(AMQPFrame::Method(1, AMQPClass::Queue(queue::AMQPMethod::DeclareOk(queue::DeclareOk {
+          queue:          "jobs".to_string(),
+          message_count:  0,
+          consumer_count: 0,
+        })))).unwrap();
+        assert!(consume.poll().unwrap().is_not_ready());
+        let consumer_tag = match sent.lock().pop() {
+          Some(AMQPFrame::Method(1, AMQPClass::Basic(basic::AMQPMethod::Consume(consume)))) => consume.consumer_tag,
+          frame                                                                            => panic!("expected Basic.Consume, got {:?}", frame),
+        };
+        conn.handle_frame(AMQPFrame::Method(1, AMQPClass::Basic(basic::AMQPMethod::ConsumeOk(basic::ConsumeOk {
+          consumer_tag: consumer_tag.clone(),
+        })))).unwrap();
+        tags.push(consumer_tag);
+      }
+      assert!(!tags[0].is_empty());
+      assert_ne!(tags[0], tags[1]);
+      Ok::<(), ()>(())
+    }).wait().unwrap();
+  }
+}
@@ -2,7 +2,8 @@ use failure::{Backtrace, Context, Fail};
 use lapin_async;
 use tokio_timer;
 
-use std::fmt;
+use std::{fmt, io};
+use std::time::Duration;
 
 use crate::transport::CodecError;
 
@@ -22,27 +23,72 @@ pub struct Error {
 ///
 /// Even though we expose the complete enumeration of possible error variants, it is not
 /// considered stable to exhaustively match on this enumeration: do it at your own risk.
+///
+/// The requests failing because the server closed the channel, for example after a failed
+/// declare, report `ChannelClosed`: its `CloseReason::error` tells apart an
+/// `AMQPSoftError::PRECONDITIONFAILED` from an `AMQPSoftError::NOTFOUND`, and so do the
+/// requests made on a channel closed by the server, otherwise they report
+/// `InvalidChannelState`. The reason of a connection close is available through
+/// `ConnectionStatus::close_reason`. `ProtocolError` wraps the other errors of lapin-async,
+/// whose `kind` tells them apart.
 #[derive(Debug, Fail)]
 #[allow(clippy::large_enum_variant)]
 pub enum ErrorKind {
+    #[fail(display = "The streamed body was announced as {} bytes but {} were sent", _0, _1)]
+    BodySizeMismatch(u64, u64),
+    #[fail(display = "The request {} was canceled", _0)]
+    Canceled(String),
+    #[fail(display = "The channel was closed: {}", _0)]
+    ChannelClosed(lapin_async::error::CloseReason),
     #[fail(display = "Failed to open channel")]
     ChannelOpenFailed,
+    #[fail(display = "The consumer {} was canceled by the server", _0)]
+    ConsumerCanceled(String),
     #[fail(display = "Couldn't decode incoming frame: {}", _0)]
     Decode(CodecError),
     #[fail(display = "The connection was closed by the remote peer")]
     ConnectionClosed,
-    #[fail(display = "Basic get returned empty")]
-    EmptyBasicGet,
+    #[fail(display = "Failed to connect to the server: {}", _0)]
+    ConnectionFailed(#[fail(cause)] io::Error),
+    #[fail(display = "The timer of the connection timeout encountered an error: {}", _0)]
+    ConnectionTimer(#[fail(cause)] tokio_timer::Error),
     #[fail(display = "Couldn't encode outcoming frame: {}", _0)]
     Encode(CodecError),
     #[fail(display = "The timer of the heartbeat encountered an error: {}", _0)]
     HeartbeatTimer(#[fail(cause)] tokio_timer::Error),
     #[fail(display = "Failed to handle incoming frame: {:?}", _0)]
     InvalidFrame(#[fail(cause)] lapin_async::error::Error),
+    #[fail(display = "The channel can't send the request in its state: {:?}", _0)]
+    InvalidChannelState(lapin_async::channel_status::ChannelState),
+    #[fail(display = "The connection can't do that in its state: {:?}", _0)]
+    InvalidConnectionState(lapin_async::connection_status::ConnectionState),
     #[fail(display = "Couldn't parse URI: {}", _0)]
     InvalidUri(String),
+    #[fail(display = "IO error: {}", _0)]
+    IOError(#[fail(cause)] io::Error),
+    #[cfg(feature = "serde_json")]
+    #[fail(display = "Couldn't serialize the message to JSON: {}", _0)]
+    JsonError(#[fail(cause)] serde_json::Error),
+    #[fail(display = "The server didn't ack a published message: {:?}", _0)]
+    MessageNotConfirmed(lapin_async::acknowledgement::Confirmation),
+    #[fail(display = "The server didn't send anything for {:?}, the connection is considered dead", _0)]
+    MissedHeartbeats(Duration),
+    #[fail(display = "No connection of the pool is established")]
+    NoHealthyConnection,
     #[fail(display = "{}: {:?}", _0, _1)]
     ProtocolError(String, #[fail(cause)] lapin_async::error::Error),
+    #[fail(display = "The timer of the connection recovery encountered an error: {}", _0)]
+    RecoveryTimer(#[fail(cause)] tokio_timer::Error),
+    #[fail(display = "The reply queue of the RPC client stopped being consumed")]
+    RpcReplyQueueClosed,
+    #[fail(display = "No response to the RPC call came within {:?}", _0)]
+    RpcTimeout(Duration),
+    #[fail(display = "The timer of the RPC call timeout encountered an error: {}", _0)]
+    RpcTimer(#[fail(cause)] tokio_timer::Error),
+    #[fail(display = "The server didn't answer to {} within {:?}", _0, _1)]
+    Timeout(String, Duration),
+    #[fail(display = "The timer of the request timeout encountered an error: {}", _0)]
+    TimeoutTimer(#[fail(cause)] tokio_timer::Error),
     /// A hack to prevent developers from exhaustively match on the enum's variants
     ///
     /// The purpose of this variant is to let the `ErrorKind` enumeration grow more variants
@@ -84,6 +130,12 @@ impl From<ErrorKind> for Error {
     }
 }
 
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        ErrorKind::IOError(error).into()
+    }
+}
+
 impl From<Context<ErrorKind>> for Error {
     fn from(inner: Context<ErrorKind>) -> Error {
         Error { inner }
@@ -94,7 +94,10 @@
 //!      // dispatching events on time.
 //!      // If we ran it as part of the "main" chain of futures, we might end up not sending
 //!      // some heartbeats if we don't poll often enough (because of some blocking task or such).
-//!      tokio::spawn(heartbeat.map_err(|_| ()));
+//!      // It's None when the heartbeat is disabled
+//!      if let Some(heartbeat) = heartbeat {
+//!        tokio::spawn(heartbeat.map_err(|_| ()));
+//!      }
 //!
 //!       // create_channel returns a future that is resolved
 //!       // once the channel is successfully created
@@ -125,12 +128,25 @@
 //! }
 //! ```
 
+pub mod auto_ack;
+#[cfg(feature = "blocking")]
+pub mod blocking;
+pub mod cancellation;
 pub mod channel;
 pub mod client;
 pub mod consumer;
+pub mod consumer_builder;
 pub mod error;
 pub mod message;
+pub mod pool;
+pub mod publish_sink;
 pub mod queue;
+pub mod recovery;
+pub mod rpc;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(test)]
+mod test_utils;
 pub mod transport;
 pub mod types;
 pub mod uri;
@@ -0,0 +1,226 @@
+use futures::{Async, Future, Poll, Stream, future, task};
+use log::{debug, warn};
+use parking_lot::Mutex;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::channel::Channel;
+use crate::client::{Client, ConnectionOptions};
+use crate::error::{Error, ErrorKind};
+use crate::recovery::{Recovery, RecoveryConfig, RecoveryEvent, RecoveryHeartbeat};
+
+/// Several connections to the same server, handing out channels from the least loaded one
+///
+/// Created with `ConnectionPool::new`, along with a `PoolDriver` which has to be spawned: it
+/// establishes the connections, drives their heartbeats and replaces the lost ones through
+/// `Client::connect_with_recovery`. Unlike with the recovery, the channels of a lost connection
+/// aren't opened again: new ones have to be created from the pool. With
+/// `RecoveryConfig::redeclare_topology`, the exchanges, queues and bindings are declared again.
+pub struct ConnectionPool<T> {
+  // `None` while the connection is being (re)established
+  clients: Arc<Mutex<Vec<Option<Client<T>>>>>,
+  next:    Arc<AtomicUsize>,
+}
+
+impl<T> Clone for ConnectionPool<T>
+where T: Send {
+  fn clone(&self) -> Self {
+    Self {
+      clients: self.clients.clone(),
+      next:    self.next.clone(),
+    }
+  }
+}
+
+impl<T: AsyncRead+AsyncWrite+Send+Sync+'static> ConnectionPool<T> {
+  /// opens `size` connections, on the streams given by clones of `stream_factory`, and retries
+  /// them according to `config`
+  ///
+  /// nothing happens until the returned `PoolDriver` is spawned
+  pub fn new<F, S>(size: usize, stream_factory: F, options: ConnectionOptions, config: RecoveryConfig) -> (Self, PoolDriver<T, F>)
+      where F: FnMut() -> S + Clone,
+            S: Future<Item = T, Error = io::Error> + Send + 'static {
+    let members = (0..size).map(|_| PoolMember {
+      recovery:  Some(Client::connect_with_recovery(stream_factory.clone(), options.clone(), config.clone()).without_channels()),
+      heartbeat: None,
+    }).collect();
+    let pool = Self::from_clients((0..size).map(|_| None).collect());
+    let driver = PoolDriver {
+      clients:    pool.clients.clone(),
+      members,
+      last_error: None,
+    };
+    (pool, driver)
+  }
+
+  pub(crate) fn from_clients(clients: Vec<Option<Client<T>>>) -> Self {
+    Self {
+      clients: Arc::new(Mutex::new(clients)),
+      next:    Arc::new(AtomicUsize::new(0)),
+    }
+  }
+
+  /// the number of connections currently established
+  pub fn healthy_count(&self) -> usize {
+    self.clients.lock().iter().flatten().filter(|client| client.status().is_connected()).count()
+  }
+
+  /// the established connection with the fewest channels, in a round-robin among the ones with
+  /// as many channels
+  pub fn client(&self) -> Option<Client<T>> {
+    let clients = self.clients.lock();
+    if clients.is_empty() {
+      return None;
+    }
+    // start after the last one we picked
+    let start = self.next.load(Ordering::Relaxed);
+    let mut best: Option<(usize, &Client<T>, usize)> = None;
+    for offset in 0..clients.len() {
+      let index = (start + offset) % clients.len();
+      if let Some(client) = &clients[index] {
+        let channel_count = client.channel_count();
+        let less_loaded = match best {
+          Some((_, _, count)) => channel_count < count,
+          None                => true,
+        };
+        if client.status().is_connected() && less_loaded {
+          best = Some((index, client, channel_count));
+        }
+      }
+    }
+    best.map(|(index, client, _)| {
+      self.next.store(index + 1, Ordering::Relaxed);
+      client.clone()
+    })
+  }
+
+  /// creates a channel on the least loaded connection, see `client`
+  ///
+  /// fails with `ErrorKind::NoHealthyConnection` if no connection is established
+  pub fn get_channel(&self) -> impl Future<Item = Channel<T>, Error = Error> + Send + 'static {
+    match self.client() {
+      Some(client) => future::Either::A(client.create_channel()),
+      None         => future::Either::B(future::err(ErrorKind::NoHealthyConnection.into())),
+    }
+  }
+}
+
+struct PoolMember<T, F> {
+  // `None` once it gave up reconnecting
+  recovery:  Option<Recovery<T, F>>,
+  heartbeat: Option<RecoveryHeartbeat>,
+}
+
+/// The task keeping the connections of a `ConnectionPool` up
+///
+/// A connection which fails to reconnect `RecoveryConfig::max_retries` times in a row is left
+/// out of the pool. The driver fails with the last error once all of them are.
+pub struct PoolDriver<T, F> {
+  clients:    Arc<Mutex<Vec<Option<Client<T>>>>>,
+  members:    Vec<PoolMember<T, F>>,
+  last_error: Option<Error>,
+}
+
+impl<T, F, S> Future for PoolDriver<T, F>
+    where T: AsyncRead+AsyncWrite+Send+Sync+'static,
+          F: FnMut() -> S,
+          S: Future<Item = T, Error = io::Error> + Send + 'static {
+  type Item = ();
+  type Error = Error;
+
+  fn poll(&mut self) -> Poll<(), Error> {
+    for (index, member) in self.members.iter_mut().enumerate() {
+      while let Some(recovery) = member.recovery.as_mut() {
+        match recovery.poll() {
+          Ok(Async::Ready(Some(RecoveryEvent::Connected(client, heartbeat)))) => {
+            debug!("connection pool; connection {} established", index);
+            self.clients.lock()[index] = Some(client);
+            member.heartbeat = heartbeat;
+          },
+          Ok(Async::Ready(Some(RecoveryEvent::Disconnected(_)))) => {
+            debug!("connection pool; connection {} lost", index);
+            self.clients.lock()[index] = None;
+            member.heartbeat = None;
+          },
+          Ok(Async::Ready(Some(RecoveryEvent::RestoreFailed(error)))) => {
+            warn!("connection pool; connection {} failed to declare its topology again: {}", index, error);
+          },
+          Ok(Async::Ready(Some(RecoveryEvent::AttemptFailed { .. }))) | Ok(Async::Ready(Some(RecoveryEvent::Restored { .. }))) => {},
+          Ok(Async::NotReady) => break,
+          // the stream only ends after failing
+          res => {
+            warn!("connection pool; connection {} gave up reconnecting", index);
+            self.clients.lock()[index] = None;
+            member.recovery = None;
+            member.heartbeat = None;
+            if let Err(error) = res {
+              self.last_error = Some(error);
+            }
+          },
+        }
+      }
+
+      if let Some(heartbeat) = member.heartbeat.as_mut() {
+        match heartbeat.poll() {
+          Ok(Async::NotReady) => {},
+          res                 => {
+            debug!("connection pool; heartbeat of connection {} stopped: {:?}", index, res.err());
+            member.heartbeat = None;
+            // poll again for the recovery to notice the lost connection
+            task::current().notify();
+          },
+        }
+      }
+    }
+
+    if self.members.iter().all(|member| member.recovery.is_none()) {
+      return match self.last_error.take() {
+        Some(error) => Err(error),
+        None        => Ok(Async::Ready(())),
+      };
+    }
+    Ok(Async::NotReady)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use env_logger;
+  use lapin_async::connection::Connection;
+  use lapin_async::connection_status::ConnectionState;
+
+  use super::*;
+  use crate::test_utils::*;
+
+  #[test]
+  fn pool_least_loaded_client() {
+    let _ = env_logger::try_init();
+
+    let client = |name: &str, channels: usize, state: ConnectionState| {
+      let conn = Connection::new();
+      conn.status.set_state(state);
+      conn.status.set_vhost(name);
+      conn.configuration.set_channel_max(2047);
+      for _ in 0..channels {
+        conn.create_channel().unwrap();
+      }
+      Client::from_transport(test_transport(&conn, Vec::new()), conn)
+    };
+
+    let pool = ConnectionPool::from_clients(vec![
+      Some(client("busy", 2, ConnectionState::Connected)),
+      None,
+      Some(client("lost", 0, ConnectionState::Error)),
+      Some(client("a", 1, ConnectionState::Connected)),
+      Some(client("b", 1, ConnectionState::Connected)),
+    ]);
+    assert_eq!(pool.healthy_count(), 3);
+    // the two least loaded ones take turns, the lost and reconnecting ones are skipped
+    let picked = (0..4).map(|_| pool.client().unwrap().status().vhost()).collect::<Vec<_>>();
+    assert_eq!(picked, vec!["a", "b", "a", "b"]);
+    assert!(ConnectionPool::<Socket>::from_clients(vec![None]).client().is_none());
+  }
+}
@@ -0,0 +1,191 @@
+use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+use futures::sync::mpsc;
+use lapin_async::connection::Connection;
+use log::trace;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use crate::channel::{Channel, Confirmation, PublishMessage};
+use crate::client::BlockedNotification;
+use crate::error::{Error, ErrorKind};
+use crate::transport::poll_shared;
+
+type PendingPublish = Box<dyn Future<Item = Option<Confirmation>, Error = Error> + Send + 'static>;
+
+/// A `Sink` publishing `PublishMessage`s on a channel, see `Channel::publish_sink`
+///
+/// the sink stops accepting messages while the connection is blocked by the server, while
+/// `ConnectionOptions::max_pending_frames` frames are waiting to be sent, or while
+/// `with_max_unconfirmed` messages are waiting for their confirmation. Flushing it resolves once
+/// all the messages are sent and, on a confirm channel, acked: a message nacked or returned by
+/// the server fails the sink with `ErrorKind::MessageNotConfirmed`
+pub struct PublishSink<T> {
+  channel:         Channel<T>,
+  conn:            Connection,
+  // only used to get woken up once the connection gets unblocked
+  blocked:         mpsc::UnboundedReceiver<BlockedNotification>,
+  pending:         Vec<PendingPublish>,
+  max_unconfirmed: usize,
+}
+
+impl<T: AsyncRead+AsyncWrite+Send+Sync+'static> PublishSink<T> {
+  pub(crate) fn new(channel: Channel<T>, conn: Connection) -> Self {
+    let blocked = channel.transport.lock().subscribe_blocked_notifications();
+    Self {
+      channel,
+      conn,
+      blocked,
+      pending:         Vec::new(),
+      max_unconfirmed: 0,
+    }
+  }
+
+  /// stop accepting messages while `max_unconfirmed` of them are waiting to be sent or
+  /// confirmed, 0 (the default) for no limit
+  pub fn with_max_unconfirmed(mut self, max_unconfirmed: usize) -> Self {
+    self.max_unconfirmed = max_unconfirmed;
+    self
+  }
+
+  /// the number of messages still waiting to be sent or confirmed
+  pub fn pending_count(&self) -> usize {
+    self.pending.len()
+  }
+
+  fn poll_pending(&mut self) -> Result<(), Error> {
+    let mut index = 0;
+    while index < self.pending.len() {
+      match self.pending[index].poll()? {
+        Async::Ready(Some(Confirmation::Ack)) | Async::Ready(None) => {
+          drop(self.pending.swap_remove(index));
+        },
+        Async::Ready(Some(confirmation)) => {
+          drop(self.pending.swap_remove(index));
+          return Err(ErrorKind::MessageNotConfirmed(confirmation).into());
+        },
+        Async::NotReady => index += 1,
+      }
+    }
+    Ok(())
+  }
+
+  fn poll_ready(&mut self) -> Poll<(), Error> {
+    while let Async::Ready(Some(_)) = self.blocked.poll().map_err(|_| ErrorKind::ConnectionClosed)? {}
+    if self.conn.status.is_blocked() {
+      trace!("publish sink; connection blocked; channel={}", self.channel.id());
+      poll_shared(&self.channel.transport)?;
+      return Ok(Async::NotReady);
+    }
+    if self.max_unconfirmed != 0 && self.pending.len() >= self.max_unconfirmed {
+      trace!("publish sink; too many unconfirmed messages; channel={} pending={}", self.channel.id(), self.pending.len());
+      return Ok(Async::NotReady);
+    }
+    self.channel.transport.lock().poll_send_buffer()
+  }
+}
+
+impl<T: AsyncRead+AsyncWrite+Send+Sync+'static> Sink for PublishSink<T> {
+  type SinkItem = PublishMessage;
+  type SinkError = Error;
+
+  fn start_send(&mut self, message: PublishMessage) -> StartSend<PublishMessage, Error> {
+    self.poll_pending()?;
+    if let Async::NotReady = self.poll_ready()? {
+      return Ok(AsyncSink::NotReady(message));
+    }
+    let PublishMessage { exchange, routing_key, payload, options, properties } = message;
+    self.pending.push(Box::new(self.channel.basic_publish(&exchange, &routing_key, payload, options, properties)));
+    Ok(AsyncSink::Ready)
+  }
+
+  fn poll_complete(&mut self) -> Poll<(), Error> {
+    self.poll_pending()?;
+    if self.pending.is_empty() {
+      Ok(Async::Ready(()))
+    } else {
+      Ok(Async::NotReady)
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use amq_protocol::frame::AMQPFrame;
+  use amq_protocol::protocol::{AMQPClass, basic};
+  use env_logger;
+  use futures::{future, stream};
+
+  use super::*;
+  use crate::test_utils::*;
+
+  #[test]
+  fn publish_sink_backpressure() {
+    let _ = env_logger::try_init();
+
+    let conn = connected_connection();
+    let inner = connected_channel(&conn);
+    let channel = Channel::from_inner(test_transport(&conn, Vec::new()), conn.clone(), inner);
+
+    let messages = (0..3).map(|i| PublishMessage::new("", "queue", vec![i]));
+    let sink = stream::iter_ok::<_, Error>(messages).forward(channel.publish_sink()).wait().unwrap().1;
+    assert_eq!(sink.pending_count(), 0);
+    assert_eq!(conn.pending_frames(), 0);
+
+    conn.status.block();
+    future::lazy(move || {
+      let mut sink = channel.publish_sink();
+      assert!(matches!(sink.start_send(PublishMessage::new("", "queue", Vec::new())), Ok(AsyncSink::NotReady(_))));
+      conn.status.unblock();
+      assert!(matches!(sink.start_send(PublishMessage::new("", "queue", Vec::new())), Ok(AsyncSink::Ready)));
+      Ok::<(), ()>(())
+    }).wait().unwrap();
+  }
+
+  #[test]
+  fn publish_sink_flush_waits_for_confirmations() {
+    let _ = env_logger::try_init();
+
+    let conn = connected_connection();
+    let inner = connected_channel(&conn);
+    inner.status.set_confirm();
+    let channel = Channel::from_inner(test_transport(&conn, Vec::new()), conn.clone(), inner);
+
+    future::lazy(move || {
+      let mut sink = channel.publish_sink();
+      assert!(matches!(sink.start_send(PublishMessage::new("", "queue", vec![1])), Ok(AsyncSink::Ready)));
+      assert!(matches!(sink.start_send(PublishMessage::new("", "queue", vec![2])), Ok(AsyncSink::Ready)));
+      // sent, but not confirmed yet
+      assert_eq!(sink.poll_complete().unwrap(), Async::NotReady);
+      assert_eq!(conn.pending_frames(), 0);
+      conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Basic(basic::AMQPMethod::Ack(basic::Ack { delivery_tag: 1, multiple: false })))).unwrap();
+      assert_eq!(sink.poll_complete().unwrap(), Async::NotReady);
+      assert_eq!(sink.pending_count(), 1);
+      conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Basic(basic::AMQPMethod::Ack(basic::Ack { delivery_tag: 2, multiple: false })))).unwrap();
+      assert_eq!(sink.poll_complete().unwrap(), Async::Ready(()));
+      assert_eq!(sink.pending_count(), 0);
+      Ok::<(), ()>(())
+    }).wait().unwrap();
+  }
+
+  #[test]
+  fn publish_sink_flush_fails_on_nack() {
+    let _ = env_logger::try_init();
+
+    let conn = connected_connection();
+    let inner = connected_channel(&conn);
+    inner.status.set_confirm();
+    let channel = Channel::from_inner(test_transport(&conn, Vec::new()), conn.clone(), inner);
+
+    future::lazy(move || {
+      let mut sink = channel.publish_sink();
+      assert!(matches!(sink.start_send(PublishMessage::new("", "queue", vec![1])), Ok(AsyncSink::Ready)));
+      assert_eq!(sink.poll_complete().unwrap(), Async::NotReady);
+      conn.handle_frame(AMQPFrame::Method(channel.id(), AMQPClass::Basic(basic::AMQPMethod::Nack(basic::Nack { delivery_tag: 1, multiple: false, requeue: false })))).unwrap();
+      match sink.poll_complete() {
+        Err(err) => assert_eq!(err.to_string(), ErrorKind::MessageNotConfirmed(Confirmation::Nack).to_string()),
+        Ok(res)  => panic!("expected the nack to fail the flush, got {:?}", res),
+      }
+      assert_eq!(sink.pending_count(), 0);
+      Ok::<(), ()>(())
+    }).wait().unwrap();
+  }
+}
@@ -1,3 +1,5 @@
+pub use lapin_async::queue_arguments::QueueArguments;
+
 #[derive(Debug, Clone)]
 pub struct Queue {
   consumer_count: u32,
@@ -0,0 +1,426 @@
+use futures::{Async, Future, Poll, Stream, future};
+use log::{debug, warn};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_timer::Delay;
+
+use std::{cmp, io, mem};
+use std::time::{Duration, Instant};
+
+use crate::channel::{BasicQosOptions, Channel, ChannelDefinition, ConfirmSelectOptions, Topology};
+use crate::client::{Client, ConnectionOptions, Heartbeat};
+use crate::consumer::Consumer;
+use crate::error::{Error, ErrorKind};
+use crate::queue::Queue;
+
+/// The heartbeat of a connection established by `Client::connect_with_recovery`, the same as
+/// the one of `Client::connect`
+pub type RecoveryHeartbeat = Heartbeat;
+
+/// Configures how `Client::connect_with_recovery` retries to connect to the server
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecoveryConfig {
+  /// how many times we retry after a failed connection attempt before giving up
+  pub max_retries:  usize,
+  /// the delay before the first retry, it doubles after each failed attempt
+  pub backoff_base: Duration,
+  /// the maximum delay between two attempts
+  pub backoff_cap:  Duration,
+  /// after a reconnection, declare the exchanges, queues and bindings of the lost connection
+  /// again (see `Client::topology`), and resume its consumers on their reopened channel
+  pub redeclare_topology: bool,
+}
+
+impl RecoveryConfig {
+  fn backoff(&self, attempt: usize) -> Duration {
+    let factor = 1u32 << cmp::min(attempt.saturating_sub(1), 31);
+    self.backoff_base.checked_mul(factor).map_or(self.backoff_cap, |backoff| cmp::min(backoff, self.backoff_cap))
+  }
+}
+
+impl Default for RecoveryConfig {
+  fn default() -> Self {
+    Self {
+      max_retries:  5,
+      backoff_base: Duration::from_millis(100),
+      backoff_cap:  Duration::from_secs(30),
+      redeclare_topology: false,
+    }
+  }
+}
+
+/// The events yielded by the `Recovery` stream
+pub enum RecoveryEvent<T> {
+  /// A connection was (re)established
+  ///
+  /// The heartbeat has to be spawned, unless it's disabled. The channels and consumers of the
+  /// previous connection don't work anymore: after a reconnection, this event is followed by
+  /// `Restored` or `RestoreFailed` once they're opened again on this new `Client`.
+  /// `Client::topology` on the previous `Client` gives the topology recorded on the lost
+  /// connection, this one only records what gets declared on it again.
+  Connected(Client<T>, Option<RecoveryHeartbeat>),
+  /// The channels of the lost connection were opened again with the same ids, confirm mode and
+  /// prefetch count, and with `RecoveryConfig::redeclare_topology`, its topology was declared
+  /// again and its consumers were resumed, in the order of `Client::channel_definitions`
+  Restored {
+    channels:  Vec<Channel<T>>,
+    consumers: Vec<Consumer<T>>,
+  },
+  /// Opening the channels or declaring the topology of the lost connection failed, the new
+  /// connection is kept
+  RestoreFailed(Error),
+  /// A connection attempt failed, the next one will happen after `retry_in`
+  AttemptFailed {
+    attempt:  usize,
+    error:    Error,
+    retry_in: Duration,
+  },
+  /// The established connection was lost, we're reconnecting
+  Disconnected(Error),
+}
+
+/// A stream (re)connecting to the server each time the connection gets lost
+///
+/// The stream fails with the last error once `RecoveryConfig::max_retries` consecutive attempts
+/// failed. It has to be polled for the connection loss to be detected.
+pub struct Recovery<T, F> {
+  stream_factory: F,
+  options:        ConnectionOptions,
+  config:         RecoveryConfig,
+  attempt:        usize,
+  state:          RecoveryState<T>,
+  // recorded on the lost connection, for the next one
+  topology:       Option<Topology>,
+  channels:       Vec<ChannelDefinition>,
+  // the connection pool hands out new channels instead
+  reopen_channels: bool,
+}
+
+type Restoring<T> = Box<dyn Future<Item = (Vec<Channel<T>>, Vec<Consumer<T>>), Error = Error> + Send + 'static>;
+
+enum RecoveryState<T> {
+  Connecting(Box<dyn Future<Item = (Client<T>, Option<RecoveryHeartbeat>), Error = Error> + Send + 'static>),
+  Waiting(Delay),
+  Restoring(Client<T>, Restoring<T>),
+  Connected(Client<T>),
+  Failed,
+}
+
+impl<T, F, S> Recovery<T, F>
+    where T: AsyncRead+AsyncWrite+Send+Sync+'static,
+          F: FnMut() -> S,
+          S: Future<Item = T, Error = io::Error> + Send + 'static {
+  pub(crate) fn new(mut stream_factory: F, options: ConnectionOptions, config: RecoveryConfig) -> Self {
+    let state = Self::connect(&mut stream_factory, options.clone());
+    Self {
+      stream_factory,
+      options,
+      config,
+      attempt: 0,
+      state,
+      topology: None,
+      channels: Vec::new(),
+      reopen_channels: true,
+    }
+  }
+
+  /// doesn't open the channels of the lost connection again
+  pub(crate) fn without_channels(mut self) -> Self {
+    self.reopen_channels = false;
+    self
+  }
+
+  fn connect(stream_factory: &mut F, options: ConnectionOptions) -> RecoveryState<T> {
+    debug!("recovery; connecting");
+    RecoveryState::Connecting(Box::new(stream_factory().map_err(|e| ErrorKind::ConnectionFailed(e).into()).and_then(move |stream| {
+      Client::connect(stream, options)
+    })))
+  }
+
+  fn reconnect(&mut self) {
+    self.state = Self::connect(&mut self.stream_factory, self.options.clone());
+  }
+}
+
+impl<T, F, S> Stream for Recovery<T, F>
+    where T: AsyncRead+AsyncWrite+Send+Sync+'static,
+          F: FnMut() -> S,
+          S: Future<Item = T, Error = io::Error> + Send + 'static {
+  type Item = RecoveryEvent<T>;
+  type Error = Error;
+
+  fn poll(&mut self) -> Poll<Option<RecoveryEvent<T>>, Error> {
+    loop {
+      match &mut self.state {
+        RecoveryState::Connecting(connecting) => {
+          match connecting.poll() {
+            Ok(Async::Ready((client, heartbeat))) => {
+              debug!("recovery; connected");
+              self.attempt = 0;
+              self.state = match self.topology.take() {
+                Some(topology) => {
+                  let channels = mem::take(&mut self.channels);
+                  let channels = if self.reopen_channels { channels } else { Vec::new() };
+                  RecoveryState::Restoring(client.clone(), restore(&client, topology, channels, self.config.redeclare_topology))
+                },
+                None           => RecoveryState::Connected(client.clone()),
+              };
+              return Ok(Async::Ready(Some(RecoveryEvent::Connected(client, heartbeat))));
+            },
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Err(error) => {
+              self.attempt += 1;
+              if self.attempt > self.config.max_retries {
+                warn!("recovery; giving up after {} attempts: {}", self.attempt, error);
+                self.state = RecoveryState::Failed;
+                return Err(error);
+              }
+              let retry_in = self.config.backoff(self.attempt);
+              warn!("recovery; attempt {} failed, retrying in {:?}: {}", self.attempt, retry_in, error);
+              self.state = RecoveryState::Waiting(Delay::new(Instant::now() + retry_in));
+              return Ok(Async::Ready(Some(RecoveryEvent::AttemptFailed { attempt: self.attempt, error, retry_in })));
+            },
+          }
+        },
+        RecoveryState::Waiting(delay) => {
+          if let Async::NotReady = delay.poll().map_err(ErrorKind::RecoveryTimer)? {
+            return Ok(Async::NotReady);
+          }
+          self.reconnect();
+        },
+        RecoveryState::Restoring(client, restoring) => {
+          let event = match restoring.poll() {
+            Ok(Async::Ready((channels, consumers))) => {
+              debug!("recovery; reopened {} channels and resumed {} consumers", channels.len(), consumers.len());
+              RecoveryEvent::Restored { channels, consumers }
+            },
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Err(error) => {
+              warn!("recovery; failed to restore the lost connection: {}", error);
+              RecoveryEvent::RestoreFailed(error)
+            },
+          };
+          self.state = RecoveryState::Connected(client.clone());
+          return Ok(Async::Ready(Some(event)));
+        },
+        RecoveryState::Connected(client) => {
+          if let Err(error) = client.poll_transport() {
+            warn!("recovery; connection lost: {}", error);
+            self.topology = Some(client.topology());
+            self.channels = client.channel_definitions();
+            self.reconnect();
+            return Ok(Async::Ready(Some(RecoveryEvent::Disconnected(error))));
+          }
+          return Ok(Async::NotReady);
+        },
+        RecoveryState::Failed => return Ok(Async::Ready(None)),
+      }
+    }
+  }
+}
+
+/// opens `channels` again on `client` with their confirm mode and prefetch count, then declares
+/// `topology` and resumes its consumers on their channel if `redeclare_topology` is set
+///
+/// the new connection only records what gets declared on it again
+fn restore<T>(client: &Client<T>, topology: Topology, channels: Vec<ChannelDefinition>, redeclare_topology: bool) -> Restoring<T>
+    where T: AsyncRead+AsyncWrite+Send+Sync+'static {
+  let reopened: Vec<_> = channels.iter().cloned().map(|definition| {
+    client.create_channel_with_id(definition.id).and_then(move |channel| {
+      let confirm = if definition.confirm {
+        future::Either::A(channel.confirm_select(ConfirmSelectOptions::default()))
+      } else {
+        future::Either::B(future::ok(()))
+      };
+      let qos_channel = channel.clone();
+      confirm.and_then(move |_| if definition.prefetch_count != 0 {
+        future::Either::A(qos_channel.basic_qos(definition.prefetch_count, BasicQosOptions { global: definition.global_qos }))
+      } else {
+        future::Either::B(future::ok(()))
+      }).map(move |_| channel)
+    })
+  }).collect();
+
+  let client = client.clone();
+  Box::new(future::join_all(reopened).and_then(move |reopened| {
+    if !redeclare_topology || topology.is_empty() {
+      return future::Either::A(future::ok((reopened, Vec::new())));
+    }
+    let declaring = match reopened.first() {
+      Some(channel) => future::Either::A(future::ok(channel.clone())),
+      None          => future::Either::B(client.create_channel()),
+    };
+    // the consumers are resumed on their own channel
+    let consumers = topology.consumers.clone();
+    future::Either::B(declaring.and_then(move |channel| {
+      channel.declare_topology_without_consumers(&topology).and_then(move |renamed| {
+        if reopened.is_empty() {
+          future::Either::A(channel.close(200, "topology declared").map(|_| (reopened, renamed)))
+        } else {
+          future::Either::B(future::ok((reopened, renamed)))
+        }
+      })
+    }).and_then(move |(reopened, renamed)| {
+      let consuming: Vec<_> = channels.iter().zip(&reopened).flat_map(|(definition, channel)| {
+        let (consumers, renamed) = (&consumers, &renamed);
+        definition.consumers.iter().filter_map(move |tag| consumers.iter().find(|consumer| consumer.tag == *tag)).map(move |consumer| {
+          let queue = renamed.get(&consumer.queue).cloned().unwrap_or_else(|| consumer.queue.clone());
+          channel.basic_consume(&Queue::new(queue, 0, 0), &consumer.tag, consumer.options(), consumer.arguments.clone())
+        })
+      }).collect();
+      future::join_all(consuming).map(move |consumers| (reopened, consumers))
+    }))
+  }))
+}
+
+#[cfg(test)]
+mod tests {
+  use amq_protocol::frame::AMQPFrame;
+  use amq_protocol::protocol::{AMQPClass, basic, channel, confirm, queue};
+  use env_logger;
+
+  use std::sync::atomic::Ordering;
+
+  use super::*;
+  use crate::channel::{BasicConsumeOptions, QueueBindOptions, QueueDeclareOptions};
+  use crate::test_utils::Broker;
+  use crate::types::FieldTable;
+
+  fn next_event<T, F, S>(recovery: Recovery<T, F>) -> (RecoveryEvent<T>, Recovery<T, F>)
+      where T: AsyncRead+AsyncWrite+Send+Sync+'static,
+            F: FnMut() -> S,
+            S: Future<Item = T, Error = io::Error> + Send + 'static {
+    match recovery.into_future().wait() {
+      Ok((Some(event), recovery)) => (event, recovery),
+      Ok((None, _))               => panic!("the recovery gave up"),
+      Err((error, _))             => panic!("the recovery failed: {}", error),
+    }
+  }
+
+  #[test]
+  fn channels_and_topology_restored() {
+    let _ = env_logger::try_init();
+
+    let first = Broker::new("amq.gen-first-");
+    let second = Broker::new("amq.gen-second-");
+    let broken = first.broken.clone();
+    let received = second.received.clone();
+    let mut brokers = vec![second, first];
+    let options = ConnectionOptions { heartbeat: 0, ..ConnectionOptions::default() };
+    let config = RecoveryConfig { redeclare_topology: true, ..RecoveryConfig::default() };
+    let recovery = Client::connect_with_recovery(move || future::ok(brokers.pop().expect("connected too many times")), options, config);
+
+    let (event, recovery) = next_event(recovery);
+    let client = match event {
+      RecoveryEvent::Connected(client, _) => client,
+      _                                   => panic!("expected a connection"),
+    };
+    let channel = client.create_confirm_channel(ConfirmSelectOptions::default()).wait().unwrap();
+    channel.basic_qos(10, BasicQosOptions::default()).wait().unwrap();
+    let queue = channel.queue_declare("", QueueDeclareOptions { exclusive: true, ..QueueDeclareOptions::default() }, FieldTable::new()).wait().unwrap();
+    assert_eq!(queue.name(), "amq.gen-first-1");
+    channel.queue_bind(&queue.name(), "amq.topic", "jobs.#", QueueBindOptions::default(), FieldTable::new()).wait().unwrap();
+    channel.basic_consume(&queue, "worker", BasicConsumeOptions::default(), FieldTable::new()).wait().unwrap();
+    // channels closed before the connection got lost aren't opened again
+    client.create_channel().and_then(|channel| channel.close(200, "Bye")).wait().unwrap();
+    broken.store(true, Ordering::SeqCst);
+
+    let (event, recovery) = next_event(recovery);
+    assert!(matches!(event, RecoveryEvent::Disconnected(_)));
+    let (event, recovery) = next_event(recovery);
+    let client = match event {
+      RecoveryEvent::Connected(client, _) => client,
+      _                                   => panic!("expected a reconnection"),
+    };
+    let (event, _recovery) = next_event(recovery);
+    match event {
+      RecoveryEvent::Restored { channels, consumers } => {
+        assert_eq!(channels.iter().map(Channel::id).collect::<Vec<_>>(), vec![1]);
+        assert_eq!(consumers.iter().map(|consumer| consumer.tag().to_string()).collect::<Vec<_>>(), vec!["worker".to_string()]);
+      },
+      RecoveryEvent::RestoreFailed(error) => panic!("restoring failed: {}", error),
+      _                                   => panic!("expected the restored channels"),
+    }
+
+    // the channel is set up again before the topology gets declared, and the server-named queue
+    // is bound and consumed under its new name
+    let requests: Vec<AMQPFrame> = received.lock().iter().filter(|frame| matches!(frame, AMQPFrame::Method(1, _))).cloned().collect();
+    assert_eq!(requests.len(), 6, "{:?}", requests);
+    assert!(matches!(&requests[0], AMQPFrame::Method(1, AMQPClass::Channel(channel::AMQPMethod::Open(_)))));
+    assert!(matches!(&requests[1], AMQPFrame::Method(1, AMQPClass::Confirm(confirm::AMQPMethod::Select(_)))));
+    assert!(matches!(&requests[2], AMQPFrame::Method(1, AMQPClass::Basic(basic::AMQPMethod::Qos(qos))) if qos.prefetch_count == 10));
+    assert!(matches!(&requests[3], AMQPFrame::Method(1, AMQPClass::Queue(queue::AMQPMethod::Declare(declare))) if declare.queue.is_empty() && declare.exclusive));
+    assert!(matches!(&requests[4], AMQPFrame::Method(1, AMQPClass::Queue(queue::AMQPMethod::Bind(bind))) if bind.queue == "amq.gen-second-1" && bind.routing_key == "jobs.#"));
+    assert!(matches!(&requests[5], AMQPFrame::Method(1, AMQPClass::Basic(basic::AMQPMethod::Consume(consume))) if consume.queue == "amq.gen-second-1" && consume.consumer_tag == "worker"));
+
+    assert_eq!(client.channel_definitions(), vec![ChannelDefinition {
+      id:             1,
+      confirm:        true,
+      prefetch_count: 10,
+      global_qos:     false,
+      consumers:      vec!["worker".to_string()],
+    }]);
+    let topology = client.topology();
+    assert_eq!(topology.queues.iter().map(|queue| queue.name.as_str()).collect::<Vec<_>>(), vec!["amq.gen-second-1"]);
+    assert_eq!(topology.bindings[0].destination, "amq.gen-second-1");
+    assert_eq!(topology.consumers[0].queue, "amq.gen-second-1");
+  }
+
+  #[test]
+  fn only_redeclared_topology_recorded() {
+    let _ = env_logger::try_init();
+
+    let first = Broker::new("amq.gen-first-");
+    let second = Broker::new("amq.gen-second-");
+    let broken = first.broken.clone();
+    let mut brokers = vec![second, first];
+    let options = ConnectionOptions { heartbeat: 0, ..ConnectionOptions::default() };
+    let recovery = Client::connect_with_recovery(move || future::ok(brokers.pop().expect("connected too many times")), options, RecoveryConfig::default());
+
+    let (event, recovery) = next_event(recovery);
+    let lost = match event {
+      RecoveryEvent::Connected(client, _) => client,
+      _                                   => panic!("expected a connection"),
+    };
+    let channel = lost.create_channel().wait().unwrap();
+    let queue = channel.queue_declare("", QueueDeclareOptions::default(), FieldTable::new()).wait().unwrap();
+    channel.basic_consume(&queue, "worker", BasicConsumeOptions::default(), FieldTable::new()).wait().unwrap();
+    broken.store(true, Ordering::SeqCst);
+
+    let (event, recovery) = next_event(recovery);
+    assert!(matches!(event, RecoveryEvent::Disconnected(_)));
+    let (event, recovery) = next_event(recovery);
+    let client = match event {
+      RecoveryEvent::Connected(client, _) => client,
+      _                                   => panic!("expected a reconnection"),
+    };
+    let (event, _recovery) = next_event(recovery);
+    match event {
+      RecoveryEvent::Restored { channels, consumers } => {
+        assert_eq!(channels.iter().map(Channel::id).collect::<Vec<_>>(), vec![1]);
+        assert!(consumers.is_empty());
+      },
+      RecoveryEvent::RestoreFailed(error) => panic!("restoring failed: {}", error),
+      _                                   => panic!("expected the restored channels"),
+    }
+
+    // the consumer isn't resumed, so it's not recorded on the new connection
+    assert!(client.topology().is_empty());
+    assert_eq!(client.channel_definitions(), vec![ChannelDefinition { id: 1, ..ChannelDefinition::default() }]);
+    assert_eq!(lost.topology().consumers[0].tag, "worker");
+  }
+
+  #[test]
+  fn backoff_is_capped() {
+    let config = RecoveryConfig {
+      max_retries:  10,
+      backoff_base: Duration::from_millis(100),
+      backoff_cap:  Duration::from_secs(1),
+      redeclare_topology: false,
+    };
+    assert_eq!(config.backoff(1), Duration::from_millis(100));
+    assert_eq!(config.backoff(2), Duration::from_millis(200));
+    assert_eq!(config.backoff(4), Duration::from_millis(800));
+    assert_eq!(config.backoff(5), Duration::from_secs(1));
+    assert_eq!(config.backoff(100), Duration::from_secs(1));
+  }
+}
@@ -0,0 +1,184 @@
+use futures::{Async, Future, Poll, Stream, task};
+use log::{debug, trace};
+use parking_lot::Mutex;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_timer::Delay;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::channel::{BasicConsumeOptions, BasicProperties, BasicPublishOptions, Channel};
+use crate::consumer::Consumer;
+use crate::error::{Error, ErrorKind};
+use crate::types::FieldTable;
+
+/// Sends requests and waits for their responses over AMQP
+///
+/// Created with `RpcClient::new`, it declares an exclusive queue for the responses and consumes
+/// it. Each request is published with a new `correlation_id` and with `reply_to` set to this
+/// queue: the server side has to publish its response to the default exchange, with `reply_to`
+/// as routing key and the same `correlation_id`. The responses which don't match any pending
+/// request (e.g. arriving after a timeout) are dropped.
+///
+/// The reply queue is only read while calls are pending, by the calls themselves.
+pub struct RpcClient<T> {
+  channel:     Channel<T>,
+  reply_queue: String,
+  consumer:    Arc<Mutex<Consumer<T>>>,
+  inner:       Arc<Mutex<Inner>>,
+}
+
+impl<T> Clone for RpcClient<T>
+where T: Send {
+  fn clone(&self) -> Self {
+    Self {
+      channel:     self.channel.clone(),
+      reply_queue: self.reply_queue.clone(),
+      consumer:    self.consumer.clone(),
+      inner:       self.inner.clone(),
+    }
+  }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+  correlation_id: u64,
+  // the task waiting for each pending correlation id
+  pending:        HashMap<String, Option<task::Task>>,
+  responses:      HashMap<String, Vec<u8>>,
+}
+
+impl Inner {
+  fn notify_pending(&self) {
+    for task in self.pending.values().flatten() {
+      task.notify();
+    }
+  }
+}
+
+impl<T: AsyncRead+AsyncWrite+Send+Sync+'static> RpcClient<T> {
+  /// declares the reply queue on `channel` and starts consuming it
+  pub fn new(channel: Channel<T>) -> impl Future<Item = Self, Error = Error> + Send + 'static {
+    let consume_channel = channel.clone();
+
+    channel.declare_temporary_queue().and_then(move |queue| {
+      let options = BasicConsumeOptions { no_ack: true, exclusive: true, ..BasicConsumeOptions::default() };
+      consume_channel.basic_consume(&queue, "", options, FieldTable::new()).map(move |consumer| {
+        RpcClient {
+          channel:     consume_channel,
+          reply_queue: queue.name(),
+          consumer:    Arc::new(Mutex::new(consumer)),
+          inner:       Arc::new(Mutex::new(Inner::default())),
+        }
+      })
+    })
+  }
+
+  /// the name of the queue the responses are sent to
+  pub fn reply_queue(&self) -> &str {
+    &self.reply_queue
+  }
+
+  /// publishes `body` and resolves to the body of its response
+  ///
+  /// fails with `ErrorKind::RpcTimeout` if no response came within `timeout`, a response
+  /// arriving afterwards is dropped
+  pub fn call(&self, exchange: &str, routing_key: &str, body: Vec<u8>, timeout: Duration) -> RpcCall<T> {
+    let correlation_id = {
+      let mut inner = self.inner.lock();
+      inner.correlation_id += 1;
+      let correlation_id = inner.correlation_id.to_string();
+      // register it before publishing, the response could come before we poll again
+      inner.pending.insert(correlation_id.clone(), None);
+      correlation_id
+    };
+    let properties = BasicProperties::default()
+      .with_correlation_id(correlation_id.clone())
+      .with_reply_to(self.reply_queue.clone());
+    trace!("rpc call; correlation_id={} exchange={} routing_key={}", correlation_id, exchange, routing_key);
+
+    RpcCall {
+      publish:        Some(Box::new(self.channel.basic_publish(exchange, routing_key, body, BasicPublishOptions::default(), properties).map(|_| ()))),
+      delay:          Delay::new(Instant::now() + timeout),
+      timeout,
+      correlation_id,
+      consumer:       self.consumer.clone(),
+      inner:          self.inner.clone(),
+    }
+  }
+}
+
+/// A pending request of an `RpcClient`, resolving to the body of its response
+pub struct RpcCall<T> {
+  publish:        Option<Box<dyn Future<Item = (), Error = Error> + Send + 'static>>,
+  delay:          Delay,
+  timeout:        Duration,
+  correlation_id: String,
+  consumer:       Arc<Mutex<Consumer<T>>>,
+  inner:          Arc<Mutex<Inner>>,
+}
+
+impl<T> RpcCall<T> {
+  fn poll_response(&self) -> Option<Vec<u8>> {
+    let mut inner = self.inner.lock();
+    let response = inner.responses.remove(&self.correlation_id);
+    if response.is_none() {
+      inner.pending.insert(self.correlation_id.clone(), Some(task::current()));
+    }
+    response
+  }
+}
+
+impl<T: AsyncRead+AsyncWrite+Send+Sync+'static> Future for RpcCall<T> {
+  type Item = Vec<u8>;
+  type Error = Error;
+
+  fn poll(&mut self) -> Poll<Vec<u8>, Error> {
+    if let Async::Ready(()) = self.delay.poll().map_err(ErrorKind::RpcTimer)? {
+      debug!("rpc call timed out; correlation_id={}", self.correlation_id);
+      return Err(ErrorKind::RpcTimeout(self.timeout).into());
+    }
+
+    if let Some(publish) = self.publish.as_mut() {
+      if let Async::NotReady = publish.poll()? {
+        return Ok(Async::NotReady);
+      }
+      self.publish = None;
+    }
+
+    loop {
+      if let Some(response) = self.poll_response() {
+        return Ok(Async::Ready(response));
+      }
+
+      let delivery = match self.consumer.lock().poll()? {
+        Async::Ready(Some(delivery)) => delivery,
+        Async::Ready(None)           => return Err(ErrorKind::RpcReplyQueueClosed.into()),
+        Async::NotReady              => return Ok(Async::NotReady),
+      };
+
+      let mut inner = self.inner.lock();
+      match delivery.properties.correlation_id() {
+        Some(correlation_id) if inner.pending.contains_key(correlation_id) => {
+          trace!("rpc response; correlation_id={}", correlation_id);
+          if let Some(Some(task)) = inner.pending.get(correlation_id) {
+            task.notify();
+          }
+          inner.responses.insert(correlation_id.clone(), delivery.data);
+        },
+        correlation_id => debug!("dropping rpc response without pending call; correlation_id={:?}", correlation_id),
+      }
+    }
+  }
+}
+
+impl<T> Drop for RpcCall<T> {
+  fn drop(&mut self) {
+    let mut inner = self.inner.lock();
+    inner.pending.remove(&self.correlation_id);
+    inner.responses.remove(&self.correlation_id);
+    // the consumer only wakes up the last task which polled it, let another call take over
+    inner.notify_pending();
+  }
+}
@@ -0,0 +1,251 @@
+//! helpers for the unit tests, running the transport over an in-memory socket
+
+use amq_protocol::frame::{AMQPContentHeader, AMQPFrame};
+use amq_protocol::protocol::{AMQPClass, basic, channel, confirm, connection, exchange, queue};
+use bytes::BytesMut;
+use futures::{Async, Poll, task};
+use lapin_async::channel::Channel as InnerChannel;
+use lapin_async::channel_status::ChannelState;
+use lapin_async::connection::Connection;
+use lapin_async::connection_status::ConnectionState;
+use lapin_async::consumer::{Consumer as InnerConsumer, ConsumerSubscriber};
+use lapin_async::inspector::Direction;
+use lapin_async::queue::Queue;
+use lapin_async::types::FieldTable;
+use parking_lot::Mutex;
+use tokio_codec::{Decoder, Encoder};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::channel::BasicProperties;
+use crate::transport::{AMQPCodec, AMQPTransport, SharedTransport};
+
+/// a socket reading the given bytes, then waiting for more data, and accepting every write
+pub(crate) struct Socket(pub(crate) io::Cursor<Vec<u8>>);
+
+impl io::Read for Socket {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    match self.0.read(buf)? {
+      0 => Err(io::ErrorKind::WouldBlock.into()),
+      n => Ok(n),
+    }
+  }
+}
+
+impl io::Write for Socket {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+impl AsyncRead for Socket {}
+impl AsyncWrite for Socket {
+  fn shutdown(&mut self) -> Poll<(), io::Error> {
+    Ok(Async::Ready(()))
+  }
+}
+
+/// a socket answering the requests of a client like a broker would, and recording them
+///
+/// the server-named queues are named after `queue_prefix`, and reading fails with
+/// `ConnectionReset` once `broken` is set. Reading wakes the current task up when there's
+/// nothing to read, since nothing else would
+pub(crate) struct Broker {
+  pub(crate) received: Arc<Mutex<Vec<AMQPFrame>>>,
+  pub(crate) broken:   Arc<AtomicBool>,
+  queue_prefix:        String,
+  queues:              usize,
+  codec:               AMQPCodec,
+  // the protocol header isn't a frame
+  header_received:     bool,
+  input:               BytesMut,
+  output:              io::Cursor<Vec<u8>>,
+}
+
+impl Broker {
+  pub(crate) fn new(queue_prefix: &str) -> Self {
+    Self {
+      received:        Arc::new(Mutex::new(Vec::new())),
+      broken:          Arc::new(AtomicBool::new(false)),
+      queue_prefix:    queue_prefix.to_string(),
+      queues:          0,
+      codec:           AMQPCodec { frame_max: 8192 },
+      header_received: false,
+      input:           BytesMut::new(),
+      output:          io::Cursor::new(Vec::new()),
+    }
+  }
+
+  fn answer(&mut self, frame: &AMQPFrame) -> Option<AMQPFrame> {
+    let (channel_id, method) = match frame {
+      AMQPFrame::Method(channel_id, method) => (*channel_id, method),
+      _                                     => return None,
+    };
+    let answer = match method {
+      AMQPClass::Connection(connection::AMQPMethod::StartOk(_)) => {
+        AMQPClass::Connection(connection::AMQPMethod::Tune(connection::Tune { channel_max: 2047, frame_max: 8192, heartbeat: 0 }))
+      },
+      AMQPClass::Connection(connection::AMQPMethod::Open(_)) => AMQPClass::Connection(connection::AMQPMethod::OpenOk(connection::OpenOk {})),
+      AMQPClass::Channel(channel::AMQPMethod::Open(_))       => AMQPClass::Channel(channel::AMQPMethod::OpenOk(channel::OpenOk {})),
+      AMQPClass::Channel(channel::AMQPMethod::Close(_))      => AMQPClass::Channel(channel::AMQPMethod::CloseOk(channel::CloseOk {})),
+      AMQPClass::Confirm(confirm::AMQPMethod::Select(_))     => AMQPClass::Confirm(confirm::AMQPMethod::SelectOk(confirm::SelectOk {})),
+      AMQPClass::Basic(basic::AMQPMethod::Qos(_))            => AMQPClass::Basic(basic::AMQPMethod::QosOk(basic::QosOk {})),
+      AMQPClass::Exchange(exchange::AMQPMethod::Declare(_))  => AMQPClass::Exchange(exchange::AMQPMethod::DeclareOk(exchange::DeclareOk {})),
+      AMQPClass::Queue(queue::AMQPMethod::Declare(declare))  => {
+        let queue = if declare.queue.is_empty() {
+          self.queues += 1;
+          format!("{}{}", self.queue_prefix, self.queues)
+        } else {
+          declare.queue.clone()
+        };
+        AMQPClass::Queue(queue::AMQPMethod::DeclareOk(queue::DeclareOk { queue, message_count: 0, consumer_count: 0 }))
+      },
+      AMQPClass::Queue(queue::AMQPMethod::Bind(_))           => AMQPClass::Queue(queue::AMQPMethod::BindOk(queue::BindOk {})),
+      AMQPClass::Basic(basic::AMQPMethod::Consume(consume))  => {
+        AMQPClass::Basic(basic::AMQPMethod::ConsumeOk(basic::ConsumeOk { consumer_tag: consume.consumer_tag.clone() }))
+      },
+      _                                                      => return None,
+    };
+    Some(AMQPFrame::Method(channel_id, answer))
+  }
+
+  fn send(&mut self, frames: Vec<AMQPFrame>) {
+    let mut output = self.output.get_ref()[self.output.position() as usize..].to_vec();
+    output.extend(encode(frames));
+    self.output = io::Cursor::new(output);
+  }
+}
+
+impl io::Read for Broker {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    if self.broken.load(Ordering::SeqCst) {
+      return Err(io::ErrorKind::ConnectionReset.into());
+    }
+    match self.output.read(buf)? {
+      0 => {
+        task::current().notify();
+        Err(io::ErrorKind::WouldBlock.into())
+      },
+      n => Ok(n),
+    }
+  }
+}
+
+impl io::Write for Broker {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    self.input.extend_from_slice(buf);
+    if !self.header_received {
+      if self.input.len() < 8 {
+        return Ok(buf.len());
+      }
+      self.input.split_to(8);
+      self.header_received = true;
+      self.send(vec![AMQPFrame::Method(0, AMQPClass::Connection(connection::AMQPMethod::Start(connection::Start {
+        version_major:     0,
+        version_minor:     9,
+        server_properties: FieldTable::new(),
+        mechanisms:        "PLAIN".to_string(),
+        locales:           "en_US".to_string(),
+      })))]);
+    }
+    let mut answers = Vec::new();
+    while let Some(frame) = self.codec.decode(&mut self.input).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))? {
+      answers.extend(self.answer(&frame));
+      self.received.lock().push(frame);
+    }
+    self.send(answers);
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+impl AsyncRead for Broker {}
+impl AsyncWrite for Broker {
+  fn shutdown(&mut self) -> Poll<(), io::Error> {
+    Ok(Async::Ready(()))
+  }
+}
+
+/// the bytes of `frames`, as sent by the server
+pub(crate) fn encode(frames: Vec<AMQPFrame>) -> Vec<u8> {
+  let mut codec = AMQPCodec { frame_max: 8192 };
+  let mut buffer = BytesMut::with_capacity(8192);
+  for frame in frames {
+    codec.encode(frame, &mut buffer).unwrap();
+  }
+  buffer.to_vec()
+}
+
+/// a transport for `conn`, reading `input` from the server
+pub(crate) fn test_transport(conn: &Connection, input: Vec<AMQPFrame>) -> Arc<SharedTransport<Socket>> {
+  Arc::new(SharedTransport::new(AMQPTransport::for_tests(Socket(io::Cursor::new(encode(input))), conn.clone())))
+}
+
+/// a connection done with its handshake
+pub(crate) fn connected_connection() -> Connection {
+  let conn = Connection::new();
+  conn.status.set_state(ConnectionState::Connected);
+  conn.configuration.set_channel_max(2047);
+  conn.configuration.set_frame_max(8192);
+  conn
+}
+
+/// a new channel of `conn`, already opened
+pub(crate) fn connected_channel(conn: &Connection) -> InnerChannel {
+  let channel = conn.create_channel().unwrap();
+  channel.status.set_state(ChannelState::Connected);
+  channel
+}
+
+/// the frames `conn` sends from now on
+pub(crate) fn record_sent_frames(conn: &Connection) -> Arc<Mutex<Vec<AMQPFrame>>> {
+  let sent = Arc::new(Mutex::new(Vec::new()));
+  let inspected = sent.clone();
+  conn.inspector.set(Arc::new(move |direction: &Direction, frame: &AMQPFrame| if *direction == Direction::Sent {
+    inspected.lock().push(frame.clone());
+  }));
+  sent
+}
+
+/// registers `subscriber` as the consumer `tag` of `queue`, without consuming on the server
+pub(crate) fn register_consumer<S: ConsumerSubscriber + 'static>(channel: &InnerChannel, queue: &str, tag: &str, subscriber: S) {
+  channel.queues.register(Queue::new(queue.to_string(), 0, 0));
+  channel.queues.register_consumer(queue, tag.to_string(), InnerConsumer::new(tag.to_string(), false, false, false, Box::new(subscriber)));
+}
+
+pub(crate) fn deliver(channel_id: u16, consumer_tag: &str, delivery_tag: u64) -> AMQPFrame {
+  AMQPFrame::Method(channel_id, AMQPClass::Basic(basic::AMQPMethod::Deliver(basic::Deliver {
+    consumer_tag: consumer_tag.to_string(),
+    delivery_tag,
+    redelivered:  false,
+    exchange:     "".to_string(),
+    routing_key:  "queue".to_string(),
+  })))
+}
+
+pub(crate) fn content_header(channel_id: u16, body_size: u64) -> AMQPFrame {
+  AMQPFrame::Header(channel_id, 60, Box::new(AMQPContentHeader {
+    class_id:   60,
+    weight:     0,
+    body_size,
+    properties: BasicProperties::default(),
+  }))
+}
+
+/// the frames of a delivery of `body` to the consumer `consumer_tag`, each chunk in a body frame
+pub(crate) fn delivery_frames(channel_id: u16, consumer_tag: &str, delivery_tag: u64, chunks: &[&[u8]]) -> Vec<AMQPFrame> {
+  let body_size = chunks.iter().map(|chunk| chunk.len() as u64).sum();
+  let mut frames = vec![deliver(channel_id, consumer_tag, delivery_tag), content_header(channel_id, body_size)];
+  frames.extend(chunks.iter().map(|chunk| AMQPFrame::Body(channel_id, chunk.to_vec())));
+  frames
+}
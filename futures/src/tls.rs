@@ -0,0 +1,40 @@
+//! Connecting to the server over TLS, with rustls (requires the `tls` feature)
+
+pub use tokio_rustls::{rustls, webpki};
+
+use futures::{Future, future};
+use tokio_rustls::{TlsConnector, client::TlsStream};
+use tokio_tcp::TcpStream;
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::client::{Client, ConnectionOptions, Heartbeat};
+use crate::error::{Error, ErrorKind};
+
+/// A TLS stream over TCP, as used by `Client::connect_tls`
+pub type AMQPTlsStream = TlsStream<TcpStream>;
+
+impl Client<AMQPTlsStream> {
+  /// connects to the server at `addr` over TLS, then performs the AMQP handshake like `connect`
+  ///
+  /// `domain` is sent through SNI and has to match the server certificate, which is checked
+  /// against the roots of `tls_config`. The usual port for TLS is 5671, which is the default port
+  /// of `amqps://` URIs. Nagle's algorithm is disabled on the TCP stream.
+  pub fn connect_tls(domain: &str, addr: &SocketAddr, options: ConnectionOptions, tls_config: Arc<rustls::ClientConfig>) ->
+    impl Future<Item = (Self, Option<Heartbeat>), Error = Error> + Send + 'static
+  {
+    let domain = webpki::DNSNameRef::try_from_ascii_str(domain).map(|domain| domain.to_owned()).map_err(|_| {
+      ErrorKind::ConnectionFailed(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid domain name: {}", domain))).into()
+    });
+    let connector = TlsConnector::from(tls_config);
+    let addr = *addr;
+
+    future::result(domain).and_then(move |domain| {
+      TcpStream::connect(&addr).and_then(|stream| stream.set_nodelay(true).map(|_| stream)).and_then(move |stream| {
+        connector.connect(domain.as_ref(), stream)
+      }).map_err(|e| ErrorKind::ConnectionFailed(e).into())
+    }).and_then(move |stream| Client::connect(stream, options))
+  }
+}
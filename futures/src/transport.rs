@@ -1,21 +1,31 @@
-use amq_protocol::frame::{AMQPFrame, GenError, Offset, gen_frame, parse_frame};
+use amq_protocol::{
+  frame::{AMQPFrame, GenError, Offset, gen_frame, parse_frame},
+  protocol::{AMQPClass, connection},
+};
 use lapin_async::{
   connection::Connection,
   connection_status::ConnectionState,
   credentials::Credentials,
+  inspector::Direction,
+  types::AMQPValue,
 };
 
 use bytes::{BufMut, BytesMut};
 use failure::Fail;
-use futures::{Async, AsyncSink, Poll, Sink, StartSend, Stream, Future, future};
+use futures::{Async, AsyncSink, Poll, Sink, StartSend, Stream, Future, future, task};
+use futures::sync::mpsc;
 use log::{error, trace};
+use parking_lot::{Mutex, MutexGuard};
 use std::{cmp, io};
+use std::ops::{Deref, DerefMut};
 use std::iter::repeat;
+use std::time::{Duration, Instant};
 use tokio_codec::{Decoder, Encoder, Framed};
 use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_timer::{timeout, Timeout};
 
 use crate::{
-  client::ConnectionOptions,
+  client::{BlockedNotification, ConnectionFailure, ConnectionOptions},
   error::{Error, ErrorKind},
 };
 
@@ -120,6 +130,16 @@ pub struct AMQPTransport<T> {
   upstream:  Framed<T,AMQPCodec>,
   conn:      Connection,
   heartbeat: Option<AMQPFrame>,
+  blocked:   Vec<mpsc::UnboundedSender<BlockedNotification>>,
+  failures:  Vec<mpsc::UnboundedSender<ConnectionFailure>>,
+  // when we last handed a frame to the socket, the heartbeat is only needed when idle
+  last_write: Instant,
+  // when we last received a frame, to detect a dead server
+  last_read:  Instant,
+  missed_heartbeats: u32,
+  max_pending_frames: usize,
+  // the finer heartbeat timeout asked with `ConnectionOptions::heartbeat_timeout`
+  heartbeat_timeout: Option<Duration>,
 }
 
 impl<T> AMQPTransport<T>
@@ -131,14 +151,36 @@ impl<T> AMQPTransport<T>
   ///
   /// returns a future of a `AMQPTransport` that is connected
   pub fn connect(stream: T, options: ConnectionOptions) -> impl Future<Item = AMQPTransport<T>, Error = Error> + Send + 'static {
-    let conn = Connection::new();
+    Self::connect_on(stream, options, Connection::new())
+  }
+
+  /// like `connect`, with a `Connection` which can be set up beforehand, e.g. with an inspector
+  pub(crate) fn connect_on(stream: T, options: ConnectionOptions, conn: Connection) -> impl Future<Item = AMQPTransport<T>, Error = Error> + Send + 'static {
     conn.status.set_vhost(&options.vhost);
     conn.configuration.set_frame_max(options.frame_max);
-    conn.configuration.set_heartbeat(options.heartbeat);
+    conn.configuration.set_reject_immediate(options.reject_immediate);
+    // a zero timeout would make the heartbeat tick without pause, it's ignored like `None`
+    let heartbeat_timeout = options.heartbeat_timeout.filter(|timeout| *timeout > Duration::from_secs(0));
+    conn.configuration.set_heartbeat(match heartbeat_timeout {
+      // the negotiation is in whole seconds, round up not to disable a sub-second heartbeat
+      Some(timeout) => (timeout.as_secs() + u64::from(timeout.subsec_nanos() > 0)).min(u64::from(u16::MAX)) as u16,
+      None          => options.heartbeat,
+    });
+    let connect_timeout = options.connect_timeout;
+    let missed_heartbeats = options.missed_heartbeats;
+    let max_pending_frames = options.max_pending_frames;
+
+    let mut properties = options.properties;
+    if let Some(connection_name) = options.connection_name {
+      properties.client_properties.insert("connection_name".to_string(), AMQPValue::LongString(connection_name));
+    }
 
-    future::result(conn.connect(Credentials::new(options.username, options.password), options.properties))
-      .map_err(|e| ErrorKind::ProtocolError("connection failed".to_string(), e).into())
-      .and_then(|_| {
+    let connector = future::result(conn.connect(Credentials::new(options.username, options.password), properties))
+      .map_err(|e| match e.kind() {
+        lapin_async::error::ErrorKind::InvalidConnectionState(state) => ErrorKind::InvalidConnectionState(state.clone()).into(),
+        _                                                            => ErrorKind::ProtocolError("connection failed".to_string(), e).into(),
+      })
+      .and_then(move |_| {
         let codec = AMQPCodec {
           frame_max: conn.configuration.frame_max(),
         };
@@ -146,18 +188,114 @@ impl<T> AMQPTransport<T>
           upstream:  codec.framed(stream),
           conn,
           heartbeat: Some(AMQPFrame::Heartbeat(0)),
+          blocked:   Vec::new(),
+          failures:  Vec::new(),
+          last_write: Instant::now(),
+          last_read:  Instant::now(),
+          missed_heartbeats,
+          max_pending_frames,
+          heartbeat_timeout,
         };
 
         AMQPTransportConnector {
           transport: Some(t),
         }
-    })
+    });
+
+    if let Some(connect_timeout) = connect_timeout {
+      future::Either::A(Timeout::new(connector, connect_timeout).map_err(|err: timeout::Error<Error>| {
+        if err.is_elapsed() {
+          ErrorKind::ConnectionFailed(io::Error::new(io::ErrorKind::TimedOut, "AMQP handshake timed out")).into()
+        } else if err.is_timer() {
+          ErrorKind::ConnectionTimer(err.into_timer().unwrap()).into()
+        } else {
+          err.into_inner().unwrap()
+        }
+      }))
+    } else {
+      future::Either::B(connector)
+    }
+  }
+
+  /// a transport over `stream` for a connection which is already set up, for the unit tests
+  #[cfg(test)]
+  pub(crate) fn for_tests(stream: T, conn: Connection) -> Self {
+    AMQPTransport {
+      upstream:  AMQPCodec { frame_max: 8192 }.framed(stream),
+      conn,
+      heartbeat: Some(AMQPFrame::Heartbeat(0)),
+      blocked:   Vec::new(),
+      failures:  Vec::new(),
+      last_write: Instant::now(),
+      last_read:  Instant::now(),
+      missed_heartbeats: 0,
+      max_pending_frames: 0,
+      heartbeat_timeout: None,
+    }
   }
 
   pub fn get_connection(&self) -> Connection {
     self.conn.clone()
   }
 
+  /// when the last frame was sent to the server
+  pub fn last_write(&self) -> Instant {
+    self.last_write
+  }
+
+  /// the heartbeat timeout our heartbeats are sent for: `ConnectionOptions::heartbeat_timeout`
+  /// unless the negotiated one is lower, zero when the heartbeat is disabled
+  pub fn heartbeat_timeout(&self) -> Duration {
+    let negotiated = self.negotiated_heartbeat();
+    match self.heartbeat_timeout {
+      Some(interval) if interval < negotiated => interval,
+      _                                       => negotiated,
+    }
+  }
+
+  fn negotiated_heartbeat(&self) -> Duration {
+    Duration::from_secs(self.conn.configuration.heartbeat().into())
+  }
+
+  /// fails the connection if the server didn't send anything, not even a heartbeat, during
+  /// `ConnectionOptions::missed_heartbeats` negotiated heartbeat timeouts
+  ///
+  /// the server only sends its heartbeats at the negotiated pace, whatever our own is
+  pub fn check_heartbeat(&self) -> Result<(), Error> {
+    let heartbeat = self.negotiated_heartbeat();
+    if heartbeat == Duration::from_secs(0) || self.missed_heartbeats == 0 || self.conn.status.is_connecting() {
+      return Ok(());
+    }
+    let timeout = heartbeat * self.missed_heartbeats;
+    if self.last_read.elapsed() > timeout {
+      if self.conn.status.is_connected() {
+        error!("no frame received from the server for {:?}, the connection is dead", timeout);
+        self.conn.set_error().map_err(|e| ErrorKind::ProtocolError("Failed to set connection in error state".to_string(), e))?;
+      }
+      Err(ErrorKind::MissedHeartbeats(timeout).into())
+    } else {
+      Ok(())
+    }
+  }
+
+  /// whether as many frames as `ConnectionOptions::max_pending_frames` wait to be sent
+  pub fn is_send_buffer_full(&self) -> bool {
+    self.max_pending_frames != 0 && self.conn.pending_frames() >= self.max_pending_frames
+  }
+
+  /// resolves once there's room for more frames, sending the pending ones meanwhile
+  pub fn poll_send_buffer(&mut self) -> Poll<(), Error> {
+    if self.is_send_buffer_full() {
+      self.poll()?;
+      if self.is_send_buffer_full() {
+        trace!("transport send buffer full; pending_frames={}", self.conn.pending_frames());
+        // the socket wakes us up once it's writable again
+        return Ok(Async::NotReady);
+      }
+    }
+    Ok(Async::Ready(()))
+  }
+
   /// Preemptively send an heartbeat frame
   pub fn send_heartbeat(&mut self) -> Poll<(), Error> {
     if let Some(frame) = self.heartbeat.take() {
@@ -171,6 +309,15 @@ impl<T> AMQPTransport<T>
     }))
   }
 
+  fn poll_transport(&mut self) -> Poll<Option<()>, Error> {
+    self.check_heartbeat()?;
+    if let Async::Ready(()) = self.poll_recv()? {
+      trace!("poll transport; status=Ready");
+      return Err(ErrorKind::ConnectionClosed.into());
+    }
+    self.poll_send().map(|r| r.map(Some))
+  }
+
   /// Poll the network to receive & handle incoming frames.
   ///
   /// # Return value
@@ -184,12 +331,19 @@ impl<T> AMQPTransport<T>
       match self.upstream.poll() {
         Ok(Async::Ready(Some(frame))) => {
           trace!("transport poll_recv; frame={:?}", frame);
+          self.conn.inspector.inspect(Direction::Received, &frame);
+          self.last_read = Instant::now();
+          let notification = blocked_notification(&frame);
           if let Err(e) = self.conn.handle_frame(frame) {
             return Err(ErrorKind::InvalidFrame(e).into());
           }
+          if let Some(notification) = notification {
+            self.notify_blocked(notification);
+          }
         },
         Ok(Async::Ready(None)) => {
           trace!("transport poll_recv; status=Ready(None)");
+          self.set_closed()?;
           return Ok(Async::Ready(()));
         },
         Ok(Async::NotReady) => {
@@ -198,10 +352,13 @@ impl<T> AMQPTransport<T>
         },
         Err(e) => {
           error!("transport poll_recv; status=Err({:?})", e);
+          self.set_closed_or_error(&e)?;
           return Err(ErrorKind::Decode(e).into());
         },
       };
     }
+    // the socket won't wake us up for the frames left to read
+    task::current().notify();
     Ok(Async::NotReady)
   }
 
@@ -224,6 +381,176 @@ impl<T> AMQPTransport<T>
   }
 }
 
+impl<T> AMQPTransport<T> {
+  /// registers a new listener for the Connection.Blocked and Connection.Unblocked notifications,
+  /// forgetting the ones that went away
+  pub fn subscribe_blocked_notifications(&mut self) -> mpsc::UnboundedReceiver<BlockedNotification> {
+    let (sender, receiver) = mpsc::unbounded();
+    self.blocked.retain(|sender| !sender.is_closed());
+    self.blocked.push(sender);
+    receiver
+  }
+
+  /// forwards the notification to every listener, forgetting the ones that went away
+  fn notify_blocked(&mut self, notification: BlockedNotification) {
+    self.blocked.retain(|sender| sender.unbounded_send(notification.clone()).is_ok());
+  }
+
+  /// registers a new listener for the failures of the connection, forgetting the ones that went
+  /// away
+  pub fn subscribe_failure_notifications(&mut self) -> mpsc::UnboundedReceiver<ConnectionFailure> {
+    let (sender, receiver) = mpsc::unbounded();
+    self.failures.retain(|sender| !sender.is_closed());
+    self.failures.push(sender);
+    receiver
+  }
+
+  /// tells the listeners the connection failed with `err`, unless it was closed normally
+  ///
+  /// the connection is gone afterwards, so the listeners are dropped and their streams end
+  pub(crate) fn notify_failure(&mut self, err: &Error) {
+    if self.failures.is_empty() {
+      return;
+    }
+    let failure = match err.kind() {
+      ErrorKind::MissedHeartbeats(timeout) => ConnectionFailure::MissedHeartbeats(*timeout),
+      _ => match self.conn.status.close_reason() {
+        Some(reason) => if reason.error().is_some() {
+          ConnectionFailure::Closed(reason)
+        } else {
+          return;
+        },
+        None         => ConnectionFailure::Error(err.to_string()),
+      },
+    };
+    trace!("connection failure; failure={:?}", failure);
+    for sender in self.failures.drain(..) {
+      let _ = sender.unbounded_send(failure.clone());
+    }
+  }
+
+  /// the stream wrapped by the transport
+  pub(crate) fn stream(&self) -> &T {
+    self.upstream.get_ref()
+  }
+
+  fn set_closed(&self) -> Result<(), Error> {
+    self.conn.set_closed().map_err(|e| ErrorKind::ProtocolError("Failed to close connection".to_string(), e).into())
+  }
+
+  /// An error on the socket means the connection is gone, anything else is a protocol error
+  fn set_closed_or_error(&self, err: &CodecError) -> Result<(), Error> {
+    if let CodecError::IoError(_) = err {
+      self.set_closed()
+    } else {
+      self.conn.set_error().map_err(|e| ErrorKind::ProtocolError("Failed to set connection in error state".to_string(), e).into())
+    }
+  }
+}
+
+/// The transport of a connection, shared by its channels and consumers
+///
+/// it's locked like a `Mutex`, and wakes up the tasks that found it busy in `poll_shared` once
+/// it's unlocked
+pub struct SharedTransport<T> {
+  transport: Mutex<AMQPTransport<T>>,
+  waiters:   Mutex<Vec<task::Task>>,
+}
+
+/// A locked `SharedTransport`, unlocked when dropped
+pub struct TransportGuard<'a, T> {
+  guard:   Option<MutexGuard<'a, AMQPTransport<T>>>,
+  waiters: &'a Mutex<Vec<task::Task>>,
+}
+
+impl<T> SharedTransport<T> {
+  pub fn new(transport: AMQPTransport<T>) -> Self {
+    Self {
+      transport: Mutex::new(transport),
+      waiters:   Mutex::new(Vec::new()),
+    }
+  }
+
+  /// blocks the current thread until the transport is unlocked
+  pub fn lock(&self) -> TransportGuard<'_, T> {
+    self.guard(self.transport.lock())
+  }
+
+  /// wakes up the current task the next time the transport gets unlocked, for it to check what
+  /// the frames received meanwhile changed
+  pub(crate) fn notify_on_unlock(&self) {
+    self.waiters.lock().push(task::current());
+  }
+
+  fn try_lock(&self) -> Option<TransportGuard<'_, T>> {
+    self.transport.try_lock().map(|guard| self.guard(guard))
+  }
+
+  fn guard<'a>(&'a self, guard: MutexGuard<'a, AMQPTransport<T>>) -> TransportGuard<'a, T> {
+    TransportGuard { guard: Some(guard), waiters: &self.waiters }
+  }
+}
+
+impl<'a, T> Deref for TransportGuard<'a, T> {
+  type Target = AMQPTransport<T>;
+
+  fn deref(&self) -> &AMQPTransport<T> {
+    self.guard.as_ref().expect("the transport is locked until the guard is dropped")
+  }
+}
+
+impl<'a, T> DerefMut for TransportGuard<'a, T> {
+  fn deref_mut(&mut self) -> &mut AMQPTransport<T> {
+    self.guard.as_mut().expect("the transport is locked until the guard is dropped")
+  }
+}
+
+impl<'a, T> Drop for TransportGuard<'a, T> {
+  fn drop(&mut self) {
+    // unlock before waking up the waiters, so that they can lock it
+    drop(self.guard.take());
+    for waiter in self.waiters.lock().drain(..) {
+      waiter.notify();
+    }
+  }
+}
+
+/// polls the transport shared by a connection's channels and consumers, unless another task is
+/// already polling it
+///
+/// whoever polls the transport sends the queued frames of everyone and dispatches the received
+/// ones to their channel, so a task waiting for something to be received doesn't block its
+/// thread waiting for its turn: it gets woken up once the transport is unlocked, to check its
+/// own state. The tasks waiting for their frames to be sent still lock the transport, to know
+/// when they are.
+pub(crate) fn poll_shared<T>(transport: &SharedTransport<T>) -> Poll<Option<()>, Error>
+    where T: AsyncRead + AsyncWrite + Send + 'static {
+  if let Some(mut guard) = transport.try_lock() {
+    return guard.poll();
+  }
+  transport.waiters.lock().push(task::current());
+  // the transport may have been unlocked before we were registered
+  match transport.try_lock() {
+    Some(mut guard) => guard.poll(),
+    None            => {
+      trace!("transport is busy, waiting for it to be unlocked");
+      Ok(Async::NotReady)
+    },
+  }
+}
+
+fn blocked_notification(frame: &AMQPFrame) -> Option<BlockedNotification> {
+  match frame {
+    AMQPFrame::Method(0, AMQPClass::Connection(connection::AMQPMethod::Blocked(blocked))) => {
+      Some(BlockedNotification::Blocked { reason: blocked.reason.clone() })
+    },
+    AMQPFrame::Method(0, AMQPClass::Connection(connection::AMQPMethod::Unblocked(_))) => {
+      Some(BlockedNotification::Unblocked)
+    },
+    _ => None,
+  }
+}
+
 impl<T> Stream for AMQPTransport<T>
     where T: AsyncRead + AsyncWrite,
           T: Send,
@@ -233,11 +560,11 @@ impl<T> Stream for AMQPTransport<T>
 
     fn poll(&mut self) -> Poll<Option<()>, Error> {
       trace!("transport poll");
-      if let Async::Ready(()) = self.poll_recv()? {
-        trace!("poll transport; status=Ready");
-        return Err(ErrorKind::ConnectionClosed.into());
+      let res = self.poll_transport();
+      if let Err(err) = res.as_ref() {
+        self.notify_failure(err);
       }
-      self.poll_send().map(|r| r.map(Some))
+      res
     }
 }
 
@@ -250,14 +577,45 @@ impl <T> Sink for AMQPTransport<T>
 
     fn start_send(&mut self, frame: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
         trace!("transport start_send; frame={:?}", frame);
-        self.upstream.start_send(frame)
-          .map_err(|e| ErrorKind::Encode(e).into())
+        // only pay for the copy when there's an inspector, the frame can be handed back to us
+        let inspected = if self.conn.inspector.is_set() { Some(frame.clone()) } else { None };
+        match self.upstream.start_send(frame) {
+          Ok(res) => {
+            if res.is_ready() {
+              self.last_write = Instant::now();
+              if let Some(frame) = inspected {
+                self.conn.inspector.inspect(Direction::Sent, &frame);
+              }
+            }
+            Ok(res)
+          },
+          Err(e)  => {
+            self.set_closed_or_error(&e)?;
+            Err(ErrorKind::Encode(e).into())
+          },
+        }
     }
 
     fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
         trace!("transport poll_complete");
-        self.upstream.poll_complete()
-          .map_err(|e| ErrorKind::Encode(e).into())
+        match self.upstream.poll_complete() {
+          Ok(res) => Ok(res),
+          Err(e)  => {
+            self.set_closed_or_error(&e)?;
+            Err(ErrorKind::Encode(e).into())
+          },
+        }
+    }
+
+    fn close(&mut self) -> Poll<(), Self::SinkError> {
+        trace!("transport close");
+        match self.upstream.close() {
+          Ok(res) => Ok(res),
+          Err(e)  => {
+            self.set_closed_or_error(&e)?;
+            Err(ErrorKind::Encode(e).into())
+          },
+        }
     }
 }
 
@@ -299,7 +657,8 @@ mod tests {
   use env_logger;
 
   use super::*;
-  use crate::channel::BasicProperties;
+  use crate::channel::{BasicProperties, BasicPropertiesExt};
+  use crate::test_utils::{connected_connection, test_transport};
 
   #[test]
   fn encode_multiple_frames() {
@@ -337,6 +696,45 @@ mod tests {
     assert_eq!(22, buffer.len());
   }
 
+  #[test]
+  fn properties_roundtrip() {
+    use amq_protocol::frame::AMQPContentHeader;
+    use amq_protocol::types::{AMQPValue, FieldTable};
+
+    let _ = env_logger::try_init();
+
+    let mut headers = FieldTable::default();
+    headers.insert("x-trace".to_string(), AMQPValue::LongString("abc".to_string()));
+    let properties = BasicProperties::default()
+      .with_content_type("application/json".to_string())
+      .with_headers(headers)
+      .with_delivery_mode(2)
+      .with_priority(5)
+      .with_correlation_id("42".to_string())
+      .with_reply_to("replies".to_string())
+      .with_timestamp(1_500_000_000)
+      .with_expiration_duration(Duration::from_secs(30));
+    let mut codec = AMQPCodec { frame_max: 8192 };
+    let mut buffer = BytesMut::with_capacity(8192);
+    let frame = AMQPFrame::Header(1, 60, Box::new(AMQPContentHeader {
+      class_id:   60,
+      weight:     0,
+      body_size:  64,
+      properties: properties.clone(),
+    }));
+    codec.encode(frame, &mut buffer).unwrap();
+    match codec.decode(&mut buffer).unwrap() {
+      Some(AMQPFrame::Header(1, 60, header)) => {
+        assert_eq!(header.properties, properties);
+        assert_eq!(header.properties.message_id(), &None);
+        assert_eq!(header.properties.expiration(), &Some("30000".to_string()));
+        assert_eq!(header.properties.expiration_duration(), Some(Duration::from_secs(30)));
+      },
+      frame => panic!("expected a content header, got {:?}", frame),
+    }
+    assert!(buffer.is_empty());
+  }
+
   #[test]
   fn encode_initial_extend_buffer() {
     let _ = env_logger::try_init();
@@ -380,4 +778,198 @@ mod tests {
     assert_eq!(false, r.is_err());
     assert_eq!(frame_max * 4, buffer.capacity());
   }
+
+  #[test]
+  fn connect_timeout() {
+    use std::time::Duration;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::runtime::Runtime;
+
+    let _ = env_logger::try_init();
+
+    // The server accepts the TCP connection but never answers the protocol header
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let options = ConnectionOptions {
+      connect_timeout: Some(Duration::from_millis(100)),
+      ..ConnectionOptions::default()
+    };
+
+    let res = Runtime::new().unwrap().block_on(
+      TcpStream::connect(&addr).map_err(|e| ErrorKind::ConnectionFailed(e).into()).and_then(move |stream| {
+        AMQPTransport::connect(stream, options)
+      })
+    );
+    match res.as_ref().map_err(Error::kind) {
+      Err(ErrorKind::ConnectionFailed(err)) if err.kind() == io::ErrorKind::TimedOut => {},
+      res => panic!("expected the handshake to time out, got {:?}", res.map(|_| ())),
+    }
+    drop(listener);
+  }
+
+  #[test]
+  fn missed_heartbeats() {
+    let _ = env_logger::try_init();
+
+    let conn = connected_connection();
+    conn.configuration.set_heartbeat(1);
+    let transport = test_transport(&conn, Vec::new());
+    let mut transport = transport.lock();
+    transport.last_read = Instant::now() - Duration::from_millis(1500);
+    transport.missed_heartbeats = 2;
+    assert!(transport.check_heartbeat().is_ok());
+    // the finer timeout of the options is only used below the negotiated one, and only for our
+    // heartbeats: the server still has two negotiated timeouts to send something
+    transport.heartbeat_timeout = Some(Duration::from_millis(500));
+    assert_eq!(transport.heartbeat_timeout(), Duration::from_millis(500));
+    assert!(transport.check_heartbeat().is_ok());
+    transport.heartbeat_timeout = Some(Duration::from_secs(5));
+    assert_eq!(transport.heartbeat_timeout(), Duration::from_secs(1));
+    transport.heartbeat_timeout = None;
+    let failures = transport.subscribe_failure_notifications();
+
+    transport.last_read = Instant::now() - Duration::from_secs(3);
+    match transport.poll().map_err(|e| e.kind().to_string()) {
+      Err(kind) => assert_eq!(kind, ErrorKind::MissedHeartbeats(Duration::from_secs(2)).to_string()),
+      Ok(res)   => panic!("expected the heartbeat check to fail, got {:?}", res),
+    }
+    assert_eq!(conn.status.state(), ConnectionState::Error);
+    assert!(transport.check_heartbeat().is_err());
+    // reported once, the stream ends afterwards
+    assert_eq!(failures.wait().collect::<Result<Vec<_>, _>>().unwrap(), vec![ConnectionFailure::MissedHeartbeats(Duration::from_secs(2))]);
+  }
+
+  #[test]
+  fn closed_listeners_forgotten() {
+    let transport = test_transport(&connected_connection(), Vec::new());
+    let mut transport = transport.lock();
+    for _ in 0..3 {
+      drop(transport.subscribe_blocked_notifications());
+      drop(transport.subscribe_failure_notifications());
+    }
+    let _blocked = transport.subscribe_blocked_notifications();
+    let _failures = transport.subscribe_failure_notifications();
+    assert_eq!(transport.blocked.len(), 1);
+    assert_eq!(transport.failures.len(), 1);
+  }
+
+  #[test]
+  fn busy_transport_wakes_waiters() {
+    use futures::future;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc::channel;
+    use std::thread;
+
+    let _ = env_logger::try_init();
+
+    let transport = test_transport(&connected_connection(), Vec::new());
+    let guard = transport.lock();
+    let released = Arc::new(AtomicBool::new(false));
+    let (waiting, wait) = channel();
+
+    let waiter = {
+      let transport = transport.clone();
+      let released = released.clone();
+      thread::spawn(move || {
+        let mut polls = 0;
+        future::poll_fn(move || {
+          polls += 1;
+          poll_shared(&transport)?;
+          if released.load(Ordering::SeqCst) {
+            Ok::<_, Error>(Async::Ready(polls))
+          } else {
+            waiting.send(()).unwrap();
+            Ok(Async::NotReady)
+          }
+        }).wait()
+      })
+    };
+    wait.recv().unwrap();
+    // the waiter isn't polled again until the transport is unlocked
+    thread::sleep(Duration::from_millis(50));
+    released.store(true, Ordering::SeqCst);
+    drop(guard);
+    assert_eq!(waiter.join().unwrap().unwrap(), 2);
+  }
+
+  #[test]
+  fn frame_inspector() {
+    use futures::future;
+    use lapin_async::inspector::Direction;
+    use std::sync::Arc;
+
+    let _ = env_logger::try_init();
+
+    let conn = connected_connection();
+    let frames = Arc::new(Mutex::new(Vec::new()));
+    let inspected = frames.clone();
+    conn.inspector.set(Arc::new(move |direction: &Direction, frame: &AMQPFrame| inspected.lock().push((*direction, frame.clone()))));
+    let transport = test_transport(&conn, vec![AMQPFrame::Heartbeat(0)]);
+
+    future::lazy(move || {
+      let mut transport = transport.lock();
+      transport.send_heartbeat().unwrap();
+      transport.poll().unwrap();
+      Ok::<(), ()>(())
+    }).wait().unwrap();
+    assert_eq!(*frames.lock(), vec![(Direction::Sent, AMQPFrame::Heartbeat(0)), (Direction::Received, AMQPFrame::Heartbeat(0))]);
+  }
+
+  #[test]
+  fn send_buffer_bounded() {
+    use futures::future;
+
+    // a socket accepting writes only once it's writable
+    struct Pipe {
+      writable: bool,
+    }
+
+    impl io::Read for Pipe {
+      fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::ErrorKind::WouldBlock.into())
+      }
+    }
+
+    impl io::Write for Pipe {
+      fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.writable {
+          Ok(buf.len())
+        } else {
+          Err(io::ErrorKind::WouldBlock.into())
+        }
+      }
+
+      fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+      }
+    }
+
+    impl AsyncRead for Pipe {}
+    impl AsyncWrite for Pipe {
+      fn shutdown(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+      }
+    }
+
+    let _ = env_logger::try_init();
+
+    let conn = connected_connection();
+    let mut transport = AMQPTransport::for_tests(Pipe { writable: false }, conn.clone());
+    transport.max_pending_frames = 1;
+    // more than what the codec buffers before writing to the socket
+    for _ in 0..3 {
+      conn.send_frame(AMQPFrame::Body(1, vec![0; 4096]));
+    }
+    assert!(transport.is_send_buffer_full());
+
+    future::lazy(move || {
+      assert_eq!(transport.poll_send_buffer().unwrap(), Async::NotReady);
+      assert_eq!(conn.pending_frames(), 1);
+      transport.upstream.get_mut().writable = true;
+      assert_eq!(transport.poll_send_buffer().unwrap(), Async::Ready(()));
+      assert_eq!(conn.pending_frames(), 0);
+      Ok::<(), ()>(())
+    }).wait().unwrap();
+  }
 }
@@ -3,15 +3,19 @@
 
 use env_logger;
 use failure::Error;
-use futures::{future::Future, Stream};
+use futures::{future::{self, Future}, Stream};
 use lapin_futures as lapin;
-use crate::lapin::channel::{BasicConsumeOptions,BasicPublishOptions,BasicQosOptions,BasicProperties,QueueDeclareOptions,QueueDeleteOptions,QueuePurgeOptions};
+use crate::lapin::channel::{BasicConsumeOptions,BasicPublishOptions,BasicQosOptions,BasicProperties,ConfirmSelectOptions,QueueDeclareOptions,QueueDeleteOptions,QueuePurgeOptions};
 use crate::lapin::client::ConnectionOptions;
+use crate::lapin::queue::QueueArguments;
+use crate::lapin::rpc::RpcClient;
 use crate::lapin::types::FieldTable;
 use log::info;
 use tokio::net::TcpStream;
 use tokio::runtime::Runtime;
 
+use std::time::Duration;
+
 #[test]
 fn connection() {
   let _ = env_logger::try_init();
@@ -65,3 +69,71 @@ fn connection() {
     })
   ).expect("runtime failure");
 }
+
+#[test]
+fn priority_queue() {
+  let _ = env_logger::try_init();
+
+  let addr = std::env::var("AMQP_ADDR").unwrap_or_else(|_| "127.0.0.1:5672".to_string()).parse().unwrap();
+
+  Runtime::new().unwrap().block_on_all(
+    TcpStream::connect(&addr).map_err(Error::from).and_then(|stream| {
+      lapin::client::Client::connect(stream, ConnectionOptions::default()).map_err(Error::from)
+    }).and_then(|(client, _)| {
+      client.create_confirm_channel(ConfirmSelectOptions::default()).and_then(|channel| {
+        let arguments = QueueArguments::new().max_priority(10).into_field_table();
+        channel.queue_declare("priorities", QueueDeclareOptions::default(), arguments).map(move |queue| (channel, queue))
+      }).and_then(|(channel, queue)| {
+        channel.queue_purge("priorities", QueuePurgeOptions::default()).and_then(move |_| {
+          // the publishes resolve once confirmed, so all the messages are queued before we consume
+          let publishes: Vec<_> = [1u8, 9, 5].iter().map(|priority| {
+            channel.basic_publish("", "priorities", vec![*priority], BasicPublishOptions::default(), BasicProperties::default().with_priority(*priority))
+          }).collect();
+          future::join_all(publishes).map(move |_| (channel, queue))
+        })
+      }).and_then(|(channel, queue)| {
+        let ch = channel.clone();
+        channel.basic_consume(&queue, "", BasicConsumeOptions { no_ack: true, ..Default::default() }, FieldTable::new()).and_then(|stream| {
+          stream.take(3).collect()
+        }).and_then(move |messages| {
+          let priorities: Vec<u8> = messages.iter().map(|message| message.data[0]).collect();
+          assert_eq!(priorities, vec![9, 5, 1]);
+          ch.queue_delete("priorities", QueueDeleteOptions::default())
+        })
+      }).map_err(Error::from)
+    })
+  ).expect("runtime failure");
+}
+
+#[test]
+fn rpc_call() {
+  let _ = env_logger::try_init();
+
+  let addr = std::env::var("AMQP_ADDR").unwrap_or_else(|_| "127.0.0.1:5672".to_string()).parse().unwrap();
+
+  Runtime::new().unwrap().block_on_all(
+    TcpStream::connect(&addr).map_err(Error::from).and_then(|stream| {
+      lapin::client::Client::connect(stream, ConnectionOptions::default()).map_err(Error::from)
+    }).and_then(|(client, _)| {
+      let rpc_client = client.create_channel().and_then(RpcClient::new);
+      // a server replying with the request in upper case
+      let server = client.create_channel().and_then(|channel| {
+        let options = QueueDeclareOptions { auto_delete: true, ..Default::default() };
+        channel.queue_declare("rpc_requests", options, FieldTable::new()).and_then(move |queue| {
+          channel.basic_consume(&queue, "", BasicConsumeOptions { no_ack: true, ..Default::default() }, FieldTable::new()).map(move |stream| (channel, stream))
+        })
+      });
+
+      server.join(rpc_client).and_then(|((channel, stream), rpc_client)| {
+        tokio::spawn(stream.take(1).for_each(move |request| {
+          let properties = BasicProperties::default().with_correlation_id(request.properties.correlation_id().clone().unwrap());
+          channel.basic_publish("", request.properties.reply_to().as_ref().unwrap(), request.data.to_ascii_uppercase(), BasicPublishOptions::default(), properties).map(|_| ())
+        }).map_err(|err| panic!("rpc server failed: {}", err)));
+
+        rpc_client.call("", "rpc_requests", b"ping".to_vec(), Duration::from_secs(5)).map(|response| {
+          assert_eq!(response, b"PING");
+        })
+      }).map_err(Error::from)
+    })
+  ).expect("runtime failure");
+}